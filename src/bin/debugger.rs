@@ -3,9 +3,9 @@ use std::io::{stdin, stdout, BufRead, BufReader, Write};
 
 use anyhow::{anyhow, Result};
 use clap::{crate_version, App, Arg};
+use tifs::fs::config::FsConfig;
 use tifs::fs::inode::Inode;
 use tifs::fs::key::{ScopedKey, ROOT_INODE};
-use tifs::fs::tikv_fs::TiFs;
 use tifs::fs::transaction::Txn;
 use tikv_client::TransactionClient;
 use tracing_subscriber::EnvFilter;
@@ -69,13 +69,7 @@ impl Console {
     }
 
     async fn interact(&self) -> Result<bool> {
-        let mut txn = Txn::begin_optimistic(
-            &self.client,
-            TiFs::DEFAULT_BLOCK_SIZE,
-            None,
-            TiFs::MAX_NAME_LEN,
-        )
-        .await?;
+        let mut txn = Txn::begin_optimistic(&self.client, &FsConfig::default()).await?;
         match self.interact_with_txn(&mut txn).await {
             Ok(exit) => {
                 txn.commit().await?;
@@ -108,6 +102,9 @@ impl Console {
             "get_raw" => self.get_attr_raw(txn, &commands[1..]).await?,
             "get_inline" => self.get_inline(txn, &commands[1..]).await?,
             "rm" => self.delete_block(txn, &commands[1..]).await?,
+            "orphans" => self.orphaned_blocks(txn, &commands[1..]).await?,
+            "snapshot" => self.create_snapshot(txn, &commands[1..]).await?,
+            "snapshots" => self.list_snapshots(txn).await?,
             cmd => return Err(anyhow!("unknow command `{}`", cmd)),
         }
 
@@ -210,4 +207,46 @@ impl Console {
             .await?;
         Ok(())
     }
+
+    async fn orphaned_blocks(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
+        let repair = match args.first() {
+            None | Some(&"") => false,
+            Some(&"--repair") => true,
+            Some(arg) => return Err(anyhow!("invalid argument `{}`", arg)),
+        };
+        let orphans = txn.scan_orphaned_blocks().await?;
+        for &(ino, block) in &orphans {
+            println!("<{}>[{}]", ino, block);
+            if repair {
+                txn.delete_orphaned_blocks(ino, block..block + 1).await?;
+            }
+        }
+        println!(
+            "{} orphaned blocks{}",
+            orphans.len(),
+            if repair { " removed" } else { "" }
+        );
+        Ok(())
+    }
+
+    async fn create_snapshot(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
+        match args.first() {
+            None | Some(&"") => Err(anyhow!("invalid arguments `{:?}`", args)),
+            Some(name) => {
+                let id = txn.create_snapshot((*name).into()).await?;
+                println!("snapshot({}) {}", id, name);
+                Ok(())
+            }
+        }
+    }
+
+    async fn list_snapshots(&self, txn: &mut Txn) -> Result<()> {
+        for snapshot in txn.list_snapshots().await? {
+            println!(
+                "{}\t{}\tversion({})\t{:?}",
+                snapshot.id, snapshot.name, snapshot.version, snapshot.created
+            );
+        }
+        Ok(())
+    }
 }
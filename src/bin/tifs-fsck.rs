@@ -0,0 +1,102 @@
+use anyhow::{anyhow, Result};
+use clap::{crate_version, App, Arg};
+use tifs::fs::config::FsConfig;
+use tifs::fs::crypto::EncryptionConfig;
+use tifs::fs::transaction::Txn;
+use tikv_client::TransactionClient;
+use tracing_subscriber::EnvFilter;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let matches = App::new("TiFS Fsck")
+        .version(crate_version!())
+        .author("Hexi Lee")
+        .about("check the consistency of an unmounted tifs")
+        .arg(
+            Arg::with_name("pd")
+                .long("pd-endpoints")
+                .multiple(true)
+                .value_name("ENDPOINTS")
+                .default_value("127.0.0.1:2379")
+                .help("set all pd endpoints of the tikv cluster")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("key")
+                .long("encryption-key")
+                .value_name("PATH")
+                .help("the key file the filesystem is mounted with, if it is encrypted")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("repair")
+                .long("repair")
+                .help("fix the errors found and move unreachable inodes to /lost+found"),
+        )
+        .get_matches();
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .try_init()
+        .unwrap();
+
+    let endpoints: Vec<&str> = matches
+        .values_of("pd")
+        .unwrap_or_default()
+        .to_owned()
+        .collect();
+    let repair = matches.is_present("repair");
+
+    let client = TransactionClient::new_with_config(endpoints, Default::default(), None)
+        .await
+        .map_err(|err| anyhow!("{}", err))?;
+
+    // blocks are addressed by index, so the block size must be the one the filesystem uses
+    let mut config = FsConfig::default();
+    let mut txn = Txn::begin_optimistic(&client, &config).await?;
+    let meta = txn.read_meta().await?;
+    txn.rollback().await?;
+    match meta {
+        Some(meta) => {
+            config.block_size = meta.block_size;
+            config.compression = meta.compression;
+            match matches.value_of("key") {
+                Some(path) => config.encryption = EncryptionConfig::load(path)?,
+                None if meta.encrypted => {
+                    return Err(anyhow!("tifs is encrypted: --encryption-key is required"))
+                }
+                None => (),
+            }
+        }
+        None => return Err(anyhow!("no tifs found: meta is missing")),
+    }
+
+    let mut txn = Txn::begin_optimistic(&client, &config).await?;
+    let report = match txn.fsck(repair).await {
+        Ok(report) => {
+            txn.commit().await?;
+            report
+        }
+        Err(err) => {
+            txn.rollback().await?;
+            return Err(err.into());
+        }
+    };
+
+    for err in &report.errors {
+        println!("{}", err);
+    }
+    println!(
+        "{} errors{}",
+        report.errors.len(),
+        if report.repaired && !report.is_clean() {
+            " repaired"
+        } else {
+            ""
+        }
+    );
+    if !report.is_clean() && !report.repaired {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use anyhow::{anyhow, Result};
+use clap::{crate_version, App, Arg};
+use tifs::fs::config::FsConfig;
+use tifs::fs::meta::format_uuid;
+use tifs::fs::transaction::Txn;
+use tikv_client::TransactionClient;
+use tracing_subscriber::EnvFilter;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let matches = App::new("TiFS Info")
+        .version(crate_version!())
+        .author("Hexi Lee")
+        .about("print how a tifs was created")
+        .arg(
+            Arg::with_name("pd")
+                .long("pd-endpoints")
+                .multiple(true)
+                .value_name("ENDPOINTS")
+                .default_value("127.0.0.1:2379")
+                .help("set all pd endpoints of the tikv cluster")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .try_init()
+        .unwrap();
+
+    let endpoints: Vec<&str> = matches
+        .values_of("pd")
+        .unwrap_or_default()
+        .to_owned()
+        .collect();
+
+    let client = TransactionClient::new_with_config(endpoints, Default::default(), None)
+        .await
+        .map_err(|err| anyhow!("{}", err))?;
+
+    // meta is stored in the clear, so neither the block size nor the key is needed
+    let config = FsConfig::default();
+    let mut txn = Txn::begin_optimistic(&client, &config).await?;
+    let meta = txn.read_meta().await?;
+    let used_blocks = match &meta {
+        Some(meta) => txn.blocks_in_use(meta).await?,
+        None => None,
+    };
+    txn.rollback().await?;
+    let meta = meta.ok_or_else(|| anyhow!("no tifs found: meta is missing"))?;
+
+    println!("label: {}", meta.label.as_deref().unwrap_or(""));
+    if meta.has_uuid() {
+        println!("uuid: {}", format_uuid(&meta.uuid));
+    }
+    println!("version: {}", meta.version);
+    println!("block size: {}", meta.block_size);
+    println!("compression: {:?}", meta.compression);
+    println!("encrypted: {}", meta.encrypted);
+    if let Some(threshold) = meta.inline_threshold {
+        println!("inline threshold: {}", threshold);
+    }
+    if let Some(used) = meta.used_inodes {
+        println!("inodes: {}", used);
+    }
+    if let Some(used) = used_blocks {
+        println!("blocks: {}", used);
+    }
+    Ok(())
+}
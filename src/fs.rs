@@ -1,15 +1,28 @@
+pub mod acl;
 pub mod async_fs;
 pub mod block;
+pub mod builder;
 pub mod client;
+pub mod config;
+pub mod crypto;
 pub mod dir;
 pub mod error;
 pub mod file_handler;
+pub mod fsck;
+pub mod gc;
 pub mod index;
 pub mod inode;
+pub mod inode_cache;
+pub mod ioctl;
 pub mod key;
 pub mod meta;
+pub mod metrics;
 pub mod mode;
+pub mod quota;
 pub mod reply;
+pub mod retry;
 pub mod serialize;
+pub mod snapshot;
 pub mod tikv_fs;
 pub mod transaction;
+pub mod xattr;
@@ -0,0 +1,282 @@
+//! POSIX ACLs as stored in the `system.posix_acl_access` and `system.posix_acl_default`
+//! xattrs by `setfacl`, in the `acl_ea` format of Linux: a little-endian `u32` version,
+//! then one `(u16 tag, u16 perm, u32 id)` record per entry.
+
+use std::convert::TryInto;
+
+use super::error::{FsError, Result};
+
+pub const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+pub const ACL_DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+const ACL_EA_VERSION: u32 = 2;
+const HEADER_SIZE: usize = 4;
+const ENTRY_SIZE: usize = 8;
+/// The id of entries that are not about a named user or group.
+const UNDEFINED_ID: u32 = u32::MAX;
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+pub fn is_acl_xattr(name: &str) -> bool {
+    name == ACL_ACCESS_XATTR || name == ACL_DEFAULT_XATTR
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AclTag {
+    UserObj,
+    User(u32),
+    GroupObj,
+    Group(u32),
+    Mask,
+    Other,
+}
+
+/// One line of an ACL; `perm` holds the rwx bits, which line up with `R_OK`, `W_OK`
+/// and `X_OK`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    pub perm: u16,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Acl {
+    pub entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    /// Decode an xattr value, refusing ACLs without exactly one owner, owning group and
+    /// other entry, or with named entries but no mask, as `setfacl` never writes them.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let invalid = |detail: &str| FsError::InvalidAcl(detail.to_owned());
+        if bytes.len() < HEADER_SIZE || (bytes.len() - HEADER_SIZE) % ENTRY_SIZE != 0 {
+            return Err(invalid("truncated"));
+        }
+        let (header, records) = bytes.split_at(HEADER_SIZE);
+        if u32::from_le_bytes(header.try_into().unwrap()) != ACL_EA_VERSION {
+            return Err(invalid("unknown version"));
+        }
+
+        let mut entries = Vec::with_capacity(records.len() / ENTRY_SIZE);
+        for record in records.chunks_exact(ENTRY_SIZE) {
+            let tag = u16::from_le_bytes(record[0..2].try_into().unwrap());
+            let perm = u16::from_le_bytes(record[2..4].try_into().unwrap());
+            let id = u32::from_le_bytes(record[4..8].try_into().unwrap());
+            if perm & !0o7 != 0 {
+                return Err(invalid("unknown permission bits"));
+            }
+            let tag = match tag {
+                ACL_USER_OBJ => AclTag::UserObj,
+                ACL_USER => AclTag::User(id),
+                ACL_GROUP_OBJ => AclTag::GroupObj,
+                ACL_GROUP => AclTag::Group(id),
+                ACL_MASK => AclTag::Mask,
+                ACL_OTHER => AclTag::Other,
+                _ => return Err(invalid("unknown tag")),
+            };
+            entries.push(AclEntry { tag, perm });
+        }
+
+        let count = |tag: AclTag| entries.iter().filter(|entry| entry.tag == tag).count();
+        let named = entries
+            .iter()
+            .any(|entry| matches!(entry.tag, AclTag::User(_) | AclTag::Group(_)));
+        if count(AclTag::UserObj) != 1
+            || count(AclTag::GroupObj) != 1
+            || count(AclTag::Other) != 1
+            || count(AclTag::Mask) > 1
+            || (named && count(AclTag::Mask) == 0)
+        {
+            return Err(invalid("missing or repeated entries"));
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.entries.len() * ENTRY_SIZE);
+        bytes.extend_from_slice(&ACL_EA_VERSION.to_le_bytes());
+        for entry in &self.entries {
+            let (tag, id) = match entry.tag {
+                AclTag::UserObj => (ACL_USER_OBJ, UNDEFINED_ID),
+                AclTag::User(uid) => (ACL_USER, uid),
+                AclTag::GroupObj => (ACL_GROUP_OBJ, UNDEFINED_ID),
+                AclTag::Group(gid) => (ACL_GROUP, gid),
+                AclTag::Mask => (ACL_MASK, UNDEFINED_ID),
+                AclTag::Other => (ACL_OTHER, UNDEFINED_ID),
+            };
+            bytes.extend_from_slice(&tag.to_le_bytes());
+            bytes.extend_from_slice(&entry.perm.to_le_bytes());
+            bytes.extend_from_slice(&id.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// The rwx bits of the file mode this ACL stands for: the owner entry, the mask or else
+    /// the owning group entry, and the other entry, as `ls -l` shows them.
+    pub fn mode(&self) -> u16 {
+        let perm_of = |tag: AclTag| {
+            self.entries
+                .iter()
+                .find(|entry| entry.tag == tag)
+                .map_or(0, |entry| entry.perm)
+        };
+        let group = if self.entries.iter().any(|entry| entry.tag == AclTag::Mask) {
+            perm_of(AclTag::Mask)
+        } else {
+            perm_of(AclTag::GroupObj)
+        };
+        perm_of(AclTag::UserObj) << 6 | group << 3 | perm_of(AclTag::Other)
+    }
+
+    /// Take the rwx bits of `mode` into the entries `mode` reads them from, as `chmod`
+    /// does to a file with an ACL; named entries are left, limited by the new mask.
+    pub fn set_mode(&mut self, mode: u16) {
+        let has_mask = self.entries.iter().any(|entry| entry.tag == AclTag::Mask);
+        for entry in &mut self.entries {
+            match entry.tag {
+                AclTag::UserObj => entry.perm = mode >> 6 & 0o7,
+                AclTag::Mask => entry.perm = mode >> 3 & 0o7,
+                AclTag::GroupObj if !has_mask => entry.perm = mode >> 3 & 0o7,
+                AclTag::Other => entry.perm = mode & 0o7,
+                _ => (),
+            }
+        }
+    }
+
+    /// Whether `uid` in group `gid` gets the rwx bits of `wanted` on a file owned by
+    /// `owner` and `group`, following the access check algorithm of POSIX.1e: the first
+    /// of owner, named user, groups and other that matches decides, and the mask limits
+    /// everything but the owner and other.
+    pub fn permits(&self, owner: u32, group: u32, uid: u32, gid: u32, wanted: u16) -> bool {
+        let perm_of = |tag: AclTag| {
+            self.entries
+                .iter()
+                .find(|entry| entry.tag == tag)
+                .map(|entry| entry.perm)
+        };
+        let grants = |perm: u16| wanted & !perm == 0;
+        let mask = perm_of(AclTag::Mask).unwrap_or(0o7);
+
+        if uid == owner {
+            return grants(perm_of(AclTag::UserObj).unwrap_or(0));
+        }
+        if let Some(perm) = perm_of(AclTag::User(uid)) {
+            return grants(perm & mask);
+        }
+        let groups: Vec<u16> = self
+            .entries
+            .iter()
+            .filter(|entry| match entry.tag {
+                AclTag::GroupObj => gid == group,
+                AclTag::Group(id) => gid == id,
+                _ => false,
+            })
+            .map(|entry| entry.perm)
+            .collect();
+        if !groups.is_empty() {
+            return groups.into_iter().any(|perm| grants(perm & mask));
+        }
+        grants(perm_of(AclTag::Other).unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: u32 = 1000;
+    const GUEST: u32 = 2000;
+
+    fn entry(tag: AclTag, perm: u16) -> AclEntry {
+        AclEntry { tag, perm }
+    }
+
+    /// `setfacl -m u:2000:rwx` on a file of mode 0640.
+    fn acl() -> Acl {
+        Acl {
+            entries: vec![
+                entry(AclTag::UserObj, 0o6),
+                entry(AclTag::User(GUEST), 0o7),
+                entry(AclTag::GroupObj, 0o4),
+                entry(AclTag::Mask, 0o7),
+                entry(AclTag::Other, 0o0),
+            ],
+        }
+    }
+
+    #[test]
+    fn decode_what_was_encoded() {
+        let bytes = acl().encode();
+        assert_eq!(bytes.len(), HEADER_SIZE + 5 * ENTRY_SIZE);
+        assert_eq!(&bytes[..HEADER_SIZE], &[2, 0, 0, 0]);
+        // the named user entry: ACL_USER, rwx, uid 2000
+        assert_eq!(&bytes[12..20], &[0x02, 0, 0o7, 0, 0xd0, 0x07, 0, 0]);
+        assert_eq!(Acl::decode(&bytes).unwrap(), acl());
+    }
+
+    #[test]
+    fn malformed_acls_are_refused() {
+        let bytes = acl().encode();
+        assert!(Acl::decode(&bytes[..bytes.len() - 1]).is_err());
+        let mut version = bytes.clone();
+        version[0] = 1;
+        assert!(Acl::decode(&version).is_err());
+
+        let mut no_mask = acl();
+        no_mask.entries.retain(|entry| entry.tag != AclTag::Mask);
+        let err = Acl::decode(&no_mask.encode()).unwrap_err();
+        assert_eq!(libc::c_int::from(err), libc::EINVAL);
+    }
+
+    #[test]
+    fn named_users_get_their_entry() {
+        let acl = acl();
+        let rwx = (libc::R_OK | libc::W_OK | libc::X_OK) as u16;
+        assert!(acl.permits(OWNER, OWNER, GUEST, GUEST, rwx));
+        assert!(!acl.permits(OWNER, OWNER, 3000, 3000, libc::R_OK as u16));
+        assert!(acl.permits(OWNER, OWNER, 3000, OWNER, libc::R_OK as u16));
+        assert!(!acl.permits(OWNER, OWNER, OWNER, OWNER, libc::X_OK as u16));
+
+        let mut removed = acl.clone();
+        removed
+            .entries
+            .retain(|entry| entry.tag != AclTag::User(GUEST));
+        assert!(!removed.permits(OWNER, OWNER, GUEST, GUEST, rwx));
+        for bit in &[libc::R_OK, libc::W_OK, libc::X_OK] {
+            assert!(!removed.permits(OWNER, OWNER, GUEST, GUEST, *bit as u16));
+        }
+    }
+
+    #[test]
+    fn chmod_and_acl_agree() {
+        let mut acl = acl();
+        assert_eq!(acl.mode(), 0o670);
+        acl.set_mode(0o750);
+        assert_eq!(acl.mode(), 0o750);
+        // the mask takes the group bits, the owning group entry keeps its own
+        assert_eq!(acl.entries[2].perm, 0o4);
+        assert_eq!(acl.entries[3].perm, 0o5);
+        assert!(!acl.permits(OWNER, OWNER, GUEST, GUEST, libc::W_OK as u16));
+
+        let mut minimal = acl.clone();
+        minimal
+            .entries
+            .retain(|entry| !matches!(entry.tag, AclTag::User(_) | AclTag::Mask));
+        minimal.set_mode(0o640);
+        assert_eq!(minimal.mode(), 0o640);
+        assert_eq!(minimal.entries[1].perm, 0o4);
+    }
+
+    #[test]
+    fn mask_limits_named_entries() {
+        let mut acl = acl();
+        acl.entries[3].perm = 0o4;
+        assert!(acl.permits(OWNER, OWNER, GUEST, GUEST, libc::R_OK as u16));
+        assert!(!acl.permits(OWNER, OWNER, GUEST, GUEST, libc::W_OK as u16));
+    }
+}
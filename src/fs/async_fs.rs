@@ -9,19 +9,23 @@ use async_trait::async_trait;
 use bytestring::ByteString;
 use fuser::{
     Filesystem, KernelConfig, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory,
-    ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyLock, ReplyLseek, ReplyOpen, ReplyStatfs,
-    ReplyWrite, ReplyXattr, Request, TimeOrNow,
+    ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek, ReplyOpen,
+    ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
 };
 use tokio::runtime::Handle;
 use tokio::task::{block_in_place, spawn};
 use tracing::trace;
 
 use super::error::{FsError, Result};
+use super::metrics::Metrics;
 use super::reply::{
-    Attr, Bmap, Create, Data, Dir, DirPlus, Entry, FsReply, Lock, Lseek, Open, StatFs, Write, Xattr,
+    Attr, Bmap, Create, Data, Dir, DirPlus, Entry, FsReply, Ioctl, Lock, Lseek, Open, StatFs,
+    Write, Xattr,
 };
 
-pub fn spawn_reply<F, R, V>(id: u64, reply: R, f: F)
+/// Answer request `id` with the outcome of `f` once it's done, counting it in
+/// `tifs_requests_total` and `tifs_request_errors_total` of `metrics`.
+pub fn spawn_reply<F, R, V>(id: u64, metrics: Metrics, reply: R, f: F)
 where
     F: Future<Output = Result<V>> + Send + 'static,
     R: FsReply<V> + Send + 'static,
@@ -30,6 +34,7 @@ where
     spawn(async move {
         trace!("reply to request({})", id);
         let result = f.await;
+        metrics.request(result.is_err());
         reply.reply(id, result);
     });
 }
@@ -55,6 +60,11 @@ pub trait AsyncFileSystem: Send + Sync {
     /// Called on filesystem exit.
     async fn destroy(&self) {}
 
+    /// Where requests are counted.
+    fn metrics(&self) -> Metrics {
+        Metrics::default()
+    }
+
     /// Look up a directory entry by name and get its attributes.
     async fn lookup(&self, _parent: u64, _name: ByteString) -> Result<Entry> {
         Err(FsError::unimplemented())
@@ -90,6 +100,7 @@ pub trait AsyncFileSystem: Send + Sync {
         _chgtime: Option<SystemTime>,
         _bkuptime: Option<SystemTime>,
         _flags: Option<u32>,
+        _caller_uid: u32,
     ) -> Result<Attr> {
         Err(FsError::unimplemented())
     }
@@ -108,6 +119,7 @@ pub trait AsyncFileSystem: Send + Sync {
         _mode: u32,
         _gid: u32,
         _uid: u32,
+        _pid: u32,
         _umask: u32,
         _rdev: u32,
     ) -> Result<Entry> {
@@ -122,6 +134,7 @@ pub trait AsyncFileSystem: Send + Sync {
         _mode: u32,
         _gid: u32,
         _uid: u32,
+        _pid: u32,
         _umask: u32,
     ) -> Result<Entry> {
         Err(FsError::unimplemented())
@@ -142,6 +155,7 @@ pub trait AsyncFileSystem: Send + Sync {
         &self,
         _gid: u32,
         _uid: u32,
+        _pid: u32,
         _parent: u64,
         _name: ByteString,
         _link: ByteString,
@@ -174,7 +188,7 @@ pub trait AsyncFileSystem: Send + Sync {
     /// anything in fh. There are also some flags (direct_io, keep_cache) which the
     /// filesystem may set, to change the way the file is opened. See fuse_file_info
     /// structure in <fuse_common.h> for more details.
-    async fn open(&self, _ino: u64, _flags: i32) -> Result<Open> {
+    async fn open(&self, _uid: u32, _gid: u32, _ino: u64, _flags: i32) -> Result<Open> {
         Ok(Open::new(0, 0))
     }
 
@@ -281,8 +295,8 @@ pub trait AsyncFileSystem: Send + Sync {
     /// requested size. Send an empty buffer on end of stream. fh will contain the
     /// value set by the opendir method, or will be undefined if the opendir method
     /// didn't set any value.
-    async fn readdir(&self, _ino: u64, _fh: u64, offset: i64) -> Result<Dir> {
-        Ok(Dir::offset(offset as usize))
+    async fn readdir(&self, _ino: u64, _fh: u64, _offset: i64) -> Result<Dir> {
+        Ok(Dir::new())
     }
 
     /// Read directory.
@@ -290,8 +304,8 @@ pub trait AsyncFileSystem: Send + Sync {
     /// requested size. Send an empty buffer on end of stream. fh will contain the
     /// value set by the opendir method, or will be undefined if the opendir method
     /// didn't set any value.
-    async fn readdirplus(&self, _ino: u64, _fh: u64, offset: i64) -> Result<DirPlus> {
-        Ok(DirPlus::offset(offset as usize))
+    async fn readdirplus(&self, _ino: u64, _fh: u64, _offset: i64) -> Result<DirPlus> {
+        Ok(DirPlus::new())
     }
 
     /// Release an open directory.
@@ -318,6 +332,7 @@ pub trait AsyncFileSystem: Send + Sync {
     /// Set an extended attribute.
     async fn setxattr(
         &self,
+        _uid: u32,
         _ino: u64,
         _name: ByteString,
         _value: Vec<u8>,
@@ -344,7 +359,7 @@ pub trait AsyncFileSystem: Send + Sync {
     }
 
     /// Remove an extended attribute.
-    async fn removexattr(&self, _ino: u64, _name: ByteString) -> Result<()> {
+    async fn removexattr(&self, _uid: u32, _ino: u64, _name: ByteString) -> Result<()> {
         Err(FsError::unimplemented())
     }
 
@@ -352,7 +367,7 @@ pub trait AsyncFileSystem: Send + Sync {
     /// This will be called for the access() system call. If the 'default_permissions'
     /// mount option is given, this method is not called. This method is not called
     /// under Linux kernel versions 2.4.x
-    async fn access(&self, _ino: u64, _mask: i32) -> Result<()> {
+    async fn access(&self, _uid: u32, _gid: u32, _ino: u64, _mask: i32) -> Result<()> {
         Err(FsError::unimplemented())
     }
 
@@ -370,6 +385,7 @@ pub trait AsyncFileSystem: Send + Sync {
         &self,
         _uid: u32,
         _gid: u32,
+        _pid: u32,
         _parent: u64,
         _name: ByteString,
         _mode: u32,
@@ -438,6 +454,19 @@ pub trait AsyncFileSystem: Send + Sync {
         Err(FsError::unimplemented())
     }
 
+    /// Control device.
+    async fn ioctl(
+        &self,
+        _ino: u64,
+        _fh: u64,
+        _flags: u32,
+        _cmd: u32,
+        _in_data: Vec<u8>,
+        _out_size: u32,
+    ) -> Result<Ioctl> {
+        Err(FsError::unimplemented())
+    }
+
     /// Copy the specified range from the source inode to the destination inode
     async fn copy_file_range(
         &self,
@@ -477,7 +506,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         let uid = req.uid();
         let gid = req.gid();
 
-        block_on(self.0.init(gid, uid, config)).map_err(|err| err.into())
+        block_on(self.0.init(gid, uid, config)).map_err(|err| err.to_errno())
     }
 
     fn destroy(&mut self) {
@@ -487,7 +516,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
     fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl.lookup(parent, name).await
         });
     }
@@ -503,11 +532,9 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
 
     fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
         let async_impl = self.0.clone();
-        spawn_reply(
-            req.unique(),
-            reply,
-            async move { async_impl.getattr(ino).await },
-        );
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
+            async_impl.getattr(ino).await
+        });
     }
 
     fn setattr(
@@ -529,11 +556,12 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyAttr,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        let caller_uid = req.uid();
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl
                 .setattr(
                     ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
-                    flags,
+                    flags, caller_uid,
                 )
                 .await
         });
@@ -541,7 +569,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
 
     fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl.readlink(ino).await
         });
     }
@@ -560,10 +588,11 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         let name = name.to_string_lossy().to_string().into();
         let uid = req.uid();
         let gid = req.gid();
+        let pid = req.pid();
 
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl
-                .mknod(parent, name, mode, gid, uid, umask, rdev)
+                .mknod(parent, name, mode, gid, uid, pid, umask, rdev)
                 .await
         });
     }
@@ -581,16 +610,19 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         let name = name.to_string_lossy().to_string().into();
         let uid = req.uid();
         let gid = req.gid();
+        let pid = req.pid();
 
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.mkdir(parent, name, mode, gid, uid, umask).await
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
+            async_impl
+                .mkdir(parent, name, mode, gid, uid, pid, umask)
+                .await
         });
     }
 
     fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl.unlink(parent, name).await
         });
     }
@@ -598,7 +630,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
     fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl.rmdir(parent, name).await
         });
     }
@@ -616,9 +648,10 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         let link = link.to_string_lossy().to_string().into();
         let uid = req.uid();
         let gid = req.gid();
+        let pid = req.pid();
 
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.symlink(gid, uid, parent, name, link).await
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
+            async_impl.symlink(gid, uid, pid, parent, name, link).await
         });
     }
 
@@ -635,7 +668,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
         let newname = newname.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl
                 .rename(parent, name, newparent, newname, flags)
                 .await
@@ -652,15 +685,17 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
     ) {
         let async_impl = self.0.clone();
         let newname = newname.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl.link(ino, newparent, newname).await
         });
     }
 
     fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.open(ino, flags).await
+        let uid = req.uid();
+        let gid = req.gid();
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
+            async_impl.open(uid, gid, ino, flags).await
         });
     }
 
@@ -676,7 +711,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyData,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl
                 .read(ino, fh, offset, size, flags, lock_owner)
                 .await
@@ -697,7 +732,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
     ) {
         let async_impl = self.0.clone();
         let data = data.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl
                 .write(ino, fh, offset, data, write_flags, flags, lock_owner)
                 .await
@@ -706,7 +741,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
 
     fn flush(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl.flush(ino, fh, lock_owner).await
         });
     }
@@ -722,28 +757,28 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl.release(ino, fh, flags, lock_owner, flush).await
         });
     }
 
     fn fsync(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl.fsync(ino, fh, datasync).await
         });
     }
 
     fn opendir(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl.opendir(ino, flags).await
         });
     }
 
     fn readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl.readdir(ino, fh, offset).await
         });
     }
@@ -757,25 +792,23 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyDirectoryPlus,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl.readdirplus(ino, fh, offset).await
         });
     }
 
     fn fsyncdir(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl.fsyncdir(ino, fh, datasync).await
         });
     }
 
     fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
         let async_impl = self.0.clone();
-        spawn_reply(
-            req.unique(),
-            reply,
-            async move { async_impl.statfs(ino).await },
-        );
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
+            async_impl.statfs(ino).await
+        });
     }
 
     fn setxattr(
@@ -789,39 +822,45 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
+        let uid = req.uid();
         let name = name.to_string_lossy().to_string().into();
         let value = value.to_owned();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.setxattr(ino, name, value, flags, position).await
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
+            async_impl
+                .setxattr(uid, ino, name, value, flags, position)
+                .await
         });
     }
 
     fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl.getxattr(ino, name, size).await
         });
     }
 
     fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl.listxattr(ino, size).await
         });
     }
 
     fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
+        let uid = req.uid();
         let name = name.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.removexattr(ino, name).await
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
+            async_impl.removexattr(uid, ino, name).await
         });
     }
     fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.access(ino, mask).await
+        let uid = req.uid();
+        let gid = req.gid();
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
+            async_impl.access(uid, gid, ino, mask).await
         });
     }
 
@@ -837,12 +876,13 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
     ) {
         let uid = req.uid();
         let gid = req.gid();
+        let pid = req.pid();
 
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl
-                .create(uid, gid, parent, name, mode, umask, flags)
+                .create(uid, gid, pid, parent, name, mode, umask, flags)
                 .await
         });
     }
@@ -860,7 +900,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyLock,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl
                 .getlk(ino, fh, lock_owner, start, end, typ, pid)
                 .await
@@ -881,7 +921,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl
                 .setlk(ino, fh, lock_owner, start, end, typ, pid, sleep)
                 .await
@@ -890,7 +930,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
 
     fn bmap(&mut self, req: &Request, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl.bmap(ino, blocksize, idx).await
         });
     }
@@ -906,7 +946,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl.fallocate(ino, fh, offset, length, mode).await
         });
     }
@@ -921,11 +961,31 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyLseek,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl.lseek(ino, fh, offset, whence).await
         });
     }
 
+    fn ioctl(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        let async_impl = self.0.clone();
+        let in_data = in_data.to_owned();
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
+            async_impl
+                .ioctl(ino, fh, flags, cmd, in_data, out_size)
+                .await
+        });
+    }
+
     fn copy_file_range(
         &mut self,
         req: &Request,
@@ -940,7 +1000,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyWrite,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(req.unique(), self.0.metrics(), reply, async move {
             async_impl
                 .copy_file_range(
                     ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags,
@@ -1,5 +1,763 @@
+use std::convert::TryInto;
+use std::ops::Range;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
 type Block = Vec<u8>;
 
 pub fn empty_block(block_size: u64) -> Block {
     vec![0; block_size as usize]
 }
+
+/// Whether `data` holds only zeros, i.e. can be left as a hole.
+pub fn is_zero(data: &[u8]) -> bool {
+    data.iter().all(|byte| *byte == 0)
+}
+
+/// Concatenate the blocks covering `[start, start + size)` of a file.
+///
+/// `blocks` yields `(index, block)` pairs in ascending order of index; any block missing
+/// from the sequence is a hole and reads as zeros, as does the tail beyond the last block.
+pub fn assemble_blocks<I>(block_size: u64, start: u64, size: u64, blocks: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = (u64, Block)>,
+{
+    let end = start + size;
+    let mut data = Vec::with_capacity(size as usize);
+    for (index, block) in blocks {
+        let block_start = index * block_size;
+        let from = start.max(block_start);
+        let to = end.min(block_start + block_size);
+        if from >= to {
+            continue;
+        }
+
+        debug_assert!(data.len() as u64 <= from - start, "blocks out of order");
+        data.resize((from - start) as usize, 0);
+        let lower = (from - block_start) as usize;
+        let upper = ((to - block_start) as usize).min(block.len());
+        if lower < upper {
+            data.extend_from_slice(&block[lower..upper]);
+        }
+    }
+    data.resize(size as usize, 0);
+    data
+}
+
+/// Bytes a read of up to `chunk_size` from `start` returns, from the size of the inode
+/// alone: blocks missing from the snapshot read as holes rather than cutting it short.
+pub fn read_size(file_size: u64, start: u64, chunk_size: Option<u64>, max_read_size: u64) -> u64 {
+    let max_size = file_size.saturating_sub(start);
+    chunk_size
+        .unwrap_or(max_size)
+        .min(max_size)
+        .min(max_read_size)
+}
+
+/// Zero the bytes of `block` in `range`, clipped to the bytes stored; `false` if none are.
+pub fn zero_range(block: &mut [u8], range: Range<usize>) -> bool {
+    let to = range.end.min(block.len());
+    if range.start >= to {
+        return false;
+    }
+    block[range.start..to].iter_mut().for_each(|byte| *byte = 0);
+    true
+}
+
+/// Split the bytes `[start, end)` of a file into the range of blocks they cover whole and
+/// the blocks at either end they cover in part, with the covered bytes of each.
+pub fn split_range(
+    block_size: u64,
+    start: u64,
+    end: u64,
+) -> (Range<u64>, Vec<(u64, Range<usize>)>) {
+    if start >= end {
+        return (0..0, Vec::new());
+    }
+    let first = start / block_size;
+    let last = (end - 1) / block_size;
+    let whole_start = (start + block_size - 1) / block_size;
+    let whole_end = (end / block_size).max(whole_start);
+
+    let mut partial = Vec::with_capacity(2);
+    if start % block_size != 0 {
+        let to = (end - first * block_size).min(block_size);
+        partial.push((first, (start % block_size) as usize..to as usize));
+    }
+    if end % block_size != 0 && (last != first || start % block_size == 0) {
+        let from = start.max(last * block_size) - last * block_size;
+        partial.push((last, from as usize..(end - last * block_size) as usize));
+    }
+    (whole_start..whole_end, partial)
+}
+
+/// The block holding the end of a file of `size` bytes and the offset of the first byte
+/// in it past the end, unless the file ends on a block boundary.
+pub fn tail_block(block_size: u64, size: u64) -> Option<(u64, usize)> {
+    match (size % block_size) as usize {
+        0 => None,
+        tail => Some((size / block_size, tail)),
+    }
+}
+
+/// What cutting a file of `size` bytes down to `new_size` drops: the blocks past the new
+/// end, and the block holding the new end with the offset its tail is zeroed from.
+pub fn shrink_blocks(
+    block_size: u64,
+    size: u64,
+    new_size: u64,
+) -> (Range<u64>, Option<(u64, usize)>) {
+    if new_size >= size {
+        return (0..0, None);
+    }
+    let kept = (new_size + block_size - 1) / block_size;
+    let end = (size + block_size - 1) / block_size;
+    (kept..end, tail_block(block_size, new_size))
+}
+
+/// A run of bytes of a file that is either all stored data or all hole.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BlockRange {
+    pub start_byte: u64,
+    pub end_byte: u64,
+    pub is_data: bool,
+}
+
+/// Split a file of `size` bytes whose stored blocks are `stored`, in ascending order, into
+/// alternating data and hole ranges covering `[0, size)`.
+pub fn block_map(block_size: u64, size: u64, stored: &[u64]) -> Vec<BlockRange> {
+    let mut ranges: Vec<BlockRange> = Vec::new();
+    let mut push = |start_byte: u64, end_byte: u64, is_data: bool| {
+        let end_byte = end_byte.min(size);
+        if start_byte >= end_byte {
+            return;
+        }
+        match ranges.last_mut() {
+            Some(last) if last.is_data == is_data && last.end_byte == start_byte => {
+                last.end_byte = end_byte
+            }
+            _ => ranges.push(BlockRange {
+                start_byte,
+                end_byte,
+                is_data,
+            }),
+        }
+    };
+
+    let mut offset = 0;
+    for block in stored {
+        let start = block * block_size;
+        push(offset, start, false);
+        push(start, start + block_size, true);
+        offset = start + block_size;
+    }
+    push(offset, size, false);
+    ranges
+}
+
+/// Where `lseek(SEEK_DATA)` from `offset` lands in a file of `size` bytes whose stored
+/// blocks are `stored`, in ascending order; `None` if only a hole is left.
+pub fn seek_data(block_size: u64, size: u64, offset: u64, stored: &[u64]) -> Option<u64> {
+    if offset >= size {
+        return None;
+    }
+    stored
+        .iter()
+        .find(|block| (*block + 1) * block_size > offset)
+        .map(|block| (block * block_size).max(offset))
+        .filter(|data| *data < size)
+}
+
+/// Where `lseek(SEEK_HOLE)` from `offset` lands; the end of the file counts as a hole.
+pub fn seek_hole(block_size: u64, size: u64, offset: u64, stored: &[u64]) -> Option<u64> {
+    if offset >= size {
+        return None;
+    }
+    let mut next = offset / block_size;
+    for block in stored.iter().copied().filter(|block| *block >= next) {
+        if block != next {
+            break;
+        }
+        next += 1;
+    }
+    Some((next * block_size).max(offset).min(size))
+}
+
+/// How the blocks of a file are stored.
+///
+/// With `Lz4` every stored block starts with a one-byte header telling whether the rest
+/// is compressed, as blocks that don't shrink are kept as is. Blocks stored without
+/// compression carry no header, so the mode is fixed in `Meta` when the filesystem is
+/// created.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum CompressionMode {
+    None,
+    Lz4,
+}
+
+impl CompressionMode {
+    const RAW: u8 = 0;
+    const LZ4: u8 = 1;
+
+    /// The value to store for `block`.
+    pub fn encode(self, block: Block) -> Vec<u8> {
+        match self {
+            Self::None => block,
+            Self::Lz4 => {
+                let compressed = lz4_flex::compress_prepend_size(&block);
+                let (header, payload) = if compressed.len() < block.len() {
+                    (Self::LZ4, compressed)
+                } else {
+                    (Self::RAW, block)
+                };
+                let mut value = Vec::with_capacity(payload.len() + 1);
+                value.push(header);
+                value.extend_from_slice(&payload);
+                value
+            }
+        }
+    }
+
+    /// The block stored as `value`, or what is wrong with it.
+    pub fn decode(self, mut value: Vec<u8>) -> std::result::Result<Block, String> {
+        match self {
+            Self::None => Ok(value),
+            Self::Lz4 => match value.split_first() {
+                // drop the header in place rather than copying the block out
+                Some((&Self::RAW, _)) => {
+                    value.remove(0);
+                    Ok(value)
+                }
+                Some((&Self::LZ4, compressed)) => {
+                    lz4_flex::decompress_size_prepended(compressed).map_err(|err| err.to_string())
+                }
+                Some((header, _)) => Err(format!("unknown block header({:#x})", header)),
+                None => Err("block without header".into()),
+            },
+        }
+    }
+}
+
+impl Default for CompressionMode {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl FromStr for CompressionMode {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> std::result::Result<Self, Self::Err> {
+        match mode {
+            "none" => Ok(Self::None),
+            "lz4" => Ok(Self::Lz4),
+            _ => Err(anyhow::anyhow!(
+                "unknown compression `{}`, expect one of none, lz4",
+                mode
+            )),
+        }
+    }
+}
+
+/// A stored block of a file with `Inode::shared_blocks`, whose values all start with a
+/// tag telling the block's own data from a reference to a `SharedBlock`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BlockValue {
+    /// Encoded as by `CompressionMode::encode`.
+    Data(Vec<u8>),
+    /// Id of the `SharedBlock` holding the data.
+    Shared(u64),
+}
+
+impl BlockValue {
+    const DATA: u8 = 0;
+    const SHARED: u8 = 1;
+
+    pub fn encode(self) -> Vec<u8> {
+        match self {
+            Self::Data(data) => {
+                let mut value = Vec::with_capacity(data.len() + 1);
+                value.push(Self::DATA);
+                value.extend_from_slice(&data);
+                value
+            }
+            Self::Shared(id) => {
+                let mut value = vec![Self::SHARED];
+                value.extend_from_slice(&id.to_be_bytes());
+                value
+            }
+        }
+    }
+
+    pub fn decode(mut value: Vec<u8>) -> std::result::Result<Self, String> {
+        match value.first() {
+            Some(&Self::DATA) => {
+                value.remove(0);
+                Ok(Self::Data(value))
+            }
+            Some(&Self::SHARED) => value[1..]
+                .try_into()
+                .map(|id| Self::Shared(u64::from_be_bytes(id)))
+                .map_err(|_| format!("shared block reference of {} bytes", value.len())),
+            Some(tag) => Err(format!("unknown block tag({:#x})", tag)),
+            None => Err("block without tag".into()),
+        }
+    }
+
+    /// The shared block a reflink points both files at for this block of the source: the
+    /// one it refers to already, or a new one taking its data under id `*next_id`, which
+    /// is returned to be stored.
+    pub fn share(self, next_id: &mut u64) -> (u64, Option<SharedBlock>) {
+        match self {
+            Self::Shared(id) => (id, None),
+            Self::Data(data) => {
+                let id = *next_id;
+                *next_id += 1;
+                // the source and the copy
+                (id, Some(SharedBlock { refs: 2, data }))
+            }
+        }
+    }
+}
+
+/// A block shared by reflinked files, deleted once no file refers to it any more.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SharedBlock {
+    pub refs: u64,
+    /// Encoded as by `CompressionMode::encode`.
+    pub data: Vec<u8>,
+}
+
+impl SharedBlock {
+    pub fn encode(self) -> Vec<u8> {
+        let mut value = Vec::with_capacity(self.data.len() + 8);
+        value.extend_from_slice(&self.refs.to_be_bytes());
+        value.extend_from_slice(&self.data);
+        value
+    }
+
+    /// Drop the reference of a file that writes its own copy of the block or goes away;
+    /// `None` once it was the last, and the block is to be deleted.
+    pub fn release(mut self) -> Option<Self> {
+        if self.refs <= 1 {
+            None
+        } else {
+            self.refs -= 1;
+            Some(self)
+        }
+    }
+
+    pub fn decode(mut value: Vec<u8>) -> std::result::Result<Self, String> {
+        if value.len() < 8 {
+            return Err(format!("shared block of {} bytes", value.len()));
+        }
+        let data = value.split_off(8);
+        let refs = u64::from_be_bytes(value[..].try_into().unwrap());
+        Ok(Self { refs, data })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_SIZE: u64 = 16;
+
+    fn filled_block(byte: u8) -> Block {
+        vec![byte; BLOCK_SIZE as usize]
+    }
+
+    #[test]
+    fn empty_block_is_zeroed() {
+        let block = empty_block(BLOCK_SIZE);
+        assert_eq!(block.len(), BLOCK_SIZE as usize);
+        assert!(block.iter().all(|byte| *byte == 0));
+
+        assert_eq!(empty_block(1 << 16).len(), 1 << 16);
+    }
+
+    #[test]
+    fn zero_blocks() {
+        assert!(is_zero(&empty_block(BLOCK_SIZE)));
+        assert!(is_zero(&[]));
+        assert!(!is_zero(&filled_block(1)));
+
+        let mut block = empty_block(BLOCK_SIZE);
+        block[BLOCK_SIZE as usize - 1] = 1;
+        assert!(!is_zero(&block));
+    }
+
+    #[test]
+    fn sparse_file_reads_back_as_zeros() {
+        // a 100-block file of zeros with data only in its first and last block
+        let stored = vec![(0, filled_block(1)), (99, filled_block(2))];
+        let data = assemble_blocks(BLOCK_SIZE, 0, 100 * BLOCK_SIZE, stored);
+        assert_eq!(data.len(), 100 * BLOCK_SIZE as usize);
+        assert!(is_zero(
+            &data[BLOCK_SIZE as usize..99 * BLOCK_SIZE as usize]
+        ));
+        assert_eq!(&data[..BLOCK_SIZE as usize], filled_block(1).as_slice());
+    }
+
+    #[test]
+    fn assemble_fills_hole_in_the_middle() {
+        let blocks = vec![(0, filled_block(1)), (2, filled_block(3))];
+        let data = assemble_blocks(BLOCK_SIZE, 0, 3 * BLOCK_SIZE, blocks);
+
+        assert_eq!(data.len(), 3 * BLOCK_SIZE as usize);
+        let (first, rest) = data.split_at(BLOCK_SIZE as usize);
+        let (second, third) = rest.split_at(BLOCK_SIZE as usize);
+        assert_eq!(first, filled_block(1).as_slice());
+        assert_eq!(second, empty_block(BLOCK_SIZE).as_slice());
+        assert_eq!(third, filled_block(3).as_slice());
+    }
+
+    #[test]
+    fn assemble_fills_multiple_holes() {
+        let blocks = vec![
+            (0, filled_block(1)),
+            (3, filled_block(4)),
+            (5, filled_block(6)),
+        ];
+        let data = assemble_blocks(BLOCK_SIZE, 0, 6 * BLOCK_SIZE, blocks);
+
+        let expected: Vec<u8> = [1, 0, 0, 4, 0, 6]
+            .iter()
+            .flat_map(|byte| filled_block(*byte))
+            .collect();
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn assemble_unaligned_range() {
+        let blocks = vec![(1, filled_block(2)), (3, filled_block(4))];
+        let start = BLOCK_SIZE + BLOCK_SIZE / 2;
+        let data = assemble_blocks(BLOCK_SIZE, start, 2 * BLOCK_SIZE, blocks);
+
+        let half = (BLOCK_SIZE / 2) as usize;
+        assert_eq!(&data[..half], &filled_block(2)[..half]);
+        assert!(data[half..half + BLOCK_SIZE as usize]
+            .iter()
+            .all(|byte| *byte == 0));
+        assert_eq!(
+            &data[half + BLOCK_SIZE as usize..],
+            &filled_block(4)[..half]
+        );
+    }
+
+    #[test]
+    fn hole_survives_write_then_read() {
+        // simulate writing block 0 and block 2 of a file, skipping block 1
+        let mut stored = std::collections::BTreeMap::new();
+        stored.insert(0, filled_block(b'a'));
+        stored.insert(2, filled_block(b'c'));
+
+        let data = assemble_blocks(BLOCK_SIZE, 0, 3 * BLOCK_SIZE, stored.clone());
+        assert!(data[BLOCK_SIZE as usize..2 * BLOCK_SIZE as usize]
+            .iter()
+            .all(|byte| *byte == 0));
+
+        // reading only the hole yields zeros, not the neighbouring blocks
+        let hole = assemble_blocks(BLOCK_SIZE, BLOCK_SIZE, BLOCK_SIZE, stored);
+        assert_eq!(hole, empty_block(BLOCK_SIZE));
+    }
+
+    #[test]
+    fn assemble_same_output_for_sorted_batch_and_scan() {
+        // scan yields ascending blocks; batch get yields them unordered and is sorted
+        let scanned = vec![
+            (1, filled_block(2)),
+            (2, filled_block(3)),
+            (4, filled_block(5)),
+        ];
+        let mut batched = vec![scanned[2].clone(), scanned[0].clone(), scanned[1].clone()];
+        batched.sort_unstable_by_key(|(block, _)| *block);
+
+        let ranges = [
+            (0, 6 * BLOCK_SIZE),                  // holes at both ends and in the middle
+            (BLOCK_SIZE + 3, BLOCK_SIZE),         // unaligned start, partial blocks
+            (2 * BLOCK_SIZE + 1, BLOCK_SIZE - 2), // inside a single block
+            (3 * BLOCK_SIZE, 2 * BLOCK_SIZE),     // starting in a hole
+        ];
+        for &(start, size) in ranges.iter() {
+            assert_eq!(
+                assemble_blocks(BLOCK_SIZE, start, size, scanned.clone()),
+                assemble_blocks(BLOCK_SIZE, start, size, batched.clone()),
+                "range [{}, {})",
+                start,
+                start + size
+            );
+        }
+    }
+
+    #[test]
+    fn assemble_pads_trailing_hole() {
+        let data = assemble_blocks(BLOCK_SIZE, 0, 2 * BLOCK_SIZE, vec![(0, filled_block(9))]);
+        assert_eq!(&data[..BLOCK_SIZE as usize], filled_block(9).as_slice());
+        assert!(data[BLOCK_SIZE as usize..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn split_ranges() {
+        assert_eq!(split_range(BLOCK_SIZE, 3, 7), (1..1, vec![(0, 3..7)]));
+        assert_eq!(split_range(BLOCK_SIZE, 0, 7), (0..0, vec![(0, 0..7)]));
+        assert_eq!(split_range(BLOCK_SIZE, 16, 32), (1..2, vec![]));
+        assert_eq!(
+            split_range(BLOCK_SIZE, 3, 37),
+            (1..2, vec![(0, 3..16), (2, 0..5)])
+        );
+        assert_eq!(
+            split_range(BLOCK_SIZE, 3, 20),
+            (1..1, vec![(0, 3..16), (1, 0..4)])
+        );
+        assert_eq!(split_range(BLOCK_SIZE, 0, 48), (0..3, vec![]));
+        assert_eq!(split_range(BLOCK_SIZE, 5, 5), (0..0, vec![]));
+    }
+
+    #[test]
+    fn reads_follow_the_inode_size() {
+        assert_eq!(read_size(100, 0, None, 64), 64);
+        assert_eq!(read_size(100, 40, Some(30), 64), 30);
+        assert_eq!(read_size(100, 90, Some(30), 64), 10);
+        assert_eq!(read_size(100, 100, Some(30), 64), 0);
+        assert_eq!(read_size(100, 200, None, 64), 0);
+
+        // a read never comes back short because a block went missing under it
+        let size = read_size(3 * BLOCK_SIZE, 0, None, u64::MAX);
+        let data = assemble_blocks(BLOCK_SIZE, 0, size, vec![(0, filled_block(1))]);
+        assert_eq!(data.len() as u64, 3 * BLOCK_SIZE);
+    }
+
+    #[test]
+    fn punched_holes_read_as_zeros() {
+        let mut blocks: std::collections::BTreeMap<u64, Block> =
+            (0..4).map(|i| (i, filled_block(9))).collect();
+        // the last block is stored short, as the tail of the file
+        blocks.insert(4, vec![9; 4]);
+        let size = 4 * BLOCK_SIZE + 4;
+
+        let (whole, partial) = split_range(BLOCK_SIZE, 5, 4 * BLOCK_SIZE + 2);
+        whole.for_each(|index| {
+            blocks.remove(&index);
+        });
+        for (index, range) in partial {
+            assert!(zero_range(blocks.get_mut(&index).unwrap(), range));
+        }
+
+        let data = assemble_blocks(BLOCK_SIZE, 0, size, blocks);
+        assert_eq!(data.len() as u64, size);
+        assert!(data[..5].iter().all(|byte| *byte == 9));
+        assert!(is_zero(&data[5..4 * BLOCK_SIZE as usize + 2]));
+        assert!(data[4 * BLOCK_SIZE as usize + 2..]
+            .iter()
+            .all(|byte| *byte == 9));
+
+        // nothing is stored past a short block
+        assert!(!zero_range(&mut [9; 4], 4..8));
+    }
+
+    #[test]
+    fn shrink_mid_first_block() {
+        assert_eq!(shrink_blocks(BLOCK_SIZE, 48, 5), (1..3, Some((0, 5))));
+        assert_eq!(shrink_blocks(BLOCK_SIZE, 48, 32), (2..3, None));
+        assert_eq!(shrink_blocks(BLOCK_SIZE, 40, 0), (0..3, None));
+        assert_eq!(shrink_blocks(BLOCK_SIZE, 5, 48), (0..0, None));
+
+        // a 3-block file cut to 5 bytes, then grown back to 3 blocks
+        let mut blocks = vec![
+            (0, filled_block(1)),
+            (1, filled_block(2)),
+            (2, filled_block(3)),
+        ];
+        let (deleted, partial) = shrink_blocks(BLOCK_SIZE, 48, 5);
+        blocks.retain(|(index, _)| !deleted.contains(index));
+        let (index, tail) = partial.unwrap();
+        blocks[index as usize].1[tail..]
+            .iter_mut()
+            .for_each(|byte| *byte = 0);
+
+        assert_eq!(
+            assemble_blocks(BLOCK_SIZE, 0, 5, blocks.clone()),
+            vec![1; 5]
+        );
+        let data = assemble_blocks(BLOCK_SIZE, 0, 48, blocks);
+        assert_eq!(&data[..5], &[1; 5]);
+        assert!(is_zero(&data[5..]));
+    }
+
+    #[test]
+    fn writes_past_the_end_read_zeros_in_between() {
+        // "hello" at 0 of a block that still holds bytes of a longer earlier content
+        let mut first = b"helloSTALE".to_vec();
+        first.resize(BLOCK_SIZE as usize, b'x');
+        let (block, tail) = tail_block(BLOCK_SIZE, 5).unwrap();
+        assert_eq!((block, tail), (0, 5));
+        first[tail..].iter_mut().for_each(|byte| *byte = 0);
+
+        // then "world" at 4 blocks in, leaving blocks 1 to 3 as holes
+        let mut last = empty_block(BLOCK_SIZE);
+        last[..5].copy_from_slice(b"world");
+        let data = assemble_blocks(
+            BLOCK_SIZE,
+            0,
+            4 * BLOCK_SIZE + 5,
+            vec![(0, first), (4, last)],
+        );
+        assert_eq!(&data[..5], b"hello");
+        assert!(is_zero(&data[5..4 * BLOCK_SIZE as usize]));
+        assert_eq!(&data[4 * BLOCK_SIZE as usize..], b"world");
+
+        assert_eq!(tail_block(BLOCK_SIZE, 32), None);
+    }
+
+    #[test]
+    fn lz4_round_trip() {
+        let text = b"the quick brown fox jumps over the lazy dog\n".repeat(100);
+        let value = CompressionMode::Lz4.encode(text.clone());
+        assert_eq!(value[0], CompressionMode::LZ4);
+        assert!(value.len() < text.len());
+        assert_eq!(CompressionMode::Lz4.decode(value).unwrap(), text);
+
+        assert_eq!(
+            CompressionMode::Lz4
+                .decode(vec![CompressionMode::RAW])
+                .unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn incompressible_blocks_are_kept_raw() {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let noise: Block = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let value = CompressionMode::Lz4.encode(noise.clone());
+        assert_eq!(value[0], CompressionMode::RAW);
+        assert_eq!(&value[1..], noise.as_slice());
+        assert_eq!(CompressionMode::Lz4.decode(value).unwrap(), noise);
+    }
+
+    #[test]
+    fn uncompressed_blocks_have_no_header() {
+        let block = filled_block(7);
+        assert_eq!(CompressionMode::None.encode(block.clone()), block);
+        assert_eq!(CompressionMode::None.decode(block.clone()).unwrap(), block);
+        assert!(CompressionMode::Lz4.decode(vec![]).is_err());
+        assert!(CompressionMode::Lz4.decode(vec![9, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn seek_around_holes() {
+        // blocks 1 and 4 are holes, the file ends half way through block 5
+        let stored = [0, 2, 3, 5];
+        let size = 5 * BLOCK_SIZE + 8;
+
+        assert_eq!(seek_data(BLOCK_SIZE, size, 0, &stored), Some(0));
+        assert_eq!(seek_data(BLOCK_SIZE, size, 5, &stored), Some(5));
+        assert_eq!(seek_data(BLOCK_SIZE, size, 20, &stored), Some(32));
+        assert_eq!(seek_data(BLOCK_SIZE, size, 64, &stored), Some(80));
+        assert_eq!(seek_data(BLOCK_SIZE, size, size, &stored), None);
+
+        assert_eq!(seek_hole(BLOCK_SIZE, size, 0, &stored), Some(16));
+        assert_eq!(seek_hole(BLOCK_SIZE, size, 20, &stored), Some(20));
+        assert_eq!(seek_hole(BLOCK_SIZE, size, 33, &stored), Some(64));
+        assert_eq!(seek_hole(BLOCK_SIZE, size, 81, &stored), Some(size));
+        assert_eq!(seek_hole(BLOCK_SIZE, size, size + 1, &stored), None);
+    }
+
+    #[test]
+    fn seek_in_a_trailing_hole() {
+        // truncated up: the last stored block is 0
+        let size = 4 * BLOCK_SIZE;
+        assert_eq!(seek_data(BLOCK_SIZE, size, 16, &[0]), None);
+        assert_eq!(seek_hole(BLOCK_SIZE, size, 3, &[0]), Some(16));
+    }
+
+    #[test]
+    fn block_map_alternates_data_and_holes() {
+        let range = |start_byte, end_byte, is_data| BlockRange {
+            start_byte,
+            end_byte,
+            is_data,
+        };
+        let size = 6 * BLOCK_SIZE + 8;
+        assert_eq!(
+            block_map(BLOCK_SIZE, size, &[0, 2, 3, 5]),
+            vec![
+                range(0, 16, true),
+                range(16, 32, false),
+                range(32, 64, true),
+                range(64, 80, false),
+                range(80, 96, true),
+                range(96, size, false),
+            ]
+        );
+        // the last block is cut at the end of the file
+        assert_eq!(
+            block_map(BLOCK_SIZE, 20, &[1]),
+            vec![range(0, 16, false), range(16, 20, true)]
+        );
+        assert!(block_map(BLOCK_SIZE, 0, &[]).is_empty());
+        assert_eq!(block_map(BLOCK_SIZE, 40, &[]), vec![range(0, 40, false)]);
+    }
+
+    #[test]
+    fn tagged_block_values() {
+        for value in vec![
+            BlockValue::Data(filled_block(7)),
+            BlockValue::Data(Vec::new()),
+            BlockValue::Shared(42),
+        ] {
+            assert_eq!(BlockValue::decode(value.clone().encode()).unwrap(), value);
+        }
+        assert!(BlockValue::decode(vec![1, 0, 0]).is_err());
+        assert!(BlockValue::decode(vec![9]).is_err());
+        assert!(BlockValue::decode(Vec::new()).is_err());
+
+        let shared = SharedBlock {
+            refs: 3,
+            data: filled_block(1),
+        };
+        assert_eq!(
+            SharedBlock::decode(shared.clone().encode()).unwrap(),
+            shared
+        );
+        assert!(SharedBlock::decode(vec![0; 7]).is_err());
+    }
+
+    #[test]
+    fn reflinked_blocks_are_copied_on_write() {
+        let mut next_id = 5;
+        let (id, shared) = BlockValue::Data(filled_block(1)).share(&mut next_id);
+        let shared = shared.unwrap();
+        assert_eq!((id, next_id, shared.refs), (5, 6, 2));
+
+        // a copy of the copy refers to the same block
+        let (again, created) = BlockValue::Shared(id).share(&mut next_id);
+        assert_eq!((again, next_id), (5, 6));
+        assert!(created.is_none());
+        let shared = SharedBlock {
+            refs: shared.refs + 1,
+            ..shared
+        };
+
+        // each file writing the block takes a private copy, the last one deletes it
+        let shared = shared.release().unwrap();
+        assert_eq!(shared.refs, 2);
+        let shared = shared.release().unwrap();
+        assert_eq!((shared.refs, shared.data), (1, filled_block(1)));
+        assert!(SharedBlock {
+            refs: 1,
+            data: Vec::new()
+        }
+        .release()
+        .is_none());
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt::Debug;
+use std::time::Duration;
+
+use tikv_client::Config;
+use tracing::warn;
+
+use super::client::{ClientPool, TlsConfig};
+use super::meta::check_label;
+use super::tikv_fs::TiFs;
+use crate::MountOption;
+
+/// Connection settings of a `TiFs`, applied by `build`.
+///
+/// ```ignore
+/// let fs = TiFsBuilder::new()
+///     .with_pd_endpoints(vec!["pd0:2379".into(), "pd1:2379".into(), "pd2:2379".into()])
+///     .with_connection_pool_size(4)
+///     .with_timeout(Duration::from_secs(2))
+///     .build()
+///     .await?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct TiFsBuilder {
+    pd_endpoints: Vec<String>,
+    config: Config,
+    pool_size: usize,
+    options: Vec<MountOption>,
+    read_only: bool,
+    label: Option<String>,
+}
+
+impl TiFsBuilder {
+    pub const DEFAULT_CONNECTION_POOL_SIZE: usize = 1;
+
+    pub fn new() -> Self {
+        Self {
+            pd_endpoints: Vec::new(),
+            config: Config::default(),
+            pool_size: Self::DEFAULT_CONNECTION_POOL_SIZE,
+            options: Vec::new(),
+            read_only: false,
+            label: None,
+        }
+    }
+
+    /// Addresses of the PD servers, `host:port`. List every PD of the cluster in
+    /// production, usually three, so that the mount survives the loss of any one of them.
+    pub fn with_pd_endpoints(mut self, pd_endpoints: Vec<String>) -> Self {
+        self.pd_endpoints = pd_endpoints;
+        self
+    }
+
+    /// Number of TiKV clients transactions are spread over. One is enough for light use;
+    /// a mount serving many concurrent writers does well with one per 2-4 cores, and more
+    /// than 16 rarely helps as each client keeps its own connections to PD and every store.
+    pub fn with_connection_pool_size(mut self, size: usize) -> Self {
+        self.pool_size = size.max(1);
+        self
+    }
+
+    /// Certificates for clusters with TLS enabled. Ignored, with a warning, unless all
+    /// three files exist, as the TLS config read by `mount_tifs` always was.
+    pub fn with_tls_config(mut self, tls: TlsConfig) -> Self {
+        if tls.exist_all() {
+            self.config = self
+                .config
+                .with_security(tls.ca_path, tls.cert_path, tls.key_path);
+        } else {
+            warn!("ignore tls config with missing files: {:?}", tls);
+        }
+        self
+    }
+
+    /// Timeout of every request to PD and TiKV, 2 seconds by default. Within a data
+    /// center 1-5 seconds is sensible; a longer one only delays the `EIO` of a request
+    /// stuck on an unreachable store.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.config = self.config.with_timeout(timeout);
+        self
+    }
+
+    /// Start from `config` instead of the defaults of `tikv_client`.
+    pub fn with_client_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Mount options, as parsed by `MountOption::to_vec`, the filesystem is set up with.
+    pub fn with_options(mut self, options: Vec<MountOption>) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Serve every request from a snapshot read as of its start and refuse changes with
+    /// `EROFS`, as the `ro` mount option does, e.g. to look into a filesystem after an
+    /// incident without touching it.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Tag a filesystem created by this mount, readable later from the
+    /// `system.tifs_label` xattr of the root or with `tifs-info`. An existing filesystem
+    /// keeps the label it was created with; `build` fails on a label `check_label` refuses.
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_owned());
+        self
+    }
+
+    pub async fn build(mut self) -> anyhow::Result<TiFs> {
+        if self.read_only {
+            self.options.push(MountOption::RO);
+        }
+        if let Some(label) = self.label.take() {
+            check_label(&label)?;
+            self.options.push(MountOption::Label(label));
+        }
+        let pool = ClientPool::connect(
+            self.pd_endpoints.clone(),
+            self.config.clone(),
+            self.pool_size,
+        )
+        .await
+        .map_err(|err| anyhow::anyhow!("{}", err))?;
+        TiFs::from_pool(pool, self.pd_endpoints, self.config, self.options).await
+    }
+}
+
+impl Default for TiFsBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,7 +1,9 @@
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
-use tikv_client::Config;
+use tikv_client::{Config, Result, TransactionClient};
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
@@ -12,7 +14,7 @@ pub struct TlsConfig {
 }
 
 impl TlsConfig {
-    fn exist_all(&self) -> bool {
+    pub(crate) fn exist_all(&self) -> bool {
         self.ca_path.exists() && self.cert_path.exists() && self.key_path.exists()
     }
 }
@@ -27,3 +29,36 @@ impl From<TlsConfig> for Config {
         }
     }
 }
+
+/// Clients of the same cluster, handed out in turn so that concurrent requests spread
+/// over several gRPC connections instead of queueing on one.
+#[derive(Clone)]
+pub struct ClientPool {
+    clients: Arc<[TransactionClient]>,
+    next: Arc<AtomicUsize>,
+}
+
+impl ClientPool {
+    /// Connect `size` clients to `pd_endpoints`; at least one is always made.
+    pub async fn connect<S>(pd_endpoints: Vec<S>, config: Config, size: usize) -> Result<Self>
+    where
+        S: Clone + Into<String>,
+    {
+        let mut clients = Vec::with_capacity(size.max(1));
+        for _ in 0..size.max(1) {
+            clients.push(
+                TransactionClient::new_with_config(pd_endpoints.clone(), config.clone(), None)
+                    .await?,
+            );
+        }
+        Ok(Self {
+            clients: clients.into(),
+            next: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    pub fn get(&self) -> &TransactionClient {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        &self.clients[next % self.clients.len()]
+    }
+}
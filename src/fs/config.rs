@@ -0,0 +1,271 @@
+use std::time::Duration;
+
+use parse_size::parse_size;
+use tracing::{debug, error};
+
+use super::block::CompressionMode;
+use super::crypto::EncryptionConfig;
+use super::dir::ReaddirOrder;
+use super::inode::ATimeMode;
+use super::inode_cache::InodeCache;
+use super::meta::Meta;
+use super::snapshot::SnapshotInfo;
+use super::tikv_fs::TiFs;
+use crate::MountOption;
+
+/// Settings of a mounted filesystem, collected from the mount options.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FsConfig {
+    pub direct_io: bool,
+    pub block_size: u64,
+    /// Files up to this size are stored in their inode; derived from `block_size` if unset.
+    pub inline_threshold: Option<u64>,
+    pub max_size: Option<u64>,
+    pub max_name_len: u32,
+    pub readdir_order: ReaddirOrder,
+    /// Maximum number of entries in a single directory, `.` and `..` included.
+    pub max_dir_entries: u64,
+    /// Upper bound of the bytes returned by a single read; larger requests get a short read.
+    pub max_read_size: u64,
+    /// Reads spanning fewer blocks than this fetch them with a batch get instead of a scan.
+    pub batch_get_threshold: u64,
+    /// Upper bound of the deleted inode numbers kept in `Meta` for reuse.
+    pub max_free_inodes: u64,
+    /// Fail a read of a block-backed file that was never made sparse as corrupted if no
+    /// block of the range read is stored, instead of reading zeros; holes next to a stored
+    /// block still read as zeros.
+    pub strict_reads: bool,
+    /// Record the creator of every new inode in read-only `tifs.*` xattrs.
+    pub audit_xattrs: bool,
+    /// Run filesystem operations in pessimistic transactions instead of optimistic ones.
+    pub pessimistic_txn: bool,
+    /// When reads update `atime`; updates cost a TiKV write per read.
+    pub atime_mode: ATimeMode,
+    /// Compression of new filesystems; an existing one keeps the mode it was created with.
+    pub compression: CompressionMode,
+    /// Loaded from the `encryption_key` file by `TiFs::construct`, which can fail the mount.
+    pub encryption: EncryptionConfig,
+    /// Inodes kept in memory for `getattr`; 0 disables the cache.
+    pub inode_cache_size: u64,
+    /// How long a cached inode is used before it is read again, to see changes made
+    /// through other mounts.
+    pub inode_cache_ttl: Duration,
+    /// Set by `TiFs::mount_snapshot`, after which every transaction reads the snapshot.
+    pub snapshot: Option<SnapshotInfo>,
+    /// Set by `ro`, after which every transaction reads the latest snapshot as of its start.
+    pub read_only: bool,
+    /// Stored in `Meta` when the filesystem is created; ignored by later mounts.
+    pub label: Option<String>,
+}
+
+impl FsConfig {
+    const INLINE_DATA_THRESHOLD_BASE: u64 = 1 << 4;
+    // symlink targets are always inlined, so the threshold must hold a full path
+    const MIN_INLINE_DATA_THRESHOLD: u64 = libc::PATH_MAX as u64;
+
+    pub const DEFAULT_MAX_DIR_ENTRIES: u64 = 1_000_000;
+    pub const DEFAULT_MAX_READ_SIZE: u64 = 4 << 20;
+    pub const DEFAULT_BATCH_GET_THRESHOLD: u64 = 8;
+    pub const DEFAULT_MAX_FREE_INODES: u64 = 4096;
+
+    pub fn from_options(options: &[MountOption]) -> Self {
+        let mut config = Self::default();
+        for option in options {
+            match option {
+                MountOption::DirectIO => config.direct_io = true,
+                MountOption::RO => config.read_only = true,
+                MountOption::RW => config.read_only = false,
+                MountOption::BlkSize(size) => {
+                    match parse_size_option("blksize", size) {
+                        // the inline threshold, which holds symlink targets, can't pass a block
+                        Some(size) if size < Self::MIN_INLINE_DATA_THRESHOLD => error!(
+                            "blksize({}) is below the {} bytes of a full path",
+                            size,
+                            Self::MIN_INLINE_DATA_THRESHOLD
+                        ),
+                        Some(size) => config.block_size = size,
+                        None => (),
+                    }
+                }
+                MountOption::InlineThreshold(size) => {
+                    if let Some(size) = parse_size_option("inline_threshold", size) {
+                        config.inline_threshold = Some(size);
+                    }
+                }
+                MountOption::MaxSize(size) => {
+                    if let Some(size) = parse_size_option("maxsize", size) {
+                        config.max_size = Some(size);
+                    }
+                }
+                MountOption::ReaddirOrder(order) => match order.parse() {
+                    Ok(order) => config.readdir_order = order,
+                    Err(err) => error!("fail to parse readdir_order({}): {}", order, err),
+                },
+                MountOption::MaxDirEntries(limit) => config.max_dir_entries = *limit,
+                MountOption::MaxReadSize(size) => {
+                    if let Some(size) = parse_size_option("max_read_size", size) {
+                        config.max_read_size = size.max(1);
+                    }
+                }
+                MountOption::BatchGetThreshold(threshold) => {
+                    config.batch_get_threshold = *threshold
+                }
+                MountOption::MaxFreeInodes(limit) => config.max_free_inodes = *limit,
+                MountOption::StrictReads => config.strict_reads = true,
+                MountOption::AuditXattrs => config.audit_xattrs = true,
+                MountOption::PessimisticTxn => config.pessimistic_txn = true,
+                MountOption::StrictAtime => config.atime_mode = ATimeMode::Strict,
+                MountOption::RelAtime => config.atime_mode = ATimeMode::RelATime,
+                MountOption::NoAtime => config.atime_mode = ATimeMode::NoATime,
+                MountOption::InodeCacheSize(size) => config.inode_cache_size = *size,
+                MountOption::InodeCacheTtlMs(ms) => {
+                    config.inode_cache_ttl = Duration::from_millis(*ms)
+                }
+                MountOption::Label(label) => config.label = Some(label.clone()),
+                MountOption::Compression(mode) => match mode.parse() {
+                    Ok(mode) => config.compression = mode,
+                    Err(err) => error!("fail to parse compression({}): {}", mode, err),
+                },
+                _ => (),
+            }
+        }
+        debug!("filesystem config: {:?}", config);
+        config
+    }
+
+    /// The effective inline threshold, never below `PATH_MAX` nor above a block.
+    pub fn inline_data_threshold(&self) -> u64 {
+        self.inline_threshold
+            .unwrap_or(self.block_size / Self::INLINE_DATA_THRESHOLD_BASE)
+            .max(Self::MIN_INLINE_DATA_THRESHOLD)
+            .min(self.block_size)
+    }
+
+    /// Whether transactions only read, as on a snapshot or a `ro` mount.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only || self.snapshot.is_some()
+    }
+
+    /// Follow the layout the filesystem was created with over the mount options; older
+    /// filesystems without a stored threshold get one on their next writable mount.
+    pub fn adopt_meta(&mut self, meta: &Meta) {
+        if let Some(threshold) = meta.inline_threshold {
+            self.inline_threshold = Some(threshold);
+        }
+    }
+}
+
+impl Default for FsConfig {
+    fn default() -> Self {
+        Self {
+            direct_io: false,
+            block_size: TiFs::DEFAULT_BLOCK_SIZE,
+            inline_threshold: None,
+            max_size: None,
+            max_name_len: TiFs::MAX_NAME_LEN,
+            readdir_order: ReaddirOrder::default(),
+            max_dir_entries: Self::DEFAULT_MAX_DIR_ENTRIES,
+            max_read_size: Self::DEFAULT_MAX_READ_SIZE,
+            batch_get_threshold: Self::DEFAULT_BATCH_GET_THRESHOLD,
+            max_free_inodes: Self::DEFAULT_MAX_FREE_INODES,
+            strict_reads: false,
+            audit_xattrs: false,
+            pessimistic_txn: false,
+            atime_mode: ATimeMode::default(),
+            compression: CompressionMode::default(),
+            encryption: EncryptionConfig::default(),
+            inode_cache_size: InodeCache::DEFAULT_CAPACITY as u64,
+            inode_cache_ttl: InodeCache::DEFAULT_TTL,
+            snapshot: None,
+            read_only: false,
+            label: None,
+        }
+    }
+}
+
+fn parse_size_option(name: &str, size: &str) -> Option<u64> {
+    parse_size(size)
+        .map_err(|err| {
+            error!("fail to parse {}({}): {}", name, size, err);
+            err
+        })
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(options: &[&str]) -> Vec<MountOption> {
+        MountOption::to_vec(options.iter().copied())
+    }
+
+    #[test]
+    fn block_sizes() {
+        for &size in [4096u64, 65536].iter() {
+            let config = FsConfig::from_options(&options(&[format!("blksize={}", size).as_str()]));
+            assert_eq!(config.block_size, size);
+            let threshold = config.inline_data_threshold();
+            assert!(threshold <= size);
+            assert!(threshold >= libc::PATH_MAX as u64);
+        }
+        // too small for a symlink target, so the default is kept
+        let config = FsConfig::from_options(&options(&["blksize=512"]));
+        assert_eq!(config.block_size, TiFs::DEFAULT_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn inline_threshold_is_clamped() {
+        let config = FsConfig::from_options(&options(&["blksize=64KiB", "inline_threshold=8KiB"]));
+        assert_eq!(config.inline_data_threshold(), 8 << 10);
+
+        let config = FsConfig::from_options(&options(&["blksize=4096", "inline_threshold=1MiB"]));
+        assert_eq!(config.inline_data_threshold(), 4096);
+
+        let config = FsConfig::from_options(&options(&["inline_threshold=1"]));
+        assert_eq!(config.inline_data_threshold(), libc::PATH_MAX as u64);
+    }
+
+    #[test]
+    fn stored_threshold_wins_over_options() {
+        let mut config = FsConfig::from_options(&options(&["inline_threshold=8KiB"]));
+        let mut meta = Meta::new(config.block_size);
+        meta.inline_threshold = Some(1 << 10);
+        config.adopt_meta(&meta);
+        assert_eq!(config.inline_data_threshold(), 1 << 10);
+    }
+
+    #[cfg(all(feature = "json", not(feature = "binc")))]
+    #[test]
+    fn filesystems_without_stored_threshold_keep_the_default() {
+        let meta =
+            Meta::deserialize(br#"{"inode_next":42,"block_size":65536,"last_stat":null}"#).unwrap();
+        assert_eq!(meta.inline_threshold, None);
+        let mut config = FsConfig::default();
+        config.adopt_meta(&meta);
+        assert_eq!(config.inline_data_threshold(), (1 << 16) / 16);
+    }
+
+    #[test]
+    fn default_threshold_follows_block_size() {
+        let config = FsConfig::from_options(&options(&["blksize=1MiB"]));
+        assert_eq!(config.inline_data_threshold(), (1 << 20) / 16);
+        assert_eq!(FsConfig::default().inline_data_threshold(), (1 << 16) / 16);
+    }
+
+    #[test]
+    fn ro_mounts_are_read_only() {
+        assert!(!FsConfig::default().is_read_only());
+        assert!(FsConfig::from_options(&options(&["ro"])).is_read_only());
+        assert!(!FsConfig::from_options(&options(&["ro,rw"])).is_read_only());
+    }
+
+    #[test]
+    fn atime_options() {
+        assert_eq!(FsConfig::default().atime_mode, ATimeMode::RelATime);
+        let config = FsConfig::from_options(&options(&["noatime"]));
+        assert_eq!(config.atime_mode, ATimeMode::NoATime);
+        let config = FsConfig::from_options(&options(&["noatime,strictatime"]));
+        assert_eq!(config.atime_mode, ATimeMode::Strict);
+    }
+}
@@ -0,0 +1,220 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+use super::error::{FsError, Result};
+use super::key::ScopedKey;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// The per-filesystem key every value key is derived from; wiped from memory on drop.
+pub struct MasterKey([u8; KEY_LEN]);
+
+impl MasterKey {
+    pub fn new(key: [u8; KEY_LEN]) -> Self {
+        Self(key)
+    }
+
+    /// Read a key file holding exactly 32 raw bytes.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut bytes = std::fs::read(path)?;
+        if bytes.len() != KEY_LEN {
+            bytes.zeroize();
+            return Err(anyhow::anyhow!(
+                "encryption key {:?} must be {} bytes",
+                path,
+                KEY_LEN
+            ));
+        }
+        let mut key = [0; KEY_LEN];
+        key.copy_from_slice(&bytes);
+        bytes.zeroize();
+        Ok(Self(key))
+    }
+}
+
+impl Drop for MasterKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// Seals the values of inodes and blocks with ChaCha20-Poly1305 before they reach TiKV.
+///
+/// Every value gets its own key, derived from the master key by HKDF-SHA256 with the
+/// scoped key as info, so a sealed value can't be moved to another key unnoticed. A
+/// sealed value is a random nonce followed by the ciphertext and its tag.
+pub struct EncryptionLayer {
+    master: MasterKey,
+}
+
+impl EncryptionLayer {
+    pub fn new(master: MasterKey) -> Self {
+        Self { master }
+    }
+
+    /// Whether values under `key` are sealed; metadata, indexes and handles are not.
+    pub fn covers(key: &[u8]) -> bool {
+        matches!(
+            ScopedKey::parse(key),
+            Ok(ScopedKey::Inode(_)) | Ok(ScopedKey::Block { .. }) | Ok(ScopedKey::SharedBlock(_))
+        )
+    }
+
+    fn cipher(&self, key: &[u8]) -> ChaCha20Poly1305 {
+        let mut subkey = [0; KEY_LEN];
+        Hkdf::<Sha256>::new(None, &self.master.0)
+            .expand(key, &mut subkey)
+            .expect("a 32-byte output is always valid for HKDF-SHA256");
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&subkey));
+        subkey.zeroize();
+        cipher
+    }
+
+    pub fn seal(&self, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        getrandom::getrandom(&mut nonce)
+            .map_err(|err| FsError::UnknownError(format!("fail to generate nonce: {}", err)))?;
+        let sealed = self
+            .cipher(key)
+            .encrypt(Nonce::from_slice(&nonce), value)
+            .map_err(|_| FsError::UnknownError("fail to encrypt value".into()))?;
+
+        let mut data = Vec::with_capacity(NONCE_LEN + sealed.len());
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&sealed);
+        Ok(data)
+    }
+
+    /// Decrypt a value sealed under `key`; a failed authentication is `CorruptedData`.
+    pub fn open(&self, key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let corrupted = |detail: &str| FsError::CorruptedData {
+            inode: match ScopedKey::parse(key) {
+                Ok(ScopedKey::Inode(ino)) | Ok(ScopedKey::Block { ino, .. }) => ino,
+                _ => 0,
+            },
+            detail: detail.to_owned(),
+        };
+        if data.len() < NONCE_LEN {
+            return Err(corrupted("sealed value is shorter than its nonce"));
+        }
+        let (nonce, sealed) = data.split_at(NONCE_LEN);
+        self.cipher(key)
+            .decrypt(Nonce::from_slice(nonce), sealed)
+            .map_err(|_| corrupted("sealed value fails authentication"))
+    }
+}
+
+impl fmt::Debug for EncryptionLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionLayer { .. }")
+    }
+}
+
+/// Encryption of a mounted filesystem.
+#[derive(Clone, Debug)]
+pub enum EncryptionConfig {
+    None,
+    ChaCha20Poly1305(Arc<EncryptionLayer>),
+}
+
+impl EncryptionConfig {
+    pub fn load(key_path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let layer = EncryptionLayer::new(MasterKey::load(key_path)?);
+        Ok(Self::ChaCha20Poly1305(Arc::new(layer)))
+    }
+
+    pub fn layer(&self) -> Option<&EncryptionLayer> {
+        match self {
+            Self::None => None,
+            Self::ChaCha20Poly1305(layer) => Some(layer),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.layer().is_some()
+    }
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl PartialEq for EncryptionConfig {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::None, Self::None) => true,
+            (Self::ChaCha20Poly1305(a), Self::ChaCha20Poly1305(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tikv_client::Key;
+
+    use super::*;
+
+    fn layer(byte: u8) -> EncryptionLayer {
+        EncryptionLayer::new(MasterKey::new([byte; KEY_LEN]))
+    }
+
+    fn key(scoped: ScopedKey) -> Vec<u8> {
+        Key::from(scoped).into()
+    }
+
+    #[test]
+    fn sealed_values_are_unreadable_without_the_key() {
+        let plain = b"secret file content, secret file content".to_vec();
+        let block = key(ScopedKey::block(2, 0));
+        let sealed = layer(1).seal(&block, &plain).unwrap();
+
+        assert_eq!(sealed.len(), NONCE_LEN + plain.len() + 16);
+        assert!(!sealed.windows(6).any(|window| window == b"secret"));
+        assert_eq!(layer(1).open(&block, &sealed).unwrap(), plain);
+
+        let err = layer(2).open(&block, &sealed).unwrap_err();
+        assert!(matches!(err, FsError::CorruptedData { inode: 2, .. }));
+    }
+
+    #[test]
+    fn sealed_values_are_bound_to_their_key() {
+        let sealed = layer(1).seal(&key(ScopedKey::inode(2)), b"inode").unwrap();
+        assert!(layer(1).open(&key(ScopedKey::inode(3)), &sealed).is_err());
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(layer(1).open(&key(ScopedKey::inode(2)), &tampered).is_err());
+        assert!(layer(1)
+            .open(&key(ScopedKey::inode(2)), &sealed[..4])
+            .is_err());
+    }
+
+    #[test]
+    fn nonces_are_not_reused() {
+        let block = key(ScopedKey::block(2, 0));
+        assert_ne!(
+            layer(1).seal(&block, b"same").unwrap(),
+            layer(1).seal(&block, b"same").unwrap()
+        );
+    }
+
+    #[test]
+    fn only_inodes_and_blocks_are_covered() {
+        assert!(EncryptionLayer::covers(&key(ScopedKey::inode(2))));
+        assert!(EncryptionLayer::covers(&key(ScopedKey::block(2, 7))));
+        assert!(!EncryptionLayer::covers(&key(ScopedKey::meta())));
+        assert!(!EncryptionLayer::covers(&key(ScopedKey::index(1, "a"))));
+    }
+}
@@ -1,23 +1,242 @@
+use std::collections::BTreeMap;
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+
+use fuser::FileType;
+use serde::{Deserialize, Serialize};
+
 use super::error::{FsError, Result};
 use super::reply::DirItem;
 use super::serialize::{deserialize, serialize, ENCODING};
 
-pub type Directory = Vec<DirItem>;
+/// Entries of a directory by name; `DirItem::seq` keeps their insertion order.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Directory {
+    entries: BTreeMap<String, DirItem>,
+    /// Above the sequence number of every entry the directory ever had, so one isn't
+    /// handed out again after the last entry is removed.
+    next_seq: u64,
+}
+
+impl Directory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Deref for Directory {
+    type Target = BTreeMap<String, DirItem>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
+}
+
+impl DerefMut for Directory {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.entries
+    }
+}
+
+/// Longest name of a directory entry in bytes, `NAME_MAX` on Linux.
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Refuse names longer than `MAX_NAME_LEN` before they become index keys.
+pub fn check_file_name(name: &str) -> Result<()> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(FsError::NameTooLong {
+            file: name.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Order of the entries returned by `readdir`.
+///
+/// Directories are keyed by name; listings are sorted on the way out so they are
+/// deterministic across mounts. Inode numbers are recycled, so a new entry may sort
+/// anywhere in `ByIno` order; readdir cookies are keyed on the inode number there, see
+/// `page`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReaddirOrder {
+    ByIno,
+    ByName,
+    ByInsertion,
+}
+
+impl ReaddirOrder {
+    pub fn sort(self, dir: &mut [DirItem]) {
+        match self {
+            Self::ByIno => dir.sort_by_key(|item| (item.ino, item.seq)),
+            Self::ByName => dir.sort_by(|a, b| a.name.cmp(&b.name)),
+            Self::ByInsertion => dir.sort_by_key(|item| item.seq),
+        }
+    }
 
-pub fn encode(dir: &[DirItem]) -> Result<Vec<u8>> {
-    serialize(dir).map_err(|err| FsError::Serialize {
+    /// The entries of `dir` as a listing in this order.
+    pub fn list(self, dir: Directory) -> Vec<DirItem> {
+        let mut items: Vec<DirItem> = dir.entries.into_values().collect();
+        self.sort(&mut items);
+        items
+    }
+
+    /// The entries of a listing sorted in this order from `offset` on, each with the
+    /// offset to resume from after it.
+    ///
+    /// Insertion order is paged by sequence number and inode order by inode number, which
+    /// stay valid while entries are added and removed between two calls; entries sharing
+    /// an inode resume from the first of them, so a listing cut among them repeats some
+    /// rather than skip any. Name order is paged by position.
+    pub fn page<T>(
+        self,
+        entries: Vec<T>,
+        offset: u64,
+        item: impl Fn(&T) -> &DirItem,
+    ) -> Vec<(u64, T)> {
+        match self {
+            Self::ByInsertion => entries
+                .into_iter()
+                .filter(|entry| item(entry).seq >= offset)
+                .map(|entry| (item(&entry).seq + 1, entry))
+                .collect(),
+            Self::ByIno => {
+                let inos: Vec<u64> = entries.iter().map(|entry| item(entry).ino).collect();
+                entries
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, entry)| item(entry).ino >= offset)
+                    .map(|(index, entry)| {
+                        let ino = item(&entry).ino;
+                        let next = if inos.get(index + 1) == Some(&ino) {
+                            ino
+                        } else {
+                            ino + 1
+                        };
+                        (next, entry)
+                    })
+                    .collect()
+            }
+            Self::ByName => entries
+                .into_iter()
+                .enumerate()
+                .skip(offset as usize)
+                .map(|(index, entry)| (index as u64 + 1, entry))
+                .collect(),
+        }
+    }
+}
+
+/// The sequence number of an entry added to `dir`.
+pub fn next_seq(dir: &Directory) -> u64 {
+    dir.values()
+        .map(|item| item.seq + 1)
+        .fold(dir.next_seq, u64::max)
+}
+
+/// Add the entry `name` to `dir` after every existing one in insertion order, replacing
+/// an entry of the same name.
+pub fn add_entry(dir: &mut Directory, ino: u64, name: &str, typ: FileType) {
+    let seq = next_seq(dir);
+    dir.next_seq = seq + 1;
+    dir.insert(
+        name.to_string(),
+        DirItem {
+            ino,
+            name: name.to_string(),
+            typ,
+            seq,
+        },
+    );
+}
+
+/// Key `items` by name; a later item replaces an earlier one of the same name.
+pub fn from_items(items: impl IntoIterator<Item = DirItem>) -> Directory {
+    let mut dir = Directory {
+        entries: items
+            .into_iter()
+            .map(|item| (item.name.clone(), item))
+            .collect(),
+        next_seq: 0,
+    };
+    dir.next_seq = next_seq(&dir);
+    dir
+}
+
+/// Point the entry `name` of `dir` at another inode, keeping its place in listings;
+/// `false` if there is no such entry.
+pub fn retarget(dir: &mut Directory, name: &str, ino: u64, typ: FileType) -> bool {
+    match dir.get_mut(name) {
+        Some(item) => {
+            item.ino = ino;
+            item.typ = typ;
+            true
+        }
+        None => false,
+    }
+}
+
+impl Default for ReaddirOrder {
+    fn default() -> Self {
+        Self::ByIno
+    }
+}
+
+impl FromStr for ReaddirOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(order: &str) -> std::result::Result<Self, Self::Err> {
+        match order {
+            "ino" => Ok(Self::ByIno),
+            "name" => Ok(Self::ByName),
+            "insertion" => Ok(Self::ByInsertion),
+            _ => Err(anyhow::anyhow!(
+                "unknown readdir order `{}`, expect one of ino, name, insertion",
+                order
+            )),
+        }
+    }
+}
+
+/// First byte of a directory record in the current format, a MessagePack `Directory`
+/// with named fields.
+///
+/// Records written before carry no version and are a list of entries in the `serialize`
+/// encoding; they are still decoded, and rewritten in this format the next time the
+/// directory is saved.
+pub const DIR_FORMAT_VERSION: u8 = 3;
+const DIR_FORMAT: &str = "msgpack";
+
+pub fn encode(dir: &Directory) -> Result<Vec<u8>> {
+    let mut data = vec![DIR_FORMAT_VERSION];
+    rmp_serde::encode::write_named(&mut data, dir).map_err(|err| FsError::Serialize {
         target: "directory",
-        typ: ENCODING,
+        typ: DIR_FORMAT,
         msg: err.to_string(),
-    })
+    })?;
+    Ok(data)
 }
 
 pub fn decode(bytes: &[u8]) -> Result<Directory> {
-    deserialize(bytes).map_err(|err| FsError::Serialize {
+    if let Some((&DIR_FORMAT_VERSION, data)) = bytes.split_first() {
+        if let Ok(dir) = rmp_serde::from_read_ref(data) {
+            return Ok(dir);
+        }
+    }
+    // a legacy record may start with the version byte by chance, so fall back whenever
+    // the versioned format fails
+    let mut items: Vec<DirItem> = deserialize(bytes).map_err(|err| FsError::Serialize {
         target: "directory",
         typ: ENCODING,
         msg: err.to_string(),
-    })
+    })?;
+    // directories stored before sequence numbers decode with all of them 0; number their
+    // entries by position, which sticks once the directory is saved again
+    if !items.windows(2).all(|pair| pair[0].seq < pair[1].seq) {
+        for (seq, item) in items.iter_mut().enumerate() {
+            item.seq = seq as u64;
+        }
+    }
+    Ok(from_items(items))
 }
 
 pub fn encode_item(item: &DirItem) -> Result<Vec<u8>> {
@@ -35,3 +254,187 @@ pub fn decode_item(bytes: &[u8]) -> Result<DirItem> {
         msg: err.to_string(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn append(dir: &mut Directory, ino: u64, name: &str) {
+        add_entry(dir, ino, name, FileType::RegularFile);
+    }
+
+    fn entries(dir: &Directory) -> Vec<(u64, &str, FileType, u64)> {
+        dir.values()
+            .map(|item| (item.ino, item.name.as_str(), item.typ, item.seq))
+            .collect()
+    }
+
+    #[test]
+    fn names_up_to_name_max() {
+        assert!(check_file_name(&"a".repeat(MAX_NAME_LEN)).is_ok());
+        let err = check_file_name(&"a".repeat(MAX_NAME_LEN + 1)).unwrap_err();
+        assert_eq!(libc::c_int::from(err), libc::ENAMETOOLONG);
+    }
+
+    fn names(page: &[(u64, DirItem)]) -> Vec<&str> {
+        page.iter().map(|(_, item)| item.name.as_str()).collect()
+    }
+
+    #[test]
+    fn insertion_pages_survive_concurrent_changes() {
+        let mut dir = Directory::new();
+        for (ino, name) in ["e", "d", "c", "b", "a"].iter().enumerate() {
+            append(&mut dir, ino as u64 + 2, name);
+        }
+        let order = ReaddirOrder::ByInsertion;
+
+        let first = order.page(order.list(dir.clone()), 0, |item| item);
+        let resume = first[2].0;
+        assert_eq!(names(&first[..3]), ["e", "d", "c"]);
+
+        // an entry before the cookie goes away and one is added while the listing runs
+        dir.remove("d");
+        append(&mut dir, 7, "f");
+        let dir = decode(&encode(&dir).unwrap()).unwrap();
+
+        let rest = order.page(order.list(dir), resume, |item| item);
+        assert_eq!(names(&rest), ["b", "a", "f"]);
+        assert_eq!(rest.last().unwrap().0, 6);
+    }
+
+    #[test]
+    fn inode_pages_survive_recycled_numbers() {
+        let mut dir = Directory::new();
+        append(&mut dir, 5, "a");
+        append(&mut dir, 9, "b");
+        append(&mut dir, 9, "c");
+        append(&mut dir, 12, "d");
+        let order = ReaddirOrder::ByIno;
+
+        let first = order.page(order.list(dir.clone()), 0, |item| item);
+        assert_eq!(names(&first), ["a", "b", "c", "d"]);
+        assert_eq!(
+            first.iter().map(|(next, _)| *next).collect::<Vec<_>>(),
+            [6, 9, 10, 13]
+        );
+
+        // a recycled number sorts before the cookie and is left out
+        dir.remove("a");
+        append(&mut dir, 3, "e");
+        let rest = order.page(order.list(dir.clone()), first[0].0, |item| item);
+        assert_eq!(names(&rest), ["b", "c", "d"]);
+        // cut between two links of an inode, both come again
+        let rest = order.page(order.list(dir), first[1].0, |item| item);
+        assert_eq!(names(&rest), ["b", "c", "d"]);
+    }
+
+    #[test]
+    fn positional_pages() {
+        let mut dir = Directory::new();
+        append(&mut dir, 3, "b");
+        append(&mut dir, 2, "a");
+        let order = ReaddirOrder::ByName;
+        let page = order.page(order.list(dir), 1, |item| item);
+        assert_eq!(names(&page), ["b"]);
+        assert_eq!(page[0].0, 2);
+    }
+
+    #[test]
+    fn entries_are_found_by_name() {
+        let mut dir = Directory::new();
+        for ino in 0..1000 {
+            append(&mut dir, ino + 2, &format!("file{}", ino));
+        }
+        assert_eq!(dir["file500"].ino, 502);
+        assert!(dir.remove("file500").is_some());
+        assert!(!dir.contains_key("file500"));
+        assert_eq!(next_seq(&dir), 1000);
+
+        // re-adding a name moves it to the end of the insertion order
+        append(&mut dir, 2000, "file0");
+        assert_eq!((dir.len(), dir["file0"].seq), (999, 1000));
+    }
+
+    #[test]
+    fn sequence_numbers_are_not_reused() {
+        let mut dir = Directory::new();
+        append(&mut dir, 2, "a");
+        append(&mut dir, 3, "b");
+        dir.remove("b");
+        let mut dir = decode(&encode(&dir).unwrap()).unwrap();
+        append(&mut dir, 4, "c");
+        assert_eq!(dir["c"].seq, 2);
+    }
+
+    #[test]
+    fn legacy_directories_are_numbered_by_position() {
+        let mut dir = Directory::new();
+        append(&mut dir, 2, "b");
+        append(&mut dir, 3, "a");
+        let mut items = ReaddirOrder::ByInsertion.list(dir);
+        items.iter_mut().for_each(|item| item.seq = 0);
+        let dir = decode(&serialize(&items).unwrap()).unwrap();
+        assert_eq!(dir["b"].seq, 0);
+        assert_eq!(dir["a"].seq, 1);
+        assert_eq!(next_seq(&dir), 2);
+    }
+
+    #[test]
+    fn exchanged_entries_keep_their_places() {
+        let mut dir = Directory::new();
+        append(&mut dir, 2, "a");
+        append(&mut dir, 3, "b");
+
+        assert!(retarget(&mut dir, "a", 3, FileType::RegularFile));
+        assert!(retarget(&mut dir, "b", 2, FileType::Directory));
+        assert!(!retarget(&mut dir, "c", 2, FileType::RegularFile));
+
+        let entries: Vec<_> = dir
+            .values()
+            .map(|item| (item.name.as_str(), item.ino, item.typ, item.seq))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                ("a", 3, FileType::RegularFile, 0),
+                ("b", 2, FileType::Directory, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn current_format_round_trips() {
+        let mut dir = Directory::new();
+        append(&mut dir, 1, ".");
+        append(&mut dir, 2, "a");
+        let data = encode(&dir).unwrap();
+        assert_eq!(data[0], DIR_FORMAT_VERSION);
+        assert_eq!(entries(&decode(&data).unwrap()), entries(&dir));
+    }
+
+    #[test]
+    fn legacy_records_are_decoded() {
+        let mut dir = Directory::new();
+        append(&mut dir, 1, ".");
+        append(&mut dir, 2, "a");
+        let legacy = serialize(&ReaddirOrder::ByInsertion.list(dir.clone())).unwrap();
+        assert_eq!(entries(&decode(&legacy).unwrap()), entries(&dir));
+    }
+
+    #[cfg(all(feature = "json", not(feature = "binc")))]
+    #[test]
+    fn hand_written_legacy_record() {
+        let legacy =
+            br#"[{"ino":1,"name":".","typ":"Directory"},{"ino":2,"name":"a","typ":"RegularFile"}]"#;
+        let dir = decode(legacy).unwrap();
+        assert_eq!(
+            entries(&dir),
+            vec![
+                (1, ".", FileType::Directory, 0),
+                (2, "a", FileType::RegularFile, 1)
+            ]
+        );
+        // saving it again moves it to the current format
+        assert_eq!(encode(&dir).unwrap()[0], DIR_FORMAT_VERSION);
+    }
+}
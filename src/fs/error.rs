@@ -1,6 +1,10 @@
 use thiserror::Error;
 use tracing::error;
 
+use super::block::CompressionMode;
+use super::quota::QuotaOwner;
+use super::snapshot::SnapshotId;
+
 #[derive(Error, Debug)]
 pub enum FsError {
     #[error("unimplemented")]
@@ -34,6 +38,9 @@ pub enum FsError {
     #[error("invalid offset({offset}) of ino({ino})")]
     InvalidOffset { ino: u64, offset: i64 },
 
+    #[error("no data or hole after offset({offset}) of inode({ino})")]
+    SeekBeyondEnd { ino: u64, offset: u64 },
+
     #[error("unknown whence({whence})")]
     UnknownWhence { whence: i32 },
 
@@ -43,14 +50,15 @@ pub enum FsError {
     #[error("dir({dir}) not empty")]
     DirNotEmpty { dir: String },
 
-    #[error("invalid string")]
-    InvalidStr,
+    #[error("invalid string: {0}")]
+    InvalidStr(#[from] std::ffi::NulError),
 
     #[error("unknown file type")]
     UnknownFileType,
 
+    /// A write conflict or a locked key, see `is_retryable`.
     #[error("key error: {0}")]
-    KeyError(String),
+    KeyError(#[source] tikv_client::Error),
 
     #[error("excess max retry times: {0}")]
     RetryTimesExcess(u64),
@@ -61,14 +69,128 @@ pub enum FsError {
     #[error("unknown error({0})")]
     UnknownError(String),
 
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("tikv error: {0}")]
+    TiKv(#[source] tikv_client::Error),
+
     #[error("invalid lock")]
     InvalidLock,
 
+    #[error("lock on inode({ino}) conflicts with one held by another owner")]
+    LockConflict { ino: u64 },
+
+    #[error("waiting for a lock on inode({ino}) would deadlock")]
+    Deadlock { ino: u64 },
+
     #[error("block size conflicts: origin({origin}) != new({new})")]
     BlockSizeConflict { origin: u64, new: u64 },
 
+    #[error("encryption conflicts: origin(encrypted: {origin}) != new(encrypted: {new})")]
+    EncryptionConflict { origin: bool, new: bool },
+
+    #[error("compression conflicts: origin({origin:?}) != new({new:?})")]
+    CompressionConflict {
+        origin: CompressionMode,
+        new: CompressionMode,
+    },
+
     #[error("no space left: MaxSize({0})")]
     NoSpaceLeft(u64),
+
+    #[error("quota of {owner:?} exceeded")]
+    QuotaExceeded { owner: QuotaOwner },
+
+    #[error("inode number space exhausted")]
+    InodeExhausted,
+
+    #[error("dir({ino}) is full: limit({limit})")]
+    DirectoryFull { ino: u64, limit: u64 },
+
+    #[error("data of inode({inode}) is corrupted: {detail}")]
+    CorruptedData { inode: u64, detail: String },
+
+    #[error("cannot find xattr({name}) of inode({ino})")]
+    XattrNotFound { ino: u64, name: String },
+
+    #[error("xattr({name}) of inode({ino}) already exist")]
+    XattrExist { ino: u64, name: String },
+
+    #[error("xattr({name}) is not in a supported namespace")]
+    UnsupportedXattr { name: String },
+
+    #[error("xattr({name}) of {size} bytes is too large")]
+    XattrTooLarge { name: String, size: usize },
+
+    #[error("invalid xattr flags({0:#x})")]
+    InvalidXattrFlags(i32),
+
+    #[error("xattr({name}) is read-only")]
+    ImmutableXattr { name: String },
+
+    #[error("xattr name or value of {len} bytes exceeds size({size})")]
+    XattrRange { len: usize, size: u32 },
+
+    #[error("file({file}) is not a directory")]
+    NotDirectory { file: String },
+
+    #[error("inode({inode}) is not a symlink")]
+    NotASymlink { inode: u64 },
+
+    #[error("invalid symlink target of {len} bytes")]
+    InvalidLinkTarget { len: usize },
+
+    #[error("file({file}) is a directory")]
+    IsDirectory { file: String },
+
+    #[error("inode({ino}) is not a regular file to reflink")]
+    InvalidReflink { ino: u64 },
+
+    #[error("cannot move dir({dir}) into itself")]
+    InvalidRename { dir: String },
+
+    #[error("invalid rename flags({0:#x})")]
+    InvalidRenameFlags(u32),
+
+    #[error("operation not permitted on inode({ino})")]
+    NotPermitted { ino: u64 },
+
+    #[error("permission denied on inode({ino})")]
+    PermissionDenied { ino: u64 },
+
+    #[error("invalid posix acl: {0}")]
+    InvalidAcl(String),
+
+    #[error("invalid access mask({0:#x})")]
+    InvalidAccessMask(i32),
+
+    #[error("invalid label({label:?}): at most {max} ascii bytes")]
+    InvalidLabel { label: String, max: usize },
+
+    #[error("unsupported open flags({0:#x})")]
+    UnsupportedOpenFlags(i32),
+
+    #[error("unsupported ioctl({0:#x})")]
+    UnsupportedIoctl(u32),
+
+    #[error("invalid argument of ioctl({cmd:#x})")]
+    InvalidIoctlArgument { cmd: u32 },
+
+    #[error("unsupported fallocate mode({0:#x})")]
+    UnsupportedFallocateMode(i32),
+
+    #[error("read-only filesystem")]
+    ReadOnly,
+
+    #[error("snapshot({name}) already exist")]
+    SnapshotExist { name: String },
+
+    #[error("cannot find snapshot({id})")]
+    SnapshotNotFound { id: SnapshotId },
+
+    #[error("cannot rename across filesystems: parent({parent}), new parent({newparent})")]
+    CrossDevice { parent: u64, newparent: u64 },
 }
 
 pub type Result<T> = std::result::Result<T, FsError>;
@@ -81,17 +203,72 @@ impl FsError {
     pub fn block_size_conflict(origin: u64, new: u64) -> Self {
         Self::BlockSizeConflict { origin, new }
     }
-}
 
-impl From<std::ffi::NulError> for FsError {
-    fn from(_: std::ffi::NulError) -> Self {
-        Self::InvalidStr
-    }
-}
-
-impl From<std::io::Error> for FsError {
-    fn from(err: std::io::Error) -> Self {
-        Self::UnknownError(err.to_string())
+    /// The errno FUSE replies with; every variant is listed so new ones get one on purpose.
+    pub fn to_errno(&self) -> libc::c_int {
+        use FsError::*;
+        match self {
+            Unimplemented => libc::ENOSYS,
+            NameTooLong { file: _ } => libc::ENAMETOOLONG,
+            FileNotFound { file: _ } => libc::ENOENT,
+            FileExist { file: _ } => libc::EEXIST,
+            InodeNotFound { inode: _ } => libc::ESTALE,
+            FhNotFound { ino: _, fh: _ } => libc::EBADF,
+            InvalidOffset { ino: _, offset: _ } => libc::EINVAL,
+            UnknownWhence { whence: _ } => libc::EINVAL,
+            SeekBeyondEnd { .. } => libc::ENXIO,
+            BlockNotFound { inode: _, block: _ } => libc::EIO,
+            DirNotEmpty { dir: _ } => libc::ENOTEMPTY,
+            UnknownFileType => libc::EINVAL,
+            KeyError(_) => libc::EAGAIN,
+            RetryTimesExcess(_) => libc::EAGAIN,
+            InvalidStr(_) => libc::EINVAL,
+            BlockSizeConflict { origin: _, new: _ } => libc::EINVAL,
+            CompressionConflict { .. } => libc::EINVAL,
+            EncryptionConflict { .. } => libc::EINVAL,
+            NoSpaceLeft(_) => libc::ENOSPC,
+            QuotaExceeded { .. } => libc::EDQUOT,
+            InodeExhausted => libc::ENOSPC,
+            DirectoryFull { .. } => libc::ENOSPC,
+            CorruptedData { .. } => libc::EIO,
+            CrossDevice { .. } => libc::EXDEV,
+            NotDirectory { .. } => libc::ENOTDIR,
+            IsDirectory { .. } => libc::EISDIR,
+            NotASymlink { .. } => libc::EINVAL,
+            InvalidLinkTarget { .. } => libc::EINVAL,
+            InvalidReflink { .. } => libc::EINVAL,
+            InvalidRename { .. } => libc::EINVAL,
+            InvalidRenameFlags(_) => libc::EINVAL,
+            XattrNotFound { .. } => libc::ENODATA,
+            XattrExist { .. } => libc::EEXIST,
+            InvalidXattrFlags(_) => libc::EINVAL,
+            UnsupportedXattr { .. } => libc::EOPNOTSUPP,
+            XattrTooLarge { .. } => libc::E2BIG,
+            ImmutableXattr { .. } => libc::EPERM,
+            XattrRange { .. } => libc::ERANGE,
+            NotPermitted { .. } => libc::EPERM,
+            PermissionDenied { .. } => libc::EACCES,
+            InvalidAcl(_) => libc::EINVAL,
+            InvalidAccessMask(_) => libc::EINVAL,
+            InvalidLabel { .. } => libc::EINVAL,
+            UnsupportedOpenFlags(_) => libc::EOPNOTSUPP,
+            UnsupportedIoctl(_) => libc::ENOTTY,
+            InvalidIoctlArgument { .. } => libc::EINVAL,
+            ReadOnly => libc::EROFS,
+            SnapshotExist { .. } => libc::EEXIST,
+            SnapshotNotFound { .. } => libc::ENOENT,
+            LockConflict { .. } => libc::EAGAIN,
+            Deadlock { .. } => libc::EDEADLK,
+            // not ENOSYS, which would make the kernel stop sending fallocate at all
+            UnsupportedFallocateMode(_) => libc::EOPNOTSUPP,
+            Io(err) => err.raw_os_error().unwrap_or(libc::EIO),
+            InvalidLock => libc::EINVAL,
+            StripPrefixError(_) => libc::EINVAL,
+            InvalidScopedKey(_) => libc::EIO,
+            Serialize { .. } => libc::EIO,
+            UnknownError(_) => libc::EIO,
+            TiKv(_) => libc::EIO,
+        }
     }
 }
 
@@ -100,34 +277,229 @@ impl From<tikv_client::Error> for FsError {
         use tikv_client::Error::*;
 
         match err {
-            KeyError(err) => Self::KeyError(format!("{:?}", err)),
-            MultipleKeyErrors(errs) => Self::KeyError(format!("{:?}", errs)),
-            _ => Self::UnknownError(err.to_string()),
+            KeyError(_) | MultipleKeyErrors(_) => Self::KeyError(err),
+            _ => Self::TiKv(err),
         }
     }
 }
 
 impl From<FsError> for libc::c_int {
     fn from(e: FsError) -> Self {
+        e.to_errno()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn wrapped_errors_are_chained() {
+        let inner = io::Error::new(io::ErrorKind::Other, "disk on fire");
+        let err = anyhow::Error::new(FsError::from(inner));
+        let chain: Vec<String> = err.chain().map(ToString::to_string).collect();
+        assert_eq!(chain, ["io error: disk on fire", "disk on fire"]);
+    }
+
+    /// The place of `err` in `every_error` and the errno it must map to; without a
+    /// catch-all arm, a new variant doesn't compile until it is listed here.
+    fn expected_errno(err: &FsError) -> (usize, libc::c_int) {
         use FsError::*;
-        match e {
-            Unimplemented => libc::ENOSYS,
-            NameTooLong { file: _ } => libc::ENAMETOOLONG,
-            FileNotFound { file: _ } => libc::ENOENT,
-            FileExist { file: _ } => libc::EEXIST,
-            InodeNotFound { inode: _ } => libc::EFAULT,
-            FhNotFound { ino: _, fh: _ } => libc::EBADF,
-            InvalidOffset { ino: _, offset: _ } => libc::EINVAL,
-            UnknownWhence { whence: _ } => libc::EINVAL,
-            BlockNotFound { inode: _, block: _ } => libc::EINVAL,
-            DirNotEmpty { dir: _ } => libc::ENOTEMPTY,
-            UnknownFileType => libc::EINVAL,
-            KeyError(_) => libc::EAGAIN,
-            RetryTimesExcess(_) => libc::EAGAIN,
-            InvalidStr => libc::EINVAL,
-            BlockSizeConflict { origin: _, new: _ } => libc::EINVAL,
-            NoSpaceLeft(_) => libc::ENOSPC,
-            _ => libc::EFAULT,
+        match err {
+            Unimplemented => (0, libc::ENOSYS),
+            InvalidScopedKey(_) => (1, libc::EIO),
+            Serialize { .. } => (2, libc::EIO),
+            NameTooLong { .. } => (3, libc::ENAMETOOLONG),
+            FileNotFound { .. } => (4, libc::ENOENT),
+            FileExist { .. } => (5, libc::EEXIST),
+            InodeNotFound { .. } => (6, libc::ESTALE),
+            FhNotFound { .. } => (7, libc::EBADF),
+            InvalidOffset { .. } => (8, libc::EINVAL),
+            SeekBeyondEnd { .. } => (9, libc::ENXIO),
+            UnknownWhence { .. } => (10, libc::EINVAL),
+            BlockNotFound { .. } => (11, libc::EIO),
+            DirNotEmpty { .. } => (12, libc::ENOTEMPTY),
+            InvalidStr(_) => (13, libc::EINVAL),
+            UnknownFileType => (14, libc::EINVAL),
+            KeyError(_) => (15, libc::EAGAIN),
+            RetryTimesExcess(_) => (16, libc::EAGAIN),
+            StripPrefixError(_) => (17, libc::EINVAL),
+            UnknownError(_) => (18, libc::EIO),
+            Io(_) => (19, libc::EIO),
+            TiKv(_) => (20, libc::EIO),
+            InvalidLock => (21, libc::EINVAL),
+            LockConflict { .. } => (22, libc::EAGAIN),
+            Deadlock { .. } => (23, libc::EDEADLK),
+            BlockSizeConflict { .. } => (24, libc::EINVAL),
+            EncryptionConflict { .. } => (25, libc::EINVAL),
+            CompressionConflict { .. } => (26, libc::EINVAL),
+            NoSpaceLeft(_) => (27, libc::ENOSPC),
+            QuotaExceeded { .. } => (28, libc::EDQUOT),
+            InodeExhausted => (29, libc::ENOSPC),
+            DirectoryFull { .. } => (30, libc::ENOSPC),
+            CorruptedData { .. } => (31, libc::EIO),
+            XattrNotFound { .. } => (32, libc::ENODATA),
+            XattrExist { .. } => (33, libc::EEXIST),
+            UnsupportedXattr { .. } => (34, libc::EOPNOTSUPP),
+            XattrTooLarge { .. } => (35, libc::E2BIG),
+            InvalidXattrFlags(_) => (36, libc::EINVAL),
+            ImmutableXattr { .. } => (37, libc::EPERM),
+            XattrRange { .. } => (38, libc::ERANGE),
+            NotDirectory { .. } => (39, libc::ENOTDIR),
+            NotASymlink { .. } => (40, libc::EINVAL),
+            InvalidLinkTarget { .. } => (41, libc::EINVAL),
+            IsDirectory { .. } => (42, libc::EISDIR),
+            InvalidReflink { .. } => (43, libc::EINVAL),
+            InvalidRename { .. } => (44, libc::EINVAL),
+            InvalidRenameFlags(_) => (45, libc::EINVAL),
+            NotPermitted { .. } => (46, libc::EPERM),
+            PermissionDenied { .. } => (47, libc::EACCES),
+            InvalidAcl(_) => (48, libc::EINVAL),
+            InvalidAccessMask(_) => (49, libc::EINVAL),
+            InvalidLabel { .. } => (50, libc::EINVAL),
+            UnsupportedOpenFlags(_) => (51, libc::EOPNOTSUPP),
+            UnsupportedIoctl(_) => (52, libc::ENOTTY),
+            InvalidIoctlArgument { .. } => (53, libc::EINVAL),
+            UnsupportedFallocateMode(_) => (54, libc::EOPNOTSUPP),
+            ReadOnly => (55, libc::EROFS),
+            SnapshotExist { .. } => (56, libc::EEXIST),
+            SnapshotNotFound { .. } => (57, libc::ENOENT),
+            CrossDevice { .. } => (58, libc::EXDEV),
         }
     }
+
+    /// One error of every variant, in the order of `expected_errno`.
+    fn every_error() -> Vec<FsError> {
+        use FsError::*;
+        let name = || "a".to_owned();
+        vec![
+            Unimplemented,
+            InvalidScopedKey(Vec::new()),
+            Serialize {
+                target: "inode",
+                typ: "json",
+                msg: name(),
+            },
+            NameTooLong { file: name() },
+            FileNotFound { file: name() },
+            FileExist { file: name() },
+            InodeNotFound { inode: 2 },
+            FhNotFound { ino: 2, fh: 1 },
+            InvalidOffset { ino: 2, offset: -1 },
+            SeekBeyondEnd { ino: 2, offset: 1 },
+            UnknownWhence { whence: 9 },
+            BlockNotFound { inode: 2, block: 0 },
+            DirNotEmpty { dir: name() },
+            InvalidStr(std::ffi::CString::new("a\0b").unwrap_err()),
+            UnknownFileType,
+            FsError::from(tikv_client::Error::MultipleKeyErrors(Vec::new())),
+            RetryTimesExcess(16),
+            StripPrefixError(std::path::Path::new("a").strip_prefix("b").unwrap_err()),
+            UnknownError(name()),
+            Io(io::Error::new(io::ErrorKind::Other, "lost")),
+            TiKv(tikv_client::Error::Unimplemented),
+            InvalidLock,
+            LockConflict { ino: 2 },
+            Deadlock { ino: 2 },
+            BlockSizeConflict {
+                origin: 4096,
+                new: 512,
+            },
+            EncryptionConflict {
+                origin: true,
+                new: false,
+            },
+            CompressionConflict {
+                origin: CompressionMode::None,
+                new: CompressionMode::Lz4,
+            },
+            NoSpaceLeft(0),
+            QuotaExceeded {
+                owner: QuotaOwner::User(1000),
+            },
+            InodeExhausted,
+            DirectoryFull { ino: 1, limit: 1 },
+            CorruptedData {
+                inode: 2,
+                detail: name(),
+            },
+            XattrNotFound {
+                ino: 2,
+                name: name(),
+            },
+            XattrExist {
+                ino: 2,
+                name: name(),
+            },
+            UnsupportedXattr { name: name() },
+            XattrTooLarge {
+                name: name(),
+                size: 1 << 20,
+            },
+            InvalidXattrFlags(4),
+            ImmutableXattr { name: name() },
+            XattrRange { len: 2, size: 1 },
+            NotDirectory { file: name() },
+            NotASymlink { inode: 2 },
+            InvalidLinkTarget { len: 0 },
+            IsDirectory { file: name() },
+            InvalidReflink { ino: 2 },
+            InvalidRename { dir: name() },
+            InvalidRenameFlags(8),
+            NotPermitted { ino: 2 },
+            PermissionDenied { ino: 2 },
+            InvalidAcl(name()),
+            InvalidAccessMask(0x10),
+            InvalidLabel {
+                label: name(),
+                max: 0,
+            },
+            UnsupportedOpenFlags(3),
+            UnsupportedIoctl(1),
+            InvalidIoctlArgument { cmd: 1 },
+            UnsupportedFallocateMode(8),
+            ReadOnly,
+            SnapshotExist { name: name() },
+            SnapshotNotFound { id: SnapshotId(1) },
+            CrossDevice {
+                parent: 1,
+                newparent: 2,
+            },
+        ]
+    }
+
+    #[test]
+    fn every_error_has_its_errno() {
+        let errors = every_error();
+        for (index, err) in errors.into_iter().enumerate() {
+            let (place, errno) = expected_errno(&err);
+            assert_eq!(place, index, "{} is out of place", err);
+            assert_eq!(err.to_errno(), errno, "{}", err);
+            assert_eq!(libc::c_int::from(err), errno);
+        }
+        // the last variant of `expected_errno` is listed too
+        assert_eq!(every_error().len(), 59);
+    }
+
+    #[test]
+    fn key_and_nul_errors_are_kept_as_sources() {
+        use std::error::Error;
+
+        let err = FsError::from(tikv_client::Error::MultipleKeyErrors(Vec::new()));
+        assert!(matches!(err, FsError::KeyError(_)));
+        assert!(err.source().is_some());
+
+        let nul = std::ffi::CString::new("a\0b").unwrap_err();
+        let err = FsError::from(nul);
+        assert_eq!(err.to_errno(), libc::EINVAL);
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn io_errors_keep_their_errno() {
+        let err = FsError::from(io::Error::from_raw_os_error(libc::ENOSPC));
+        assert_eq!(libc::c_int::from(err), libc::ENOSPC);
+    }
 }
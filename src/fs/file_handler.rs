@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 
 use super::error::{FsError, Result};
+use super::inode::Inode;
 use super::serialize::{deserialize, serialize, ENCODING};
 
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy, Deserialize, Serialize)]
@@ -36,3 +37,52 @@ impl Default for FileHandler {
         Self::new(0)
     }
 }
+
+/// What a file opened by `Txn::open` may be used for.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct OpenCaps(u8);
+
+impl OpenCaps {
+    pub const READ: Self = Self(1);
+    pub const WRITE: Self = Self(1 << 1);
+    pub const APPEND: Self = Self(1 << 2);
+
+    pub const fn contains(self, caps: Self) -> bool {
+        self.0 & caps.0 == caps.0
+    }
+
+    pub fn can_read(self) -> bool {
+        self.contains(Self::READ)
+    }
+
+    pub fn can_write(self) -> bool {
+        self.contains(Self::WRITE)
+    }
+
+    /// Writes go to the end of the file whatever the offset, as with `O_APPEND`.
+    pub fn is_append_only(self) -> bool {
+        self.contains(Self::APPEND)
+    }
+}
+
+impl std::ops::BitOr for OpenCaps {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl std::ops::BitOrAssign for OpenCaps {
+    fn bitor_assign(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+}
+
+/// A file opened by `Txn::open`.
+#[derive(Debug, Clone)]
+pub struct OpenInfo {
+    pub fh: u64,
+    pub inode: Inode,
+    pub caps: OpenCaps,
+}
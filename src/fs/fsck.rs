@@ -0,0 +1,442 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use fuser::FileType;
+use thiserror::Error;
+
+use super::dir::Directory;
+use super::key::ROOT_INODE;
+use super::tikv_fs::{DIR_PARENT, DIR_SELF};
+
+/// Directory under the root that `fsck --repair` moves unreachable inodes into.
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/// An inconsistency found by `Txn::fsck`.
+#[derive(Clone, Debug, Eq, PartialEq, Error)]
+pub enum FsckError {
+    #[error("index({parent}/{name}) -> inode({ino}) is not listed in its directory")]
+    DanglingIndex { parent: u64, name: String, ino: u64 },
+
+    #[error("entry({parent}/{name}) -> inode({ino}) points to a missing inode")]
+    DanglingEntry { parent: u64, name: String, ino: u64 },
+
+    #[error("entry({parent}/{name}) -> inode({ino}) is indexed as {index:?}")]
+    IndexMismatch {
+        parent: u64,
+        name: String,
+        ino: u64,
+        index: Option<u64>,
+    },
+
+    #[error("dir({ino}) has no directory record")]
+    MissingDirectory { ino: u64 },
+
+    #[error("inode({ino}) is not linked from any directory")]
+    OrphanInode { ino: u64 },
+
+    #[error("inode({ino}) has nlink({nlink}) but {expected} links")]
+    WrongNlink { ino: u64, nlink: u32, expected: u32 },
+
+    /// A tmpfile closed for good without a name, as a crash leaves it.
+    #[error("tmpfile({ino}) has neither a name nor an open handle")]
+    AbandonedTmpfile { ino: u64 },
+
+    /// Also covers the record of a removed directory, stored as its block 0.
+    #[error("block(<{ino}>[{block}]) belongs to a missing inode")]
+    OrphanBlock { ino: u64, block: u64 },
+
+    /// `Meta::used_blocks` and the usage shards add up to something else than the blocks
+    /// stored.
+    #[error("usage counts {counted} blocks but {stored} are stored")]
+    WrongBlockCount { counted: u64, stored: u64 },
+
+    /// An inode or directory record that fails to decode, as `FsError::CorruptedData`.
+    #[error("inode({ino}) is corrupted: {detail}")]
+    Corrupted { ino: u64, detail: String },
+}
+
+/// Result of `Txn::fsck`.
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub errors: Vec<FsckError>,
+    /// Whether the errors have been repaired in the same transaction.
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// The namespace of a filesystem as loaded by `Txn::fsck`.
+#[derive(Debug, Default)]
+pub struct FsImage {
+    /// `nlink` and kind of every inode.
+    pub inodes: BTreeMap<u64, (u32, FileType)>,
+    /// Entries of every directory that has a directory record.
+    pub dirs: BTreeMap<u64, Directory>,
+    /// Every `(parent, name) -> ino` index.
+    pub indexes: BTreeMap<(u64, String), u64>,
+    /// Inodes made by `Txn::make_tmp_inode` and not linked yet, with their open handles.
+    pub tmpfiles: BTreeMap<u64, u64>,
+    /// Inodes, or directory records, that failed to decode, with what was wrong; the
+    /// former are left out of `inodes`, the latter out of `dirs`.
+    pub corrupted: BTreeMap<u64, String>,
+    /// Block keys found by scanning.
+    pub stored_blocks: u64,
+    /// Blocks stored as counted by `Meta::used_blocks` and the usage shards; `None` until
+    /// `statfs` counts them once.
+    pub counted_blocks: Option<u64>,
+}
+
+impl FsImage {
+    /// The `nlink` every inode should have: one per directory entry pointing to it, and
+    /// one more for tmpfiles and for a root without `..`, as made before it listed one.
+    pub fn link_counts(&self) -> BTreeMap<u64, u32> {
+        let mut counts: BTreeMap<u64, u32> = BTreeMap::new();
+        let root_has_parent = self.dirs.get(&ROOT_INODE).map_or(false, |dir| {
+            dir.get(&*DIR_PARENT)
+                .map_or(false, |item| item.ino == ROOT_INODE)
+        });
+        counts.insert(ROOT_INODE, if root_has_parent { 0 } else { 1 });
+        for ino in self.tmpfiles.keys() {
+            counts.insert(*ino, 1);
+        }
+        for item in self.dirs.values().flat_map(|dir| dir.values()) {
+            if self.inodes.contains_key(&item.ino) {
+                *counts.entry(item.ino).or_default() += 1;
+            }
+        }
+        counts
+    }
+
+    /// Inodes but the root and tmpfiles that no entry other than `.` and `..` points to.
+    pub fn orphans(&self) -> BTreeSet<u64> {
+        let linked: BTreeSet<u64> = self
+            .dirs
+            .values()
+            .flat_map(|dir| dir.values())
+            .filter(|item| DIR_SELF != item.name && DIR_PARENT != item.name)
+            .map(|item| item.ino)
+            .collect();
+        self.inodes
+            .keys()
+            .copied()
+            .filter(|ino| {
+                *ino != ROOT_INODE && !linked.contains(ino) && !self.tmpfiles.contains_key(ino)
+            })
+            .collect()
+    }
+
+    pub fn check(&self) -> Vec<FsckError> {
+        let mut errors: Vec<FsckError> = self
+            .corrupted
+            .iter()
+            .map(|(ino, detail)| FsckError::Corrupted {
+                ino: *ino,
+                detail: detail.clone(),
+            })
+            .collect();
+
+        for (ino, (_, kind)) in &self.inodes {
+            if *kind == FileType::Directory
+                && !self.dirs.contains_key(ino)
+                && !self.corrupted.contains_key(ino)
+            {
+                errors.push(FsckError::MissingDirectory { ino: *ino });
+            }
+        }
+
+        for (parent, dir) in &self.dirs {
+            for item in dir.values() {
+                if self.corrupted.contains_key(&item.ino) {
+                    continue;
+                }
+                if !self.inodes.contains_key(&item.ino) {
+                    errors.push(FsckError::DanglingEntry {
+                        parent: *parent,
+                        name: item.name.clone(),
+                        ino: item.ino,
+                    });
+                    continue;
+                }
+                let index = self.indexes.get(&(*parent, item.name.clone())).copied();
+                if index != Some(item.ino) {
+                    errors.push(FsckError::IndexMismatch {
+                        parent: *parent,
+                        name: item.name.clone(),
+                        ino: item.ino,
+                        index,
+                    });
+                }
+            }
+        }
+
+        for ((parent, name), ino) in &self.indexes {
+            let listed = self
+                .dirs
+                .get(parent)
+                .map_or(false, |dir| dir.contains_key(name));
+            if !listed {
+                errors.push(FsckError::DanglingIndex {
+                    parent: *parent,
+                    name: name.clone(),
+                    ino: *ino,
+                });
+            }
+        }
+
+        for (ino, handles) in &self.tmpfiles {
+            if *handles == 0 {
+                errors.push(FsckError::AbandonedTmpfile { ino: *ino });
+            }
+        }
+
+        let orphans = self.orphans();
+        let counts = self.link_counts();
+        for (ino, (nlink, _)) in &self.inodes {
+            if orphans.contains(ino) {
+                errors.push(FsckError::OrphanInode { ino: *ino });
+                continue;
+            }
+            let expected = counts.get(ino).copied().unwrap_or_default();
+            if *nlink != expected {
+                errors.push(FsckError::WrongNlink {
+                    ino: *ino,
+                    nlink: *nlink,
+                    expected,
+                });
+            }
+        }
+
+        if let Some(counted) = self.counted_blocks {
+            if counted != self.stored_blocks {
+                errors.push(FsckError::WrongBlockCount {
+                    counted,
+                    stored: self.stored_blocks,
+                });
+            }
+        }
+
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::dir::from_items;
+    use crate::fs::reply::DirItem;
+
+    fn item(ino: u64, name: &str, typ: FileType) -> DirItem {
+        DirItem {
+            ino,
+            name: name.to_string(),
+            typ,
+            seq: 0,
+        }
+    }
+
+    /// `/` with a file `a` and an empty directory `d`.
+    fn image() -> FsImage {
+        let mut image = FsImage::default();
+        image.inodes.insert(ROOT_INODE, (3, FileType::Directory));
+        image.inodes.insert(2, (1, FileType::RegularFile));
+        image.inodes.insert(3, (2, FileType::Directory));
+        image.dirs.insert(
+            ROOT_INODE,
+            from_items(vec![
+                item(ROOT_INODE, ".", FileType::Directory),
+                item(2, "a", FileType::RegularFile),
+                item(3, "d", FileType::Directory),
+            ]),
+        );
+        image.dirs.insert(
+            3,
+            from_items(vec![
+                item(3, ".", FileType::Directory),
+                item(ROOT_INODE, "..", FileType::Directory),
+            ]),
+        );
+        for (parent, dir) in &image.dirs {
+            for item in dir.values() {
+                image.indexes.insert((*parent, item.name.clone()), item.ino);
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn consistent_image() {
+        assert!(image().check().is_empty());
+    }
+
+    #[test]
+    fn directories_count_dot_and_dot_dot() {
+        // a new directory is linked by its entry and its `.`; its `..` links the parent
+        let counts = image().link_counts();
+        assert_eq!(counts[&3], 2);
+        assert_eq!(counts[&ROOT_INODE], 3);
+        assert_eq!(counts[&2], 1);
+    }
+
+    #[test]
+    fn root_is_its_own_parent() {
+        let mut image = image();
+        image.inodes.insert(ROOT_INODE, (3, FileType::Directory));
+        image
+            .dirs
+            .get_mut(&ROOT_INODE)
+            .unwrap()
+            .insert("..".into(), item(ROOT_INODE, "..", FileType::Directory));
+        image.indexes.insert((ROOT_INODE, "..".into()), ROOT_INODE);
+        assert!(image.check().is_empty());
+        // every directory lists both `.` and `..`
+        for dir in image.dirs.values() {
+            assert!(dir.contains_key(&*DIR_SELF));
+            assert!(dir.contains_key(&*DIR_PARENT));
+        }
+    }
+
+    #[test]
+    fn dangling_index_and_entry() {
+        let mut image = image();
+        image.indexes.insert((ROOT_INODE, "gone".into()), 2);
+        image.inodes.remove(&2);
+        assert_eq!(
+            image.check(),
+            vec![
+                FsckError::DanglingEntry {
+                    parent: ROOT_INODE,
+                    name: "a".into(),
+                    ino: 2
+                },
+                FsckError::DanglingIndex {
+                    parent: ROOT_INODE,
+                    name: "gone".into(),
+                    ino: 2
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn orphans_and_nlink() {
+        let mut image = image();
+        image.inodes.insert(4, (1, FileType::RegularFile));
+        image.inodes.insert(2, (5, FileType::RegularFile));
+        image.indexes.remove(&(3, "..".to_string()));
+        assert_eq!(
+            image.check(),
+            vec![
+                FsckError::IndexMismatch {
+                    parent: 3,
+                    name: "..".into(),
+                    ino: ROOT_INODE,
+                    index: None
+                },
+                FsckError::WrongNlink {
+                    ino: 2,
+                    nlink: 5,
+                    expected: 1
+                },
+                FsckError::OrphanInode { ino: 4 },
+            ]
+        );
+    }
+
+    #[test]
+    fn unlinked_tmpfiles_are_not_orphans() {
+        let mut image = image();
+        image.inodes.insert(4, (1, FileType::RegularFile));
+        image.tmpfiles.insert(4, 1);
+        assert!(image.check().is_empty());
+
+        // a tmpfile with a name left over is counted for both
+        image.indexes.insert((ROOT_INODE, "t".into()), 4);
+        image
+            .dirs
+            .get_mut(&ROOT_INODE)
+            .unwrap()
+            .insert("t".into(), item(4, "t", FileType::RegularFile));
+        assert_eq!(
+            image.check(),
+            vec![FsckError::WrongNlink {
+                ino: 4,
+                nlink: 1,
+                expected: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn closed_tmpfiles_are_abandoned() {
+        let mut image = image();
+        image.inodes.insert(4, (1, FileType::RegularFile));
+        image.tmpfiles.insert(4, 0);
+        assert_eq!(image.check(), vec![FsckError::AbandonedTmpfile { ino: 4 }]);
+    }
+
+    #[test]
+    fn corrupted_inodes_are_reported_once() {
+        let mut image = image();
+        image.inodes.remove(&2);
+        image.corrupted.insert(2, "truncated".into());
+        image.dirs.remove(&3);
+        image.corrupted.insert(3, "unknown format".into());
+        let errors = image.check();
+        assert_eq!(
+            errors[..2],
+            [
+                FsckError::Corrupted {
+                    ino: 2,
+                    detail: "truncated".into()
+                },
+                FsckError::Corrupted {
+                    ino: 3,
+                    detail: "unknown format".into()
+                },
+            ]
+        );
+        // neither the entry of the undecodable inode nor the unreadable record counts
+        // as missing
+        assert!(!errors
+            .iter()
+            .any(|err| matches!(err, FsckError::DanglingEntry { .. })));
+        assert!(!errors.contains(&FsckError::MissingDirectory { ino: 3 }));
+        assert_eq!(errors[0].to_string(), "inode(2) is corrupted: truncated");
+    }
+
+    #[test]
+    fn block_counter_matches_scan() {
+        let mut image = image();
+        image.stored_blocks = 5;
+        // not counted yet
+        assert!(image.check().is_empty());
+        image.counted_blocks = Some(5);
+        assert!(image.check().is_empty());
+
+        image.counted_blocks = Some(7);
+        assert_eq!(
+            image.check(),
+            vec![FsckError::WrongBlockCount {
+                counted: 7,
+                stored: 5
+            }]
+        );
+    }
+
+    #[test]
+    fn directory_without_record() {
+        let mut image = image();
+        image.dirs.remove(&3);
+        image.indexes.retain(|(parent, _), _| *parent != 3);
+        let errors = image.check();
+        assert!(errors.contains(&FsckError::MissingDirectory { ino: 3 }));
+        assert!(errors.contains(&FsckError::WrongNlink {
+            ino: ROOT_INODE,
+            nlink: 3,
+            expected: 2
+        }));
+    }
+}
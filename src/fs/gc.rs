@@ -0,0 +1,130 @@
+//! Removal of the blocks an `unlink` or `clear_data` interrupted by a crash leaves behind.
+use std::collections::HashSet;
+use std::ops::Range;
+use std::time::Duration;
+
+use tikv_client::Key;
+use tokio::time::sleep;
+use tracing::{debug, info};
+
+use super::client::ClientPool;
+use super::config::FsConfig;
+use super::error::Result;
+use super::key::ScopedKey;
+use super::metrics::Metrics;
+use super::retry::{with_retry, DEFAULT_MAX_ATTEMPTS};
+use super::transaction::Txn;
+
+/// Pause between two pages of block keys, which bounds a pass to about
+/// `TiFs::SCAN_LIMIT` keys per `PAGE_INTERVAL`.
+pub const PAGE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A page checked by `Txn::scan_orphaned_blocks_page`.
+#[derive(Debug, Default)]
+pub struct OrphanScan {
+    pub scanned: u64,
+    /// `(ino, block)` of the blocks whose inode doesn't exist, in key order.
+    pub orphans: Vec<(u64, u64)>,
+    /// Where the next page starts; `None` after the last one.
+    pub next: Option<Key>,
+}
+
+/// The inodes the sorted `(ino, block)` pairs of a page belong to, once each.
+pub fn block_owners(blocks: &[(u64, u64)]) -> Vec<u64> {
+    let mut inos: Vec<u64> = blocks.iter().map(|(ino, _)| *ino).collect();
+    inos.dedup();
+    inos
+}
+
+/// The blocks among sorted `(ino, block)` pairs whose inode is not in `live`.
+pub fn orphaned_blocks(blocks: &[(u64, u64)], live: &HashSet<u64>) -> Vec<(u64, u64)> {
+    blocks
+        .iter()
+        .filter(|(ino, _)| !live.contains(ino))
+        .copied()
+        .collect()
+}
+
+/// Group sorted `(ino, block)` pairs into runs of consecutive blocks of an inode.
+pub fn block_runs(blocks: &[(u64, u64)]) -> Vec<(u64, Range<u64>)> {
+    let mut runs: Vec<(u64, Range<u64>)> = Vec::new();
+    for &(ino, block) in blocks {
+        match runs.last_mut() {
+            Some((last, range)) if *last == ino && range.end == block => range.end += 1,
+            _ => runs.push((ino, block..block + 1)),
+        }
+    }
+    runs
+}
+
+/// Scan every block key once and delete those of missing inodes.
+///
+/// Pages are read from a snapshot, so the scan never conflicts with the mount; each run of
+/// orphans is deleted in a transaction of its own, which checks again that the inode is
+/// still missing, as its number may have been reused in the meantime.
+pub async fn collect_garbage(
+    client: &ClientPool,
+    config: &FsConfig,
+    metrics: &Metrics,
+) -> Result<u64> {
+    let mut start = ScopedKey::all_blocks().start;
+    let mut deleted = 0;
+    loop {
+        let version = client.get().current_timestamp().await?.version();
+        let page = Txn::begin_snapshot(client.get(), version, config)
+            .scan_orphaned_blocks_page(start)
+            .await?;
+
+        let mut page_deleted = 0;
+        for (ino, range) in block_runs(&page.orphans) {
+            page_deleted += with_retry(
+                client.get(),
+                config,
+                metrics,
+                DEFAULT_MAX_ATTEMPTS,
+                move |txn| Box::pin(txn.delete_orphaned_blocks(ino, range.clone())),
+            )
+            .await?;
+        }
+        metrics.gc(page.scanned, page_deleted);
+        deleted += page_deleted;
+
+        match page.next {
+            Some(next) => start = next,
+            None => break,
+        }
+        sleep(PAGE_INTERVAL).await;
+    }
+    if deleted > 0 {
+        info!("gc: deleted {} orphaned blocks", deleted);
+    } else {
+        debug!("gc: no orphaned blocks");
+    }
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_of_missing_inodes_are_orphans() {
+        let page = [(2, 0), (2, 1), (3, 0), (3, 1), (3, 4), (5, 2), (7, 0)];
+        assert_eq!(block_owners(&page), vec![2, 3, 5, 7]);
+
+        let live: HashSet<u64> = [2, 7].iter().copied().collect();
+        let orphans = orphaned_blocks(&page, &live);
+        assert_eq!(orphans, vec![(3, 0), (3, 1), (3, 4), (5, 2)]);
+        assert_eq!(block_runs(&orphans), vec![(3, 0..2), (3, 4..5), (5, 2..3)]);
+        assert!(orphaned_blocks(&page, &block_owners(&page).into_iter().collect()).is_empty());
+    }
+
+    #[test]
+    fn consecutive_blocks_are_deleted_together() {
+        assert_eq!(
+            block_runs(&[(2, 0), (2, 1), (2, 2), (2, 5), (3, 6), (3, 7)]),
+            vec![(2, 0..3), (2, 5..6), (3, 6..8)]
+        );
+        assert!(block_runs(&[]).is_empty());
+    }
+}
@@ -1,13 +1,58 @@
 use std::collections::HashSet;
-use std::ops::{Deref, DerefMut};
+use std::ops::{Deref, DerefMut, Range};
+use std::time::{Duration, SystemTime};
 
-use fuser::FileAttr;
-use libc::F_UNLCK;
+use fuser::{FileAttr, FileType, TimeOrNow};
+use libc::{F_RDLCK, F_UNLCK, F_WRLCK};
 use serde::{Deserialize, Serialize};
 
+use super::acl::Acl;
 use super::error::{FsError, Result};
+use super::file_handler::OpenCaps;
+use super::mode::as_file_perm;
+use super::reply::Entry;
 use super::serialize::{deserialize, serialize, ENCODING};
 
+/// `FileAttr::blocks` is counted in 512-byte units, whatever the block size is.
+pub const STAT_BLOCK_SIZE: u64 = 512;
+
+/// Longest symlink target, `PATH_MAX` without the trailing nul.
+pub const MAX_LINK_TARGET_LEN: usize = libc::PATH_MAX as usize - 1;
+
+/// Refuse symlink targets POSIX doesn't allow: empty or longer than a path.
+pub fn check_link_target(target: &[u8]) -> Result<()> {
+    if target.is_empty() || target.len() > MAX_LINK_TARGET_LEN {
+        return Err(FsError::InvalidLinkTarget { len: target.len() });
+    }
+    Ok(())
+}
+
+/// Whether reads update `atime`, chosen by the `strictatime`, `relatime` and `noatime`
+/// mount options.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ATimeMode {
+    /// Every read.
+    Strict,
+    /// Reads of a file modified since its last access, or last accessed a day ago.
+    RelATime,
+    /// Never; reads don't write to TiKV at all.
+    NoATime,
+}
+
+impl ATimeMode {
+    pub const RELATIME_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+}
+
+impl Default for ATimeMode {
+    fn default() -> Self {
+        Self::RelATime
+    }
+}
+
+/// The POSIX locks held on an inode.
+///
+/// `locks` is authoritative; `owner_set` and `lk_type` are kept in sync with it, as they
+/// were all earlier versions stored.
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct LockState {
     pub owner_set: HashSet<u64>,
@@ -15,6 +60,29 @@ pub struct LockState {
     pub lk_type: i32,
     #[cfg(any(target_os = "freebsd", target_os = "macos"))]
     pub lk_type: i16,
+    #[serde(default)]
+    pub locks: Vec<FileLock>,
+}
+
+/// A lock on the bytes `[start, end]` of a file; FUSE passes both ends inclusive.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FileLock {
+    pub owner: u64,
+    pub pid: u32,
+    /// `F_RDLCK`, `F_WRLCK` or `F_UNLCK`, widened to `i32` on every platform.
+    pub typ: i32,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// A lock an owner is blocked on, kept until it gets the lock to detect deadlocks.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LockWait {
+    pub ino: u64,
+    pub lock: FileLock,
+    /// When the waiter last wrote the record; it is ignored once `LockWait::TTL` old.
+    #[serde(default)]
+    pub refreshed: Option<SystemTime>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -24,16 +92,208 @@ pub struct Inode {
     pub inline_data: Option<Vec<u8>>,
     pub next_fh: u64,
     pub opened_fh: u64,
+    /// Told apart from earlier inodes with the same number, as NFS file handles need.
+    #[serde(default)]
+    pub generation: u64,
+    /// Made by `Txn::make_tmp_inode` and not linked anywhere yet; its `nlink` of 1 stands
+    /// for the name `Txn::link` gives it, and goes with its last open handle.
+    #[serde(default)]
+    pub tmpfile: bool,
+    /// Block values carry a `BlockValue` tag, as some of them may refer to blocks shared
+    /// with reflinked files; set for good by `Txn::reflink`.
+    #[serde(default)]
+    pub shared_blocks: bool,
+    /// Directory the inode was last linked into by its creation or a rename; the root is
+    /// its own parent, and 0 stands for none, as for inodes made before it was recorded.
+    #[serde(default)]
+    pub parent_ino: u64,
+    /// Some block inside the size may be a hole by design, left by growing the size, a
+    /// write past the end, a punched hole or a block of zeros; strict reads tell a missing
+    /// block of other files apart as lost.
+    #[serde(default)]
+    pub sparse: bool,
 }
 
 impl Inode {
-    fn update_blocks(&mut self, block_size: u64) {
-        self.blocks = (self.size + block_size - 1) / block_size;
+    fn update_blocks(&mut self) {
+        self.blocks = (self.size + STAT_BLOCK_SIZE - 1) / STAT_BLOCK_SIZE;
     }
 
-    pub fn set_size(&mut self, size: u64, block_size: u64) {
+    pub fn set_size(&mut self, size: u64) {
         self.size = size;
-        self.update_blocks(block_size);
+        self.update_blocks();
+    }
+
+    /// Record a read at `now` as `mode` asks; `false` if the inode is left unchanged and
+    /// doesn't need to be saved.
+    pub fn touch_atime(&mut self, mode: ATimeMode, now: SystemTime) -> bool {
+        let update = match mode {
+            ATimeMode::Strict => true,
+            ATimeMode::NoATime => false,
+            ATimeMode::RelATime => {
+                self.atime <= self.mtime
+                    || self.atime <= self.ctime
+                    || now
+                        .duration_since(self.atime)
+                        .map_or(false, |age| age >= ATimeMode::RELATIME_INTERVAL)
+            }
+        };
+        if update {
+            self.atime = now;
+        }
+        update
+    }
+
+    /// Check that `uid` in group `gid` may open this inode with `flags`, against `acl` if
+    /// the inode has one, or else the owner, group or other bits of `perm`, whichever
+    /// class the caller falls in first; root may open anything.
+    ///
+    /// Only the primary group of the caller is known here, so access granted to one of
+    /// its supplementary groups is refused.
+    pub fn check_open(
+        &self,
+        flags: i32,
+        uid: u32,
+        gid: u32,
+        acl: Option<&Acl>,
+    ) -> Result<OpenCaps> {
+        let mut caps = match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => OpenCaps::READ,
+            libc::O_WRONLY => OpenCaps::WRITE,
+            libc::O_RDWR => OpenCaps::READ | OpenCaps::WRITE,
+            _ => return Err(FsError::UnsupportedOpenFlags(flags)),
+        };
+        if flags & libc::O_APPEND != 0 {
+            caps |= OpenCaps::APPEND;
+        }
+
+        let writes = caps.can_write() || flags & libc::O_TRUNC != 0;
+        if self.kind == FileType::Directory && writes {
+            return Err(FsError::IsDirectory {
+                file: self.ino.to_string(),
+            });
+        }
+        if uid == 0 {
+            return Ok(caps);
+        }
+
+        let mut wanted = 0;
+        if caps.can_read() {
+            wanted |= libc::R_OK as u16;
+        }
+        if writes {
+            wanted |= libc::W_OK as u16;
+        }
+        if !self.permits(uid, gid, wanted, acl) {
+            return Err(FsError::PermissionDenied { ino: self.ino });
+        }
+        Ok(caps)
+    }
+
+    /// Check that `uid` owns this inode, or is root, as changing its ACLs asks.
+    pub fn check_owner(&self, uid: u32) -> Result<()> {
+        if uid == 0 || uid == self.uid {
+            Ok(())
+        } else {
+            Err(FsError::NotPermitted { ino: self.ino })
+        }
+    }
+
+    /// Check `mask` of `access(2)` for `uid` in group `gid` against `acl` if the inode
+    /// has one, or else the same classes as `check_open`. Root may read and write
+    /// anything, but only execute files some class may execute; `F_OK` only asks whether
+    /// the inode exists.
+    pub fn check_access(&self, mask: i32, uid: u32, gid: u32, acl: Option<&Acl>) -> Result<()> {
+        if mask & !(libc::R_OK | libc::W_OK | libc::X_OK) != 0 {
+            return Err(FsError::InvalidAccessMask(mask));
+        }
+        let allowed = if uid == 0 {
+            self.kind == FileType::Directory || self.perm & 0o111 != 0 || mask & libc::X_OK == 0
+        } else {
+            self.permits(uid, gid, mask as u16, acl)
+        };
+        if !allowed {
+            return Err(FsError::PermissionDenied { ino: self.ino });
+        }
+        Ok(())
+    }
+
+    /// Whether `uid` in group `gid` gets the rwx bits of `wanted`, which line up with
+    /// `R_OK`, `W_OK` and `X_OK`, from `acl` or else from the class of `perm` the caller
+    /// falls in first: owner, group or other.
+    fn permits(&self, uid: u32, gid: u32, wanted: u16, acl: Option<&Acl>) -> bool {
+        if let Some(acl) = acl {
+            return acl.permits(self.uid, self.gid, uid, gid, wanted);
+        }
+        let bits = if uid == self.uid {
+            self.perm >> 6
+        } else if gid == self.gid {
+            self.perm >> 3
+        } else {
+            self.perm
+        };
+        wanted & !bits & 0o7 == 0
+    }
+
+    /// Close one open handle. The last one of a tmpfile takes its placeholder link along,
+    /// so saving the inode deletes it.
+    pub fn release_handle(&mut self) {
+        self.opened_fh = self.opened_fh.saturating_sub(1);
+        if self.tmpfile && self.opened_fh == 0 {
+            self.nlink = 0;
+        }
+    }
+
+    /// Record that the entries of this directory changed at `now`, leaving a record of
+    /// `size` bytes: POSIX wants the mtime and ctime of the directory moved.
+    pub fn entries_changed(&mut self, size: u64, now: SystemTime) {
+        self.set_size(size);
+        self.atime = now;
+        self.mtime = now;
+        self.ctime = now;
+    }
+
+    /// Whether saving the inode deletes it: no name links it and no handle keeps it open.
+    pub fn is_unlinked(&self) -> bool {
+        self.nlink == 0 && self.opened_fh == 0
+    }
+
+    /// Blocks that may hold data of the inode, deleted along with it; block 0 is always
+    /// in, as it holds the record of a directory.
+    pub fn block_span(&self, block_size: u64) -> Range<u64> {
+        0..((self.size + block_size - 1) / block_size).max(1)
+    }
+
+    /// Make sure this inode has a directory record to read or write.
+    pub fn check_dir(&self) -> Result<()> {
+        if self.kind == FileType::Directory {
+            Ok(())
+        } else {
+            Err(FsError::NotDirectory {
+                file: self.ino.to_string(),
+            })
+        }
+    }
+
+    /// Make sure this inode holds a symlink target to read or write.
+    pub fn check_symlink(&self) -> Result<()> {
+        if self.kind == FileType::Symlink {
+            Ok(())
+        } else {
+            Err(FsError::NotASymlink { inode: self.ino })
+        }
+    }
+
+    /// Take the owner, permissions and timestamps of `src`, as `cp --preserve` does;
+    /// kind, size and everything else stay, and `ctime` moves to `now`.
+    pub fn copy_metadata(&mut self, src: &Inode, now: SystemTime) {
+        self.uid = src.uid;
+        self.gid = src.gid;
+        self.perm = src.perm;
+        self.atime = src.atime;
+        self.mtime = src.mtime;
+        self.crtime = src.crtime;
+        self.ctime = now;
     }
 
     pub fn serialize(&self) -> Result<Vec<u8>> {
@@ -51,6 +311,25 @@ impl Inode {
             msg: err.to_string(),
         })
     }
+
+    /// Decode the value stored under the key of inode `ino`; a value that doesn't decode,
+    /// or holds another inode, is `CorruptedData`.
+    pub fn decode(ino: u64, bytes: &[u8]) -> Result<Self> {
+        let inode = Self::deserialize(bytes).map_err(|err| FsError::CorruptedData {
+            inode: ino,
+            detail: err.to_string(),
+        })?;
+        if inode.ino != ino {
+            return Err(FsError::CorruptedData {
+                inode: ino,
+                detail: format!(
+                    "inode ino field {} doesn't match key ino {}",
+                    inode.ino, ino
+                ),
+            });
+        }
+        Ok(inode)
+    }
 }
 
 impl From<FileAttr> for Inode {
@@ -61,6 +340,11 @@ impl From<FileAttr> for Inode {
             inline_data: None,
             next_fh: 0,
             opened_fh: 0,
+            generation: 0,
+            tmpfile: false,
+            shared_blocks: false,
+            parent_ino: 0,
+            sparse: false,
         }
     }
 }
@@ -71,6 +355,12 @@ impl From<Inode> for FileAttr {
     }
 }
 
+impl From<Inode> for Entry {
+    fn from(inode: Inode) -> Self {
+        Entry::new(inode.file_attr, inode.generation)
+    }
+}
+
 impl From<Inode> for LockState {
     fn from(inode: Inode) -> Self {
         inode.lock_state
@@ -94,10 +384,736 @@ impl DerefMut for Inode {
 impl LockState {
     #[cfg(target_os = "linux")]
     pub fn new(owner_set: HashSet<u64>, lk_type: i32) -> LockState {
-        LockState { owner_set, lk_type }
+        LockState {
+            owner_set,
+            lk_type,
+            locks: Vec::new(),
+        }
     }
     #[cfg(any(target_os = "freebsd", target_os = "macos"))]
     pub fn new(owner_set: HashSet<u64>, lk_type: i16) -> LockState {
-        LockState { owner_set, lk_type }
+        LockState {
+            owner_set,
+            lk_type,
+            locks: Vec::new(),
+        }
+    }
+
+    /// The locks held by other owners that keep `wanted` from being taken.
+    pub fn conflicts<'a>(&'a self, wanted: &'a FileLock) -> impl Iterator<Item = &'a FileLock> {
+        self.locks
+            .iter()
+            .filter(move |held| held.conflicts_with(wanted))
+    }
+
+    /// Give `lock` to its owner in place of whatever the owner held in its range; an
+    /// `F_UNLCK` lock only releases the range. Conflicts must be checked beforehand.
+    pub fn apply(&mut self, lock: FileLock) {
+        let mut locks = Vec::with_capacity(self.locks.len() + 2);
+        for held in self.locks.drain(..) {
+            if held.owner != lock.owner || !held.overlaps(&lock) {
+                locks.push(held);
+                continue;
+            }
+            // keep the parts of the old lock on either side of the new range
+            if held.start < lock.start {
+                locks.push(FileLock {
+                    end: lock.start - 1,
+                    ..held.clone()
+                });
+            }
+            if held.end > lock.end {
+                locks.push(FileLock {
+                    start: lock.end + 1,
+                    ..held
+                });
+            }
+        }
+        if lock.typ != F_UNLCK as i32 {
+            locks.push(lock);
+        }
+        self.locks = locks;
+
+        self.owner_set = self.locks.iter().map(|held| held.owner).collect();
+        self.lk_type = if self.locks.iter().any(|held| held.typ == F_WRLCK as i32) {
+            F_WRLCK
+        } else if self.locks.is_empty() {
+            F_UNLCK
+        } else {
+            F_RDLCK
+        };
+    }
+}
+
+impl FileLock {
+    fn overlaps(&self, other: &FileLock) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+
+    /// Whether `self` and `other` can't be held at the same time.
+    pub fn conflicts_with(&self, other: &FileLock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other)
+            && (self.typ == F_WRLCK as i32 || other.typ == F_WRLCK as i32)
+    }
+}
+
+impl LockWait {
+    /// How long a record outlives its last refresh, for waiters gone without removing it.
+    pub const TTL: Duration = Duration::from_secs(1);
+
+    /// Whether the waiter is still around at `now`, as it refreshes the record while
+    /// it polls.
+    pub fn is_live(&self, now: SystemTime) -> bool {
+        match self.refreshed {
+            Some(refreshed) => now < refreshed + Self::TTL,
+            None => false,
+        }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        serialize(self).map_err(|err| FsError::Serialize {
+            target: "lock wait",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        deserialize(bytes).map_err(|err| FsError::Serialize {
+            target: "lock wait",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+}
+
+/// Attributes changed by one `setattr`; `None` fields are left as they are.
+///
+/// `size` is applied by `Txn::setattr`, which has to drop the truncated blocks.
+#[derive(Clone, Debug, Default)]
+pub struct SetAttrRequest {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub atime: Option<TimeOrNow>,
+    pub mtime: Option<TimeOrNow>,
+    pub ctime: Option<SystemTime>,
+    pub crtime: Option<SystemTime>,
+    pub flags: Option<u32>,
+}
+
+impl SetAttrRequest {
+    /// Check that `caller_uid` may make these changes to `inode`.
+    ///
+    /// Only root can give a file away; the other attributes, apart from the size and
+    /// setting the times to now, are reserved to the owner. Group membership is not known
+    /// here, so an owner may change the group to any gid.
+    pub fn check_permission(&self, inode: &Inode, caller_uid: u32) -> Result<()> {
+        if caller_uid == 0 {
+            return Ok(());
+        }
+        let chown = self.uid.map_or(false, |uid| uid != inode.uid);
+        let owner_only = self.gid.map_or(false, |gid| gid != inode.gid)
+            || self.mode.is_some()
+            || matches!(self.atime, Some(TimeOrNow::SpecificTime(_)))
+            || matches!(self.mtime, Some(TimeOrNow::SpecificTime(_)))
+            || self.ctime.is_some()
+            || self.crtime.is_some()
+            || self.flags.is_some();
+        if chown || (owner_only && caller_uid != inode.uid) {
+            Err(FsError::NotPermitted { ino: inode.ino })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether this only truncates `inode` to the size it has, which changes nothing, not
+    /// even the times: POSIX only moves them when the size changes.
+    pub fn is_noop_truncate(&self, inode: &Inode) -> bool {
+        self.size == Some(inode.size)
+            && self.mode.is_none()
+            && self.uid.is_none()
+            && self.gid.is_none()
+            && self.atime.is_none()
+            && self.mtime.is_none()
+            && self.ctime.is_none()
+            && self.crtime.is_none()
+            && self.flags.is_none()
+    }
+
+    /// Apply every field but `size` to `inode`; `ctime` becomes `now` unless given.
+    pub fn apply(&self, inode: &mut Inode, now: SystemTime) {
+        let resolve = |time: TimeOrNow| match time {
+            TimeOrNow::SpecificTime(time) => time,
+            TimeOrNow::Now => now,
+        };
+        if let Some(mode) = self.mode {
+            inode.perm = as_file_perm(mode);
+        }
+        if let Some(uid) = self.uid {
+            inode.uid = uid;
+        }
+        if let Some(gid) = self.gid {
+            inode.gid = gid;
+        }
+        if let Some(atime) = self.atime {
+            inode.atime = resolve(atime);
+        }
+        if let Some(mtime) = self.mtime {
+            inode.mtime = resolve(mtime);
+        }
+        if let Some(crtime) = self.crtime {
+            inode.crtime = crtime;
+        }
+        if let Some(flags) = self.flags {
+            inode.flags = flags;
+        }
+        inode.ctime = self.ctime.unwrap_or(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const OWNER: u32 = 1000;
+
+    fn file() -> Inode {
+        let epoch = SystemTime::UNIX_EPOCH;
+        Inode::from(FileAttr {
+            ino: 2,
+            size: 0,
+            blocks: 0,
+            atime: epoch,
+            mtime: epoch,
+            ctime: epoch,
+            crtime: epoch,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            uid: OWNER,
+            gid: OWNER,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        })
+    }
+
+    #[test]
+    fn only_directories_have_records() {
+        assert!(matches!(
+            file().check_dir(),
+            Err(FsError::NotDirectory { file }) if file == "2"
+        ));
+        let mut dir = file();
+        dir.kind = FileType::Directory;
+        assert!(dir.check_dir().is_ok());
+    }
+
+    #[test]
+    fn link_targets() {
+        assert!(matches!(
+            check_link_target(b""),
+            Err(FsError::InvalidLinkTarget { len: 0 })
+        ));
+        assert!(check_link_target(b"a").is_ok());
+        assert!(check_link_target(&[b'a'; MAX_LINK_TARGET_LEN]).is_ok());
+        assert!(check_link_target(&[b'a'; MAX_LINK_TARGET_LEN + 1]).is_err());
+
+        assert!(matches!(
+            file().check_symlink(),
+            Err(FsError::NotASymlink { inode: 2 })
+        ));
+        let mut link = file();
+        link.kind = FileType::Symlink;
+        assert!(link.check_symlink().is_ok());
+    }
+
+    #[test]
+    fn undecodable_inodes_are_corrupted() {
+        let bytes = file().serialize().unwrap();
+        assert_eq!(Inode::decode(2, &bytes).unwrap().ino, 2);
+
+        let err = Inode::decode(2, &bytes[..bytes.len() / 2]).unwrap_err();
+        assert!(matches!(err, FsError::CorruptedData { inode: 2, .. }));
+        assert!(err.to_string().starts_with("data of inode(2) is corrupted"));
+        assert_eq!(libc::c_int::from(err), libc::EIO);
+
+        let err = Inode::decode(3, &bytes).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "data of inode(3) is corrupted: inode ino field 2 doesn't match key ino 3"
+        );
+    }
+
+    #[test]
+    fn copied_metadata_keeps_kind_and_size() {
+        let mut src = file();
+        src.uid = 2000;
+        src.gid = 3000;
+        src.perm = 0o600;
+        src.atime = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+        src.mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(2);
+        src.crtime = SystemTime::UNIX_EPOCH + Duration::from_secs(3);
+        src.set_size(4096);
+
+        let mut dst = file();
+        dst.ino = 3;
+        dst.kind = FileType::Symlink;
+        dst.set_size(10);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(4);
+        dst.copy_metadata(&src, now);
+
+        assert_eq!((dst.uid, dst.gid, dst.perm), (2000, 3000, 0o600));
+        assert_eq!(
+            (dst.atime, dst.mtime, dst.crtime),
+            (src.atime, src.mtime, src.crtime)
+        );
+        assert_eq!(dst.ctime, now);
+        assert_eq!(
+            (dst.ino, dst.kind, dst.size, dst.blocks),
+            (3, FileType::Symlink, 10, 1)
+        );
+    }
+
+    #[test]
+    fn blocks_follow_size() {
+        let mut inode = file();
+        inode.set_size(4097);
+        assert_eq!(inode.blocks, 9);
+        inode.set_size(512);
+        assert_eq!(inode.blocks, 1);
+        inode.set_size(0);
+        assert_eq!(inode.blocks, 0);
+    }
+
+    #[test]
+    fn truncating_to_the_same_size_is_a_noop() {
+        let mut inode = file();
+        inode.set_size(10);
+        let truncate = |size| SetAttrRequest {
+            size: Some(size),
+            ..Default::default()
+        };
+        assert!(truncate(10).is_noop_truncate(&inode));
+        assert!(!truncate(5).is_noop_truncate(&inode));
+        let touch = SetAttrRequest {
+            mtime: Some(TimeOrNow::Now),
+            ..truncate(10)
+        };
+        assert!(!touch.is_noop_truncate(&inode));
+    }
+
+    #[test]
+    fn setattr_applies_each_field_alone() {
+        let now = SystemTime::now();
+        let then = SystemTime::UNIX_EPOCH + Duration::from_secs(42);
+        let cases: Vec<(SetAttrRequest, Box<dyn Fn(&Inode) -> bool>)> = vec![
+            (
+                SetAttrRequest {
+                    mode: Some(0o100600),
+                    ..Default::default()
+                },
+                Box::new(|inode| inode.perm == as_file_perm(0o100600)),
+            ),
+            (
+                SetAttrRequest {
+                    uid: Some(7),
+                    ..Default::default()
+                },
+                Box::new(|inode| inode.uid == 7 && inode.gid == OWNER),
+            ),
+            (
+                SetAttrRequest {
+                    gid: Some(7),
+                    ..Default::default()
+                },
+                Box::new(|inode| inode.gid == 7 && inode.uid == OWNER),
+            ),
+            (
+                SetAttrRequest {
+                    atime: Some(TimeOrNow::SpecificTime(then)),
+                    ..Default::default()
+                },
+                Box::new(move |inode| inode.atime == then && inode.mtime == SystemTime::UNIX_EPOCH),
+            ),
+            (
+                SetAttrRequest {
+                    mtime: Some(TimeOrNow::Now),
+                    ..Default::default()
+                },
+                Box::new(move |inode| inode.mtime == now && inode.atime == SystemTime::UNIX_EPOCH),
+            ),
+            (
+                SetAttrRequest {
+                    crtime: Some(then),
+                    ..Default::default()
+                },
+                Box::new(move |inode| inode.crtime == then),
+            ),
+            (
+                SetAttrRequest {
+                    flags: Some(1),
+                    ..Default::default()
+                },
+                Box::new(|inode| inode.flags == 1),
+            ),
+        ];
+
+        for (request, check) in cases {
+            let mut inode = file();
+            request.apply(&mut inode, now);
+            assert!(check(&inode), "{:?} -> {:?}", request, inode);
+            assert_eq!(inode.ctime, now, "{:?}", request);
+            assert_eq!(inode.size, 0);
+        }
+    }
+
+    #[test]
+    fn setattr_applies_fields_together() {
+        let now = SystemTime::now();
+        let then = SystemTime::UNIX_EPOCH + Duration::from_secs(42);
+        let request = SetAttrRequest {
+            mode: Some(0o100755),
+            uid: Some(0),
+            gid: Some(0),
+            atime: Some(TimeOrNow::Now),
+            mtime: Some(TimeOrNow::SpecificTime(then)),
+            ctime: Some(then),
+            ..Default::default()
+        };
+        let mut inode = file();
+        request.apply(&mut inode, now);
+        assert_eq!(inode.perm, as_file_perm(0o100755));
+        assert_eq!((inode.uid, inode.gid), (0, 0));
+        assert_eq!(inode.atime, now);
+        assert_eq!(inode.mtime, then);
+        assert_eq!(inode.ctime, then);
+
+        let mut unchanged = file();
+        SetAttrRequest::default().apply(&mut unchanged, now);
+        assert_eq!(unchanged.ctime, now);
+        unchanged.ctime = SystemTime::UNIX_EPOCH;
+        assert_eq!(unchanged, file());
+    }
+
+    #[test]
+    fn setattr_permissions() {
+        let inode = file();
+        let chown = SetAttrRequest {
+            uid: Some(7),
+            ..Default::default()
+        };
+        let chmod = SetAttrRequest {
+            mode: Some(0o100600),
+            ..Default::default()
+        };
+        let touch = SetAttrRequest {
+            atime: Some(TimeOrNow::Now),
+            mtime: Some(TimeOrNow::Now),
+            ..Default::default()
+        };
+        let keep_owner = SetAttrRequest {
+            uid: Some(OWNER),
+            gid: Some(7),
+            ..Default::default()
+        };
+
+        assert!(chown.check_permission(&inode, 0).is_ok());
+        let err = chown.check_permission(&inode, OWNER).unwrap_err();
+        assert_eq!(libc::c_int::from(err), libc::EPERM);
+
+        assert!(chmod.check_permission(&inode, OWNER).is_ok());
+        assert!(chmod.check_permission(&inode, 7).is_err());
+
+        assert!(keep_owner.check_permission(&inode, OWNER).is_ok());
+        assert!(keep_owner.check_permission(&inode, 7).is_err());
+
+        assert!(touch.check_permission(&inode, 7).is_ok());
+    }
+
+    fn lock(owner: u64, typ: i32, start: u64, end: u64) -> FileLock {
+        FileLock {
+            owner,
+            pid: owner as u32,
+            typ,
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn exclusive_lock_waits_for_release() {
+        let mut state = file().lock_state;
+        state.apply(lock(1, F_WRLCK as i32, 0, u64::MAX));
+        assert_eq!(state.lk_type, F_WRLCK);
+
+        let wanted = lock(2, F_WRLCK as i32, 10, 20);
+        assert_eq!(state.conflicts(&wanted).count(), 1);
+        assert_eq!(state.conflicts(&lock(1, F_WRLCK as i32, 10, 20)).count(), 0);
+
+        state.apply(lock(1, F_UNLCK as i32, 0, u64::MAX));
+        assert_eq!(state.conflicts(&wanted).count(), 0);
+        assert_eq!(state.lk_type, F_UNLCK);
+        assert!(state.owner_set.is_empty());
+
+        state.apply(wanted);
+        assert_eq!(state.owner_set, [2].iter().copied().collect::<HashSet<_>>());
+    }
+
+    #[test]
+    fn lock_waits_expire() {
+        let now = SystemTime::now();
+        let wait = LockWait {
+            ino: 2,
+            lock: lock(1, F_WRLCK as i32, 0, 99),
+            refreshed: Some(now),
+        };
+        assert!(wait.is_live(now));
+        assert!(!wait.is_live(now + LockWait::TTL));
+        assert!(!LockWait {
+            refreshed: None,
+            ..wait
+        }
+        .is_live(now));
+    }
+
+    #[test]
+    fn shared_locks_coexist() {
+        let mut state = file().lock_state;
+        state.apply(lock(1, F_RDLCK as i32, 0, 99));
+        assert_eq!(state.conflicts(&lock(2, F_RDLCK as i32, 0, 99)).count(), 0);
+        state.apply(lock(2, F_RDLCK as i32, 0, 99));
+        assert_eq!(state.lk_type, F_RDLCK);
+        assert_eq!(state.conflicts(&lock(3, F_WRLCK as i32, 50, 50)).count(), 2);
+        assert_eq!(
+            state.conflicts(&lock(3, F_WRLCK as i32, 100, 200)).count(),
+            0
+        );
+    }
+
+    #[test]
+    fn unlocking_splits_ranges() {
+        let mut state = file().lock_state;
+        state.apply(lock(1, F_WRLCK as i32, 0, 99));
+        state.apply(lock(1, F_UNLCK as i32, 40, 59));
+        let mut ranges: Vec<_> = state
+            .locks
+            .iter()
+            .map(|held| (held.start, held.end))
+            .collect();
+        ranges.sort_unstable();
+        assert_eq!(ranges, vec![(0, 39), (60, 99)]);
+        assert_eq!(state.conflicts(&lock(2, F_WRLCK as i32, 40, 59)).count(), 0);
+
+        // converting a range replaces the old lock instead of stacking on it
+        state.apply(lock(1, F_RDLCK as i32, 0, 99));
+        assert_eq!(state.locks, vec![lock(1, F_RDLCK as i32, 0, 99)]);
+        assert_eq!(state.lk_type, F_RDLCK);
+    }
+
+    #[test]
+    fn relatime_skips_repeated_reads() {
+        let mut inode = file();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        inode.mtime = modified;
+        inode.ctime = modified;
+
+        let first = modified + Duration::from_secs(10);
+        assert!(inode.touch_atime(ATimeMode::RelATime, first));
+        assert_eq!(inode.atime, first);
+        assert!(!inode.touch_atime(ATimeMode::RelATime, first + Duration::from_secs(60)));
+        assert_eq!(inode.atime, first);
+
+        let next_day = first + ATimeMode::RELATIME_INTERVAL;
+        assert!(inode.touch_atime(ATimeMode::RelATime, next_day));
+        assert_eq!(inode.atime, next_day);
+
+        assert!(!inode.touch_atime(ATimeMode::NoATime, next_day + ATimeMode::RELATIME_INTERVAL));
+        assert!(inode.touch_atime(ATimeMode::Strict, next_day + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn repeated_reads_save_the_inode_as_the_mode_asks() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let saves = |mode| {
+            let mut inode = file();
+            (1..=1000)
+                .filter(|i| inode.touch_atime(mode, start + Duration::from_millis(*i)))
+                .count()
+        };
+        assert_eq!(saves(ATimeMode::NoATime), 0);
+        assert_eq!(saves(ATimeMode::RelATime), 1);
+        assert_eq!(saves(ATimeMode::Strict), 1000);
+    }
+
+    #[test]
+    fn open_checks_the_caller_class() {
+        let mut inode = file();
+        inode.perm = 0o640;
+        let caps = inode.check_open(libc::O_RDWR, OWNER, 0, None).unwrap();
+        assert!(caps.can_read() && caps.can_write() && !caps.is_append_only());
+        let caps = inode.check_open(libc::O_RDONLY, 2000, OWNER, None).unwrap();
+        assert!(caps.can_read() && !caps.can_write());
+        assert!(matches!(
+            inode.check_open(libc::O_WRONLY, 2000, OWNER, None),
+            Err(FsError::PermissionDenied { ino: 2 })
+        ));
+        assert!(matches!(
+            inode.check_open(libc::O_RDONLY, 2000, 2000, None),
+            Err(FsError::PermissionDenied { ino: 2 })
+        ));
+
+        inode.perm = 0o604;
+        assert!(inode.check_open(libc::O_RDONLY, 2000, 2000, None).is_ok());
+        // the group class applies even when the other bits would allow it
+        assert!(inode.check_open(libc::O_RDONLY, 2000, OWNER, None).is_err());
+        assert!(inode
+            .check_open(libc::O_RDONLY | libc::O_TRUNC, 2000, 2000, None)
+            .is_err());
+    }
+
+    #[test]
+    fn open_follows_the_acl() {
+        use crate::fs::acl::{AclEntry, AclTag};
+
+        let mut inode = file();
+        inode.perm = 0o640;
+        let entry = |tag, perm| AclEntry { tag, perm };
+        let acl = Acl {
+            entries: vec![
+                entry(AclTag::UserObj, 0o6),
+                entry(AclTag::User(2000), 0o6),
+                entry(AclTag::GroupObj, 0o4),
+                entry(AclTag::Mask, 0o6),
+                entry(AclTag::Other, 0o0),
+            ],
+        };
+        assert!(inode.check_open(libc::O_RDWR, 2000, 2000, None).is_err());
+        assert!(inode
+            .check_open(libc::O_RDWR, 2000, 2000, Some(&acl))
+            .is_ok());
+        assert!(inode
+            .check_open(libc::O_RDONLY, 3000, 3000, Some(&acl))
+            .is_err());
+    }
+
+    #[test]
+    fn tmpfiles_go_with_their_last_handle() {
+        let mut inode = file();
+        inode.tmpfile = true;
+        inode.opened_fh = 2;
+        inode.release_handle();
+        assert_eq!((inode.opened_fh, inode.nlink), (1, 1));
+        inode.release_handle();
+        assert_eq!((inode.opened_fh, inode.nlink), (0, 0));
+
+        // a named file keeps its link
+        let mut inode = file();
+        inode.opened_fh = 1;
+        inode.release_handle();
+        assert_eq!((inode.opened_fh, inode.nlink), (0, 1));
+    }
+
+    #[test]
+    fn entry_changes_move_directory_times() {
+        let mut dir = file();
+        dir.kind = FileType::Directory;
+        let created = SystemTime::UNIX_EPOCH + Duration::from_secs(60);
+        dir.entries_changed(40, created);
+        assert_eq!((dir.size, dir.mtime, dir.ctime), (40, created, created));
+
+        let removed = created + Duration::from_secs(1);
+        dir.entries_changed(20, removed);
+        assert_eq!((dir.size, dir.mtime, dir.ctime), (20, removed, removed));
+        assert_eq!(dir.crtime, SystemTime::UNIX_EPOCH);
+    }
+
+    #[test]
+    fn last_unlink_takes_every_block() {
+        let mut inode = file();
+        inode.set_size(3 * 4096 + 1);
+        assert_eq!(inode.block_span(4096), 0..4);
+        inode.set_size(0);
+        assert_eq!(inode.block_span(4096), 0..1);
+
+        inode.nlink = 0;
+        inode.opened_fh = 1;
+        assert!(!inode.is_unlinked());
+        inode.release_handle();
+        assert!(inode.is_unlinked());
+    }
+
+    #[test]
+    fn access_follows_the_class_of_the_caller() {
+        let mut inode = file();
+        inode.perm = 0o421;
+        assert!(inode.check_access(libc::R_OK, OWNER, OWNER, None).is_ok());
+        assert!(inode.check_access(libc::W_OK, OWNER, OWNER, None).is_err());
+        assert!(inode.check_access(libc::W_OK, 2000, OWNER, None).is_ok());
+        assert!(inode
+            .check_access(libc::R_OK | libc::W_OK, 2000, OWNER, None)
+            .is_err());
+        assert!(inode.check_access(libc::X_OK, 2000, 2000, None).is_ok());
+        assert!(inode.check_access(libc::R_OK, 2000, 2000, None).is_err());
+        assert!(inode.check_access(libc::F_OK, 2000, 2000, None).is_ok());
+        assert!(matches!(
+            inode.check_access(0x10, OWNER, OWNER, None),
+            Err(FsError::InvalidAccessMask(0x10))
+        ));
+    }
+
+    #[test]
+    fn only_the_owner_sets_acls() {
+        let inode = file();
+        assert!(inode.check_owner(OWNER).is_ok());
+        assert!(inode.check_owner(0).is_ok());
+        assert!(matches!(
+            inode.check_owner(2000),
+            Err(FsError::NotPermitted { ino: 2 })
+        ));
+    }
+
+    #[test]
+    fn root_executes_only_executables() {
+        let mut inode = file();
+        inode.perm = 0o600;
+        assert!(inode
+            .check_access(libc::R_OK | libc::W_OK, 0, 0, None)
+            .is_ok());
+        assert!(matches!(
+            inode.check_access(libc::X_OK, 0, 0, None),
+            Err(FsError::PermissionDenied { ino: 2 })
+        ));
+        inode.perm = 0o601;
+        assert!(inode.check_access(libc::X_OK, 0, 0, None).is_ok());
+        inode.perm = 0;
+        inode.kind = FileType::Directory;
+        assert!(inode.check_access(libc::X_OK, 0, 0, None).is_ok());
+    }
+
+    #[test]
+    fn root_opens_anything_but_directories_for_writing() {
+        let mut inode = file();
+        inode.perm = 0;
+        let caps = inode
+            .check_open(libc::O_WRONLY | libc::O_APPEND, 0, 0, None)
+            .unwrap();
+        assert!(caps.can_write() && caps.is_append_only());
+
+        inode.kind = FileType::Directory;
+        assert!(inode.check_open(libc::O_RDONLY, 0, 0, None).is_ok());
+        assert!(matches!(
+            inode.check_open(libc::O_RDWR, 0, 0, None),
+            Err(FsError::IsDirectory { .. })
+        ));
+        assert!(matches!(
+            inode.check_open(libc::O_ACCMODE, OWNER, OWNER, None),
+            Err(FsError::UnsupportedOpenFlags(_))
+        ));
     }
 }
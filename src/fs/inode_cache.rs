@@ -0,0 +1,148 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+
+use super::inode::Inode;
+
+/// Inodes recently read by `getattr`, so stat-heavy workloads don't reach TiKV every time.
+///
+/// Transactions invalidate an inode when they save or remove it, and again once they
+/// commit. A reader takes a `ticket` before its transaction begins and `fill`s the cache
+/// with it afterwards; the fill is dropped if anything was invalidated in between, so a
+/// value read before a concurrent commit can't outlive it. Changes made through other
+/// mounts are seen once the entry is evicted or older than `ttl`.
+pub struct InodeCache {
+    ttl: Duration,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    entries: LruCache<u64, (Inode, Instant)>,
+    invalidations: u64,
+}
+
+impl InodeCache {
+    pub const DEFAULT_CAPACITY: usize = 4096;
+    pub const DEFAULT_TTL: Duration = Duration::from_secs(1);
+
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            ttl,
+            inner: Mutex::new(Inner {
+                entries: LruCache::new(capacity),
+                invalidations: 0,
+            }),
+        }
+    }
+
+    pub fn get(&self, ino: u64) -> Option<Inode> {
+        self.get_at(ino, Instant::now())
+    }
+
+    fn get_at(&self, ino: u64, now: Instant) -> Option<Inode> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.entries.get(&ino) {
+            Some((inode, filled)) if now.saturating_duration_since(*filled) < self.ttl => {
+                Some(inode.clone())
+            }
+            Some(_) => {
+                inner.entries.pop(&ino);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn ticket(&self) -> u64 {
+        self.inner.lock().unwrap().invalidations
+    }
+
+    /// Cache `inode`, read in a transaction that began after `ticket` was taken.
+    pub fn fill(&self, ticket: u64, inode: Inode) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.invalidations == ticket {
+            inner.entries.put(inode.ino, (inode, Instant::now()));
+        }
+    }
+
+    pub fn invalidate(&self, ino: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.invalidations += 1;
+        inner.entries.pop(&ino);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use fuser::{FileAttr, FileType};
+
+    use super::*;
+
+    fn inode(ino: u64, size: u64) -> Inode {
+        let epoch = SystemTime::UNIX_EPOCH;
+        Inode::from(FileAttr {
+            ino,
+            size,
+            blocks: 0,
+            atime: epoch,
+            mtime: epoch,
+            ctime: epoch,
+            crtime: epoch,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        })
+    }
+
+    #[test]
+    fn hits_after_a_single_fill() {
+        let cache = InodeCache::new(InodeCache::DEFAULT_CAPACITY, InodeCache::DEFAULT_TTL);
+        let mut misses = 0;
+        for _ in 0..1000 {
+            if cache.get(2).is_none() {
+                misses += 1;
+                let ticket = cache.ticket();
+                cache.fill(ticket, inode(2, 7));
+            }
+        }
+        assert_eq!(misses, 1);
+        assert_eq!(cache.get(2).unwrap().size, 7);
+    }
+
+    #[test]
+    fn invalidated_inodes_are_read_again() {
+        let cache = InodeCache::new(2, InodeCache::DEFAULT_TTL);
+        cache.fill(cache.ticket(), inode(2, 7));
+        cache.invalidate(2);
+        assert!(cache.get(2).is_none());
+    }
+
+    #[test]
+    fn fills_racing_an_invalidation_are_dropped() {
+        let cache = InodeCache::new(2, InodeCache::DEFAULT_TTL);
+        let ticket = cache.ticket();
+        // a writer commits between the read and the fill
+        cache.invalidate(2);
+        cache.fill(ticket, inode(2, 7));
+        assert!(cache.get(2).is_none());
+    }
+
+    #[test]
+    fn entries_expire() {
+        let cache = InodeCache::new(2, Duration::from_secs(1));
+        cache.fill(cache.ticket(), inode(2, 7));
+        let now = Instant::now();
+        assert!(cache.get_at(2, now).is_some());
+        assert!(cache.get_at(2, now + Duration::from_secs(2)).is_none());
+        // expired entries are dropped, not kept until evicted
+        assert!(cache.get_at(2, now).is_none());
+    }
+}
@@ -0,0 +1,77 @@
+use std::convert::TryInto;
+use std::mem::size_of;
+
+use super::block::BlockRange;
+use super::error::{FsError, Result};
+
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+/// `_IOWR(typ, nr, size)` of Linux.
+const fn iowr(typ: u8, nr: u8, size: usize) -> u32 {
+    ((IOC_READ | IOC_WRITE) << 30) | ((size as u32) << 16) | ((typ as u32) << 8) | nr as u32
+}
+
+/// Ranges answered by a single `TIFS_IOC_BLOCK_MAP`.
+pub const BLOCK_MAP_BATCH: usize = 170;
+const BLOCK_RANGE_LEN: usize = 3 * size_of::<u64>();
+const BLOCK_RANGE_DATA: u64 = 1;
+
+/// The data and hole ranges of a file, like `FS_IOC_FIEMAP`, which the kernel doesn't pass
+/// to FUSE.
+///
+/// The argument starts with the index of the first range wanted as a native-endian `u64`.
+/// The answer is up to `BLOCK_MAP_BATCH` ranges of `start`, `end` and flags (`1` for
+/// data), each a native-endian `u64`; fewer ranges mean the map is complete.
+pub const TIFS_IOC_BLOCK_MAP: u32 = iowr(b'T', 1, BLOCK_MAP_BATCH * BLOCK_RANGE_LEN);
+
+/// The index of the first range a `TIFS_IOC_BLOCK_MAP` asks for.
+pub fn block_map_start(in_data: &[u8]) -> Result<usize> {
+    in_data
+        .get(..size_of::<u64>())
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(|bytes| u64::from_ne_bytes(bytes) as usize)
+        .ok_or(FsError::InvalidIoctlArgument {
+            cmd: TIFS_IOC_BLOCK_MAP,
+        })
+}
+
+pub fn encode_block_map(ranges: &[BlockRange], start: usize) -> Vec<u8> {
+    let batch = ranges.iter().skip(start).take(BLOCK_MAP_BATCH);
+    let mut data = Vec::with_capacity(BLOCK_MAP_BATCH * BLOCK_RANGE_LEN);
+    for range in batch {
+        let flags = if range.is_data { BLOCK_RANGE_DATA } else { 0 };
+        data.extend_from_slice(&range.start_byte.to_ne_bytes());
+        data.extend_from_slice(&range.end_byte.to_ne_bytes());
+        data.extend_from_slice(&flags.to_ne_bytes());
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_map_is_answered_in_batches() {
+        let ranges: Vec<BlockRange> = (0..BLOCK_MAP_BATCH as u64 + 2)
+            .map(|i| BlockRange {
+                start_byte: i * 16,
+                end_byte: i * 16 + 16,
+                is_data: i % 2 == 0,
+            })
+            .collect();
+
+        let first = encode_block_map(&ranges, 0);
+        assert_eq!(first.len(), BLOCK_MAP_BATCH * BLOCK_RANGE_LEN);
+        assert!(first.len() <= (TIFS_IOC_BLOCK_MAP >> 16 & 0x3fff) as usize);
+
+        let start = block_map_start(&(BLOCK_MAP_BATCH as u64).to_ne_bytes()).unwrap();
+        let rest = encode_block_map(&ranges, start);
+        assert_eq!(rest.len(), 2 * BLOCK_RANGE_LEN);
+        assert_eq!(rest[..8], (BLOCK_MAP_BATCH as u64 * 16).to_ne_bytes());
+        assert_eq!(rest[16..24], BLOCK_RANGE_DATA.to_ne_bytes());
+
+        assert!(block_map_start(&[0; 4]).is_err());
+    }
+}
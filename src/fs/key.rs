@@ -1,27 +1,104 @@
+use std::fmt;
 use std::mem::size_of;
 use std::ops::Range;
 
 use tikv_client::Key;
 
 use super::error::{FsError, Result};
+use super::quota::QuotaOwner;
 
 pub const ROOT_INODE: u64 = fuser::FUSE_ROOT_ID;
 
+/// The first byte of every key, keeping each kind of record in a key range of its own.
+///
+/// The values are persisted, so existing ones must never be renumbered.
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy)]
+#[repr(u8)]
+pub enum Namespace {
+    Meta = 0,
+    Inode = 1,
+    Block = 2,
+    Handler = 3,
+    Index = 4,
+    Xattr = 5,
+    Lock = 6,
+    XattrMeta = 7,
+    Quota = 8,
+    Snapshot = 9,
+    SharedBlock = 10,
+    Usage = 11,
+}
+
+impl Namespace {
+    pub fn from_u8(byte: u8) -> Option<Self> {
+        use Namespace::*;
+
+        [
+            Meta,
+            Inode,
+            Block,
+            Handler,
+            Index,
+            Xattr,
+            Lock,
+            XattrMeta,
+            Quota,
+            Snapshot,
+            SharedBlock,
+            Usage,
+        ]
+        .iter()
+        .copied()
+        .find(|namespace| *namespace as u8 == byte)
+    }
+}
+
+/// A key in the TiKV key space of tifs.
+///
+/// Every key starts with its `Namespace` byte; integers are big-endian so keys of one inode
+/// sort together and ranges can be scanned.
+///
+/// | scope       | layout                          | value                 |
+/// |-------------|---------------------------------|-----------------------|
+/// | `0x00`      | meta                            | `Meta`                |
+/// | `0x01`      | inode: `ino`                    | `Inode`               |
+/// | `0x02`      | block: `ino`, `block`           | raw block data        |
+/// | `0x03`      | file handler: `ino`, `handler`  | `FileHandler`         |
+/// | `0x04`      | index: `parent`, utf-8 `name`   | `Index`               |
+/// | `0x05`      | xattr: `ino`, utf-8 `name`      | raw xattr value       |
+/// | `0x06`      | lock wait: `owner`              | `LockWait`            |
+/// | `0x07`      | xattr meta: `ino`               | xattr summary         |
+/// | `0x08`      | quota: `0` user/`1` group, `id` | `Quota`               |
+/// | `0x09`      | snapshot catalog                | `SnapshotCatalog`     |
+/// | `0x0a`      | shared block: `id`              | `SharedBlock`         |
+/// | `0x0b`      | usage: `shard`                  | block count delta     |
+/// | `0x0c-0xff` | reserved                        |                       |
+///
+/// Names fill the rest of the key after a fixed-width integer, so the encoding is
+/// injective for any name, NUL bytes included, without escaping or a length prefix.
+///
+/// Inode, block and shared block values are sealed by `EncryptionLayer` on encrypted filesystems.
+///
+/// Keys are displayed (and debugged) as paths such as `inode/42/block/7`.
+#[derive(Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy)]
 pub enum ScopedKey<'a> {
     Meta,
     Inode(u64),
     Block { ino: u64, block: u64 },
     FileHandler { ino: u64, handler: u64 },
     FileIndex { parent: u64, name: &'a str },
+    Xattr { ino: u64, name: &'a str },
+    Lock(u64),
+    XattrMeta(u64),
+    Quota(QuotaOwner),
+    SnapshotCatalog,
+    SharedBlock(u64),
+    Usage(u64),
 }
 
 impl<'a> ScopedKey<'a> {
-    const META: u8 = 0;
-    const INODE: u8 = 1;
-    const BLOCK: u8 = 2;
-    const HANDLER: u8 = 3;
-    const INDEX: u8 = 4;
+    const USER_QUOTA: u8 = 0;
+    const GROUP_QUOTA: u8 = 1;
 
     pub const fn meta() -> Self {
         Self::Meta
@@ -47,36 +124,116 @@ impl<'a> ScopedKey<'a> {
         Self::FileIndex { parent, name }
     }
 
+    pub fn xattr(ino: u64, name: &'a str) -> Self {
+        Self::Xattr { ino, name }
+    }
+
+    pub const fn lock(owner: u64) -> Self {
+        Self::Lock(owner)
+    }
+
+    pub const fn xattr_meta(ino: u64) -> Self {
+        Self::XattrMeta(ino)
+    }
+
+    pub const fn quota(owner: QuotaOwner) -> Self {
+        Self::Quota(owner)
+    }
+
+    pub const fn snapshot_catalog() -> Self {
+        Self::SnapshotCatalog
+    }
+
+    pub const fn shared_block(id: u64) -> Self {
+        Self::SharedBlock(id)
+    }
+
+    pub const fn usage(shard: u64) -> Self {
+        Self::Usage(shard)
+    }
+
     pub fn block_range(ino: u64, block_range: Range<u64>) -> Range<Key> {
         debug_assert_ne!(0, ino);
         Self::block(ino, block_range.start).into()..Self::block(ino, block_range.end).into()
     }
 
+    /// Every xattr of inode `ino`.
+    pub fn xattr_range(ino: u64) -> Range<Key> {
+        let end = match ino.checked_add(1) {
+            Some(next) => Self::xattr(next, "").into(),
+            // the last inode's run to the end of the namespace
+            None => Self::namespace_range(Namespace::Xattr).end,
+        };
+        Self::xattr(ino, "").into()..end
+    }
+
+    /// The keys of the blocks in `block_range`, one by one.
+    pub fn block_keys(ino: u64, block_range: Range<u64>) -> impl Iterator<Item = Key> {
+        debug_assert_ne!(0, ino);
+        block_range.map(move |block| Self::block(ino, block).into())
+    }
+
+    /// Every key of `namespace`, for scans over all inodes such as fsck's.
+    pub fn namespace_range(namespace: Namespace) -> Range<Key> {
+        let byte = namespace as u8;
+        vec![byte].into()..vec![byte + 1].into()
+    }
+
+    /// Every block key of every inode.
+    pub fn all_blocks() -> Range<Key> {
+        Self::namespace_range(Namespace::Block)
+    }
+
+    /// Every usage shard, see `Meta::used_blocks`.
+    pub fn all_usage() -> Range<Key> {
+        Self::namespace_range(Namespace::Usage)
+    }
+
+    /// Every directory index of every inode.
+    pub fn all_indexes() -> Range<Key> {
+        Self::namespace_range(Namespace::Index)
+    }
+
     pub fn inode_range(ino_range: Range<u64>) -> Range<Key> {
         Self::inode(ino_range.start).into()..Self::inode(ino_range.end).into()
     }
 
-    pub fn scope(&self) -> u8 {
+    pub fn namespace(&self) -> Namespace {
         use ScopedKey::*;
 
         match self {
-            Meta => Self::META,
-            Inode(_) => Self::INODE,
-            Block { ino: _, block: _ } => Self::BLOCK,
-            FileHandler { ino: _, handler: _ } => Self::HANDLER,
-            FileIndex { parent: _, name: _ } => Self::INDEX,
+            Meta => Namespace::Meta,
+            Inode(_) => Namespace::Inode,
+            Block { ino: _, block: _ } => Namespace::Block,
+            FileHandler { ino: _, handler: _ } => Namespace::Handler,
+            FileIndex { parent: _, name: _ } => Namespace::Index,
+            Xattr { ino: _, name: _ } => Namespace::Xattr,
+            Lock(_) => Namespace::Lock,
+            XattrMeta(_) => Namespace::XattrMeta,
+            Quota(_) => Namespace::Quota,
+            SnapshotCatalog => Namespace::Snapshot,
+            SharedBlock(_) => Namespace::SharedBlock,
+            Usage(_) => Namespace::Usage,
         }
     }
 
+    pub fn scope(&self) -> u8 {
+        self.namespace() as u8
+    }
+
     pub fn len(&self) -> usize {
         use ScopedKey::*;
 
         1 + match self {
-            Meta => 0,
+            Meta | SnapshotCatalog => 0,
             Inode(_) => size_of::<u64>(),
             Block { ino: _, block: _ } => size_of::<u64>() * 2,
             FileHandler { ino: _, handler: _ } => size_of::<u64>() * 2,
             FileIndex { parent: _, name } => size_of::<u64>() + name.len(),
+            Xattr { ino: _, name } => size_of::<u64>() + name.len(),
+            Lock(_) => size_of::<u64>(),
+            XattrMeta(_) | SharedBlock(_) | Usage(_) => size_of::<u64>(),
+            Quota(_) => 1 + size_of::<u32>(),
         }
     }
 
@@ -87,25 +244,25 @@ impl<'a> ScopedKey<'a> {
     pub fn parse(key: &'a [u8]) -> Result<Self> {
         let invalid_key = || FsError::InvalidScopedKey(key.to_owned());
         let (scope, data) = key.split_first().ok_or_else(invalid_key)?;
-        match *scope {
-            Self::META => Ok(Self::meta()),
-            Self::INODE => {
+        match Namespace::from_u8(*scope).ok_or_else(invalid_key)? {
+            Namespace::Meta => Ok(Self::meta()),
+            Namespace::Inode => {
                 let ino = u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
                 Ok(Self::inode(ino))
             }
-            Self::BLOCK => {
+            Namespace::Block => {
                 let mut arrays = data.array_chunks();
                 let ino = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
                 let block = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
                 Ok(Self::block(ino, block))
             }
-            Self::HANDLER => {
+            Namespace::Handler => {
                 let mut arrays = data.array_chunks();
                 let ino = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
                 let handler = u64::from_be_bytes(*arrays.next().ok_or_else(invalid_key)?);
                 Ok(Self::handler(ino, handler))
             }
-            Self::INDEX => {
+            Namespace::Index => {
                 let parent =
                     u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
                 Ok(Self::index(
@@ -113,11 +270,72 @@ impl<'a> ScopedKey<'a> {
                     std::str::from_utf8(&data[size_of::<u64>()..]).map_err(|_| invalid_key())?,
                 ))
             }
-            _ => Err(invalid_key()),
+            Namespace::Xattr => {
+                let ino = u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
+                Ok(Self::xattr(
+                    ino,
+                    std::str::from_utf8(&data[size_of::<u64>()..]).map_err(|_| invalid_key())?,
+                ))
+            }
+            Namespace::Lock => {
+                let ino = u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
+                Ok(Self::lock(ino))
+            }
+            Namespace::XattrMeta => {
+                let ino = u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
+                Ok(Self::xattr_meta(ino))
+            }
+            Namespace::Snapshot => Ok(Self::snapshot_catalog()),
+            Namespace::SharedBlock => {
+                let id = u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
+                Ok(Self::shared_block(id))
+            }
+            Namespace::Usage => {
+                let shard =
+                    u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
+                Ok(Self::usage(shard))
+            }
+            Namespace::Quota => {
+                let (kind, id) = data.split_first().ok_or_else(invalid_key)?;
+                let id = u32::from_be_bytes(*id.array_chunks().next().ok_or_else(invalid_key)?);
+                match *kind {
+                    Self::USER_QUOTA => Ok(Self::quota(QuotaOwner::User(id))),
+                    Self::GROUP_QUOTA => Ok(Self::quota(QuotaOwner::Group(id))),
+                    _ => Err(invalid_key()),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for ScopedKey<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ScopedKey::*;
+
+        match self {
+            Meta => write!(f, "meta"),
+            Inode(ino) => write!(f, "inode/{}", ino),
+            Block { ino, block } => write!(f, "inode/{}/block/{}", ino, block),
+            FileHandler { ino, handler } => write!(f, "inode/{}/handler/{}", ino, handler),
+            FileIndex { parent, name } => write!(f, "inode/{}/index/{}", parent, name),
+            Xattr { ino, name } => write!(f, "inode/{}/xattr/{}", ino, name),
+            Lock(owner) => write!(f, "lock/{}", owner),
+            XattrMeta(ino) => write!(f, "inode/{}/xattr_meta", ino),
+            Quota(QuotaOwner::User(id)) => write!(f, "quota/user/{}", id),
+            Quota(QuotaOwner::Group(id)) => write!(f, "quota/group/{}", id),
+            SnapshotCatalog => write!(f, "snapshot_catalog"),
+            SharedBlock(id) => write!(f, "shared_block/{}", id),
+            Usage(shard) => write!(f, "usage/{}", shard),
         }
     }
 }
 
+impl fmt::Debug for ScopedKey<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
 impl<'a> From<ScopedKey<'a>> for Key {
     fn from(key: ScopedKey<'a>) -> Self {
         use ScopedKey::*;
@@ -125,8 +343,10 @@ impl<'a> From<ScopedKey<'a>> for Key {
         let mut data = Vec::with_capacity(key.len());
         data.push(key.scope());
         match key {
-            Meta => (),
-            Inode(ino) => data.extend(ino.to_be_bytes().iter()),
+            Meta | SnapshotCatalog => (),
+            Inode(ino) | Lock(ino) | XattrMeta(ino) | SharedBlock(ino) | Usage(ino) => {
+                data.extend(ino.to_be_bytes().iter())
+            }
             Block { ino, block } => {
                 data.extend(ino.to_be_bytes().iter());
                 data.extend(block.to_be_bytes().iter())
@@ -139,7 +359,155 @@ impl<'a> From<ScopedKey<'a>> for Key {
                 data.extend(parent.to_be_bytes().iter());
                 data.extend(name.as_bytes().iter());
             }
+            Xattr { ino, name } => {
+                data.extend(ino.to_be_bytes().iter());
+                data.extend(name.as_bytes().iter());
+            }
+            Quota(QuotaOwner::User(id)) => {
+                data.push(ScopedKey::USER_QUOTA);
+                data.extend(id.to_be_bytes().iter());
+            }
+            Quota(QuotaOwner::Group(id)) => {
+                data.push(ScopedKey::GROUP_QUOTA);
+                data.extend(id.to_be_bytes().iter());
+            }
         }
         data.into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn parse_what_was_encoded() {
+        let keys = [
+            ScopedKey::meta(),
+            ScopedKey::inode(42),
+            ScopedKey::block(42, 7),
+            ScopedKey::handler(42, 3),
+            ScopedKey::index(42, "name"),
+            ScopedKey::xattr(42, "user.tag"),
+            ScopedKey::lock(42),
+            ScopedKey::xattr_meta(42),
+            ScopedKey::quota(QuotaOwner::User(42)),
+            ScopedKey::quota(QuotaOwner::Group(42)),
+            ScopedKey::snapshot_catalog(),
+            ScopedKey::shared_block(42),
+            ScopedKey::usage(7),
+        ];
+        for key in keys.iter().copied() {
+            let raw: Vec<u8> = Key::from(key).into();
+            assert_eq!(raw.len(), key.len());
+            assert_eq!(raw[0], key.scope());
+            assert_eq!(ScopedKey::parse(&raw).unwrap(), key);
+        }
+    }
+
+    #[test]
+    fn index_keys_are_injective() {
+        // xorshift, so the names are random but the same on every run
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let alphabet = ["\0", "a", "\0\0", "/", "\u{ff}", "\u{100}"];
+        let mut encoded: HashMap<Vec<u8>, (u64, String)> = HashMap::new();
+        for _ in 0..10_000 {
+            let parent = next() % 4;
+            let name: String = (0..next() % 6)
+                .map(|_| alphabet[(next() % alphabet.len() as u64) as usize])
+                .collect();
+            let raw: Vec<u8> = Key::from(ScopedKey::index(parent, &name)).into();
+            assert_eq!(
+                ScopedKey::parse(&raw).unwrap(),
+                ScopedKey::index(parent, &name)
+            );
+            let previous = encoded.entry(raw).or_insert_with(|| (parent, name.clone()));
+            assert_eq!(*previous, (parent, name));
+        }
+    }
+
+    #[test]
+    fn xattr_ranges_hold_the_xattrs_of_one_inode() {
+        for &ino in [1, 42, u64::MAX].iter() {
+            let range = ScopedKey::xattr_range(ino);
+            assert!(range.contains(&ScopedKey::xattr(ino, "user.a").into()));
+            assert!(!range.contains(&ScopedKey::xattr(ino - 1, "user.a").into()));
+            assert!(!range.contains(&ScopedKey::xattr_meta(ino).into()));
+        }
+    }
+
+    #[test]
+    fn display_as_paths() {
+        assert_eq!(ScopedKey::block(42, 7).to_string(), "inode/42/block/7");
+        assert_eq!(
+            ScopedKey::index(1, "a.txt").to_string(),
+            "inode/1/index/a.txt"
+        );
+        assert_eq!(
+            format!("{:?}", ScopedKey::quota(QuotaOwner::Group(5))),
+            "quota/group/5"
+        );
+        assert_eq!(format!("{:?}", ScopedKey::meta()), "meta");
+    }
+
+    #[test]
+    fn displayed_keys_are_not_keys() {
+        // every displayed key starts with a letter, which is a reserved scope byte
+        for key in [
+            ScopedKey::meta(),
+            ScopedKey::block(42, 7),
+            ScopedKey::index(42, "name"),
+            ScopedKey::lock(42),
+            ScopedKey::quota(QuotaOwner::User(42)),
+            ScopedKey::snapshot_catalog(),
+        ]
+        .iter()
+        {
+            let displayed = key.to_string();
+            assert!(
+                ScopedKey::parse(displayed.as_bytes()).is_err(),
+                "{}",
+                displayed
+            );
+            let raw: Vec<u8> = Key::from(*key).into();
+            assert_ne!(raw, displayed.into_bytes());
+        }
+    }
+
+    #[test]
+    fn namespaces_keep_their_bytes() {
+        assert_eq!(Namespace::Meta as u8, 0);
+        assert_eq!(Namespace::Block as u8, 2);
+        assert_eq!(Namespace::Snapshot as u8, 9);
+        assert_eq!(Namespace::SharedBlock as u8, 10);
+        assert_eq!(Namespace::Usage as u8, 11);
+        for byte in 0..=11 {
+            assert_eq!(Namespace::from_u8(byte).unwrap() as u8, byte);
+        }
+        assert_eq!(Namespace::from_u8(0x0c), None);
+
+        let range = ScopedKey::namespace_range(Namespace::Xattr);
+        let key: Key = ScopedKey::xattr(u64::MAX, "user.z").into();
+        assert!(range.start <= key && key < range.end);
+        let key: Key = ScopedKey::lock(0).into();
+        assert!(key >= range.end);
+    }
+
+    #[test]
+    fn reserved_scopes_are_invalid() {
+        for scope in [0x0cu8, 0xff].iter() {
+            let mut raw = vec![*scope];
+            raw.extend(42u64.to_be_bytes().iter());
+            assert!(ScopedKey::parse(&raw).is_err());
+        }
+        assert!(ScopedKey::parse(&[0x08, 2, 0, 0, 0, 42]).is_err());
+    }
+}
@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
 
+use super::block::CompressionMode;
 use super::error::{FsError, Result};
 use super::key::ROOT_INODE;
 use super::reply::StatFs;
@@ -7,18 +9,239 @@ use super::serialize::{deserialize, serialize, ENCODING};
 
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct Meta {
+    /// Layout version, 0 for records written before it existed; see `Meta::VERSION`.
+    #[serde(default)]
+    pub version: u32,
     pub inode_next: u64,
     pub block_size: u64,
     pub last_stat: Option<StatFs>,
+    /// Numbers of deleted inodes, handed out again before `inode_next` grows.
+    #[serde(default)]
+    pub free_inodes: Vec<u64>,
+    /// Data and directory blocks stored when `statfs` first counted them, or `fsck` last
+    /// did; `None` until then. Blocks stored and freed since are kept apart, as deltas in
+    /// the `USAGE_SHARDS` usage keys, so writers don't all rewrite the meta record.
+    #[serde(default)]
+    pub used_blocks: Option<u64>,
+    /// Inodes alive; `None` until `statfs` counts them once.
+    #[serde(default)]
+    pub used_inodes: Option<u64>,
+    /// How file blocks are stored, chosen when the filesystem is created.
+    #[serde(default)]
+    pub compression: CompressionMode,
+    /// Whether inode and block values are sealed with the key given at mount time.
+    #[serde(default)]
+    pub encrypted: bool,
+    /// Generation of the last inode made with a recycled number; see `alloc_ino`.
+    #[serde(default)]
+    pub generation: u64,
+    /// Inline data threshold chosen when the filesystem was created; `None` for older
+    /// filesystems, which keep the threshold of their mount options.
+    #[serde(default)]
+    pub inline_threshold: Option<u64>,
+    /// Id of the next `SharedBlock` made by `Txn::reflink`.
+    #[serde(default)]
+    pub shared_block_next: u64,
+    /// Name operators tag the filesystem with when creating it; see `check_label`.
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Identity of the filesystem drawn by `generate_uuid` when it is created; all zeros
+    /// for older filesystems until a writable mount gives them one.
+    #[serde(default)]
+    pub uuid: [u8; 16],
+}
+
+/// `Meta` as stored by filesystems created before it had a version, decoded when a
+/// record doesn't fit the current layout; codecs without field names can't skip the
+/// missing fields otherwise.
+#[derive(Deserialize, Serialize)]
+struct MetaV0 {
+    inode_next: u64,
+    block_size: u64,
+    last_stat: Option<StatFs>,
+}
+
+impl From<MetaV0> for Meta {
+    fn from(old: MetaV0) -> Self {
+        Self {
+            inode_next: old.inode_next,
+            last_stat: old.last_stat,
+            // the usage counters of an old filesystem are unknown until `statfs` counts them
+            used_blocks: None,
+            used_inodes: None,
+            ..Meta::new(old.block_size)
+        }
+    }
+}
+
+/// Longest label, as the volume labels of ext4.
+pub const MAX_LABEL_LEN: usize = 16;
+
+/// Check that `label` fits in `MAX_LABEL_LEN` bytes of printable ascii.
+pub fn check_label(label: &str) -> Result<()> {
+    if label.len() > MAX_LABEL_LEN
+        || !label
+            .bytes()
+            .all(|byte| byte.is_ascii_graphic() || byte == b' ')
+    {
+        return Err(FsError::InvalidLabel {
+            label: label.to_owned(),
+            max: MAX_LABEL_LEN,
+        });
+    }
+    Ok(())
+}
+
+/// A random UUID, version 4 of RFC 4122, from the CSPRNG of the OS.
+pub fn generate_uuid() -> Result<[u8; 16]> {
+    let mut uuid = [0; 16];
+    getrandom::getrandom(&mut uuid)
+        .map_err(|err| FsError::UnknownError(format!("fail to generate uuid: {}", err)))?;
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    Ok(uuid)
+}
+
+/// `uuid` in the hyphenated form `blkid` prints.
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex: String = uuid.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Number of usage keys the block deltas of transactions are spread over.
+pub const USAGE_SHARDS: u64 = 64;
+
+/// The usage shard blocks of `ino` are counted in; writers of `ino` conflict on its inode
+/// anyway, so writers of different inodes seldom conflict on their shard.
+pub fn usage_shard(ino: u64) -> u64 {
+    ino % USAGE_SHARDS
+}
+
+pub fn serialize_usage(delta: i64) -> Result<Vec<u8>> {
+    serialize(&delta).map_err(|err| FsError::Serialize {
+        target: "usage",
+        typ: ENCODING,
+        msg: err.to_string(),
+    })
+}
+
+pub fn deserialize_usage(bytes: &[u8]) -> Result<i64> {
+    deserialize(bytes).map_err(|err| FsError::Serialize {
+        target: "usage",
+        typ: ENCODING,
+        msg: err.to_string(),
+    })
+}
+
+/// Usage of the filesystem, in blocks of `block_size` and inodes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FsStats {
+    pub total_blocks: u64,
+    pub free_blocks: u64,
+    pub used_inodes: u64,
+    pub free_inodes: u64,
 }
 
 impl Meta {
+    /// Version written by this build. Fields added later must decode from older records
+    /// (`#[serde(default)]`), and unknown fields of newer ones are ignored.
+    pub const VERSION: u32 = 1;
+
     pub const fn new(block_size: u64) -> Self {
         Self {
+            version: Self::VERSION,
             inode_next: ROOT_INODE,
             block_size,
             last_stat: None,
+            free_inodes: Vec::new(),
+            used_blocks: Some(0),
+            used_inodes: Some(0),
+            compression: CompressionMode::None,
+            encrypted: false,
+            generation: 0,
+            inline_threshold: None,
+            shared_block_next: 0,
+            label: None,
+            uuid: [0; 16],
+        }
+    }
+
+    pub fn has_uuid(&self) -> bool {
+        self.uuid != [0; 16]
+    }
+
+    /// Give a filesystem created before `uuid` and `inline_threshold` were recorded the
+    /// ones it keeps from now on, `inline_threshold` being the one it was written with so
+    /// far; `true` if anything changed and the meta must be saved.
+    pub fn fill_missing(&mut self, inline_threshold: u64) -> Result<bool> {
+        let mut changed = false;
+        if !self.has_uuid() {
+            self.uuid = generate_uuid()?;
+            changed = true;
+        }
+        if self.inline_threshold.is_none() {
+            self.inline_threshold = Some(inline_threshold);
+            changed = true;
+        }
+        Ok(changed)
+    }
+
+    /// Take an unused inode number, preferring a recycled one, along with the generation
+    /// of the inode to make with it.
+    ///
+    /// A number never used before has generation 0; a recycled one gets a generation no
+    /// earlier inode had, so NFS file handles of a deleted file can't reach its successor.
+    pub fn alloc_ino(&mut self) -> Result<(u64, u64)> {
+        let (ino, generation) = match self.free_inodes.pop() {
+            Some(ino) => {
+                self.generation += 1;
+                (ino, self.generation)
+            }
+            None if self.inode_next == u64::MAX => return Err(FsError::InodeExhausted),
+            None => {
+                self.inode_next += 1;
+                (self.inode_next - 1, 0)
+            }
+        };
+        if let Some(used) = self.used_inodes.as_mut() {
+            *used += 1;
         }
+        Ok((ino, generation))
+    }
+
+    /// Give the number of a deleted inode back, unless `max_free` numbers are kept already.
+    ///
+    /// Releasing a number twice keeps a single copy, so it can't be handed out twice.
+    pub fn release_ino(&mut self, ino: u64, max_free: u64) {
+        if self.free_inodes.contains(&ino) {
+            return;
+        }
+        if (self.free_inodes.len() as u64) < max_free {
+            self.free_inodes.push(ino);
+        }
+        if let Some(used) = self.used_inodes.as_mut() {
+            *used = used.saturating_sub(1);
+        }
+    }
+
+    /// Blocks stored now: `used_blocks` with the `deltas` of the usage shards added, once
+    /// they are counted.
+    pub fn blocks_in_use(&self, deltas: impl IntoIterator<Item = i64>) -> Option<u64> {
+        let used = self.used_blocks?;
+        Some(deltas.into_iter().fold(used, |used, delta| {
+            if delta < 0 {
+                used.saturating_sub(delta.unsigned_abs())
+            } else {
+                used.saturating_add(delta as u64)
+            }
+        }))
     }
 
     pub fn serialize(&self) -> Result<Vec<u8>> {
@@ -30,10 +253,221 @@ impl Meta {
     }
 
     pub fn deserialize(bytes: &[u8]) -> Result<Self> {
-        deserialize(bytes).map_err(|err| FsError::Serialize {
-            target: "meta",
-            typ: ENCODING,
-            msg: err.to_string(),
+        let mut meta = match deserialize::<Self>(bytes) {
+            Ok(meta) => meta,
+            // only a record that is exactly a v0 one, so a damaged current record can't
+            // silently lose the fields v0 lacks
+            Err(err) => deserialize::<MetaV0>(bytes)
+                .ok()
+                .filter(|old| serialize(old).map_or(false, |data| data == bytes))
+                .map(Self::from)
+                .ok_or_else(|| FsError::Serialize {
+                    target: "meta",
+                    typ: ENCODING,
+                    msg: err.to_string(),
+                })?,
+        };
+        match meta.version {
+            0 => {
+                debug!("upgrade meta from version 0 to {}", Self::VERSION);
+                meta.version = Self::VERSION;
+            }
+            version if version > Self::VERSION => {
+                warn!(
+                    "meta version({}) is newer than supported({}), unknown fields are ignored",
+                    version,
+                    Self::VERSION
+                );
+            }
+            _ => (),
+        }
+        Ok(meta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alloc_ino() {
+        let mut meta = Meta::new(1 << 16);
+        assert_eq!(meta.alloc_ino().unwrap().0, ROOT_INODE);
+        assert_eq!(meta.alloc_ino().unwrap().0, ROOT_INODE + 1);
+        assert_eq!(meta.inode_next, ROOT_INODE + 2);
+    }
+
+    #[test]
+    fn alloc_ino_exhausted() {
+        let mut meta = Meta::new(1 << 16);
+        meta.inode_next = u64::MAX;
+        assert!(matches!(meta.alloc_ino(), Err(FsError::InodeExhausted)));
+        assert_eq!(meta.inode_next, u64::MAX);
+    }
+
+    #[test]
+    fn recycle_inodes() {
+        let mut meta = Meta::new(1 << 16);
+        let root = meta.alloc_ino().unwrap().0;
+        for _ in 0..10_000 {
+            let ino = meta.alloc_ino().unwrap().0;
+            meta.release_ino(ino, 4096);
+        }
+        assert_eq!(root, ROOT_INODE);
+        assert_eq!(meta.inode_next, ROOT_INODE + 2);
+        assert_eq!(meta.free_inodes, vec![ROOT_INODE + 1]);
+    }
+
+    #[test]
+    fn recycled_inodes_get_a_new_generation() {
+        let mut meta = Meta::new(1 << 16);
+        meta.alloc_ino().unwrap();
+        let (ino, generation) = meta.alloc_ino().unwrap();
+        assert_eq!(generation, 0);
+
+        meta.release_ino(ino, 4096);
+        assert_eq!(meta.alloc_ino().unwrap(), (ino, 1));
+        meta.release_ino(ino, 4096);
+        assert_eq!(meta.alloc_ino().unwrap(), (ino, 2));
+
+        // fresh numbers stay at generation 0
+        assert_eq!(meta.alloc_ino().unwrap(), (ino + 1, 0));
+    }
+
+    #[test]
+    fn free_list_is_capped() {
+        let mut meta = Meta::new(1 << 16);
+        let inos: Vec<u64> = (0..8).map(|_| meta.alloc_ino().unwrap().0).collect();
+        for ino in inos {
+            meta.release_ino(ino, 4);
+        }
+        assert_eq!(meta.free_inodes.len(), 4);
+        meta.release_ino(ROOT_INODE, 8);
+        assert_eq!(meta.free_inodes.len(), 4);
+        meta.release_ino(ROOT_INODE + 7, 8);
+        meta.release_ino(ROOT_INODE + 7, 8);
+        assert_eq!(meta.free_inodes.len(), 5);
+        assert_eq!(meta.inode_next, ROOT_INODE + 8);
+    }
+
+    #[cfg(all(feature = "json", not(feature = "binc")))]
+    #[test]
+    fn deserialize_without_free_inodes() {
+        let meta =
+            Meta::deserialize(br#"{"inode_next":42,"block_size":65536,"last_stat":null}"#).unwrap();
+        assert_eq!(meta.inode_next, 42);
+        assert_eq!(meta.version, Meta::VERSION);
+        assert!(meta.free_inodes.is_empty());
+    }
+
+    #[test]
+    fn upgrade_from_version_0() {
+        #[derive(Serialize)]
+        struct OldMeta {
+            inode_next: u64,
+            block_size: u64,
+            last_stat: Option<StatFs>,
+        }
+        let old = serialize(&OldMeta {
+            inode_next: 42,
+            block_size: 4096,
+            last_stat: None,
         })
+        .unwrap();
+
+        let meta = Meta::deserialize(&old).unwrap();
+        assert_eq!(meta.version, Meta::VERSION);
+        assert_eq!(meta.inode_next, 42);
+        assert_eq!(meta.block_size, 4096);
+        assert!(meta.free_inodes.is_empty());
+        assert_eq!(meta.compression, CompressionMode::None);
+
+        let decoded = Meta::deserialize(&meta.serialize().unwrap()).unwrap();
+        assert_eq!(decoded, meta);
+    }
+
+    #[cfg(all(feature = "json", not(feature = "binc")))]
+    #[test]
+    fn unknown_fields_are_ignored() {
+        let meta = Meta::deserialize(
+            br#"{"version":2,"inode_next":42,"block_size":65536,"last_stat":null,"future":[1,2]}"#,
+        )
+        .unwrap();
+        assert_eq!(meta.version, 2);
+        assert_eq!(meta.inode_next, 42);
+    }
+
+    #[test]
+    fn usage_counters() {
+        let mut meta = Meta::new(1 << 16);
+        let inos: Vec<u64> = (0..3).map(|_| meta.alloc_ino().unwrap().0).collect();
+        meta.release_ino(inos[1], 4096);
+        meta.release_ino(inos[1], 4096);
+        assert_eq!(meta.used_inodes, Some(2));
+
+        assert_eq!(meta.blocks_in_use(vec![10, -4]), Some(6));
+        assert_eq!(meta.blocks_in_use(vec![-7, 3]), Some(3));
+        meta.used_blocks = Some(5);
+        assert_eq!(meta.blocks_in_use(vec![]), Some(5));
+
+        meta.used_blocks = None;
+        assert_eq!(meta.blocks_in_use(vec![1]), None);
+
+        for delta in [0, -3, i64::MAX].iter() {
+            let bytes = serialize_usage(*delta).unwrap();
+            assert_eq!(deserialize_usage(&bytes).unwrap(), *delta);
+        }
+    }
+
+    #[test]
+    fn labels_survive_a_round_trip() {
+        assert!(check_label("backup-2021").is_ok());
+        assert!(check_label(&"x".repeat(MAX_LABEL_LEN)).is_ok());
+        for label in ["x".repeat(MAX_LABEL_LEN + 1).as_str(), "étiquette", "a\nb"].iter() {
+            let err = check_label(label).unwrap_err();
+            assert_eq!(libc::c_int::from(err), libc::EINVAL, "{:?}", label);
+        }
+
+        let mut meta = Meta::new(1 << 16);
+        meta.label = Some("backup-2021".into());
+        let decoded = Meta::deserialize(&meta.serialize().unwrap()).unwrap();
+        assert_eq!(decoded.label.as_deref(), Some("backup-2021"));
+    }
+
+    #[test]
+    fn uuids_are_random_version_4() {
+        let uuid = generate_uuid().unwrap();
+        assert_ne!(uuid, generate_uuid().unwrap());
+        let text = format_uuid(&uuid);
+        assert_eq!(text.len(), 36);
+        assert_eq!(&text[14..15], "4");
+        assert!("89ab".contains(&text[19..20]), "{}", text);
+
+        let mut meta = Meta::new(1 << 16);
+        assert!(!meta.has_uuid());
+        meta.uuid = uuid;
+        let decoded = Meta::deserialize(&meta.serialize().unwrap()).unwrap();
+        assert_eq!(decoded.uuid, uuid);
+    }
+
+    #[test]
+    fn uuid_text() {
+        let uuid = [
+            0x12, 0x3e, 0x45, 0x67, 0xe8, 0x9b, 0x42, 0xd3, 0xa4, 0x56, 0x42, 0x66, 0x14, 0x17,
+            0x40, 0x00,
+        ];
+        assert_eq!(format_uuid(&uuid), "123e4567-e89b-42d3-a456-426614174000");
+    }
+
+    #[test]
+    fn older_filesystems_get_missing_fields_once() {
+        let mut meta = Meta::new(1 << 16);
+        assert!(meta.fill_missing(4096).unwrap());
+        assert!(meta.has_uuid());
+        assert_eq!(meta.inline_threshold, Some(4096));
+
+        let uuid = meta.uuid;
+        assert!(!meta.fill_missing(1 << 10).unwrap());
+        assert_eq!((meta.uuid, meta.inline_threshold), (uuid, Some(4096)));
     }
 }
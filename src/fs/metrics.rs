@@ -0,0 +1,139 @@
+//! I/O and transaction accounting through the `metrics` crate.
+//!
+//! Without a `MetricsHandle`, everything goes to the global recorder, so a binary exposes
+//! the metrics to Prometheus by installing one before mounting, e.g. with
+//! `metrics-exporter-prometheus`:
+//!
+//! ```ignore
+//! metrics_exporter_prometheus::PrometheusBuilder::new()
+//!     .listen_address(([0, 0, 0, 0], 9000))
+//!     .install()?;
+//! ```
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const READ_BYTES_TOTAL: &str = "tifs_read_bytes_total";
+pub const WRITE_BYTES_TOTAL: &str = "tifs_write_bytes_total";
+pub const TRANSACTION_TOTAL: &str = "tifs_transaction_total";
+pub const TRANSACTION_CONFLICTS_TOTAL: &str = "tifs_transaction_conflicts_total";
+pub const REQUESTS_TOTAL: &str = "tifs_requests_total";
+pub const REQUEST_ERRORS_TOTAL: &str = "tifs_request_errors_total";
+pub const GC_SCANNED_BLOCKS_TOTAL: &str = "tifs_gc_scanned_blocks_total";
+pub const GC_DELETED_BLOCKS_TOTAL: &str = "tifs_gc_deleted_blocks_total";
+pub const READ_LATENCY_SECONDS: &str = "tifs_read_latency_seconds";
+pub const WRITE_LATENCY_SECONDS: &str = "tifs_write_latency_seconds";
+
+/// A recorder `Metrics` reports to instead of the global one.
+pub trait MetricsSink: Debug + Send + Sync {
+    fn increment_counter(&self, name: &'static str, value: u64);
+    fn record_histogram(&self, name: &'static str, value: f64);
+}
+
+pub type MetricsHandle = Arc<dyn MetricsSink>;
+
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    handle: Option<MetricsHandle>,
+}
+
+impl Metrics {
+    pub fn new(handle: Option<MetricsHandle>) -> Self {
+        Self { handle }
+    }
+
+    fn counter(&self, name: &'static str, value: u64) {
+        match &self.handle {
+            Some(handle) => handle.increment_counter(name, value),
+            None => metrics::counter!(name, value),
+        }
+    }
+
+    fn histogram(&self, name: &'static str, elapsed: Duration) {
+        match &self.handle {
+            Some(handle) => handle.record_histogram(name, elapsed.as_secs_f64()),
+            None => metrics::histogram!(name, elapsed.as_secs_f64()),
+        }
+    }
+
+    pub fn read(&self, bytes: u64, elapsed: Duration) {
+        self.counter(READ_BYTES_TOTAL, bytes);
+        self.histogram(READ_LATENCY_SECONDS, elapsed);
+    }
+
+    pub fn write(&self, bytes: u64, elapsed: Duration) {
+        self.counter(WRITE_BYTES_TOTAL, bytes);
+        self.histogram(WRITE_LATENCY_SECONDS, elapsed);
+    }
+
+    /// A transaction attempt, committed or not.
+    pub fn transaction(&self) {
+        self.counter(TRANSACTION_TOTAL, 1);
+    }
+
+    /// An attempt that failed for a retryable reason, such as a write conflict.
+    pub fn conflict(&self) {
+        self.counter(TRANSACTION_CONFLICTS_TOTAL, 1);
+    }
+
+    /// A page of block keys checked by the garbage collector, and the orphans it deleted.
+    pub fn gc(&self, scanned: u64, deleted: u64) {
+        self.counter(GC_SCANNED_BLOCKS_TOTAL, scanned);
+        self.counter(GC_DELETED_BLOCKS_TOTAL, deleted);
+    }
+
+    /// A FUSE request answered, with an error or not.
+    pub fn request(&self, failed: bool) {
+        self.counter(REQUESTS_TOTAL, 1);
+        if failed {
+            self.counter(REQUEST_ERRORS_TOTAL, 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct MockRecorder {
+        counters: Mutex<HashMap<&'static str, u64>>,
+        histograms: Mutex<HashMap<&'static str, Vec<f64>>>,
+    }
+
+    impl MetricsSink for MockRecorder {
+        fn increment_counter(&self, name: &'static str, value: u64) {
+            *self.counters.lock().unwrap().entry(name).or_default() += value;
+        }
+
+        fn record_histogram(&self, name: &'static str, value: f64) {
+            self.histograms
+                .lock()
+                .unwrap()
+                .entry(name)
+                .or_default()
+                .push(value);
+        }
+    }
+
+    #[test]
+    fn writes_count_their_bytes() {
+        let recorder = Arc::new(MockRecorder::default());
+        let metrics = Metrics::new(Some(recorder.clone()));
+        metrics.write(4096, Duration::from_millis(3));
+        metrics.write(10, Duration::from_millis(1));
+        metrics.transaction();
+
+        let counters = recorder.counters.lock().unwrap();
+        assert_eq!(counters[WRITE_BYTES_TOTAL], 4106);
+        assert_eq!(counters[TRANSACTION_TOTAL], 1);
+        assert!(!counters.contains_key(READ_BYTES_TOTAL));
+        assert_eq!(
+            recorder.histograms.lock().unwrap()[WRITE_LATENCY_SECONDS],
+            vec![0.003, 0.001]
+        );
+    }
+}
@@ -5,34 +5,51 @@ pub const fn as_file_perm(mode: u32) -> u16 {
 }
 
 #[cfg(any(target_os = "freebsd", target_os = "macos"))]
-pub fn as_file_kind(mode: u32) -> FileType {
+pub fn try_file_kind(mode: u32) -> Option<FileType> {
     use FileType::*;
 
     match mode as u16 & libc::S_IFMT {
-        libc::S_IFREG => RegularFile,
-        libc::S_IFLNK => Symlink,
-        libc::S_IFDIR => Directory,
-        libc::S_IFIFO => NamedPipe,
-        libc::S_IFBLK => BlockDevice,
-        libc::S_IFCHR => CharDevice,
-        libc::S_IFSOCK => Socket,
-        _ => unimplemented!("{}", mode),
+        libc::S_IFREG => Some(RegularFile),
+        libc::S_IFLNK => Some(Symlink),
+        libc::S_IFDIR => Some(Directory),
+        libc::S_IFIFO => Some(NamedPipe),
+        libc::S_IFBLK => Some(BlockDevice),
+        libc::S_IFCHR => Some(CharDevice),
+        libc::S_IFSOCK => Some(Socket),
+        _ => None,
     }
 }
 
 #[cfg(target_os = "linux")]
-pub fn as_file_kind(mode: u32) -> FileType {
+pub fn try_file_kind(mode: u32) -> Option<FileType> {
     use FileType::*;
 
     match mode & libc::S_IFMT as u32 {
-        libc::S_IFREG => RegularFile,
-        libc::S_IFLNK => Symlink,
-        libc::S_IFDIR => Directory,
-        libc::S_IFIFO => NamedPipe,
-        libc::S_IFBLK => BlockDevice,
-        libc::S_IFCHR => CharDevice,
-        libc::S_IFSOCK => Socket,
-        _ => unimplemented!("{}", mode),
+        libc::S_IFREG => Some(RegularFile),
+        libc::S_IFLNK => Some(Symlink),
+        libc::S_IFDIR => Some(Directory),
+        libc::S_IFIFO => Some(NamedPipe),
+        libc::S_IFBLK => Some(BlockDevice),
+        libc::S_IFCHR => Some(CharDevice),
+        libc::S_IFSOCK => Some(Socket),
+        _ => None,
+    }
+}
+
+/// The mode and `rdev` of a node made by `mknod(2)`, or `None` for a type it can't make.
+/// A mode without a type makes a regular file, and only devices keep `rdev`.
+pub fn mknod_mode(mode: u32, rdev: u32) -> Option<(u32, u32)> {
+    use FileType::*;
+
+    let mode = if mode & libc::S_IFMT as u32 == 0 {
+        mode | libc::S_IFREG as u32
+    } else {
+        mode
+    };
+    match try_file_kind(mode)? {
+        RegularFile | NamedPipe | Socket => Some((mode, 0)),
+        BlockDevice | CharDevice => Some((mode, rdev)),
+        Directory | Symlink => None,
     }
 }
 
@@ -69,3 +86,26 @@ pub fn make_mode(tpy: FileType, perm: u16) -> u32 {
 
     kind | perm as u32
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mknod_modes() {
+        let rdev = libc::makedev(1, 3) as u32;
+        let chr = libc::S_IFCHR as u32 | 0o666;
+        assert_eq!(mknod_mode(chr, rdev), Some((chr, rdev)));
+        assert_eq!(try_file_kind(chr), Some(FileType::CharDevice));
+        assert_eq!(try_file_kind(0o644), None);
+
+        let fifo = libc::S_IFIFO as u32 | 0o644;
+        assert_eq!(mknod_mode(fifo, rdev), Some((fifo, 0)));
+        assert_eq!(
+            mknod_mode(0o644, 0),
+            Some((libc::S_IFREG as u32 | 0o644, 0))
+        );
+        assert_eq!(mknod_mode(libc::S_IFDIR as u32 | 0o755, 0), None);
+        assert_eq!(mknod_mode(libc::S_IFLNK as u32 | 0o777, 0), None);
+    }
+}
@@ -0,0 +1,108 @@
+use serde::{Deserialize, Serialize};
+
+use super::error::{FsError, Result};
+use super::serialize::{deserialize, serialize, ENCODING};
+
+/// The user or group a quota applies to.
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy)]
+pub enum QuotaOwner {
+    User(u32),
+    Group(u32),
+}
+
+/// Bytes of the blocks stored for regular files owned by a user or group, and the limits
+/// on them; a limit of 0 means none. Data inlined in the inode takes no blocks.
+///
+/// Usage is only counted once a quota is set, so files written before don't show up in it.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy, Deserialize, Serialize)]
+pub struct Quota {
+    pub used_bytes: u64,
+    pub hard_limit: u64,
+    pub soft_limit: u64,
+}
+
+/// Quotas returned by `Txn::get_quota`; `None` where no quota is set.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct QuotaInfo {
+    pub user: Option<Quota>,
+    pub group: Option<Quota>,
+}
+
+impl Quota {
+    /// Add `delta` bytes to the usage, unless growing it would break the hard limit.
+    /// Shrinking is always allowed, even for an owner already over the limit.
+    pub fn charge(&mut self, delta: i64) -> bool {
+        if delta > 0 {
+            let used = self.used_bytes.saturating_add(delta as u64);
+            if self.hard_limit != 0 && used > self.hard_limit {
+                return false;
+            }
+            self.used_bytes = used;
+        } else {
+            self.used_bytes = self.used_bytes.saturating_sub(delta.unsigned_abs());
+        }
+        true
+    }
+
+    pub fn over_soft_limit(&self) -> bool {
+        self.soft_limit != 0 && self.used_bytes > self.soft_limit
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        serialize(self).map_err(|err| FsError::Serialize {
+            target: "quota",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        deserialize(bytes).map_err(|err| FsError::Serialize {
+            target: "quota",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_past_the_hard_limit_are_refused() {
+        let mut quota = Quota {
+            used_bytes: 0,
+            hard_limit: 100,
+            soft_limit: 50,
+        };
+        assert!(quota.charge(60));
+        assert!(quota.over_soft_limit());
+        assert!(!quota.charge(41));
+        assert_eq!(quota.used_bytes, 60);
+        assert!(quota.charge(40));
+        assert_eq!(quota.used_bytes, 100);
+    }
+
+    #[test]
+    fn deleting_releases_usage() {
+        let mut quota = Quota {
+            used_bytes: 150,
+            hard_limit: 100,
+            soft_limit: 0,
+        };
+        // lowering the limit below the usage still lets files be removed
+        assert!(quota.charge(-120));
+        assert_eq!(quota.used_bytes, 30);
+        assert!(!quota.over_soft_limit());
+        assert!(quota.charge(-100));
+        assert_eq!(quota.used_bytes, 0);
+    }
+
+    #[test]
+    fn zero_means_unlimited() {
+        let mut quota = Quota::default();
+        assert!(quota.charge(i64::MAX));
+        assert!(!quota.over_soft_limit());
+    }
+}
@@ -1,6 +1,7 @@
 use std::fmt::Debug;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use bytes::Bytes;
 use fuser::*;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, error, trace};
@@ -55,11 +56,11 @@ impl Attr {
 
 #[derive(Debug)]
 pub struct Data {
-    pub data: Vec<u8>,
+    pub data: Bytes,
 }
 impl Data {
-    pub fn new(data: Vec<u8>) -> Self {
-        Self { data }
+    pub fn new(data: impl Into<Bytes>) -> Self {
+        Self { data: data.into() }
     }
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -67,50 +68,40 @@ pub struct DirItem {
     pub ino: u64,
     pub name: String,
     pub typ: FileType,
+    /// Position of the entry in its directory, increasing in insertion order and never
+    /// handed out twice in the same directory.
+    #[serde(default)]
+    pub seq: u64,
 }
+
+/// Entries of a `readdir` reply, each with the offset the listing resumes from after it.
 #[derive(Debug, Default)]
 pub struct Dir {
-    offset: usize,
-    items: Vec<DirItem>,
+    items: Vec<(i64, DirItem)>,
 }
 
 impl Dir {
-    pub fn offset(offset: usize) -> Self {
-        Self {
-            offset,
-            items: Default::default(),
-        }
-    }
-
     pub fn new() -> Self {
         Default::default()
     }
 
-    pub fn push(&mut self, item: DirItem) {
-        self.items.push(item)
+    pub fn push(&mut self, offset: i64, item: DirItem) {
+        self.items.push((offset, item))
     }
 }
 
 #[derive(Debug, Default)]
 pub struct DirPlus {
-    offset: usize,
-    items: Vec<(DirItem, Entry)>,
+    items: Vec<(i64, DirItem, Entry)>,
 }
 
 impl DirPlus {
-    pub fn offset(offset: usize) -> Self {
-        Self {
-            offset,
-            items: Default::default(),
-        }
-    }
-
     pub fn new() -> Self {
         Default::default()
     }
 
-    pub fn push(&mut self, item: DirItem, entry: Entry) {
-        self.items.push((item, entry))
+    pub fn push(&mut self, offset: i64, item: DirItem, entry: Entry) {
+        self.items.push((offset, item, entry))
     }
 }
 
@@ -236,6 +227,18 @@ impl Lseek {
     }
 }
 
+#[derive(Debug)]
+pub struct Ioctl {
+    result: i32,
+    data: Vec<u8>,
+}
+
+impl Ioctl {
+    pub fn new(result: i32, data: Vec<u8>) -> Self {
+        Self { result, data }
+    }
+}
+
 pub trait FsReply<T: Debug>: Sized {
     fn reply_ok(self, item: T);
     fn reply_err(self, err: libc::c_int);
@@ -249,7 +252,7 @@ pub trait FsReply<T: Debug>: Sized {
             Err(err) => {
                 debug!("err. reply with {} for request ({})", err, id);
 
-                let err = err.into();
+                let err = err.to_errno();
                 if err == -1 {
                     error!("returned -1");
                 }
@@ -288,7 +291,7 @@ impl FsReply<Attr> for ReplyAttr {
 
 impl FsReply<Data> for ReplyData {
     fn reply_ok(self, item: Data) {
-        self.data(item.data.as_slice());
+        self.data(&item.data);
     }
     fn reply_err(self, err: libc::c_int) {
         self.error(err);
@@ -297,13 +300,8 @@ impl FsReply<Data> for ReplyData {
 
 impl FsReply<Dir> for ReplyDirectory {
     fn reply_ok(mut self, dir: Dir) {
-        for (index, item) in dir.items.into_iter().enumerate() {
-            if self.add(
-                item.ino,
-                (index + 1 + dir.offset) as i64,
-                item.typ,
-                item.name,
-            ) {
+        for (offset, item) in dir.items {
+            if self.add(item.ino, offset, item.typ, item.name) {
                 break;
             }
         }
@@ -316,10 +314,10 @@ impl FsReply<Dir> for ReplyDirectory {
 
 impl FsReply<DirPlus> for ReplyDirectoryPlus {
     fn reply_ok(mut self, dir: DirPlus) {
-        for (index, (item, entry)) in dir.items.into_iter().enumerate() {
+        for (offset, item, entry) in dir.items {
             if self.add(
                 item.ino,
-                (dir.offset + index) as i64,
+                offset,
                 item.name,
                 &entry.time,
                 &entry.stat,
@@ -402,6 +400,15 @@ impl FsReply<Bmap> for ReplyBmap {
     }
 }
 
+impl FsReply<Ioctl> for ReplyIoctl {
+    fn reply_ok(self, item: Ioctl) {
+        self.ioctl(item.result, &item.data)
+    }
+    fn reply_err(self, err: libc::c_int) {
+        self.error(err);
+    }
+}
+
 impl FsReply<Lseek> for ReplyLseek {
     fn reply_ok(self, item: Lseek) {
         self.offset(item.offset)
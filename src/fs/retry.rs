@@ -0,0 +1,167 @@
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::pin::Pin;
+use std::time::Duration;
+
+use tikv_client::TransactionClient;
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+use super::config::FsConfig;
+use super::error::{FsError, Result};
+use super::metrics::Metrics;
+use super::transaction::Txn;
+
+pub type BoxedFuture<'a, T> = Pin<Box<dyn 'a + Send + Future<Output = Result<T>>>>;
+
+/// Attempts `TiFs` gives a transaction before failing the request with `EAGAIN`.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 16;
+
+/// Whether a failed transaction may succeed if run again.
+///
+/// Write conflicts and locked keys both reach us as `KeyError`; every other error is a
+/// verdict on the request itself and is returned as is.
+pub fn is_retryable(err: &FsError) -> bool {
+    matches!(err, FsError::KeyError(_))
+}
+
+/// Truncated exponential backoff with jitter.
+///
+/// The n-th delay is drawn from `[ceiling / 2, ceiling]`, where the ceiling doubles from
+/// `BASE` and is truncated to `CAP`.
+#[derive(Debug)]
+pub struct Backoff {
+    attempts: u32,
+    random: RandomState,
+}
+
+impl Backoff {
+    pub const BASE: Duration = Duration::from_millis(1);
+    pub const CAP: Duration = Duration::from_secs(1);
+
+    pub fn new() -> Self {
+        Self {
+            attempts: 0,
+            random: RandomState::new(),
+        }
+    }
+
+    fn ceiling(&self) -> Duration {
+        Self::BASE
+            .checked_mul(1u32.checked_shl(self.attempts).unwrap_or(u32::MAX))
+            .map_or(Self::CAP, |delay| delay.min(Self::CAP))
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self.ceiling();
+        self.attempts += 1;
+
+        let mut hasher = self.random.build_hasher();
+        hasher.write_u32(self.attempts);
+        let half = ceiling / 2;
+        let jitter = hasher.finish() % (half.as_nanos() as u64 + 1);
+        half + Duration::from_nanos(jitter)
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run `f` in a fresh transaction and commit it, retrying with backoff while the
+/// transaction fails for a retryable reason; every attempt and conflict is counted in
+/// `metrics`.
+///
+/// Gives up with `RetryTimesExcess` after `max_attempts` attempts.
+///
+/// A failed commit can't be retried on its own: the conflicting writes mean what `f` read
+/// is stale, so every attempt runs `f` from scratch in a new transaction. This is also why
+/// `f` must not have effects outside of the transaction, such as replying to FUSE or
+/// bumping counters, as they would happen once per attempt.
+pub async fn with_retry<F, T>(
+    client: &TransactionClient,
+    config: &FsConfig,
+    metrics: &Metrics,
+    max_attempts: u32,
+    mut f: F,
+) -> Result<T>
+where
+    T: 'static + Send,
+    F: for<'a> FnMut(&'a mut Txn) -> BoxedFuture<'a, T>,
+{
+    let mut backoff = Backoff::new();
+    for attempt in 1..=max_attempts {
+        let mut txn = Txn::begin(client, config).await?;
+        metrics.transaction();
+        match run_once(&mut txn, &mut f).await {
+            Err(err) if is_retryable(&err) => {
+                metrics.conflict();
+                let delay = backoff.next_delay();
+                info!(
+                    "retry transaction in {:?} after attempt {}: {}",
+                    delay, attempt, err
+                );
+                if attempt < max_attempts {
+                    sleep(delay).await;
+                }
+            }
+            result => return result,
+        }
+    }
+    warn!("give up the transaction after {} attempts", max_attempts);
+    Err(FsError::RetryTimesExcess(max_attempts as u64))
+}
+
+async fn run_once<F, T>(txn: &mut Txn, f: &mut F) -> Result<T>
+where
+    T: 'static + Send,
+    F: for<'a> FnMut(&'a mut Txn) -> BoxedFuture<'a, T>,
+{
+    match f(txn).await {
+        Ok(v) => {
+            txn.commit().await?;
+            debug!(
+                tifs_txn_duration_seconds = txn.duration_so_far().as_secs_f64(),
+                duration = ?txn.duration_so_far(),
+                ops = txn.op_count(),
+                "transaction committed"
+            );
+            Ok(v)
+        }
+        Err(e) => {
+            txn.rollback().await?;
+            debug!("transaction rollbacked");
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_grow_and_are_truncated() {
+        let mut backoff = Backoff::new();
+        let mut ceiling = Backoff::BASE;
+        for _ in 0..64 {
+            let delay = backoff.next_delay();
+            assert!(delay >= ceiling / 2, "{:?} < {:?} / 2", delay, ceiling);
+            assert!(delay <= ceiling, "{:?} > {:?}", delay, ceiling);
+            ceiling = (ceiling * 2).min(Backoff::CAP);
+        }
+    }
+
+    #[test]
+    fn only_key_errors_are_retried() {
+        assert!(is_retryable(&FsError::from(
+            tikv_client::Error::MultipleKeyErrors(Vec::new())
+        )));
+        assert!(!is_retryable(&FsError::InodeNotFound { inode: 2 }));
+        assert!(!is_retryable(&FsError::FileExist { file: "a".into() }));
+        assert!(!is_retryable(&FsError::RetryTimesExcess(1)));
+    }
+}
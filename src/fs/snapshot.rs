@@ -0,0 +1,99 @@
+use std::fmt;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use super::error::{FsError, Result};
+use super::serialize::{deserialize, serialize, ENCODING};
+
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy, Deserialize, Serialize)]
+pub struct SnapshotId(pub u64);
+
+impl fmt::Display for SnapshotId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A named point in the history of the filesystem, readable through `TiFs::mount_snapshot`.
+///
+/// `version` is a TiKV timestamp; the view stays readable only while TiKV keeps the MVCC
+/// versions it needs, so `tikv_gc_life_time` must cover the age of the snapshots in use.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct SnapshotInfo {
+    pub id: SnapshotId,
+    pub name: String,
+    pub version: u64,
+    pub created: SystemTime,
+}
+
+/// Every snapshot taken, in order of creation.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct SnapshotCatalog {
+    pub snapshots: Vec<SnapshotInfo>,
+}
+
+impl SnapshotCatalog {
+    /// Record a snapshot at `version` under a name no other snapshot has.
+    pub fn add(&mut self, name: &str, version: u64, created: SystemTime) -> Result<SnapshotId> {
+        if self.snapshots.iter().any(|snapshot| snapshot.name == name) {
+            return Err(FsError::SnapshotExist {
+                name: name.to_owned(),
+            });
+        }
+        let id = SnapshotId(self.snapshots.last().map_or(1, |last| last.id.0 + 1));
+        self.snapshots.push(SnapshotInfo {
+            id,
+            name: name.to_owned(),
+            version,
+            created,
+        });
+        Ok(id)
+    }
+
+    pub fn find(&self, id: SnapshotId) -> Result<&SnapshotInfo> {
+        self.snapshots
+            .iter()
+            .find(|snapshot| snapshot.id == id)
+            .ok_or(FsError::SnapshotNotFound { id })
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        serialize(self).map_err(|err| FsError::Serialize {
+            target: "snapshot catalog",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        deserialize(bytes).map_err(|err| FsError::Serialize {
+            target: "snapshot catalog",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_are_numbered_in_order() {
+        let now = SystemTime::UNIX_EPOCH;
+        let mut catalog = SnapshotCatalog::default();
+        assert_eq!(catalog.add("daily", 100, now).unwrap(), SnapshotId(1));
+        assert_eq!(catalog.add("weekly", 200, now).unwrap(), SnapshotId(2));
+        assert!(matches!(
+            catalog.add("daily", 300, now),
+            Err(FsError::SnapshotExist { .. })
+        ));
+
+        assert_eq!(catalog.find(SnapshotId(2)).unwrap().version, 200);
+        assert!(catalog.find(SnapshotId(3)).is_err());
+
+        let decoded = SnapshotCatalog::deserialize(&catalog.serialize().unwrap()).unwrap();
+        assert_eq!(decoded, catalog);
+    }
+}
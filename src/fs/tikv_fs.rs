@@ -1,8 +1,6 @@
 use std::fmt::{self, Debug};
-use std::future::Future;
-use std::matches;
-use std::pin::Pin;
-use std::time::{Duration, SystemTime};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::anyhow;
 use async_trait::async_trait;
@@ -10,41 +8,73 @@ use bytes::Bytes;
 use bytestring::ByteString;
 use fuser::consts::FOPEN_DIRECT_IO;
 use fuser::*;
-use libc::{F_RDLCK, F_UNLCK, F_WRLCK, SEEK_CUR, SEEK_END, SEEK_SET};
-use parse_size::parse_size;
-use tikv_client::{Config, TransactionClient};
-use tokio::time::sleep;
-use tracing::{debug, error, info, instrument, trace, warn};
+use libc::{F_UNLCK, SEEK_CUR, SEEK_DATA, SEEK_END, SEEK_HOLE, SEEK_SET};
+use tikv_client::Config;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, instrument, warn};
 
 use super::async_fs::AsyncFileSystem;
-use super::dir::Directory;
+use super::builder::TiFsBuilder;
+use super::client::ClientPool;
+use super::config::FsConfig;
+use super::crypto::EncryptionConfig;
+use super::dir::{self, check_file_name, ReaddirOrder};
 use super::error::{FsError, Result};
+use super::file_handler::FileHandler;
+use super::gc::collect_garbage;
+use super::inode::{FileLock, SetAttrRequest};
+use super::inode_cache::InodeCache;
+use super::ioctl::{block_map_start, encode_block_map, TIFS_IOC_BLOCK_MAP};
 use super::key::ROOT_INODE;
+use super::meta::format_uuid;
+use super::metrics::{Metrics, MetricsHandle};
 use super::mode::make_mode;
 use super::reply::{
-    get_time, Attr, Create, Data, Dir, Entry, Lock, Lseek, Open, StatFs, Write, Xattr,
+    get_time, Attr, Create, Data, Dir, DirItem, DirPlus, Entry, Ioctl, Lock, Lseek, Open, StatFs,
+    Write, Xattr,
 };
+use super::retry::{with_retry, BoxedFuture, DEFAULT_MAX_ATTEMPTS};
+use super::snapshot::SnapshotId;
 use super::transaction::Txn;
+use super::xattr::encode_names;
 use crate::MountOption;
 
 pub const DIR_SELF: ByteString = ByteString::from_static(".");
 pub const DIR_PARENT: ByteString = ByteString::from_static("..");
 
+/// Xattrs under this prefix are maintained by tifs and read-only to users.
+pub const TIFS_XATTR_PREFIX: &str = "tifs.";
+pub const CREATOR_PID_XATTR: &str = "tifs.creator_pid";
+pub const CREATOR_UID_XATTR: &str = "tifs.creator_uid";
+pub const CREATOR_GID_XATTR: &str = "tifs.creator_gid";
+/// Read-only xattr of the root holding `Meta::label`, if the filesystem has one.
+pub const LABEL_XATTR: &str = "system.tifs_label";
+/// Read-only xattr of the root holding `Meta::uuid` in text form.
+pub const UUID_XATTR: &str = "system.tifs_uuid";
+
 pub struct TiFs {
     pub pd_endpoints: Vec<String>,
     pub config: Config,
-    pub client: TransactionClient,
-    pub direct_io: bool,
-    pub block_size: u64,
-    pub max_size: Option<u64>,
+    pub client: ClientPool,
+    pub fs_config: FsConfig,
+    pub inode_cache: Option<Arc<InodeCache>>,
+    pub metrics: Metrics,
 }
 
-type BoxedFuture<'a, T> = Pin<Box<dyn 'a + Send + Future<Output = Result<T>>>>;
-
 impl TiFs {
     pub const SCAN_LIMIT: u32 = 1 << 10;
     pub const DEFAULT_BLOCK_SIZE: u64 = 1 << 16;
-    pub const MAX_NAME_LEN: u32 = 1 << 8;
+    pub const MAX_NAME_LEN: u32 = dir::MAX_NAME_LEN as u32;
+    /// How often a blocked `setlkw` checks whether the lock has been released.
+    pub const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+    /// Connect to `pd_endpoints` with the default client settings and mount options.
+    pub async fn new(pd_endpoints: Vec<String>) -> anyhow::Result<Self> {
+        TiFsBuilder::new()
+            .with_pd_endpoints(pd_endpoints)
+            .build()
+            .await
+    }
 
     #[instrument]
     pub async fn construct<S>(
@@ -55,183 +85,195 @@ impl TiFs {
     where
         S: Clone + Debug + Into<String>,
     {
-        let client = TransactionClient::new_with_config(pd_endpoints.clone(), cfg.clone(), None)
+        TiFsBuilder::new()
+            .with_pd_endpoints(pd_endpoints.into_iter().map(Into::into).collect())
+            .with_client_config(cfg)
+            .with_options(options)
+            .build()
             .await
-            .map_err(|err| anyhow!("{}", err))?;
+    }
+
+    /// Set up the filesystem over the connected `client`; see `TiFsBuilder::build`.
+    pub(crate) async fn from_pool(
+        client: ClientPool,
+        pd_endpoints: Vec<String>,
+        cfg: Config,
+        options: Vec<MountOption>,
+    ) -> anyhow::Result<Self> {
         info!("connected to pd endpoints: {:?}", pd_endpoints);
-        Ok(TiFs {
+
+        let mut fs_config = FsConfig::from_options(&options);
+        if let Some(key_path) = options.iter().find_map(|opt| match opt {
+            MountOption::EncryptionKey(path) => Some(path),
+            _ => None,
+        }) {
+            fs_config.encryption = EncryptionConfig::load(key_path)?;
+        }
+
+        let inode_cache = match fs_config.inode_cache_size {
+            0 => None,
+            size => Some(Arc::new(InodeCache::new(
+                size as usize,
+                fs_config.inode_cache_ttl,
+            ))),
+        };
+
+        let fs = TiFs {
             client,
-            pd_endpoints: pd_endpoints.clone().into_iter().map(Into::into).collect(),
+            pd_endpoints,
             config: cfg,
-            direct_io: options
-                .iter()
-                .any(|option| matches!(option, MountOption::DirectIO)),
-            block_size: options
-                .iter()
-                .find_map(|option| match option {
-                    MountOption::BlkSize(size) => parse_size(size)
-                        .map_err(|err| {
-                            error!("fail to parse blksize({}): {}", size, err);
-                            err
-                        })
-                        .map(|size| {
-                            debug!("block size: {}", size);
-                            size
-                        })
-                        .ok(),
-                    _ => None,
-                })
-                .unwrap_or(Self::DEFAULT_BLOCK_SIZE),
-            max_size: options.iter().find_map(|option| match option {
-                MountOption::MaxSize(size) => parse_size(size)
-                    .map_err(|err| {
-                        error!("fail to parse maxsize({}): {}", size, err);
-                        err
-                    })
-                    .map(|size| {
-                        debug!("max size: {}", size);
-                        size
-                    })
-                    .ok(),
-                _ => None,
-            }),
+            fs_config,
+            inode_cache,
+            metrics: Metrics::default(),
+        };
+        match options.iter().find_map(|opt| match opt {
+            MountOption::Snapshot(id) => Some(SnapshotId(*id)),
+            _ => None,
+        }) {
+            Some(id) => Ok(fs.mount_snapshot(id).await?),
+            None => Ok(fs),
+        }
+    }
+
+    /// A read-only view of the filesystem as it was when snapshot `id` was created.
+    pub async fn mount_snapshot(&self, id: SnapshotId) -> Result<TiFs> {
+        let snapshot = self
+            .spin(move |txn| Box::pin(txn.find_snapshot(id)))
+            .await?;
+        info!("mount snapshot({}) {:?}", id, snapshot.name);
+        let fs_config = FsConfig {
+            snapshot: Some(snapshot),
+            ..self.fs_config.clone()
+        };
+        let inode_cache = match fs_config.inode_cache_size {
+            0 => None,
+            size => Some(Arc::new(InodeCache::new(
+                size as usize,
+                fs_config.inode_cache_ttl,
+            ))),
+        };
+        Ok(TiFs {
+            client: self.client.clone(),
+            pd_endpoints: self.pd_endpoints.clone(),
+            config: self.config.clone(),
+            fs_config,
+            inode_cache,
+            metrics: self.metrics.clone(),
         })
     }
 
-    #[instrument(skip(txn, f))]
-    async fn process_txn<F, T>(&self, txn: &mut Txn, f: F) -> Result<T>
-    where
-        T: 'static + Send,
-        F: for<'a> FnOnce(&'a TiFs, &'a mut Txn) -> BoxedFuture<'a, T>,
-    {
-        match f(self, txn).await {
-            Ok(v) => {
-                let commit_start = SystemTime::now();
-                txn.commit().await?;
-                debug!(
-                    "transaction committed in {} ms",
-                    commit_start.elapsed().unwrap().as_millis()
-                );
-                Ok(v)
+    /// The UUID the filesystem was created with, all zeros if it has none yet; `init`
+    /// gives one to older filesystems mounted writable.
+    pub async fn uuid(&self) -> Result<[u8; 16]> {
+        let uuid = self.spin(move |txn| Box::pin(txn.uuid())).await?;
+        Ok(uuid.unwrap_or_default())
+    }
+
+    /// Run `gc::collect_garbage` every `interval` until the handle is aborted; a snapshot
+    /// or `ro` mount, being read-only, has nothing to collect.
+    pub fn start_gc(&self, interval: Duration) -> JoinHandle<()> {
+        let client = self.client.clone();
+        let fs_config = self.fs_config.clone();
+        let metrics = self.metrics.clone();
+        tokio::spawn(async move {
+            if fs_config.is_read_only() {
+                return;
             }
-            Err(e) => {
-                txn.rollback().await?;
-                debug!("transaction rollbacked");
-                Err(e)
+            loop {
+                tokio::time::sleep(interval).await;
+                if let Err(err) = collect_garbage(&client, &fs_config, &metrics).await {
+                    warn!("gc: {}", err);
+                }
             }
-        }
+        })
     }
 
-    async fn with_optimistic<F, T>(&self, f: F) -> Result<T>
-    where
-        T: 'static + Send,
-        F: for<'a> FnOnce(&'a TiFs, &'a mut Txn) -> BoxedFuture<'a, T>,
-    {
-        let mut txn = Txn::begin_optimistic(
-            &self.client,
-            self.block_size,
-            self.max_size,
-            Self::MAX_NAME_LEN,
-        )
-        .await?;
-        self.process_txn(&mut txn, f).await
+    /// The `FOPEN_*` flags of a file opened with `flags`.
+    fn open_flags(&self, flags: i32) -> u32 {
+        let mut open_flags = 0;
+        #[cfg(target_os = "linux")]
+        if self.fs_config.direct_io || flags & libc::O_DIRECT != 0 {
+            open_flags |= FOPEN_DIRECT_IO;
+        }
+        #[cfg(not(target_os = "linux"))]
+        if self.fs_config.direct_io {
+            open_flags |= FOPEN_DIRECT_IO;
+        }
+        open_flags
     }
 
-    async fn spin<F, T>(&self, delay: Option<Duration>, mut f: F) -> Result<T>
-    where
-        T: 'static + Send,
-        F: for<'a> FnMut(&'a TiFs, &'a mut Txn) -> BoxedFuture<'a, T>,
-    {
-        loop {
-            match self.with_optimistic(&mut f).await {
-                Ok(v) => break Ok(v),
-                Err(FsError::KeyError(err)) => {
-                    trace!("spin because of a key error({})", err);
-                    if let Some(time) = delay {
-                        sleep(time).await;
-                    }
-                }
-                Err(err) => break Err(err),
-            }
+    /// Report metrics to `handle` instead of the global `metrics` recorder.
+    pub fn with_metrics(self, handle: MetricsHandle) -> Self {
+        Self {
+            metrics: Metrics::new(Some(handle)),
+            ..self
         }
     }
 
-    async fn spin_no_delay<F, T>(&self, f: F) -> Result<T>
+    /// Run `f` in a transaction, retried by `with_retry` while it conflicts with others.
+    async fn spin<F, T>(&self, mut f: F) -> Result<T>
     where
         T: 'static + Send,
-        F: for<'a> FnMut(&'a TiFs, &'a mut Txn) -> BoxedFuture<'a, T>,
+        F: for<'a> FnMut(&'a mut Txn) -> BoxedFuture<'a, T>,
     {
-        self.spin(None, f).await
+        let inode_cache = self.inode_cache.clone();
+        with_retry(
+            self.client.get(),
+            &self.fs_config,
+            &self.metrics,
+            DEFAULT_MAX_ATTEMPTS,
+            move |txn| {
+                txn.set_inode_cache(inode_cache.clone());
+                f(txn)
+            },
+        )
+        .await
     }
 
-    async fn read_dir(&self, ino: u64) -> Result<Directory> {
-        self.spin_no_delay(move |_, txn| Box::pin(txn.read_dir(ino)))
-            .await
+    async fn read_dir(&self, ino: u64) -> Result<Vec<DirItem>> {
+        let dir = self.spin(move |txn| Box::pin(txn.read_dir(ino))).await?;
+        Ok(self.fs_config.readdir_order.list(dir))
     }
 
     async fn read_inode(&self, ino: u64) -> Result<FileAttr> {
-        let ino = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.read_inode(ino)))
-            .await?;
-        Ok(ino.file_attr)
+        let cache = match &self.inode_cache {
+            Some(cache) => cache,
+            None => {
+                let inode = self.spin(move |txn| Box::pin(txn.read_inode(ino))).await?;
+                return Ok(inode.file_attr);
+            }
+        };
+        if let Some(inode) = cache.get(ino) {
+            return Ok(inode.file_attr);
+        }
+        let ticket = cache.ticket();
+        let inode = self.spin(move |txn| Box::pin(txn.read_inode(ino))).await?;
+        let attr = inode.file_attr;
+        cache.fill(ticket, inode);
+        Ok(attr)
     }
 
-    async fn setlkw(
-        &self,
-        ino: u64,
-        lock_owner: u64,
-        #[cfg(target_os = "linux")] typ: i32,
-        #[cfg(any(target_os = "freebsd", target_os = "macos"))] typ: i16,
-    ) -> Result<()> {
-        while !self
-            .spin_no_delay(move |_, txn| {
-                Box::pin(async move {
-                    let mut inode = txn.read_inode(ino).await?;
-                    match typ {
-                        F_WRLCK => {
-                            if inode.lock_state.owner_set.len() > 1 {
-                                Ok(false)
-                            } else if inode.lock_state.owner_set.is_empty() {
-                                inode.lock_state.lk_type = F_WRLCK;
-                                inode.lock_state.owner_set.insert(lock_owner);
-                                txn.save_inode(&inode).await?;
-                                Ok(true)
-                            } else if inode.lock_state.owner_set.get(&lock_owner)
-                                == Some(&lock_owner)
-                            {
-                                inode.lock_state.lk_type = F_WRLCK;
-                                txn.save_inode(&inode).await?;
-                                Ok(true)
-                            } else {
-                                Err(FsError::InvalidLock)
-                            }
-                        }
-                        F_RDLCK => {
-                            if inode.lock_state.lk_type == F_WRLCK {
-                                Ok(false)
-                            } else {
-                                inode.lock_state.lk_type = F_RDLCK;
-                                inode.lock_state.owner_set.insert(lock_owner);
-                                txn.save_inode(&inode).await?;
-                                Ok(true)
-                            }
-                        }
-                        _ => Err(FsError::InvalidLock),
-                    }
-                })
-            })
-            .await?
-        {}
-        Ok(())
+    fn is_read_only(&self) -> bool {
+        self.fs_config.is_read_only()
     }
 
-    fn check_file_name(name: &str) -> Result<()> {
-        if name.len() <= Self::MAX_NAME_LEN as usize {
-            Ok(())
+    /// Refuse to change a snapshot or a `ro` mount; their transactions would fail to write
+    /// anyway, this just fails before any transaction is opened.
+    fn check_writable(&self) -> Result<()> {
+        if self.is_read_only() {
+            Err(FsError::ReadOnly)
         } else {
-            Err(FsError::NameTooLong {
-                file: name.to_string(),
+            Ok(())
+        }
+    }
+
+    fn check_xattr_writable(name: &str) -> Result<()> {
+        if name.starts_with(TIFS_XATTR_PREFIX) || name == LABEL_XATTR || name == UUID_XATTR {
+            Err(FsError::ImmutableXattr {
+                name: name.to_string(),
             })
+        } else {
+            Ok(())
         }
     }
 }
@@ -244,6 +286,10 @@ impl Debug for TiFs {
 
 #[async_trait]
 impl AsyncFileSystem for TiFs {
+    fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
     #[tracing::instrument]
     async fn init(&self, gid: u32, uid: u32, config: &mut KernelConfig) -> Result<()> {
         // config
@@ -254,15 +300,48 @@ impl AsyncFileSystem for TiFs {
             .add_capabilities(fuser::consts::FUSE_FLOCK_LOCKS)
             .expect("kernel config failed to add cap_fuse FUSE_CAP_FLOCK_LOCKS");
 
-        self.spin_no_delay(move |fs, txn| {
+        // both readdir and readdirplus are implemented, so let the kernel pick per listing
+        #[cfg(not(target_os = "macos"))]
+        if let Err(unsupported) = config.add_capabilities(
+            fuser::consts::FUSE_DO_READDIRPLUS | fuser::consts::FUSE_READDIRPLUS_AUTO,
+        ) {
+            warn!("kernel doesn't support readdirplus: {:#x}", unsupported);
+        }
+
+        info!("initializing tifs on {:?} ...", &self.pd_endpoints);
+        self.spin(move |txn| {
             Box::pin(async move {
-                info!("initializing tifs on {:?} ...", &fs.pd_endpoints);
-                if let Some(meta) = txn.read_meta().await? {
+                if let Some(mut meta) = txn.read_meta().await? {
                     if meta.block_size != txn.block_size() {
                         let err = FsError::block_size_conflict(meta.block_size, txn.block_size());
                         error!("{}", err);
                         return Err(err);
                     }
+                    if meta.encrypted != txn.encrypted() {
+                        let err = FsError::EncryptionConflict {
+                            origin: meta.encrypted,
+                            new: txn.encrypted(),
+                        };
+                        error!("{}", err);
+                        return Err(err);
+                    }
+                    if meta.compression != txn.compression() {
+                        let err = FsError::CompressionConflict {
+                            origin: meta.compression,
+                            new: txn.compression(),
+                        };
+                        error!("{}", err);
+                        return Err(err);
+                    }
+                    // filesystems created before Meta recorded these get them once
+                    if !txn.is_read_only() && meta.fill_missing(txn.inline_data_threshold())? {
+                        info!(
+                            "record uuid {} and inline threshold {:?}",
+                            format_uuid(&meta.uuid),
+                            meta.inline_threshold
+                        );
+                        txn.save_meta(&meta).await?;
+                    }
                 }
 
                 let root_inode = txn.read_inode(ROOT_INODE).await;
@@ -274,6 +353,7 @@ impl AsyncFileSystem for TiFs {
                             make_mode(FileType::Directory, 0o777),
                             gid,
                             uid,
+                            None,
                         )
                         .await?;
                     debug!("make root directory {:?}", &attr);
@@ -288,12 +368,18 @@ impl AsyncFileSystem for TiFs {
 
     #[tracing::instrument]
     async fn lookup(&self, parent: u64, name: ByteString) -> Result<Entry> {
-        Self::check_file_name(&name)?;
-        self.spin_no_delay(move |_, txn| {
+        check_file_name(&name)?;
+        self.spin(move |txn| {
             let name = name.clone();
             Box::pin(async move {
-                let ino = txn.lookup(parent, name).await?;
-                Ok(Entry::new(txn.read_inode(ino).await?.into(), 0))
+                let ino = txn.lookup(parent, name.clone()).await?;
+                match txn.read_inode(ino).await {
+                    Err(FsError::InodeNotFound { .. }) => Err(FsError::CorruptedData {
+                        inode: parent,
+                        detail: format!("entry({}) points to missing inode({})", name, ino),
+                    }),
+                    result => Ok(result?.into()),
+                }
             })
         })
         .await
@@ -320,34 +406,28 @@ impl AsyncFileSystem for TiFs {
         _chgtime: Option<SystemTime>,
         _bkuptime: Option<SystemTime>,
         flags: Option<u32>,
+        caller_uid: u32,
     ) -> Result<Attr> {
-        self.spin_no_delay(move |_, txn| {
+        self.check_writable()?;
+        // TODO: how to deal with fh, chgtime, bkuptime?
+        let attrs = SetAttrRequest {
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+            ctime,
+            crtime,
+            flags,
+        };
+        self.spin(move |txn| {
+            let attrs = attrs.clone();
             Box::pin(async move {
-                // TODO: how to deal with fh, chgtime, bkuptime?
-                let mut attr = txn.read_inode(ino).await?;
-                attr.perm = match mode {
-                    Some(m) => m as _,
-                    None => attr.perm,
-                };
-                attr.uid = uid.unwrap_or(attr.uid);
-                attr.gid = gid.unwrap_or(attr.gid);
-                attr.set_size(size.unwrap_or(attr.size), txn.block_size());
-                attr.atime = match atime {
-                    None => attr.atime,
-                    Some(TimeOrNow::SpecificTime(t)) => t,
-                    Some(TimeOrNow::Now) => SystemTime::now(),
-                };
-                attr.mtime = match mtime {
-                    Some(TimeOrNow::SpecificTime(t)) => t,
-                    Some(TimeOrNow::Now) | None => SystemTime::now(),
-                };
-                attr.ctime = ctime.unwrap_or_else(SystemTime::now);
-                attr.crtime = crtime.unwrap_or(attr.crtime);
-                attr.flags = flags.unwrap_or(attr.flags);
-                txn.save_inode(&attr).await?;
+                let inode = txn.setattr(ino, caller_uid, attrs).await?;
                 Ok(Attr {
                     time: get_time(),
-                    attr: attr.into(),
+                    attr: inode.into(),
                 })
             })
         })
@@ -356,32 +436,56 @@ impl AsyncFileSystem for TiFs {
 
     #[tracing::instrument]
     async fn readdir(&self, ino: u64, _fh: u64, offset: i64) -> Result<Dir> {
-        let mut dir = Dir::offset(offset as usize);
-        let directory = self.read_dir(ino).await?;
-        for item in directory.into_iter().skip(offset as usize) {
-            dir.push(item)
+        let order = self.fs_config.readdir_order;
+        let directory = if order == ReaddirOrder::ByInsertion {
+            let offset = offset as u64;
+            let dir = self
+                .spin(move |txn| Box::pin(txn.read_dir_from(ino, offset)))
+                .await?;
+            order.list(dir)
+        } else {
+            self.read_dir(ino).await?
+        };
+        let mut dir = Dir::new();
+        for (next, item) in order.page(directory, offset as u64, |item| item) {
+            dir.push(next as i64, item)
         }
         debug!("read directory {:?}", &dir);
         Ok(dir)
     }
 
     #[tracing::instrument]
-    async fn open(&self, ino: u64, flags: i32) -> Result<Open> {
-        // TODO: deal with flags
-        let fh = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.open(ino)))
+    async fn readdirplus(&self, ino: u64, _fh: u64, offset: i64) -> Result<DirPlus> {
+        let entries = self
+            .spin(move |txn| Box::pin(txn.readdir_plus(ino)))
             .await?;
 
-        let mut open_flags = 0;
-        #[cfg(target_os = "linux")]
-        if self.direct_io || flags & libc::O_DIRECT != 0 {
-            open_flags |= FOPEN_DIRECT_IO;
+        let mut dir = DirPlus::new();
+        let order = self.fs_config.readdir_order;
+        for (next, (item, entry)) in order.page(entries, offset as u64, |(item, _)| item) {
+            dir.push(next as i64, item, entry);
         }
-        #[cfg(not(target_os = "linux"))]
-        if self.direct_io {
-            open_flags |= FOPEN_DIRECT_IO;
-        }
-        Ok(Open::new(fh, open_flags))
+        debug!("read directory plus {:?}", &dir);
+        Ok(dir)
+    }
+
+    #[tracing::instrument]
+    async fn open(&self, uid: u32, gid: u32, ino: u64, flags: i32) -> Result<Open> {
+        let fh = if self.is_read_only() {
+            if flags & libc::O_ACCMODE != libc::O_RDONLY {
+                return Err(FsError::ReadOnly);
+            }
+            self.spin(move |txn| {
+                Box::pin(async move { txn.check_open(ino, flags, uid, gid).await })
+            })
+            .await?;
+            0
+        } else {
+            self.spin(move |txn| Box::pin(txn.open(ino, flags, uid, gid)))
+                .await?
+                .fh
+        };
+        Ok(Open::new(fh, self.open_flags(flags)))
     }
 
     #[tracing::instrument]
@@ -394,9 +498,18 @@ impl AsyncFileSystem for TiFs {
         _flags: i32,
         _lock_owner: Option<u64>,
     ) -> Result<Data> {
-        let data = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.read(ino, fh, offset, size)))
-            .await?;
+        let started_at = Instant::now();
+        let data = if self.is_read_only() {
+            if offset < 0 {
+                return Err(FsError::InvalidOffset { ino, offset });
+            }
+            self.spin(move |txn| Box::pin(txn.read_data(ino, offset as u64, Some(size as u64))))
+                .await?
+        } else {
+            self.spin(move |txn| Box::pin(txn.read(ino, fh, offset, size)))
+                .await?
+        };
+        self.metrics.read(data.len() as u64, started_at.elapsed());
         Ok(Data::new(data))
     }
 
@@ -411,10 +524,13 @@ impl AsyncFileSystem for TiFs {
         _flags: i32,
         _lock_owner: Option<u64>,
     ) -> Result<Write> {
+        self.check_writable()?;
+        let started_at = Instant::now();
         let data: Bytes = data.into();
         let len = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.write(ino, fh, offset, data.clone())))
+            .spin(move |txn| Box::pin(txn.write(ino, fh, offset, data.clone())))
             .await?;
+        self.metrics.write(len as u64, started_at.elapsed());
         Ok(Write::new(len as u32))
     }
 
@@ -427,19 +543,22 @@ impl AsyncFileSystem for TiFs {
         mode: u32,
         gid: u32,
         uid: u32,
+        pid: u32,
         _umask: u32,
     ) -> Result<Entry> {
-        Self::check_file_name(&name)?;
+        self.check_writable()?;
+        check_file_name(&name)?;
         let attr = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.mkdir(parent, name.clone(), mode, gid, uid)))
+            .spin(move |txn| Box::pin(txn.mkdir(parent, name.clone(), mode, gid, uid, Some(pid))))
             .await?;
-        Ok(Entry::new(attr.into(), 0))
+        Ok(attr.into())
     }
 
     #[tracing::instrument]
     async fn rmdir(&self, parent: u64, raw_name: ByteString) -> Result<()> {
-        Self::check_file_name(&raw_name)?;
-        self.spin_no_delay(move |_, txn| Box::pin(txn.rmdir(parent, raw_name.clone())))
+        self.check_writable()?;
+        check_file_name(&raw_name)?;
+        self.spin(move |txn| Box::pin(txn.rmdir(parent, raw_name.clone())))
             .await
     }
 
@@ -451,53 +570,84 @@ impl AsyncFileSystem for TiFs {
         mode: u32,
         gid: u32,
         uid: u32,
+        pid: u32,
         _umask: u32,
         rdev: u32,
     ) -> Result<Entry> {
-        Self::check_file_name(&name)?;
+        self.check_writable()?;
+        check_file_name(&name)?;
         let attr = self
-            .spin_no_delay(move |_, txn| {
-                Box::pin(txn.make_inode(parent, name.clone(), mode, gid, uid, rdev))
+            .spin(move |txn| {
+                Box::pin(txn.mknod(parent, name.clone(), mode, gid, uid, rdev, Some(pid)))
             })
             .await?;
-        Ok(Entry::new(attr.into(), 0))
+        Ok(attr.into())
     }
 
     #[tracing::instrument]
-    async fn access(&self, _ino: u64, _mask: i32) -> Result<()> {
-        Ok(())
+    async fn access(&self, uid: u32, gid: u32, ino: u64, mask: i32) -> Result<()> {
+        if mask & libc::W_OK != 0 {
+            self.check_writable()?;
+        }
+        self.spin(move |txn| Box::pin(txn.access(ino, mask, uid, gid)))
+            .await
     }
 
     async fn create(
         &self,
         uid: u32,
         gid: u32,
+        pid: u32,
         parent: u64,
         name: ByteString,
         mode: u32,
-        umask: u32,
+        _umask: u32,
         flags: i32,
     ) -> Result<Create> {
-        Self::check_file_name(&name)?;
-        let entry = self.mknod(parent, name, mode, gid, uid, umask, 0).await?;
-        let open = self.open(entry.stat.ino, flags).await?;
+        self.check_writable()?;
+        check_file_name(&name)?;
+        let (inode, fh) = self
+            .spin(move |txn| {
+                let name = name.clone();
+                Box::pin(async move {
+                    let (mut inode, _) = txn
+                        .create(parent, name, mode, gid, uid, flags, Some(pid))
+                        .await?;
+                    let fh = txn.open_handle(&mut inode).await?;
+                    Ok((inode, fh))
+                })
+            })
+            .await?;
         Ok(Create::new(
-            entry.stat,
-            entry.generation,
-            open.fh,
-            open.flags,
+            inode.file_attr,
+            inode.generation,
+            fh,
+            self.open_flags(flags),
         ))
     }
 
     async fn lseek(&self, ino: u64, fh: u64, offset: i64, whence: i32) -> Result<Lseek> {
-        self.spin_no_delay(move |_, txn| {
+        self.spin(move |txn| {
             Box::pin(async move {
-                let mut file_handler = txn.read_fh(ino, fh).await?;
+                // handles of a snapshot aren't stored, their cursor stays at 0
+                let mut file_handler = if txn.is_read_only() {
+                    FileHandler::default()
+                } else {
+                    txn.read_fh(ino, fh).await?
+                };
                 let inode = txn.read_inode(ino).await?;
                 let target_cursor = match whence {
                     SEEK_SET => offset,
                     SEEK_CUR => file_handler.cursor as i64 + offset,
                     SEEK_END => inode.size as i64 + offset,
+                    SEEK_DATA | SEEK_HOLE if offset < 0 => {
+                        return Err(FsError::SeekBeyondEnd {
+                            ino,
+                            offset: offset as u64,
+                        })
+                    }
+                    SEEK_DATA => txn.seek_data(ino, offset as u64).await? as i64,
+                    SEEK_HOLE => txn.seek_hole(ino, offset as u64).await? as i64,
                     _ => return Err(FsError::UnknownWhence { whence }),
                 };
 
@@ -508,14 +658,37 @@ impl AsyncFileSystem for TiFs {
                     });
                 }
 
-                file_handler.cursor = target_cursor as u64;
-                txn.save_fh(ino, fh, &file_handler).await?;
+                if !txn.is_read_only() {
+                    file_handler.cursor = target_cursor as u64;
+                    txn.save_fh(ino, fh, &file_handler).await?;
+                }
                 Ok(Lseek::new(target_cursor))
             })
         })
         .await
     }
 
+    async fn ioctl(
+        &self,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        in_data: Vec<u8>,
+        _out_size: u32,
+    ) -> Result<Ioctl> {
+        match cmd {
+            TIFS_IOC_BLOCK_MAP => {
+                let start = block_map_start(&in_data)?;
+                let ranges = self
+                    .spin(move |txn| Box::pin(txn.get_block_map(ino)))
+                    .await?;
+                Ok(Ioctl::new(0, encode_block_map(&ranges, start)))
+            }
+            _ => Err(FsError::UnsupportedIoctl(cmd)),
+        }
+    }
+
     async fn release(
         &self,
         ino: u64,
@@ -524,21 +697,25 @@ impl AsyncFileSystem for TiFs {
         _lock_owner: Option<u64>,
         _flush: bool,
     ) -> Result<()> {
-        self.spin_no_delay(move |_, txn| Box::pin(txn.close(ino, fh)))
-            .await
+        if self.is_read_only() {
+            return Ok(());
+        }
+        self.spin(move |txn| Box::pin(txn.close(ino, fh))).await
     }
 
     /// Create a hard link.
     async fn link(&self, ino: u64, newparent: u64, newname: ByteString) -> Result<Entry> {
-        Self::check_file_name(&newname)?;
+        self.check_writable()?;
+        check_file_name(&newname)?;
         let inode = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.link(ino, newparent, newname.clone())))
+            .spin(move |txn| Box::pin(txn.link(ino, newparent, newname.clone())))
             .await?;
-        Ok(Entry::new(inode.into(), 0))
+        Ok(inode.into())
     }
 
     async fn unlink(&self, parent: u64, raw_name: ByteString) -> Result<()> {
-        self.spin_no_delay(move |_, txn| Box::pin(txn.unlink(parent, raw_name.clone())))
+        self.check_writable()?;
+        self.spin(move |txn| Box::pin(txn.unlink(parent, raw_name.clone())))
             .await
     }
 
@@ -548,24 +725,19 @@ impl AsyncFileSystem for TiFs {
         raw_name: ByteString,
         newparent: u64,
         new_raw_name: ByteString,
-        _flags: u32,
+        flags: u32,
     ) -> Result<()> {
-        Self::check_file_name(&raw_name)?;
-        Self::check_file_name(&new_raw_name)?;
-        self.spin_no_delay(move |_, txn| {
-            let name = raw_name.clone();
-            let new_name = new_raw_name.clone();
-            Box::pin(async move {
-                let ino = txn.lookup(parent, name.clone()).await?;
-                txn.link(ino, newparent, new_name).await?;
-                txn.unlink(parent, name).await?;
-                let inode = txn.read_inode(ino).await?;
-                if inode.file_attr.kind == FileType::Directory {
-                    txn.unlink(ino, DIR_PARENT).await?;
-                    txn.link(newparent, ino, DIR_PARENT).await?;
-                }
-                Ok(())
-            })
+        self.check_writable()?;
+        check_file_name(&raw_name)?;
+        check_file_name(&new_raw_name)?;
+        self.spin(move |txn| {
+            Box::pin(txn.rename2(
+                parent,
+                raw_name.clone(),
+                newparent,
+                new_raw_name.clone(),
+                flags,
+            ))
         })
         .await
     }
@@ -575,38 +747,24 @@ impl AsyncFileSystem for TiFs {
         &self,
         gid: u32,
         uid: u32,
+        pid: u32,
         parent: u64,
         name: ByteString,
         link: ByteString,
     ) -> Result<Entry> {
-        Self::check_file_name(&name)?;
-        self.spin_no_delay(move |_, txn| {
-            let name = name.clone();
-            let link = link.clone();
-            Box::pin(async move {
-                let mut attr = txn
-                    .make_inode(
-                        parent,
-                        name,
-                        make_mode(FileType::Symlink, 0o777),
-                        gid,
-                        uid,
-                        0,
-                    )
-                    .await?;
-
-                txn.write_link(&mut attr, link.into_bytes()).await?;
-                Ok(Entry::new(attr.into(), 0))
+        self.check_writable()?;
+        check_file_name(&name)?;
+        let attr = self
+            .spin(move |txn| {
+                Box::pin(txn.symlink(parent, name.clone(), link.clone(), gid, uid, Some(pid)))
             })
-        })
-        .await
+            .await?;
+        Ok(attr.into())
     }
 
     async fn readlink(&self, ino: u64) -> Result<Data> {
-        self.spin(None, move |_, txn| {
-            Box::pin(async move { Ok(Data::new(txn.read_link(ino).await?)) })
-        })
-        .await
+        self.spin(move |txn| Box::pin(async move { Ok(Data::new(txn.read_link(ino).await?)) }))
+            .await
     }
 
     #[tracing::instrument]
@@ -616,21 +774,72 @@ impl AsyncFileSystem for TiFs {
         _fh: u64,
         offset: i64,
         length: i64,
-        _mode: i32,
+        mode: i32,
     ) -> Result<()> {
-        self.spin_no_delay(move |_, txn| {
+        self.check_writable()?;
+        self.spin(move |txn| {
             Box::pin(async move {
                 let mut inode = txn.read_inode(ino).await?;
-                txn.fallocate(&mut inode, offset, length).await
+                txn.fallocate(&mut inode, offset, length, mode).await
             })
         })
         .await?;
         Ok(())
     }
 
+    #[tracing::instrument]
+    async fn copy_file_range(
+        &self,
+        ino_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        _fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        _flags: u32,
+    ) -> Result<Write> {
+        self.check_writable()?;
+        if offset_in < 0 {
+            return Err(FsError::InvalidOffset {
+                ino: ino_in,
+                offset: offset_in,
+            });
+        }
+        if offset_out < 0 {
+            return Err(FsError::InvalidOffset {
+                ino: ino_out,
+                offset: offset_out,
+            });
+        }
+        let len = len.min(u32::MAX as u64);
+        let copied = self
+            .spin(move |txn| {
+                Box::pin(txn.copy_file_range(
+                    ino_in,
+                    offset_in as u64,
+                    ino_out,
+                    offset_out as u64,
+                    len,
+                ))
+            })
+            .await?;
+        Ok(Write::new(copied as u32))
+    }
+
     // TODO: Find an api to calculate total and available space on tikv.
     async fn statfs(&self, _ino: u64) -> Result<StatFs> {
-        self.spin_no_delay(|_, txn| Box::pin(txn.statfs())).await
+        let stats = self.spin(|txn| Box::pin(txn.statfs())).await?;
+        Ok(StatFs::new(
+            stats.total_blocks,
+            stats.free_blocks,
+            stats.free_blocks,
+            stats.used_inodes,
+            stats.free_inodes,
+            self.fs_config.block_size as u32,
+            self.fs_config.max_name_len,
+            0,
+        ))
     }
 
     #[tracing::instrument]
@@ -639,85 +848,41 @@ impl AsyncFileSystem for TiFs {
         ino: u64,
         _fh: u64,
         lock_owner: u64,
-        _start: u64,
-        _end: u64,
+        start: u64,
+        end: u64,
         typ: i32,
         pid: u32,
         sleep: bool,
     ) -> Result<()> {
-        #[cfg(any(target_os = "freebsd", target_os = "macos"))]
-        let typ = typ as i16;
-        let not_again = self.spin_no_delay(move |_, txn| {
-            Box::pin(async move {
-                let mut inode = txn.read_inode(ino).await?;
-                warn!("setlk, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
-                if inode.file_attr.kind == FileType::Directory {
-                    return Err(FsError::InvalidLock);
-                }
-                match typ {
-                    F_RDLCK if inode.lock_state.lk_type == F_WRLCK => {
-                        if sleep {
-                            warn!("setlk F_RDLCK return sleep, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
-                            Ok(false)
-                        } else {
-                            Err(FsError::InvalidLock)
-                        }
-                    }
-                    F_RDLCK => {
-                        inode.lock_state.owner_set.insert(lock_owner);
-                        inode.lock_state.lk_type = F_RDLCK;
-                        txn.save_inode(&inode).await?;
-                        warn!("setlk F_RDLCK return, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
-                        Ok(true)
-                    }
-                    F_WRLCK => match inode.lock_state.lk_type {
-                        F_RDLCK if inode.lock_state.owner_set.len() == 1
-                        && inode.lock_state.owner_set.get(&lock_owner) == Some(&lock_owner)  => {
-                            inode.lock_state.lk_type = F_WRLCK;
-                            txn.save_inode(&inode).await?;
-                            warn!("setlk F_WRLCK on F_RDLCK return, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
-                            Ok(true)
-                        }
-                        F_RDLCK if sleep => {
-                            warn!("setlk F_WRLCK on F_RDLCK sleep return, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
-                            Ok(false)
-                        },
-                        F_RDLCK => Err(FsError::InvalidLock),
-                        F_UNLCK => {
-                            inode.lock_state.owner_set.clear();
-                            inode.lock_state.owner_set.insert(lock_owner);
-                            inode.lock_state.lk_type = F_WRLCK;
-                            warn!("setlk F_WRLCK on F_UNLCK return, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
-                            txn.save_inode(&inode).await?;
-                            Ok(true)
-                        },
-                        F_WRLCK if sleep => {
-                            warn!("setlk F_WRLCK on F_WRLCK return sleep, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
-                            Ok(false)
-                        }
-                        F_WRLCK => Err(FsError::InvalidLock),
-                        _ => Err(FsError::InvalidLock),
-                    },
-                    F_UNLCK => {
-                        inode.lock_state.owner_set.remove(&lock_owner);
-                        if inode.lock_state.owner_set.is_empty() {
-                            inode.lock_state.lk_type = F_UNLCK;
-                        }
-                        txn.save_inode(&inode).await?;
-                        warn!("setlk F_UNLCK return, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
-                        Ok(true)
+        self.check_writable()?;
+        let mut waiting = false;
+        let err = loop {
+            let result = self
+                .spin(move |txn| Box::pin(txn.set_lock(ino, lock_owner, typ, start, end, pid)))
+                .await;
+            match result {
+                Err(FsError::LockConflict { .. }) if sleep => {
+                    waiting = true;
+                    let result = self
+                        .spin(move |txn| {
+                            Box::pin(txn.wait_lock(ino, lock_owner, typ, start, end, pid))
+                        })
+                        .await;
+                    if let Err(err) = result {
+                        break err;
                     }
-                    _ => Err(FsError::InvalidLock),
+                    tokio::time::sleep(Self::LOCK_POLL_INTERVAL).await;
                 }
-            })
-        })
-        .await?;
-
-        if !not_again {
-            self.setlkw(ino, lock_owner, typ).await
-        } else {
-            Ok(())
+                Err(err) => break err,
+                // setting the lock removed the wait record
+                Ok(()) => return Ok(()),
+            }
+        };
+        if waiting {
+            self.spin(move |txn| Box::pin(txn.cancel_lock_wait(lock_owner)))
+                .await?;
         }
+        Err(err)
     }
 
     #[tracing::instrument]
@@ -725,46 +890,74 @@ impl AsyncFileSystem for TiFs {
         &self,
         ino: u64,
         _fh: u64,
-        _lock_owner: u64,
-        _start: u64,
-        _end: u64,
-        _typ: i32,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
         pid: u32,
     ) -> Result<Lock> {
-        // TODO: read only operation need not txn?
-        self.spin_no_delay(move |_, txn| {
-            Box::pin(async move {
-                let inode = txn.read_inode(ino).await?;
-                warn!("getlk, inode:{:?}, pid:{:?}", inode, pid);
-                Ok(Lock::_new(0, 0, inode.lock_state.lk_type as i32, 0))
-            })
+        let wanted = FileLock {
+            owner: lock_owner,
+            pid,
+            typ,
+            start,
+            end,
+        };
+        let state = self
+            .spin(move |txn| Box::pin(txn.get_lock(ino, lock_owner)))
+            .await?;
+        Ok(match state.conflicts(&wanted).next() {
+            Some(held) => Lock::_new(held.start, held.end, held.typ, held.pid),
+            None => Lock::_new(start, end, F_UNLCK as i32, 0),
         })
-        .await
     }
 
     /// Set an extended attribute.
     async fn setxattr(
         &self,
-        _ino: u64,
-        _name: ByteString,
-        _value: Vec<u8>,
-        _flags: i32,
+        uid: u32,
+        ino: u64,
+        name: ByteString,
+        value: Vec<u8>,
+        flags: i32,
         _position: u32,
     ) -> Result<()> {
-        // TODO: implement me
-        Ok(())
+        self.check_writable()?;
+        Self::check_xattr_writable(&name)?;
+        let value = Bytes::from(value);
+        self.spin(move |txn| {
+            let name = name.clone();
+            let value = value.clone();
+            Box::pin(async move {
+                txn.read_inode(ino).await?;
+                txn.check_and_set_xattr(uid, ino, name, value, flags).await
+            })
+        })
+        .await
     }
 
     /// Get an extended attribute.
     /// If `size` is 0, the size of the value should be sent with `reply.size()`.
     /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
     /// `reply.error(ERANGE)` if it doesn't.
-    async fn getxattr(&self, _ino: u64, _name: ByteString, size: u32) -> Result<Xattr> {
-        // TODO: implement me
+    async fn getxattr(&self, ino: u64, name: ByteString, size: u32) -> Result<Xattr> {
+        let key = name.clone();
+        let value = self
+            .spin(move |txn| Box::pin(txn.get_xattr(ino, key.clone())))
+            .await?
+            .ok_or_else(|| FsError::XattrNotFound {
+                ino,
+                name: name.to_string(),
+            })?;
         if size == 0 {
-            Ok(Xattr::size(0))
+            Ok(Xattr::size(value.len() as u32))
+        } else if value.len() <= size as usize {
+            Ok(Xattr::data(value.to_vec()))
         } else {
-            Ok(Xattr::data(Vec::new()))
+            Err(FsError::XattrRange {
+                len: value.len(),
+                size,
+            })
         }
     }
 
@@ -772,18 +965,42 @@ impl AsyncFileSystem for TiFs {
     /// If `size` is 0, the size of the value should be sent with `reply.size()`.
     /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
     /// `reply.error(ERANGE)` if it doesn't.
-    async fn listxattr(&self, _ino: u64, size: u32) -> Result<Xattr> {
-        // TODO: implement me
+    async fn listxattr(&self, ino: u64, size: u32) -> Result<Xattr> {
+        let names = self
+            .spin(move |txn| {
+                Box::pin(async move {
+                    let mut names = txn.list_xattrs(ino).await?;
+                    if ino == ROOT_INODE {
+                        if txn.label().await?.is_some() {
+                            names.push(LABEL_XATTR.into());
+                        }
+                        if txn.uuid().await?.is_some() {
+                            names.push(UUID_XATTR.into());
+                        }
+                        names.sort();
+                    }
+                    Ok(names)
+                })
+            })
+            .await?;
+        let list = encode_names(&names);
         if size == 0 {
-            Ok(Xattr::size(0))
+            Ok(Xattr::size(list.len() as u32))
+        } else if list.len() <= size as usize {
+            Ok(Xattr::data(list))
         } else {
-            Ok(Xattr::data(Vec::new()))
+            Err(FsError::XattrRange {
+                len: list.len(),
+                size,
+            })
         }
     }
 
     /// Remove an extended attribute.
-    async fn removexattr(&self, _ino: u64, _name: ByteString) -> Result<()> {
-        // TODO: implement me
-        Ok(())
+    async fn removexattr(&self, uid: u32, ino: u64, name: ByteString) -> Result<()> {
+        self.check_writable()?;
+        Self::check_xattr_writable(&name)?;
+        self.spin(move |txn| Box::pin(txn.remove_xattr(uid, ino, name.clone())))
+            .await
     }
 }
@@ -1,43 +1,223 @@
-use std::ops::{Deref, DerefMut};
-use std::time::SystemTime;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use bytes::Bytes;
 use bytestring::ByteString;
 use fuser::{FileAttr, FileType};
-use tikv_client::{Transaction, TransactionClient, TransactionOptions};
-use tracing::{debug, instrument, trace};
-
-use super::block::empty_block;
-use super::dir::Directory;
+use tikv_client::{
+    BoundRange, Key, KvPair, Snapshot, Timestamp, TimestampExt, Transaction, TransactionClient,
+    TransactionOptions, Value,
+};
+use tracing::{debug, field, instrument, trace, warn, Span};
+
+use super::acl::{is_acl_xattr, Acl, ACL_ACCESS_XATTR};
+use super::block::{
+    assemble_blocks, block_map, empty_block, is_zero, read_size, seek_data, seek_hole,
+    shrink_blocks, split_range, tail_block, zero_range, BlockRange, BlockValue, CompressionMode,
+    SharedBlock,
+};
+use super::config::FsConfig;
+use super::crypto::EncryptionLayer;
+use super::dir::{add_entry, check_file_name, retarget, Directory};
 use super::error::{FsError, Result};
-use super::file_handler::FileHandler;
+use super::file_handler::{FileHandler, OpenCaps, OpenInfo};
+use super::fsck::{FsImage, FsckError, FsckReport, LOST_AND_FOUND};
+use super::gc::{block_owners, orphaned_blocks, OrphanScan};
 use super::index::Index;
-use super::inode::Inode;
+use super::inode::{
+    check_link_target, FileLock, Inode, LockState, LockWait, SetAttrRequest, STAT_BLOCK_SIZE,
+};
+use super::inode_cache::InodeCache;
 use super::key::{ScopedKey, ROOT_INODE};
-use super::meta::Meta;
-use super::mode::{as_file_kind, as_file_perm, make_mode};
-use super::reply::{DirItem, StatFs};
-use super::tikv_fs::{DIR_PARENT, DIR_SELF};
+use super::meta::{
+    check_label, deserialize_usage, format_uuid, generate_uuid, serialize_usage, usage_shard,
+    FsStats, Meta,
+};
+use super::mode::{as_file_perm, make_mode, mknod_mode, try_file_kind};
+use super::quota::{Quota, QuotaInfo, QuotaOwner};
+use super::reply::{DirItem, Entry};
+use super::snapshot::{SnapshotCatalog, SnapshotId, SnapshotInfo};
+use super::tikv_fs::{
+    TiFs, CREATOR_GID_XATTR, CREATOR_PID_XATTR, CREATOR_UID_XATTR, DIR_PARENT, DIR_SELF,
+    LABEL_XATTR, UUID_XATTR,
+};
+use super::xattr::{check_name_and_size, check_set_flags};
+
+/// The `renameat2(2)` flags, with their Linux values, which FUSE passes through.
+pub const RENAME_NOREPLACE: u32 = 1 << 0;
+pub const RENAME_EXCHANGE: u32 = 1 << 1;
+
+/// Concurrency control of a `Txn`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TxnMode {
+    /// Conflicts are detected at commit; the loser is retried by `TiFs::spin`.
+    Optimistic,
+    /// Keys are locked before they are written, so concurrent writers wait instead.
+    Pessimistic,
+}
+
+/// What a `Txn` runs on.
+enum Backend {
+    Transaction(Transaction),
+    /// A read-only view at an earlier timestamp; any write fails with `ReadOnly`.
+    Snapshot(Snapshot),
+}
 
 pub struct Txn {
-    txn: Transaction,
-    block_size: u64,
-    max_blocks: Option<u64>,
-    max_name_len: u32,
+    /// Numbers the transactions of this process, to group their spans in traces.
+    id: u64,
+    txn: Backend,
+    client: TransactionClient,
+    mode: TxnMode,
+    config: FsConfig,
+    meta_cache: Option<Meta>,
+    started_at: Instant,
+    op_count: usize,
+    /// Blocks stored minus blocks freed so far by usage shard, added to the usage keys on
+    /// commit.
+    block_deltas: BTreeMap<u64, i64>,
+    /// Blocks stored minus blocks freed so far by inode, charged to quotas on commit.
+    file_block_deltas: BTreeMap<u64, i64>,
+    /// Kind and owners of the inodes saved so far, to charge their blocks to.
+    file_owners: HashMap<u64, (FileType, u32, u32)>,
+    inode_cache: Option<Arc<InodeCache>>,
+    /// Inodes saved or removed, invalidated in `inode_cache` again after commit.
+    written_inodes: BTreeSet<u64>,
+    /// Whether `commit` or `rollback` has been called; checked on drop.
+    finished: bool,
 }
 
 impl Txn {
-    const INLINE_DATA_THRESHOLD_BASE: u64 = 1 << 4;
-
-    fn inline_data_threshold(&self) -> u64 {
-        self.block_size / Self::INLINE_DATA_THRESHOLD_BASE
+    pub fn inline_data_threshold(&self) -> u64 {
+        self.config.inline_data_threshold()
     }
 
     pub fn block_size(&self) -> u64 {
-        self.block_size
+        self.config.block_size
+    }
+
+    pub fn compression(&self) -> CompressionMode {
+        self.config.compression
+    }
+
+    pub fn encrypted(&self) -> bool {
+        self.config.encryption.is_enabled()
+    }
+
+    fn encode_block(&self, block: Vec<u8>) -> Vec<u8> {
+        self.config.compression.encode(block)
+    }
+
+    fn decode_block(&self, ino: u64, block: u64, value: Vec<u8>) -> Result<Vec<u8>> {
+        self.config
+            .compression
+            .decode(value)
+            .map_err(|detail| FsError::CorruptedData {
+                inode: ino,
+                detail: format!("block({}): {}", block, detail),
+            })
+    }
+
+    /// The value to store for `block` of `inode`, tagged if its blocks may be shared.
+    fn encode_file_block(&self, inode: &Inode, block: Vec<u8>) -> Vec<u8> {
+        let value = self.encode_block(block);
+        if inode.shared_blocks {
+            BlockValue::Data(value).encode()
+        } else {
+            value
+        }
+    }
+
+    fn decode_block_value(&self, ino: u64, block: u64, value: Vec<u8>) -> Result<BlockValue> {
+        BlockValue::decode(value).map_err(|detail| FsError::CorruptedData {
+            inode: ino,
+            detail: format!("block({}): {}", block, detail),
+        })
+    }
+
+    /// The data of `block` of `inode` stored as `value`, along with the id of the
+    /// `SharedBlock` it was read from, if any.
+    async fn load_block(
+        &mut self,
+        inode: &Inode,
+        block: u64,
+        value: Vec<u8>,
+    ) -> Result<(Vec<u8>, Option<u64>)> {
+        if !inode.shared_blocks {
+            return Ok((self.decode_block(inode.ino, block, value)?, None));
+        }
+        match self.decode_block_value(inode.ino, block, value)? {
+            BlockValue::Data(value) => Ok((self.decode_block(inode.ino, block, value)?, None)),
+            BlockValue::Shared(id) => {
+                let shared = self.read_shared_block(inode.ino, id).await?;
+                Ok((self.decode_block(inode.ino, block, shared.data)?, Some(id)))
+            }
+        }
+    }
+
+    async fn read_shared_block(&mut self, ino: u64, id: u64) -> Result<SharedBlock> {
+        let value = self
+            .get(ScopedKey::shared_block(id))
+            .await?
+            .ok_or_else(|| FsError::CorruptedData {
+                inode: ino,
+                detail: format!("shared block({}) is missing", id),
+            })?;
+        SharedBlock::decode(value).map_err(|detail| FsError::CorruptedData {
+            inode: ino,
+            detail: format!("shared block({}): {}", id, detail),
+        })
+    }
+
+    /// Drop a reference of `ino` to shared block `id`, deleting the block with the last one.
+    async fn unref_shared_block(&mut self, ino: u64, id: u64) -> Result<()> {
+        let shared = self.read_shared_block(ino, id).await?;
+        let key = ScopedKey::shared_block(id);
+        match shared.release() {
+            Some(shared) => self.put(key, shared.encode()).await,
+            None => self.delete(key).await,
+        }
+    }
+
+    /// Drop the references the blocks of `inode` in `range` hold on shared blocks, before
+    /// the blocks are overwritten or deleted.
+    async fn release_shared_blocks(&mut self, inode: &Inode, range: Range<u64>) -> Result<()> {
+        if !inode.shared_blocks {
+            return Ok(());
+        }
+        for pair in self
+            .scan_all(ScopedKey::block_range(inode.ino, range))
+            .await?
+        {
+            let block = match ScopedKey::parse(pair.key().into())? {
+                ScopedKey::Block { block, .. } => block,
+                _ => unreachable!("the keys from scanning should be always valid block keys"),
+            };
+            if let BlockValue::Shared(id) =
+                self.decode_block_value(inode.ino, block, pair.into_value())?
+            {
+                self.unref_shared_block(inode.ino, id).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn max_blocks(&self) -> Option<u64> {
+        self.config.max_size.map(|size| size / self.block_size())
     }
 
-    fn check_space_left(&self, meta: &Meta) -> Result<()> {
+    async fn check_space_left(&mut self, meta: &Meta) -> Result<()> {
+        if let Some(max_blocks) = self.max_blocks() {
+            if let Some(used) = self.blocks_in_use(meta).await? {
+                if used >= max_blocks {
+                    return Err(FsError::NoSpaceLeft(max_blocks * self.block_size()));
+                }
+                return Ok(());
+            }
+        }
         match meta.last_stat {
             Some(ref stat) if stat.bavail == 0 => {
                 Err(FsError::NoSpaceLeft(stat.bsize as u64 * stat.blocks))
@@ -46,29 +226,349 @@ impl Txn {
         }
     }
 
-    pub async fn begin_optimistic(
-        client: &TransactionClient,
-        block_size: u64,
-        max_size: Option<u64>,
-        max_name_len: u32,
-    ) -> Result<Self> {
-        Ok(Txn {
-            txn: client
-                .begin_with_options(TransactionOptions::new_optimistic().use_async_commit())
-                .await?,
-            block_size,
-            max_blocks: max_size.map(|size| size / block_size),
-            max_name_len,
+    fn check_dir_entries(&self, ino: u64, dir: &Directory) -> Result<()> {
+        let limit = self.config.max_dir_entries;
+        if dir.len() as u64 >= limit {
+            Err(FsError::DirectoryFull { ino, limit })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Begin a transaction in the mode chosen by `config`, or a read of its snapshot, or
+    /// of the latest one on a read-only mount.
+    pub async fn begin(client: &TransactionClient, config: &FsConfig) -> Result<Self> {
+        if let Some(snapshot) = &config.snapshot {
+            Self::begin_snapshot(client, snapshot.version, config)
+                .adopt_meta()
+                .await
+        } else if config.read_only {
+            let version = client.current_timestamp().await?.version();
+            Self::begin_snapshot(client, version, config)
+                .adopt_meta()
+                .await
+        } else if config.pessimistic_txn {
+            Self::begin_pessimistic(client, config).await
+        } else {
+            Self::begin_optimistic(client, config).await
+        }
+    }
+
+    pub async fn begin_optimistic(client: &TransactionClient, config: &FsConfig) -> Result<Self> {
+        let txn = client
+            .begin_with_options(TransactionOptions::new_optimistic().use_async_commit())
+            .await?;
+        Self::new(
+            client,
+            Backend::Transaction(txn),
+            TxnMode::Optimistic,
+            config,
+        )
+        .adopt_meta()
+        .await
+    }
+
+    pub async fn begin_pessimistic(client: &TransactionClient, config: &FsConfig) -> Result<Self> {
+        let txn = client
+            .begin_with_options(TransactionOptions::new_pessimistic().use_async_commit())
+            .await?;
+        Self::new(
+            client,
+            Backend::Transaction(txn),
+            TxnMode::Pessimistic,
+            config,
+        )
+        .adopt_meta()
+        .await
+    }
+
+    /// Read the filesystem as it was at TiKV timestamp `version`.
+    pub fn begin_snapshot(client: &TransactionClient, version: u64, config: &FsConfig) -> Self {
+        let snapshot = client.snapshot(
+            Timestamp::from_version(version),
+            TransactionOptions::new_optimistic().read_only(),
+        );
+        Self::new(
+            client,
+            Backend::Snapshot(snapshot),
+            TxnMode::Optimistic,
+            config,
+        )
+    }
+
+    fn new(client: &TransactionClient, txn: Backend, mode: TxnMode, config: &FsConfig) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        Txn {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            txn,
+            client: client.clone(),
+            mode,
+            config: config.clone(),
+            meta_cache: None,
+            started_at: Instant::now(),
+            op_count: 0,
+            block_deltas: BTreeMap::new(),
+            file_block_deltas: BTreeMap::new(),
+            file_owners: HashMap::new(),
+            inode_cache: None,
+            written_inodes: BTreeSet::new(),
+            finished: false,
+        }
+    }
+
+    /// Take the layout stored in `Meta` over the one of the mount options, so inline data
+    /// is laid out the same whatever the filesystem is mounted with.
+    async fn adopt_meta(mut self) -> Result<Self> {
+        if let Some(meta) = self.read_meta().await? {
+            self.config.adopt_meta(&meta);
+        }
+        Ok(self)
+    }
+
+    /// Keep `cache` free of the inodes this transaction writes.
+    pub fn set_inode_cache(&mut self, cache: Option<Arc<InodeCache>>) {
+        self.inode_cache = cache;
+    }
+
+    fn invalidate_cached_inode(&mut self, ino: u64) {
+        if let Some(cache) = &self.inode_cache {
+            cache.invalidate(ino);
+            self.written_inodes.insert(ino);
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn mode(&self) -> TxnMode {
+        self.mode
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        matches!(self.txn, Backend::Snapshot(_))
+    }
+
+    fn transaction(&mut self) -> Result<&mut Transaction> {
+        match &mut self.txn {
+            Backend::Transaction(txn) => Ok(txn),
+            Backend::Snapshot(_) => Err(FsError::ReadOnly),
+        }
+    }
+
+    /// Commit, adding the blocks stored and freed by this transaction to their usage
+    /// shards and to the quotas of their owners first.
+    #[instrument(skip(self), fields(txn_id = self.id))]
+    pub async fn commit(&mut self) -> Result<()> {
+        // a quota it breaks fails the transaction as a whole
+        if let Err(err) = self.save_counters().await {
+            self.rollback().await?;
+            return Err(err);
+        }
+        // a failed commit leaves nothing to roll back either
+        self.finished = true;
+        if let Backend::Transaction(txn) = &mut self.txn {
+            txn.commit().await?;
+        }
+        if let Some(cache) = &self.inode_cache {
+            for ino in std::mem::take(&mut self.written_inodes) {
+                cache.invalidate(ino);
+            }
+        }
+        Ok(())
+    }
+
+    async fn save_counters(&mut self) -> Result<()> {
+        for (shard, delta) in std::mem::take(&mut self.block_deltas) {
+            if delta != 0 {
+                self.add_usage(shard, delta).await?;
+            }
+        }
+        self.account_quota().await
+    }
+
+    /// Lock `key` before writing it in a pessimistic transaction; optimistic transactions
+    /// leave conflict detection to the commit.
+    async fn lock_for_write(&mut self, key: impl Into<Key>) -> Result<()> {
+        if self.mode == TxnMode::Pessimistic {
+            self.op_count += 1;
+            self.transaction()?
+                .lock_keys(std::iter::once(key.into()))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Roll back what this transaction wrote; a snapshot has nothing to roll back.
+    #[instrument(skip(self), fields(txn_id = self.id))]
+    pub async fn rollback(&mut self) -> Result<()> {
+        self.finished = true;
+        if let Backend::Transaction(txn) = &mut self.txn {
+            txn.rollback().await?;
+        }
+        Ok(())
+    }
+
+    pub fn duration_so_far(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// Number of `get`, `put` and `delete` issued through this transaction.
+    pub fn op_count(&self) -> usize {
+        self.op_count
+    }
+
+    // values are sealed on the way to TiKV and opened on the way back, so everything
+    // above the accessors below sees plain values whether the filesystem is encrypted or not
+    fn seal_value(&self, key: &Key, value: Value) -> Result<Value> {
+        match self.config.encryption.layer() {
+            Some(layer) if EncryptionLayer::covers(key.into()) => layer.seal(key.into(), &value),
+            _ => Ok(value),
+        }
+    }
+
+    fn open_value(&self, key: &Key, value: Value) -> Result<Value> {
+        match self.config.encryption.layer() {
+            Some(layer) if EncryptionLayer::covers(key.into()) => layer.open(key.into(), &value),
+            _ => Ok(value),
+        }
+    }
+
+    fn open_pair(&self, pair: KvPair) -> Result<KvPair> {
+        let key = pair.key().clone();
+        let value = self.open_value(&key, pair.into_value())?;
+        Ok(KvPair::new(key, value))
+    }
+
+    pub async fn get(&mut self, key: impl Into<Key>) -> Result<Option<Value>> {
+        self.op_count += 1;
+        let key = key.into();
+        let value = match &mut self.txn {
+            Backend::Transaction(txn) => txn.get(key.clone()).await?,
+            Backend::Snapshot(snapshot) => snapshot.get(key.clone()).await?,
+        };
+        value.map(|value| self.open_value(&key, value)).transpose()
+    }
+
+    /// Read `key` and lock it against concurrent writers; a snapshot is never written, so
+    /// it reads the key as `get` does.
+    pub async fn get_for_update(&mut self, key: impl Into<Key>) -> Result<Option<Value>> {
+        let key = key.into();
+        let value = match &mut self.txn {
+            Backend::Transaction(txn) => txn.get_for_update(key.clone()).await?,
+            Backend::Snapshot(snapshot) => snapshot.get(key.clone()).await?,
+        };
+        value.map(|value| self.open_value(&key, value)).transpose()
+    }
+
+    pub async fn key_exists(&mut self, key: impl Into<Key>) -> Result<bool> {
+        Ok(match &mut self.txn {
+            Backend::Transaction(txn) => txn.key_exists(key).await?,
+            Backend::Snapshot(snapshot) => snapshot.key_exists(key).await?,
         })
     }
 
-    pub async fn open(&mut self, ino: u64) -> Result<u64> {
-        let mut inode = self.read_inode(ino).await?;
+    pub async fn batch_get(
+        &mut self,
+        keys: impl IntoIterator<Item = impl Into<Key>>,
+    ) -> Result<impl Iterator<Item = KvPair>> {
+        let pairs: Vec<KvPair> = match &mut self.txn {
+            Backend::Transaction(txn) => txn.batch_get(keys).await?.collect(),
+            Backend::Snapshot(snapshot) => snapshot.batch_get(keys).await?.collect(),
+        };
+        let pairs = pairs
+            .into_iter()
+            .map(|pair| self.open_pair(pair))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(pairs.into_iter())
+    }
+
+    pub async fn scan(
+        &mut self,
+        range: impl Into<BoundRange>,
+        limit: u32,
+    ) -> Result<impl Iterator<Item = KvPair>> {
+        let pairs: Vec<KvPair> = match &mut self.txn {
+            Backend::Transaction(txn) => txn.scan(range, limit).await?.collect(),
+            Backend::Snapshot(snapshot) => snapshot.scan(range, limit).await?.collect(),
+        };
+        let pairs = pairs
+            .into_iter()
+            .map(|pair| self.open_pair(pair))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(pairs.into_iter())
+    }
+
+    pub async fn scan_keys(
+        &mut self,
+        range: impl Into<BoundRange>,
+        limit: u32,
+    ) -> Result<impl Iterator<Item = Key>> {
+        let keys: Vec<Key> = match &mut self.txn {
+            Backend::Transaction(txn) => txn.scan_keys(range, limit).await?.collect(),
+            Backend::Snapshot(snapshot) => snapshot.scan_keys(range, limit).await?.collect(),
+        };
+        Ok(keys.into_iter())
+    }
+
+    pub async fn put(&mut self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
+        self.op_count += 1;
+        let key = key.into();
+        let value = self.seal_value(&key, value.into())?;
+        Ok(self.transaction()?.put(key, value).await?)
+    }
+
+    pub async fn delete(&mut self, key: impl Into<Key>) -> Result<()> {
+        self.op_count += 1;
+        Ok(self.transaction()?.delete(key).await?)
+    }
+
+    /// Open `ino` for `uid` in group `gid` after checking `flags` against the kind and
+    /// permissions of the inode, see `Inode::check_open`.
+    pub async fn open(&mut self, ino: u64, flags: i32, uid: u32, gid: u32) -> Result<OpenInfo> {
+        let (mut inode, caps) = self.check_open(ino, flags, uid, gid).await?;
+        let fh = self.open_handle(&mut inode).await?;
+        Ok(OpenInfo { fh, inode, caps })
+    }
+
+    /// Check `flags` of `open(2)` on `ino` for `uid` in group `gid` against the kind,
+    /// permissions and ACL of the inode, see `Inode::check_open`.
+    pub async fn check_open(
+        &mut self,
+        ino: u64,
+        flags: i32,
+        uid: u32,
+        gid: u32,
+    ) -> Result<(Inode, OpenCaps)> {
+        let inode = self.read_inode(ino).await?;
+        let acl = self.access_acl(ino).await?;
+        let caps = inode.check_open(flags, uid, gid, acl.as_ref())?;
+        Ok((inode, caps))
+    }
+
+    /// Check `mask` of `access(2)` on `ino` for `uid` in group `gid`, see
+    /// `Inode::check_access`.
+    pub async fn access(&mut self, ino: u64, mask: i32, uid: u32, gid: u32) -> Result<()> {
+        let inode = self.read_inode(ino).await?;
+        let acl = self.access_acl(ino).await?;
+        inode.check_access(mask, uid, gid, acl.as_ref())
+    }
+
+    async fn access_acl(&mut self, ino: u64) -> Result<Option<Acl>> {
+        match self.get_xattr(ino, ACL_ACCESS_XATTR.into()).await? {
+            Some(value) => Ok(Some(Acl::decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Allocate a file handle of `inode` without any check, as `create` does for the file
+    /// it made, which its creator may open whatever its mode.
+    pub async fn open_handle(&mut self, inode: &mut Inode) -> Result<u64> {
         let fh = inode.next_fh;
-        self.save_fh(ino, fh, &FileHandler::default()).await?;
+        self.save_fh(inode.ino, fh, &FileHandler::default()).await?;
         inode.next_fh += 1;
         inode.opened_fh += 1;
-        self.save_inode(&inode).await?;
+        self.save_inode(inode).await?;
         Ok(fh)
     }
 
@@ -77,7 +577,7 @@ impl Txn {
         self.delete(ScopedKey::handler(ino, fh)).await?;
 
         let mut inode = self.read_inode(ino).await?;
-        inode.opened_fh -= 1;
+        inode.release_handle();
         self.save_inode(&inode).await
     }
 
@@ -95,7 +595,7 @@ impl Txn {
             .await?)
     }
 
-    pub async fn read(&mut self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>> {
+    pub async fn read(&mut self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Bytes> {
         let handler = self.read_fh(ino, fh).await?;
         let start = handler.cursor as i64 + offset;
         if start < 0 {
@@ -114,6 +614,110 @@ impl Txn {
         self.write_data(ino, start as u64, data).await
     }
 
+    /// Create the regular file `parent/name` as `open(2)` with `O_CREAT` does, returning
+    /// it and whether it is new.
+    ///
+    /// An existing file is an error with `O_EXCL`; otherwise `flags` are checked against
+    /// it as `open` does, and it is returned as is, emptied first with `O_TRUNC`.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create(
+        &mut self,
+        parent: u64,
+        name: ByteString,
+        mode: u32,
+        gid: u32,
+        uid: u32,
+        flags: i32,
+        creator_pid: Option<u32>,
+    ) -> Result<(Inode, bool)> {
+        let mode = make_mode(FileType::RegularFile, as_file_perm(mode));
+        let (inode, created) = self
+            .get_or_make_inode(parent, name.clone(), mode, gid, uid, creator_pid)
+            .await?;
+        if created {
+            return Ok((inode, true));
+        }
+        if flags & libc::O_EXCL != 0 {
+            return Err(FsError::FileExist {
+                file: name.to_string(),
+            });
+        }
+        if inode.kind == FileType::Directory {
+            return Err(FsError::IsDirectory {
+                file: name.to_string(),
+            });
+        }
+        // an existing file is opened as `open` would, before `O_TRUNC` writes to it
+        let acl = self.access_acl(inode.ino).await?;
+        inode.check_open(flags, uid, gid, acl.as_ref())?;
+        if flags & libc::O_TRUNC != 0 {
+            self.truncate(inode.ino, 0).await?;
+            return Ok((self.read_inode(inode.ino).await?, false));
+        }
+        Ok((inode, false))
+    }
+
+    /// The inode at `parent/name`, or a new one made there with `mode` if there is none,
+    /// along with whether it is new. A directory `mode` makes a directory, `.` and `..`
+    /// included.
+    ///
+    /// Both the lookup and the creation are part of this transaction, so a concurrent
+    /// creation of the same name makes one of the two commits conflict.
+    pub async fn get_or_create_inode(
+        &mut self,
+        parent: u64,
+        name: ByteString,
+        mode: u32,
+        gid: u32,
+        uid: u32,
+    ) -> Result<(Inode, bool)> {
+        self.get_or_make_inode(parent, name, mode, gid, uid, None)
+            .await
+    }
+
+    async fn get_or_make_inode(
+        &mut self,
+        parent: u64,
+        name: ByteString,
+        mode: u32,
+        gid: u32,
+        uid: u32,
+        creator_pid: Option<u32>,
+    ) -> Result<(Inode, bool)> {
+        if let Some(ino) = self.get_index(parent, name.clone()).await? {
+            return Ok((self.read_inode(ino).await?, false));
+        }
+        let inode = match try_file_kind(mode).ok_or(FsError::UnknownFileType)? {
+            FileType::Directory => {
+                let perm = as_file_perm(mode) as u32;
+                self.mkdir(parent, name, perm, gid, uid, creator_pid)
+                    .await?
+            }
+            _ => {
+                self.make_inode(parent, name, mode, gid, uid, 0, creator_pid)
+                    .await?
+            }
+        };
+        Ok((inode, true))
+    }
+
+    /// Make a regular file without a name, as `open(2)` with `O_TMPFILE`; `link` names it.
+    ///
+    /// Unless named, it is deleted when its last handle is closed; `fsck` reclaims one
+    /// left without handles by a crash.
+    pub async fn make_tmp_inode(&mut self, mode: u32, gid: u32, uid: u32) -> Result<Inode> {
+        let mode = make_mode(FileType::RegularFile, as_file_perm(mode));
+        // parent 0 skips the directory entry
+        let mut inode = self
+            .make_inode(0, Default::default(), mode, gid, uid, 0, None)
+            .await?;
+        inode.tmpfile = true;
+        self.save_inode(&inode).await?;
+        Ok(inode)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(self), fields(txn_id = self.id))]
     pub async fn make_inode(
         &mut self,
         parent: u64,
@@ -122,19 +726,37 @@ impl Txn {
         gid: u32,
         uid: u32,
         rdev: u32,
+        creator_pid: Option<u32>,
     ) -> Result<Inode> {
-        let mut meta = self
-            .read_meta()
-            .await?
-            .unwrap_or_else(|| Meta::new(self.block_size));
-        self.check_space_left(&meta)?;
-        let ino = meta.inode_next;
-        meta.inode_next += 1;
+        check_file_name(&name)?;
+        let file_type = try_file_kind(mode).ok_or(FsError::UnknownFileType)?;
+        // parent 0 makes the root or a tmpfile; anything else below the root is not a directory
+        if (1..ROOT_INODE).contains(&parent) {
+            return Err(FsError::InodeNotFound { inode: parent });
+        }
+
+        let mut meta = match self.read_meta().await? {
+            Some(meta) => meta,
+            None => {
+                if let Some(label) = &self.config.label {
+                    check_label(label)?;
+                }
+                Meta {
+                    compression: self.compression(),
+                    encrypted: self.encrypted(),
+                    inline_threshold: Some(self.inline_data_threshold()),
+                    label: self.config.label.clone(),
+                    uuid: generate_uuid()?,
+                    ..Meta::new(self.block_size())
+                }
+            }
+        };
+        self.check_space_left(&meta).await?;
+        let (ino, generation) = meta.alloc_ino()?;
 
         debug!("get ino({})", ino);
         self.save_meta(&meta).await?;
 
-        let file_type = as_file_kind(mode);
         if parent >= ROOT_INODE {
             if self.get_index(parent, name.clone()).await?.is_some() {
                 return Err(FsError::FileExist {
@@ -143,20 +765,24 @@ impl Txn {
             }
             self.set_index(parent, name.clone(), ino).await?;
 
-            let mut dir = self.read_dir(parent).await?;
+            let parent_inode = self.read_inode(parent).await?;
+            let mut dir = match self.read_dir_for_update(&parent_inode).await {
+                Err(FsError::BlockNotFound { inode, block: 0 }) if inode == parent => {
+                    debug!("parent directory({}) has no directory record", parent);
+                    return Err(FsError::InodeNotFound { inode: parent });
+                }
+                result => result?,
+            };
             debug!("read dir({:?})", &dir);
+            self.check_dir_entries(parent, &dir)?;
 
-            dir.push(DirItem {
-                ino,
-                name: name.to_string(),
-                typ: file_type,
-            });
+            add_entry(&mut dir, ino, &name, file_type);
 
-            self.save_dir(parent, &dir).await?;
-            // TODO: update attributes of directory
+            // also bumps the mtime and ctime of the parent
+            self.save_dir(parent_inode, &dir).await?;
         }
 
-        let inode = FileAttr {
+        let mut inode: Inode = FileAttr {
             ino,
             size: 0,
             blocks: 0,
@@ -170,53 +796,292 @@ impl Txn {
             uid,
             gid,
             rdev,
-            blksize: self.block_size as u32,
+            blksize: self.block_size() as u32,
             flags: 0,
         }
         .into();
+        inode.generation = generation;
+        // the root is its own parent, a tmpfile has none until it is linked
+        inode.parent_ino = if ino == ROOT_INODE {
+            ROOT_INODE
+        } else {
+            parent
+        };
 
         debug!("made inode ({:?})", &inode);
 
         self.save_inode(&inode).await?;
+        if self.config.audit_xattrs {
+            self.save_creator(ino, creator_pid, uid, gid).await?;
+        }
         Ok(inode)
     }
 
-    pub async fn get_index(&mut self, parent: u64, name: ByteString) -> Result<Option<u64>> {
-        let key = ScopedKey::index(parent, &name);
-        self.get(key)
-            .await
-            .map_err(FsError::from)
-            .and_then(|value| {
-                value
-                    .map(|data| Ok(Index::deserialize(&data)?.ino))
-                    .transpose()
-            })
+    async fn save_creator(&mut self, ino: u64, pid: Option<u32>, uid: u32, gid: u32) -> Result<()> {
+        // tifs.* is outside of the user namespaces, so skip the checks of `set_xattr`
+        if let Some(pid) = pid {
+            self.put(ScopedKey::xattr(ino, CREATOR_PID_XATTR), pid.to_string())
+                .await?;
+        }
+        self.put(ScopedKey::xattr(ino, CREATOR_UID_XATTR), uid.to_string())
+            .await?;
+        self.put(ScopedKey::xattr(ino, CREATOR_GID_XATTR), gid.to_string())
+            .await?;
+        Ok(())
     }
 
-    pub async fn set_index(&mut self, parent: u64, name: ByteString, ino: u64) -> Result<()> {
-        let key = ScopedKey::index(parent, &name);
-        let value = Index::new(ino).serialize()?;
-        Ok(self.put(key, value).await?)
+    /// The label the filesystem was created with, if any.
+    pub async fn label(&mut self) -> Result<Option<String>> {
+        Ok(self.read_meta().await?.and_then(|meta| meta.label))
     }
 
-    pub async fn remove_index(&mut self, parent: u64, name: ByteString) -> Result<()> {
-        let key = ScopedKey::index(parent, &name);
-        Ok(self.delete(key).await?)
+    /// The UUID of the filesystem, if it has one yet.
+    pub async fn uuid(&mut self) -> Result<Option<[u8; 16]>> {
+        Ok(self
+            .read_meta()
+            .await?
+            .filter(Meta::has_uuid)
+            .map(|meta| meta.uuid))
     }
 
-    pub async fn read_inode(&mut self, ino: u64) -> Result<Inode> {
-        let value = self
-            .get(ScopedKey::inode(ino))
-            .await?
-            .ok_or(FsError::InodeNotFound { inode: ino })?;
-        Ok(Inode::deserialize(&value)?)
+    pub async fn get_xattr(&mut self, ino: u64, name: ByteString) -> Result<Option<Bytes>> {
+        if ino == ROOT_INODE && name == LABEL_XATTR {
+            return Ok(self.label().await?.map(Bytes::from));
+        }
+        if ino == ROOT_INODE && name == UUID_XATTR {
+            return Ok(self.uuid().await?.map(|uuid| format_uuid(&uuid).into()));
+        }
+        let value = self.get(ScopedKey::xattr(ino, &name)).await?;
+        Ok(value.map(Bytes::from))
     }
 
-    pub async fn save_inode(&mut self, inode: &Inode) -> Result<()> {
-        let key = ScopedKey::inode(inode.ino);
+    pub async fn set_xattr(&mut self, ino: u64, name: ByteString, value: Bytes) -> Result<()> {
+        check_name_and_size(&name, value.len())?;
+        Ok(self
+            .put(ScopedKey::xattr(ino, &name), value.to_vec())
+            .await?)
+    }
 
-        if inode.nlink == 0 && inode.opened_fh == 0 {
-            self.delete(key).await?;
+    /// Names of all xattrs of `ino`, in byte order.
+    pub async fn list_xattrs(&mut self, ino: u64) -> Result<Vec<ByteString>> {
+        let Range { mut start, end } = ScopedKey::xattr_range(ino);
+        let mut names = Vec::new();
+        loop {
+            let keys: Vec<Key> = self
+                .scan_keys(start..end.clone(), TiFs::SCAN_LIMIT)
+                .await?
+                .collect();
+            for key in &keys {
+                match ScopedKey::parse(key.into())? {
+                    ScopedKey::Xattr { ino: _, name } => names.push(ByteString::from(name)),
+                    _ => unreachable!("the keys from scanning should be always valid xattr keys"),
+                }
+            }
+
+            if (keys.len() as u32) < TiFs::SCAN_LIMIT {
+                break Ok(names);
+            }
+            let mut next: Vec<u8> = keys.into_iter().last().unwrap().into();
+            next.push(0);
+            start = next.into();
+        }
+    }
+
+    async fn clear_xattrs(&mut self, ino: u64) -> Result<()> {
+        for name in self.list_xattrs(ino).await? {
+            self.delete(ScopedKey::xattr(ino, &name)).await?;
+        }
+        Ok(())
+    }
+
+    /// Set an xattr honoring the `XATTR_CREATE`/`XATTR_REPLACE` flags of `setxattr`.
+    ///
+    /// The existence check and the write happen in this transaction, so a concurrent
+    /// writer of the same attribute makes one of the commits fail with a write conflict.
+    /// The kernel leaves the ACLs unchecked, so only the owner and root, as `uid`, may set
+    /// them.
+    pub async fn check_and_set_xattr(
+        &mut self,
+        uid: u32,
+        ino: u64,
+        name: ByteString,
+        value: Bytes,
+        flags: i32,
+    ) -> Result<()> {
+        let exists = self.get_xattr(ino, name.clone()).await?.is_some();
+        check_set_flags(ino, &name, flags, exists)?;
+        if is_acl_xattr(&name) {
+            let mut inode = self.read_inode(ino).await?;
+            inode.check_owner(uid)?;
+            // `access` trusts stored ACLs to decode
+            let acl = Acl::decode(&value)?;
+            if name == ACL_ACCESS_XATTR {
+                // the mode shows the ACL, as `chmod` changes both
+                inode.perm = inode.perm & !0o777 | acl.mode();
+                inode.ctime = SystemTime::now();
+                self.save_inode(&inode).await?;
+            }
+        }
+        self.set_xattr(ino, name, value).await
+    }
+
+    /// Remove an xattr; like setting them, removing ACLs is left to the owner and root.
+    pub async fn remove_xattr(&mut self, uid: u32, ino: u64, name: ByteString) -> Result<()> {
+        if is_acl_xattr(&name) {
+            self.read_inode(ino).await?.check_owner(uid)?;
+        }
+        if self.get_xattr(ino, name.clone()).await?.is_none() {
+            return Err(FsError::XattrNotFound {
+                ino,
+                name: name.to_string(),
+            });
+        }
+        Ok(self.delete(ScopedKey::xattr(ino, &name)).await?)
+    }
+
+    pub async fn get_index(&mut self, parent: u64, name: ByteString) -> Result<Option<u64>> {
+        check_file_name(&name)?;
+        let key = ScopedKey::index(parent, &name);
+        self.get(key)
+            .await
+            .map_err(FsError::from)
+            .and_then(|value| {
+                value
+                    .map(|data| Ok(Index::deserialize(&data)?.ino))
+                    .transpose()
+            })
+    }
+
+    pub async fn set_index(&mut self, parent: u64, name: ByteString, ino: u64) -> Result<()> {
+        check_file_name(&name)?;
+        let key = ScopedKey::index(parent, &name);
+        let value = Index::new(ino).serialize()?;
+        Ok(self.put(key, value).await?)
+    }
+
+    /// Set many `(parent, name, ino)` index entries at once, e.g. to populate directories
+    /// on import or restore, with the checks `make_inode` runs on one name.
+    ///
+    /// Existing entries and parents are each read with a single batch get, and the puts
+    /// are buffered until the commit.
+    pub async fn batch_set_index(&mut self, entries: Vec<(u64, ByteString, u64)>) -> Result<()> {
+        let mut keys = BTreeSet::<Key>::new();
+        for (parent, name, _) in &entries {
+            check_file_name(name)?;
+            if !keys.insert(ScopedKey::index(*parent, name).into()) {
+                return Err(FsError::FileExist {
+                    file: name.to_string(),
+                });
+            }
+        }
+        self.op_count += 1;
+        if let Some(pair) = self.batch_get(keys).await?.next() {
+            let file = match ScopedKey::parse(pair.key().into())? {
+                ScopedKey::FileIndex { name, .. } => name.to_string(),
+                _ => unreachable!("the keys from batch get should be always valid index keys"),
+            };
+            return Err(FsError::FileExist { file });
+        }
+
+        let parents: Vec<u64> = entries
+            .iter()
+            .map(|(parent, _, _)| *parent)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let inodes = self.batch_read_inodes(&parents).await?;
+        for parent in parents {
+            inodes
+                .get(&parent)
+                .ok_or(FsError::InodeNotFound { inode: parent })?
+                .check_dir()?;
+        }
+
+        let mut pairs = Vec::with_capacity(entries.len());
+        for (parent, name, ino) in entries {
+            let key = Key::from(ScopedKey::index(parent, &name));
+            pairs.push((key, Index::new(ino).serialize()?));
+        }
+        self.batch_put(pairs).await
+    }
+
+    /// Put many pairs, locking all their keys with one request in a pessimistic
+    /// transaction.
+    ///
+    /// The client has no batch put, so each pair is put in turn; puts are buffered until
+    /// the commit, which sends them together, so either every pair is committed or none is.
+    pub async fn batch_put<K: Into<Key>>(&mut self, pairs: Vec<(K, Vec<u8>)>) -> Result<()> {
+        let pairs: Vec<(Key, Vec<u8>)> = pairs
+            .into_iter()
+            .map(|(key, value)| (key.into(), value))
+            .collect();
+        if self.mode == TxnMode::Pessimistic && !pairs.is_empty() {
+            self.op_count += 1;
+            self.transaction()?
+                .lock_keys(pairs.iter().map(|(key, _)| key.clone()))
+                .await?;
+        }
+        for (key, value) in pairs {
+            self.put(key, value).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn remove_index(&mut self, parent: u64, name: ByteString) -> Result<()> {
+        let key = ScopedKey::index(parent, &name);
+        Ok(self.delete(key).await?)
+    }
+
+    pub async fn read_inode(&mut self, ino: u64) -> Result<Inode> {
+        let value = self
+            .get(ScopedKey::inode(ino))
+            .await?
+            .ok_or(FsError::InodeNotFound { inode: ino })?;
+        Inode::decode(ino, &value)
+    }
+
+    /// Read the inodes in `inos` with a single batch get; missing ones are left out.
+    pub async fn batch_read_inodes(&mut self, inos: &[u64]) -> Result<HashMap<u64, Inode>> {
+        let mut inodes = HashMap::with_capacity(inos.len());
+        if inos.is_empty() {
+            return Ok(inodes);
+        }
+        self.op_count += 1;
+        for pair in self
+            .batch_get(inos.iter().map(|ino| ScopedKey::inode(*ino)))
+            .await?
+        {
+            let ino = if let Ok(ScopedKey::Inode(ino)) = ScopedKey::parse(pair.key().into()) {
+                ino
+            } else {
+                unreachable!("the keys from batch get should be always valid inode keys")
+            };
+            inodes.insert(ino, Inode::decode(ino, pair.value())?);
+        }
+        Ok(inodes)
+    }
+
+    pub async fn save_inode(&mut self, inode: &Inode) -> Result<()> {
+        // numbers below the root are reserved and never stored
+        if inode.ino < ROOT_INODE {
+            return Err(FsError::CorruptedData {
+                inode: inode.ino,
+                detail: format!("inode number {} is below the root", inode.ino),
+            });
+        }
+        let key: Key = ScopedKey::inode(inode.ino).into();
+
+        self.lock_for_write(key.clone()).await?;
+        self.invalidate_cached_inode(inode.ino);
+        self.file_owners
+            .insert(inode.ino, (inode.kind, inode.uid, inode.gid));
+        if inode.is_unlinked() {
+            self.delete(key).await?;
+            self.clear_xattrs(inode.ino).await?;
+            // a recycled number must not inherit the data of its last owner
+            self.delete_file_blocks(inode, inode.block_span(self.block_size()))
+                .await?;
+            self.release_ino(inode.ino).await?;
         } else {
             self.put(key, inode.serialize()?).await?;
             debug!("save inode: {:?}", inode);
@@ -224,27 +1089,190 @@ impl Txn {
         Ok(())
     }
 
+    /// Delete the inode record of `ino` and recycle its number; its data must be cleared.
     pub async fn remove_inode(&mut self, ino: u64) -> Result<()> {
+        self.invalidate_cached_inode(ino);
+        // its blocks are freed, charged to the owners on commit
+        if !self.file_owners.contains_key(&ino) {
+            let inode = self.read_inode(ino).await?;
+            self.file_owners
+                .insert(ino, (inode.kind, inode.uid, inode.gid));
+        }
         self.delete(ScopedKey::inode(ino)).await?;
+        self.release_ino(ino).await
+    }
+
+    /// Charge the blocks stored and freed by this transaction to the quotas of the owners
+    /// of their regular files, failing with `QuotaExceeded` if that breaks a hard limit.
+    ///
+    /// Owners come from the inodes saved by the transaction; the rare file whose blocks
+    /// changed without its inode being saved is read.
+    async fn account_quota(&mut self) -> Result<()> {
+        let mut deltas: HashMap<QuotaOwner, i64> = HashMap::new();
+        for (ino, blocks) in std::mem::take(&mut self.file_block_deltas) {
+            if blocks == 0 {
+                continue;
+            }
+            let owner = match self.file_owners.get(&ino) {
+                Some(owner) => Some(*owner),
+                None => match self.read_inode(ino).await {
+                    Ok(inode) => Some((inode.kind, inode.uid, inode.gid)),
+                    Err(FsError::InodeNotFound { .. }) => None,
+                    Err(err) => return Err(err),
+                },
+            };
+            if let Some((FileType::RegularFile, uid, gid)) = owner {
+                let bytes = blocks * self.block_size() as i64;
+                *deltas.entry(QuotaOwner::User(uid)).or_default() += bytes;
+                *deltas.entry(QuotaOwner::Group(gid)).or_default() += bytes;
+            }
+        }
+        self.charge_quotas(deltas).await
+    }
+
+    /// Move the blocks stored for regular file `inode` from the quotas of `uid` and `gid`
+    /// to those of its owners, when `chown(2)` hands it over.
+    async fn transfer_quota(&mut self, inode: &Inode, uid: u32, gid: u32) -> Result<()> {
+        if inode.kind != FileType::RegularFile || (inode.uid, inode.gid) == (uid, gid) {
+            return Ok(());
+        }
+        let end_block = (inode.size + self.block_size() - 1) / self.block_size();
+        let stored = self.stored_blocks(inode.ino, 0..end_block).await?.len() as i64;
+        // blocks changed by this transaction are charged to the new owners on commit
+        let pending = self
+            .file_block_deltas
+            .get(&inode.ino)
+            .copied()
+            .unwrap_or_default();
+        let bytes = (stored - pending) * self.block_size() as i64;
+
+        let mut deltas: HashMap<QuotaOwner, i64> = HashMap::new();
+        *deltas.entry(QuotaOwner::User(uid)).or_default() -= bytes;
+        *deltas.entry(QuotaOwner::Group(gid)).or_default() -= bytes;
+        *deltas.entry(QuotaOwner::User(inode.uid)).or_default() += bytes;
+        *deltas.entry(QuotaOwner::Group(inode.gid)).or_default() += bytes;
+        self.charge_quotas(deltas).await
+    }
+
+    /// Add `deltas` bytes to the quotas set for their owners.
+    async fn charge_quotas(&mut self, mut deltas: HashMap<QuotaOwner, i64>) -> Result<()> {
+        deltas.retain(|_, delta| *delta != 0);
+        if deltas.is_empty() {
+            return Ok(());
+        }
+
+        let owners: Vec<QuotaOwner> = deltas.keys().copied().collect();
+        for (owner, mut quota) in self.read_quotas(&owners).await? {
+            if !quota.charge(deltas[&owner]) {
+                return Err(FsError::QuotaExceeded { owner });
+            }
+            self.lock_for_write(ScopedKey::quota(owner)).await?;
+            self.put(ScopedKey::quota(owner), quota.serialize()?)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The quotas set for `owners`, with a single batch get.
+    async fn read_quotas(&mut self, owners: &[QuotaOwner]) -> Result<Vec<(QuotaOwner, Quota)>> {
+        self.op_count += 1;
+        let mut quotas = Vec::with_capacity(owners.len());
+        for pair in self
+            .batch_get(owners.iter().map(|owner| ScopedKey::quota(*owner)))
+            .await?
+        {
+            let owner = if let Ok(ScopedKey::Quota(owner)) = ScopedKey::parse(pair.key().into()) {
+                owner
+            } else {
+                unreachable!("the keys from batch get should be always valid quota keys")
+            };
+            quotas.push((owner, Quota::deserialize(pair.value())?));
+        }
+        Ok(quotas)
+    }
+
+    /// Set the limits of the quota of `uid` and of `gid`, keeping their usage; one set
+    /// for the first time starts counting from zero.
+    pub async fn set_quota(
+        &mut self,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        hard: u64,
+        soft: u64,
+    ) -> Result<()> {
+        let owners: Vec<QuotaOwner> = uid
+            .map(QuotaOwner::User)
+            .into_iter()
+            .chain(gid.map(QuotaOwner::Group))
+            .collect();
+        let existing: HashMap<QuotaOwner, Quota> =
+            self.read_quotas(&owners).await?.into_iter().collect();
+        for owner in owners {
+            let quota = Quota {
+                hard_limit: hard,
+                soft_limit: soft,
+                ..existing.get(&owner).copied().unwrap_or_default()
+            };
+            self.lock_for_write(ScopedKey::quota(owner)).await?;
+            self.put(ScopedKey::quota(owner), quota.serialize()?)
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn get_quota(&mut self, uid: Option<u32>, gid: Option<u32>) -> Result<QuotaInfo> {
+        let mut info = QuotaInfo::default();
+        if let Some(uid) = uid {
+            info.user = self.read_quota(QuotaOwner::User(uid)).await?;
+        }
+        if let Some(gid) = gid {
+            info.group = self.read_quota(QuotaOwner::Group(gid)).await?;
+        }
+        Ok(info)
+    }
+
+    async fn read_quota(&mut self, owner: QuotaOwner) -> Result<Option<Quota>> {
+        self.get(ScopedKey::quota(owner))
+            .await?
+            .map(|value| Quota::deserialize(&value))
+            .transpose()
+    }
+
+    async fn release_ino(&mut self, ino: u64) -> Result<()> {
+        if let Some(mut meta) = self.read_meta().await? {
+            meta.release_ino(ino, self.config.max_free_inodes);
+            self.save_meta(&meta).await?;
+        }
         Ok(())
     }
 
     pub async fn read_meta(&mut self) -> Result<Option<Meta>> {
+        if let Some(meta) = &self.meta_cache {
+            return Ok(Some(meta.clone()));
+        }
+
         let opt_data = self.get(ScopedKey::meta()).await?;
-        opt_data.map(|data| Meta::deserialize(&data)).transpose()
+        let meta = opt_data.map(|data| Meta::deserialize(&data)).transpose()?;
+        self.meta_cache = meta.clone();
+        Ok(meta)
     }
 
     pub async fn save_meta(&mut self, meta: &Meta) -> Result<()> {
+        self.lock_for_write(ScopedKey::meta()).await?;
         self.put(ScopedKey::meta(), meta.serialize()?).await?;
+        self.meta_cache = Some(meta.clone());
         Ok(())
     }
 
     async fn transfer_inline_data_to_block(&mut self, inode: &mut Inode) -> Result<()> {
-        debug_assert!(inode.size <= self.inline_data_threshold());
         let key = ScopedKey::block(inode.ino, 0);
         let mut data = inode.inline_data.clone().unwrap();
-        data.resize(self.block_size as usize, 0);
-        self.put(key, data).await?;
+        // `setattr` may have grown the size past the data, and the data may be past a
+        // threshold lowered since it was written, but never past a block
+        data.truncate(inode.size as usize);
+        data.resize(self.block_size() as usize, 0);
+        self.put(key, self.encode_file_block(inode, data)).await?;
+        self.count_blocks(inode.ino, 1);
         inode.inline_data = None;
         Ok(())
     }
@@ -255,7 +1283,6 @@ impl Txn {
         start: u64,
         data: &[u8],
     ) -> Result<usize> {
-        debug_assert!(inode.size <= self.inline_data_threshold());
         let size = data.len() as u64;
         debug_assert!(
             start + size <= self.inline_data_threshold(),
@@ -277,7 +1304,7 @@ impl Txn {
         inode.atime = SystemTime::now();
         inode.mtime = SystemTime::now();
         inode.ctime = SystemTime::now();
-        inode.set_size(inlined.len() as u64, self.block_size);
+        inode.set_size(inlined.len() as u64);
         inode.inline_data = Some(inlined);
         self.save_inode(inode).await?;
 
@@ -290,8 +1317,8 @@ impl Txn {
         start: u64,
         size: u64,
     ) -> Result<Vec<u8>> {
-        debug_assert!(inode.size <= self.inline_data_threshold());
-
+        // inline data past the threshold, as left by a mount with a higher one, reads
+        // as usual; the next write moves it to blocks
         let start = start as usize;
         let size = size as usize;
 
@@ -303,106 +1330,950 @@ impl Txn {
             data[..to_copy].copy_from_slice(&inlined[start..start + to_copy]);
         }
 
-        inode.atime = SystemTime::now();
-        self.save_inode(inode).await?;
+        if !self.is_read_only() && inode.touch_atime(self.config.atime_mode, SystemTime::now()) {
+            self.save_inode(inode).await?;
+        }
 
         Ok(data)
     }
 
+    /// Read up to `chunk_size` bytes of `ino` from `start`, short at the end of the file.
+    ///
+    /// The inode is read before any block, from TiKV rather than the inode cache, and both
+    /// come from the snapshot of this transaction: a file deleted concurrently is either
+    /// read whole or `InodeNotFound`, never cut short by blocks gone missing in between.
+    #[instrument(
+        skip(self),
+        fields(txn_id = self.id, size = field::Empty, block_count = field::Empty)
+    )]
     pub async fn read_data(
         &mut self,
         ino: u64,
         start: u64,
         chunk_size: Option<u64>,
-    ) -> Result<Vec<u8>> {
+    ) -> Result<Bytes> {
         let mut attr = self.read_inode(ino).await?;
         if start >= attr.size {
-            return Ok(Vec::new());
+            return Ok(Bytes::new());
         }
 
-        let max_size = attr.size - start;
-        let size = chunk_size.unwrap_or(max_size).min(max_size);
+        let size = read_size(attr.size, start, chunk_size, self.config.max_read_size);
+        Span::current().record("size", &size);
 
         if attr.inline_data.is_some() {
-            return self.read_inline_data(&mut attr, start, size).await;
+            return Ok(self.read_inline_data(&mut attr, start, size).await?.into());
         }
 
         let target = start + size;
-        let start_block = start / self.block_size;
-        let end_block = (target + self.block_size - 1) / self.block_size;
-
-        let pairs = self
-            .scan(
+        let start_block = start / self.block_size();
+        let end_block = (target + self.block_size() - 1) / self.block_size();
+        Span::current().record("block_count", &(end_block - start_block));
+
+        // a few blocks are cheaper to fetch point by point than to scan
+        let pairs: Vec<_> = if end_block - start_block < self.config.batch_get_threshold {
+            self.op_count += 1;
+            self.batch_get(ScopedKey::block_keys(ino, start_block..end_block))
+                .await?
+                .collect()
+        } else {
+            self.scan(
                 ScopedKey::block_range(ino, start_block..end_block),
                 (end_block - start_block) as u32,
             )
+            .await?
+            .collect()
+        };
+
+        if pairs.is_empty() && self.config.strict_reads && !attr.sparse {
+            return Err(FsError::CorruptedData {
+                inode: ino,
+                detail: format!("no data block in range [{}, {})", start_block, end_block),
+            });
+        }
+
+        let mut blocks = Vec::with_capacity(pairs.len());
+        for pair in pairs {
+            let block = if let Ok(ScopedKey::Block { ino: _, block }) =
+                ScopedKey::parse(pair.key().into())
+            {
+                block
+            } else {
+                unreachable!("the keys from scanning should be always valid block keys")
+            };
+            let (data, _) = self.load_block(&attr, block, pair.into_value()).await?;
+            blocks.push((block, data));
+        }
+        // batch get returns pairs in no particular order
+        blocks.sort_unstable_by_key(|(block, _)| *block);
+
+        let offset = (start % self.block_size()) as usize;
+        let data: Bytes = match blocks.pop() {
+            // the requested range lies in a single stored block, hand a slice of it over
+            // without copying
+            Some((block, value))
+                if end_block == start_block + 1
+                    && blocks.is_empty()
+                    && block == start_block
+                    && value.len() >= offset + size as usize =>
+            {
+                Bytes::from(value).slice(offset..offset + size as usize)
+            }
+            last => {
+                blocks.extend(last);
+                assemble_blocks(self.block_size(), start, size, blocks).into()
+            }
+        };
+
+        // a snapshot is read-only, like a `ro` mount, which doesn't update atime either
+        if !self.is_read_only() && attr.touch_atime(self.config.atime_mode, SystemTime::now()) {
+            self.save_inode(&attr).await?;
+        }
+        Ok(data)
+    }
+
+    /// Copy up to `len` bytes from `src_ino` to `dst_ino` inside this transaction and return
+    /// the number of bytes copied, which is short at the end of the source or beyond
+    /// `max_read_size`.
+    pub async fn copy_file_range(
+        &mut self,
+        src_ino: u64,
+        src_offset: u64,
+        dst_ino: u64,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<u64> {
+        let src = self.read_inode(src_ino).await?;
+        if src_offset >= src.size {
+            return Ok(0);
+        }
+        let len = len
+            .min(src.size - src_offset)
+            .min(self.config.max_read_size);
+        let dst = self.read_inode(dst_ino).await?;
+
+        // with both offsets aligned, whole blocks are moved as stored values
+        let block_size = self.block_size();
+        let whole_blocks = if src_offset % block_size == 0
+            && dst_offset % block_size == 0
+            && src.inline_data.is_none()
+            && dst.inline_data.is_none()
+            // tagged values can't be moved as they are, their references would need counting
+            && !src.shared_blocks
+            && !dst.shared_blocks
+        {
+            len / block_size
+        } else {
+            0
+        };
+        let tail_start = whole_blocks * block_size;
+
+        // a destination above an overlapping source is copied from the end, so every
+        // source block is read before it gets overwritten
+        let backward =
+            src_ino == dst_ino && dst_offset > src_offset && dst_offset < src_offset + len;
+        if backward && tail_start < len {
+            self.copy_bytes(src_ino, src_offset, dst_ino, dst_offset, tail_start, len)
+                .await?;
+        }
+        let dst_blocks = dst_offset / block_size..dst_offset / block_size + whole_blocks;
+        let stored_before = self.stored_blocks(dst_ino, dst_blocks.clone()).await?.len();
+        let mut blocks: Vec<u64> = (0..whole_blocks).collect();
+        if backward {
+            blocks.reverse();
+        }
+        for index in blocks {
+            let src_key = ScopedKey::block(src_ino, src_offset / block_size + index);
+            let dst_key = ScopedKey::block(dst_ino, dst_offset / block_size + index);
+            match self.get(src_key).await? {
+                Some(value) => self.put(dst_key, value).await?,
+                None => self.delete(dst_key).await?,
+            }
+        }
+        let stored_after = self.stored_blocks(dst_ino, dst_blocks).await?.len();
+        self.count_blocks(dst_ino, stored_after as i64 - stored_before as i64);
+        if !backward && tail_start < len {
+            self.copy_bytes(src_ino, src_offset, dst_ino, dst_offset, tail_start, len)
+                .await?;
+        }
+
+        let mut dst = self.read_inode(dst_ino).await?;
+        // holes of the source, or a gap left before `dst_offset`, carry over
+        if src.sparse || dst_offset > dst.size {
+            dst.sparse = true;
+        }
+        if dst_offset + len > dst.size {
+            dst.set_size(dst_offset + len);
+        }
+        dst.mtime = SystemTime::now();
+        dst.ctime = SystemTime::now();
+        self.save_inode(&dst).await?;
+        Ok(len)
+    }
+
+    /// Give `dst_ino` the owner, permissions and timestamps of `src_ino` in one
+    /// transaction, for `cp --preserve` without a `getattr` and `setattr` round trip.
+    #[instrument(skip(self), fields(txn_id = self.id))]
+    pub async fn clone_metadata(&mut self, src_ino: u64, dst_ino: u64) -> Result<()> {
+        let src = self.read_inode(src_ino).await?;
+        let mut dst = self.read_inode(dst_ino).await?;
+        dst.copy_metadata(&src, SystemTime::now());
+        self.save_inode(&dst).await
+    }
+
+    /// Make `dst_name` in `dst_parent` a copy of regular file `src_ino` that shares its
+    /// blocks until either file writes them.
+    ///
+    /// Every stored block of the source moves to a `SharedBlock` both files refer to, so a
+    /// later write to either file copies only the blocks it changes; the shared block is
+    /// deleted along with the last reference to it.
+    #[instrument(skip(self), fields(txn_id = self.id))]
+    pub async fn reflink(
+        &mut self,
+        src_ino: u64,
+        dst_parent: u64,
+        dst_name: ByteString,
+    ) -> Result<Inode> {
+        let mut src = self.read_inode(src_ino).await?;
+        match src.kind {
+            FileType::RegularFile => (),
+            FileType::Directory => {
+                return Err(FsError::IsDirectory {
+                    file: src_ino.to_string(),
+                })
+            }
+            _ => return Err(FsError::InvalidReflink { ino: src_ino }),
+        }
+        let mode = make_mode(FileType::RegularFile, src.perm);
+        let mut dst = self
+            .make_inode(dst_parent, dst_name, mode, src.gid, src.uid, 0, None)
             .await?;
 
-        let mut data = pairs
-            .enumerate()
-            .flat_map(|(i, pair)| {
-                let key = if let Ok(ScopedKey::Block { ino: _, block }) =
-                    ScopedKey::parse(pair.key().into())
-                {
-                    block
+        if let Some(data) = &src.inline_data {
+            dst.inline_data = Some(data.clone());
+        } else {
+            let end_block = (src.size + self.block_size() - 1) / self.block_size();
+            let pairs = self
+                .scan_all(ScopedKey::block_range(src_ino, 0..end_block))
+                .await?;
+            let mut meta = self.read_meta().await?.unwrap();
+            for pair in pairs {
+                let block = match ScopedKey::parse(pair.key().into())? {
+                    ScopedKey::Block { block, .. } => block,
+                    _ => unreachable!("the keys from scanning should be always valid block keys"),
+                };
+                let value = if src.shared_blocks {
+                    self.decode_block_value(src_ino, block, pair.into_value())?
                 } else {
-                    unreachable!("the keys from scanning should be always valid block keys")
+                    BlockValue::Data(pair.into_value())
                 };
-                let value = pair.into_value();
-                (start_block as usize + i..key as usize)
-                    .map(|_| empty_block(self.block_size))
-                    .chain(vec![value])
-            })
-            .enumerate()
-            .fold(
-                Vec::with_capacity(
-                    ((end_block - start_block) * self.block_size - start % self.block_size)
-                        as usize,
-                ),
-                |mut data, (i, value)| {
-                    let mut slice = value.as_slice();
-                    if i == 0 {
-                        slice = &slice[(start % self.block_size) as usize..]
+                let (id, created) = value.share(&mut meta.shared_block_next);
+                match created {
+                    Some(shared) => {
+                        self.put(ScopedKey::shared_block(id), shared.encode())
+                            .await?;
+                        self.put(
+                            ScopedKey::block(src_ino, block),
+                            BlockValue::Shared(id).encode(),
+                        )
+                        .await?;
+                    }
+                    None => {
+                        let mut shared = self.read_shared_block(src_ino, id).await?;
+                        shared.refs += 1;
+                        self.put(ScopedKey::shared_block(id), shared.encode())
+                            .await?;
                     }
+                }
+                self.put(
+                    ScopedKey::block(dst.ino, block),
+                    BlockValue::Shared(id).encode(),
+                )
+                .await?;
+                self.count_blocks(dst.ino, 1);
+            }
+            self.save_meta(&meta).await?;
 
-                    data.extend_from_slice(slice);
-                    data
-                },
-            );
+            // from now on every block of both files is tagged
+            if !src.shared_blocks {
+                src.shared_blocks = true;
+                self.save_inode(&src).await?;
+            }
+            dst.shared_blocks = true;
+        }
 
-        data.resize(size as usize, 0);
-        attr.atime = SystemTime::now();
-        self.save_inode(&attr).await?;
-        Ok(data)
+        dst.set_size(src.size);
+        dst.sparse = src.sparse;
+        self.save_inode(&dst).await?;
+        Ok(dst)
+    }
+
+    async fn copy_bytes(
+        &mut self,
+        src_ino: u64,
+        src_offset: u64,
+        dst_ino: u64,
+        dst_offset: u64,
+        from: u64,
+        to: u64,
+    ) -> Result<()> {
+        let data = self
+            .read_data(src_ino, src_offset + from, Some(to - from))
+            .await?;
+        self.write_data(dst_ino, dst_offset + from, data).await?;
+        Ok(())
     }
 
+    /// Empty file `ino`, returning the size it had.
+    #[instrument(skip(self), fields(txn_id = self.id))]
     pub async fn clear_data(&mut self, ino: u64) -> Result<u64> {
         let mut attr = self.read_inode(ino).await?;
-        let end_block = (attr.size + self.block_size - 1) / self.block_size;
-
-        for block in 0..end_block {
-            self.delete(ScopedKey::block(ino, block)).await?;
+        if attr.inline_data.is_some() {
+            // inline data has no blocks to delete
+            attr.inline_data = None;
+        } else {
+            let end_block = (attr.size + self.block_size() - 1) / self.block_size();
+            self.delete_file_blocks(&attr, 0..end_block).await?;
         }
 
         let clear_size = attr.size;
-        attr.size = 0;
+        attr.set_size(0);
         attr.atime = SystemTime::now();
+        attr.mtime = SystemTime::now();
+        attr.ctime = SystemTime::now();
         self.save_inode(&attr).await?;
         Ok(clear_size)
     }
 
-    #[instrument(skip(self, data))]
+    /// Read, check and update the attributes of `ino` in one go, on behalf of `caller_uid`.
+    ///
+    /// A `size` truncates or extends the file as `truncate(2)` does.
+    #[instrument(skip(self), fields(txn_id = self.id))]
+    pub async fn setattr(
+        &mut self,
+        ino: u64,
+        caller_uid: u32,
+        attrs: SetAttrRequest,
+    ) -> Result<Inode> {
+        let mut inode = self.read_inode(ino).await?;
+        attrs.check_permission(&inode, caller_uid)?;
+        if attrs.is_noop_truncate(&inode) {
+            return Ok(inode);
+        }
+        if let Some(size) = attrs.size {
+            if size != inode.size {
+                self.resize(&mut inode, size).await?;
+            }
+        }
+        let (uid, gid) = (inode.uid, inode.gid);
+        attrs.apply(&mut inode, SystemTime::now());
+        self.transfer_quota(&inode, uid, gid).await?;
+        if attrs.mode.is_some() {
+            if let Some(mut acl) = self.access_acl(ino).await? {
+                acl.set_mode(inode.perm);
+                self.set_xattr(ino, ACL_ACCESS_XATTR.into(), acl.encode().into())
+                    .await?;
+            }
+        }
+        self.save_inode(&inode).await?;
+        Ok(inode)
+    }
+
+    /// Resize file `ino` to `size` in this transaction, as `truncate(2)`: a grown file
+    /// reads zeros past its old end, a shrunk one loses the data past its new end.
+    pub async fn truncate(&mut self, ino: u64, size: u64) -> Result<()> {
+        let mut inode = self.read_inode(ino).await?;
+        if inode.kind == FileType::Directory {
+            return Err(FsError::IsDirectory {
+                file: ino.to_string(),
+            });
+        }
+        // the times only move with the size
+        if size == inode.size {
+            return Ok(());
+        }
+        self.resize(&mut inode, size).await?;
+        inode.ctime = SystemTime::now();
+        self.save_inode(&inode).await
+    }
+
+    /// Resize `inode`, dropping the data past a shrunk end; the caller saves the inode.
+    async fn resize(&mut self, inode: &mut Inode, size: u64) -> Result<()> {
+        if let Some(inlined) = inode.inline_data.as_mut() {
+            if size <= self.inline_data_threshold() {
+                inlined.resize(size as usize, 0);
+                inode.set_size(size);
+                inode.mtime = SystemTime::now();
+                return Ok(());
+            }
+            self.transfer_inline_data_to_block(inode).await?;
+        }
+
+        if size > inode.size {
+            inode.sparse = true;
+        }
+        let (deleted, partial) = shrink_blocks(self.block_size(), inode.size, size);
+        self.delete_file_blocks(inode, deleted).await?;
+        // the cut-off tail of the last block must read as zeros if the file grows again
+        if let Some((index, tail)) = partial {
+            self.zero_block_tail(inode, index, tail).await?;
+        }
+        inode.set_size(size);
+        inode.mtime = SystemTime::now();
+        Ok(())
+    }
+
+    /// Zero block `index` of `inode` from byte `tail` on, if it is stored.
+    async fn zero_block_tail(&mut self, inode: &Inode, index: u64, tail: usize) -> Result<()> {
+        let key = ScopedKey::block(inode.ino, index);
+        if let Some(value) = self.get(key).await? {
+            let (mut block, shared) = self.load_block(inode, index, value).await?;
+            if tail < block.len() && !is_zero(&block[tail..]) {
+                block[tail..].iter_mut().for_each(|byte| *byte = 0);
+                if let Some(id) = shared {
+                    self.unref_shared_block(inode.ino, id).await?;
+                }
+                self.put(key, self.encode_file_block(inode, block)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Call `f` with every key in `range`, scanned a page at a time.
+    async fn for_each_key_page<F>(&mut self, range: Range<Key>, mut f: F) -> Result<()>
+    where
+        F: FnMut(Vec<Key>),
+    {
+        let Range { mut start, end } = range;
+        loop {
+            self.op_count += 1;
+            let keys: Vec<Key> = self
+                .scan_keys(start..end.clone(), TiFs::SCAN_LIMIT)
+                .await?
+                .collect();
+            if (keys.len() as u32) < TiFs::SCAN_LIMIT {
+                f(keys);
+                break Ok(());
+            }
+            // resume right after the last key of this batch
+            let mut next: Vec<u8> = keys.last().cloned().unwrap().into();
+            next.push(0);
+            start = next.into();
+            f(keys);
+        }
+    }
+
+    async fn count_keys(&mut self, range: Range<Key>) -> Result<u64> {
+        let mut count = 0;
+        self.for_each_key_page(range, |keys| count += keys.len() as u64)
+            .await?;
+        Ok(count)
+    }
+
+    /// Count `delta` blocks of `ino` stored, or freed if negative, on commit.
+    fn count_blocks(&mut self, ino: u64, delta: i64) {
+        *self.block_deltas.entry(usage_shard(ino)).or_default() += delta;
+        *self.file_block_deltas.entry(ino).or_default() += delta;
+    }
+
+    async fn add_usage(&mut self, shard: u64, delta: i64) -> Result<()> {
+        let key = ScopedKey::usage(shard);
+        self.lock_for_write(key).await?;
+        let current = match self.get(key).await? {
+            Some(value) => deserialize_usage(&value)?,
+            None => 0,
+        };
+        self.put(key, serialize_usage(current + delta)?).await
+    }
+
+    async fn usage_deltas(&mut self) -> Result<Vec<i64>> {
+        self.scan_all(ScopedKey::all_usage())
+            .await?
+            .iter()
+            .map(|pair| deserialize_usage(pair.value()))
+            .collect()
+    }
+
+    /// Blocks stored as counted by `meta` and the usage shards, as committed when this
+    /// transaction began; `None` until `statfs` counts them once.
+    pub async fn blocks_in_use(&mut self, meta: &Meta) -> Result<Option<u64>> {
+        if meta.used_blocks.is_none() {
+            return Ok(None);
+        }
+        let deltas = self.usage_deltas().await?;
+        Ok(meta.blocks_in_use(deltas))
+    }
+
+    /// Make `stored` the count of `meta`, with the usage shards starting over from zero.
+    async fn reset_block_usage(&mut self, meta: &mut Meta, stored: u64) -> Result<()> {
+        for pair in self.scan_all(ScopedKey::all_usage()).await? {
+            self.delete(pair.key().clone()).await?;
+        }
+        meta.used_blocks = Some(stored);
+        self.save_meta(meta).await
+    }
+
+    /// The offset of the first byte of data at or after `offset`, as `lseek(SEEK_DATA)`.
+    pub async fn seek_data(&mut self, ino: u64, offset: u64) -> Result<u64> {
+        self.seek(ino, offset, seek_data).await
+    }
+
+    /// The offset of the first hole at or after `offset`, as `lseek(SEEK_HOLE)`; the end
+    /// of the file is one.
+    pub async fn seek_hole(&mut self, ino: u64, offset: u64) -> Result<u64> {
+        self.seek(ino, offset, seek_hole).await
+    }
+
+    async fn seek(
+        &mut self,
+        ino: u64,
+        offset: u64,
+        target: fn(u64, u64, u64, &[u64]) -> Option<u64>,
+    ) -> Result<u64> {
+        let inode = self.read_inode(ino).await?;
+        let block_size = self.block_size();
+        let stored: Vec<u64> = if inode.inline_data.is_some() {
+            // inline data has no holes
+            (0..(inode.size + block_size - 1) / block_size).collect()
+        } else {
+            let end_block = (inode.size + block_size - 1) / block_size;
+            self.stored_blocks(ino, offset / block_size..end_block.max(offset / block_size))
+                .await?
+                .into_iter()
+                .map(|key| match ScopedKey::parse((&key).into()) {
+                    Ok(ScopedKey::Block { block, .. }) => Ok(block),
+                    _ => Err(FsError::InvalidScopedKey(key.into())),
+                })
+                .collect::<Result<_>>()?
+        };
+        target(block_size, inode.size, offset, &stored)
+            .ok_or(FsError::SeekBeyondEnd { ino, offset })
+    }
+
+    /// Keys of the blocks of `ino` in `range` that are stored, holes left out.
+    async fn stored_blocks(&mut self, ino: u64, range: Range<u64>) -> Result<Vec<Key>> {
+        let mut stored = Vec::new();
+        self.for_each_key_page(ScopedKey::block_range(ino, range), |keys| {
+            stored.extend(keys)
+        })
+        .await?;
+        Ok(stored)
+    }
+
+    /// Delete the stored blocks of `inode` in `range` and the references they hold.
+    async fn delete_file_blocks(&mut self, inode: &Inode, range: Range<u64>) -> Result<()> {
+        self.release_shared_blocks(inode, range.clone()).await?;
+        self.delete_blocks(inode.ino, range).await?;
+        Ok(())
+    }
+
+    /// Delete the stored blocks of `ino` in `range`, and return how many there were.
+    ///
+    /// References to shared blocks are not released, see `delete_file_blocks`.
+    pub async fn delete_blocks(&mut self, ino: u64, range: Range<u64>) -> Result<u64> {
+        let stored = self.stored_blocks(ino, range).await?;
+        let count = stored.len() as u64;
+        self.count_blocks(ino, -(count as i64));
+        for key in stored {
+            self.delete(key).await?;
+        }
+        Ok(count)
+    }
+
+    /// Recount `blocks` from the data blocks actually stored in TiKV.
+    ///
+    /// `set_size` assumes a dense file; holes make the real allocation smaller.
+    pub async fn recompute_blocks(&mut self, ino: u64) -> Result<Inode> {
+        let mut inode = self.read_inode(ino).await?;
+        if inode.inline_data.is_some() {
+            return Ok(inode);
+        }
+
+        let end_block = (inode.size + self.block_size() - 1) / self.block_size();
+        let allocated = self
+            .scan_keys(
+                ScopedKey::block_range(ino, 0..end_block),
+                end_block.min(u32::MAX as u64) as u32,
+            )
+            .await?
+            .count() as u64;
+        inode.blocks = allocated * self.block_size() / STAT_BLOCK_SIZE;
+        self.save_inode(&inode).await?;
+        Ok(inode)
+    }
+
+    /// The data and hole ranges of `ino`, from a single scan of its block keys.
+    pub async fn get_block_map(&mut self, ino: u64) -> Result<Vec<BlockRange>> {
+        let inode = self.read_inode(ino).await?;
+        let block_size = self.block_size();
+        if inode.inline_data.is_some() {
+            // inline data is all data, an empty file has no range at all
+            let ranges = if inode.size == 0 {
+                Vec::new()
+            } else {
+                vec![BlockRange {
+                    start_byte: 0,
+                    end_byte: inode.size,
+                    is_data: true,
+                }]
+            };
+            return Ok(ranges);
+        }
+
+        let end_block = (inode.size + block_size - 1) / block_size;
+        self.op_count += 1;
+        let stored = self
+            .scan_keys(
+                ScopedKey::block_range(ino, 0..end_block),
+                end_block.min(u32::MAX as u64) as u32,
+            )
+            .await?
+            .map(|key| match ScopedKey::parse((&key).into()) {
+                Ok(ScopedKey::Block { block, .. }) => Ok(block),
+                _ => Err(FsError::InvalidScopedKey(key.into())),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(block_map(block_size, inode.size, &stored))
+    }
+
+    /// Find the `(ino, block)` of every stored block whose inode doesn't exist.
+    pub async fn scan_orphaned_blocks(&mut self) -> Result<Vec<(u64, u64)>> {
+        let mut orphans = Vec::new();
+        let mut start = ScopedKey::all_blocks().start;
+        loop {
+            let page = self.scan_orphaned_blocks_page(start).await?;
+            orphans.extend(page.orphans);
+            match page.next {
+                Some(next) => start = next,
+                None => break Ok(orphans),
+            }
+        }
+    }
+
+    /// Check a single page of block keys from `start`, see `scan_orphaned_blocks`.
+    pub async fn scan_orphaned_blocks_page(&mut self, start: Key) -> Result<OrphanScan> {
+        let end = ScopedKey::all_blocks().end;
+        let keys: Vec<Key> = self
+            .scan_keys(start..end, TiFs::SCAN_LIMIT)
+            .await?
+            .collect();
+        let mut blocks = Vec::with_capacity(keys.len());
+        for key in &keys {
+            match ScopedKey::parse(key.into())? {
+                ScopedKey::Block { ino, block } => blocks.push((ino, block)),
+                _ => unreachable!("the keys from scanning should be always valid block keys"),
+            }
+        }
+        // the inodes of a page are looked up together
+        let mut live = HashSet::new();
+        let owners = block_owners(&blocks);
+        if !owners.is_empty() {
+            self.op_count += 1;
+            for pair in self
+                .batch_get(owners.into_iter().map(ScopedKey::inode))
+                .await?
+            {
+                if let ScopedKey::Inode(ino) = ScopedKey::parse(pair.key().into())? {
+                    live.insert(ino);
+                }
+            }
+        }
+        let orphans = orphaned_blocks(&blocks, &live);
+
+        let scanned = keys.len() as u64;
+        let next = if scanned < TiFs::SCAN_LIMIT as u64 {
+            None
+        } else {
+            // resume right after the last key of this batch
+            let mut next: Vec<u8> = keys.into_iter().last().unwrap().into();
+            next.push(0);
+            Some(next.into())
+        };
+        Ok(OrphanScan {
+            scanned,
+            orphans,
+            next,
+        })
+    }
+
+    /// Delete the blocks of `ino` in `range` unless the inode exists by now, and return
+    /// how many were deleted.
+    ///
+    /// Only `Inode::shared_blocks` tells a reference to a shared block from data, so the
+    /// values of an orphan are never taken for references: a shared block one of them held
+    /// is left with a reference too many rather than risk freeing the data of a live file.
+    pub async fn delete_orphaned_blocks(&mut self, ino: u64, range: Range<u64>) -> Result<u64> {
+        if self.key_exists(ScopedKey::inode(ino)).await? {
+            return Ok(0);
+        }
+        self.delete_blocks(ino, range).await
+    }
+
+    /// Every pair in `range`, scanned a page at a time.
+    async fn scan_all(&mut self, range: Range<Key>) -> Result<Vec<KvPair>> {
+        let Range { mut start, end } = range;
+        let mut pairs = Vec::new();
+        loop {
+            self.op_count += 1;
+            let page: Vec<KvPair> = self
+                .scan(start..end.clone(), TiFs::SCAN_LIMIT)
+                .await?
+                .collect();
+            let full = page.len() as u32 == TiFs::SCAN_LIMIT;
+            if full {
+                // resume right after the last key of this batch
+                let mut next: Vec<u8> = page.last().unwrap().key().clone().into();
+                next.push(0);
+                start = next.into();
+            }
+            pairs.extend(page);
+            if !full {
+                break Ok(pairs);
+            }
+        }
+    }
+
+    /// Check that indexes, directories, inodes and blocks agree with each other, and with
+    /// `repair` fix what was found in this transaction.
+    ///
+    /// The whole namespace is loaded in memory, so this is meant for an unmounted
+    /// filesystem. Inodes and directory records that don't decode are all reported as
+    /// `Corrupted`; nothing is repaired then, as what they held is unknown and a repair
+    /// would drop the entries leading to them.
+    #[instrument(skip(self), fields(txn_id = self.id))]
+    pub async fn fsck(&mut self, repair: bool) -> Result<FsckReport> {
+        let mut image = self.load_image().await?;
+        let mut errors = image.check();
+        errors.extend(
+            self.scan_orphaned_blocks()
+                .await?
+                .into_iter()
+                .map(|(ino, block)| FsckError::OrphanBlock { ino, block }),
+        );
+        let repair = repair && image.corrupted.is_empty();
+        if repair && !errors.is_empty() {
+            self.repair_image(&mut image, &errors).await?;
+        }
+        Ok(FsckReport {
+            errors,
+            repaired: repair,
+        })
+    }
+
+    async fn load_image(&mut self) -> Result<FsImage> {
+        let mut image = FsImage::default();
+        for pair in self
+            .scan_all(ScopedKey::inode_range(ROOT_INODE..u64::MAX))
+            .await?
+        {
+            let ino = match ScopedKey::parse(pair.key().into())? {
+                ScopedKey::Inode(ino) => ino,
+                _ => unreachable!("the keys from scanning should be always valid inode keys"),
+            };
+            let inode = match Inode::decode(ino, pair.value()) {
+                Ok(inode) => inode,
+                Err(FsError::CorruptedData { inode, detail }) => {
+                    image.corrupted.insert(inode, detail);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            image.inodes.insert(inode.ino, (inode.nlink, inode.kind));
+            if inode.tmpfile {
+                image.tmpfiles.insert(inode.ino, inode.opened_fh);
+            }
+        }
+
+        let dirs: Vec<u64> = image
+            .inodes
+            .iter()
+            .filter(|(_, (_, kind))| *kind == FileType::Directory)
+            .map(|(ino, _)| *ino)
+            .collect();
+        for ino in dirs {
+            match self.get_dir(ino).await {
+                Ok(dir) => {
+                    image.dirs.insert(ino, dir);
+                }
+                Err(FsError::BlockNotFound { .. }) => (),
+                Err(FsError::CorruptedData { inode, detail }) => {
+                    image.corrupted.insert(inode, detail);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        for pair in self.scan_all(ScopedKey::all_indexes()).await? {
+            if let ScopedKey::FileIndex { parent, name } = ScopedKey::parse(pair.key().into())? {
+                let ino = Index::deserialize(pair.value())?.ino;
+                image.indexes.insert((parent, name.to_string()), ino);
+            }
+        }
+
+        image.stored_blocks = self.count_keys(ScopedKey::all_blocks()).await?;
+        if let Some(meta) = self.read_meta().await? {
+            image.counted_blocks = self.blocks_in_use(&meta).await?;
+        }
+        Ok(image)
+    }
+
+    async fn repair_image(&mut self, image: &mut FsImage, errors: &[FsckError]) -> Result<()> {
+        let orphans: Vec<u64> = errors
+            .iter()
+            .filter_map(|err| match err {
+                FsckError::OrphanInode { ino } => Some(*ino),
+                _ => None,
+            })
+            .collect();
+        // made first, as `mkdir` saves the root directory as it is stored
+        let lost_and_found = if orphans.is_empty() {
+            None
+        } else {
+            Some(self.lost_and_found(image).await?)
+        };
+
+        let mut dirty = BTreeSet::new();
+        for err in errors {
+            match err {
+                FsckError::DanglingIndex { parent, name, .. } => {
+                    self.remove_index(*parent, name.as_str().into()).await?;
+                    image.indexes.remove(&(*parent, name.clone()));
+                }
+                FsckError::DanglingEntry { parent, name, ino } => {
+                    if let Some(dir) = image.dirs.get_mut(parent) {
+                        dir.remove(name);
+                        dirty.insert(*parent);
+                    }
+                    if image.indexes.get(&(*parent, name.clone())) == Some(ino) {
+                        self.remove_index(*parent, name.as_str().into()).await?;
+                        image.indexes.remove(&(*parent, name.clone()));
+                    }
+                }
+                FsckError::IndexMismatch {
+                    parent, name, ino, ..
+                } => {
+                    self.set_index(*parent, name.as_str().into(), *ino).await?;
+                    image.indexes.insert((*parent, name.clone()), *ino);
+                }
+                FsckError::MissingDirectory { ino } => {
+                    // the record is stored again below
+                    self.count_blocks(*ino, 1);
+                    image.dirs.insert(*ino, Directory::new());
+                    dirty.insert(*ino);
+                }
+                FsckError::OrphanBlock { ino, block } => {
+                    self.delete_orphaned_blocks(*ino, *block..*block + 1)
+                        .await?;
+                }
+                FsckError::WrongBlockCount { stored, .. } => {
+                    // blocks deleted by the repair are counted on commit
+                    if let Some(mut meta) = self.read_meta().await? {
+                        self.reset_block_usage(&mut meta, *stored).await?;
+                    }
+                }
+                FsckError::AbandonedTmpfile { ino } => {
+                    let mut inode = self.read_inode(*ino).await?;
+                    // without its placeholder link, saving deletes it with its data
+                    inode.nlink = 0;
+                    self.save_inode(&inode).await?;
+                    image.inodes.remove(ino);
+                    image.tmpfiles.remove(ino);
+                }
+                FsckError::OrphanInode { .. }
+                | FsckError::WrongNlink { .. }
+                | FsckError::Corrupted { .. } => (),
+            }
+        }
+
+        if let Some(lost_and_found) = lost_and_found {
+            for ino in orphans {
+                let name = format!("#{}", ino);
+                let kind = image.inodes[&ino].1;
+                self.set_index(lost_and_found, name.as_str().into(), ino)
+                    .await?;
+                image.indexes.insert((lost_and_found, name.clone()), ino);
+                if let Some(dir) = image.dirs.get_mut(&lost_and_found) {
+                    add_entry(dir, ino, &name, kind);
+                }
+                dirty.insert(lost_and_found);
+
+                if let Some(dir) = image.dirs.get_mut(&ino) {
+                    add_entry(dir, lost_and_found, &DIR_PARENT, FileType::Directory);
+                    self.set_index(ino, DIR_PARENT, lost_and_found).await?;
+                    image
+                        .indexes
+                        .insert((ino, DIR_PARENT.to_string()), lost_and_found);
+                    dirty.insert(ino);
+                }
+            }
+        }
+
+        for ino in dirty {
+            let inode = self.read_inode(ino).await?;
+            self.save_dir(inode, &image.dirs[&ino]).await?;
+        }
+
+        for (ino, expected) in image.link_counts() {
+            if !image.inodes.contains_key(&ino) {
+                continue;
+            }
+            let mut inode = self.read_inode(ino).await?;
+            if inode.nlink != expected {
+                inode.nlink = expected;
+                self.save_inode(&inode).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Find `/lost+found`, or make it, and load it into `image`.
+    async fn lost_and_found(&mut self, image: &mut FsImage) -> Result<u64> {
+        if let Some(ino) = image
+            .indexes
+            .get(&(ROOT_INODE, LOST_AND_FOUND.to_string()))
+            .copied()
+        {
+            return match image.inodes.get(&ino) {
+                Some((_, FileType::Directory)) => Ok(ino),
+                _ => Err(FsError::NotDirectory {
+                    file: LOST_AND_FOUND.to_string(),
+                }),
+            };
+        }
+
+        let inode = self
+            .mkdir(ROOT_INODE, LOST_AND_FOUND.into(), 0o700, 0, 0, None)
+            .await?;
+        image.inodes.insert(inode.ino, (inode.nlink, inode.kind));
+        image
+            .indexes
+            .insert((ROOT_INODE, LOST_AND_FOUND.to_string()), inode.ino);
+        let root = self.read_dir(ROOT_INODE).await?;
+        image.dirs.insert(ROOT_INODE, root);
+        let entries = self.read_dir(inode.ino).await?;
+        for item in entries.values() {
+            image
+                .indexes
+                .insert((inode.ino, item.name.clone()), item.ino);
+        }
+        image.dirs.insert(inode.ino, entries);
+        Ok(inode.ino)
+    }
+
+    #[instrument(
+        skip(self, data),
+        fields(txn_id = self.id, size = data.len(), block_count = field::Empty)
+    )]
     pub async fn write_data(&mut self, ino: u64, start: u64, data: Bytes) -> Result<usize> {
         let write_start = SystemTime::now();
         debug!("write data at ({})[{}]", ino, start);
         let meta = self.read_meta().await?.unwrap();
-        self.check_space_left(&meta)?;
+        self.check_space_left(&meta).await?;
 
         let mut inode = self.read_inode(ino).await?;
         let size = data.len();
         let target = start + size as u64;
 
-        if inode.inline_data.is_some() && target > self.inline_data_threshold() {
+        if inode.inline_data.is_some() && target.max(inode.size) > self.inline_data_threshold() {
             self.transfer_inline_data_to_block(&mut inode).await?;
         }
 
@@ -412,95 +2283,208 @@ impl Txn {
             return self.write_inline_data(&mut inode, start, &data).await;
         }
 
-        let mut block_index = start / self.block_size;
-        let start_key = ScopedKey::block(ino, block_index);
-        let start_index = (start % self.block_size) as usize;
-
-        let first_block_size = self.block_size as usize - start_index;
-
-        let (first_block, mut rest) = data.split_at(first_block_size.min(data.len()));
-
-        let mut start_value = self
-            .get(start_key)
+        if data.is_empty() {
+            return self.finish_write(inode, target, size, write_start).await;
+        }
+
+        let block_size = self.block_size();
+        // the gap between the old end and `start` must read as zeros; holes do, but the
+        // block holding the old end may keep bytes past it from before a truncate
+        if start > inode.size {
+            inode.sparse = true;
+            if let Some((index, tail)) = tail_block(block_size, inode.size) {
+                self.zero_block_tail(&inode, index, tail).await?;
+            }
+        }
+        let first_block = start / block_size;
+        let last_block = (target - 1) / block_size;
+        Span::current().record("block_count", &(last_block - first_block + 1));
+
+        // only the blocks at both ends can be partially covered; fetch their old content
+        // in a single round trip, whole blocks are overwritten without being read
+        let mut partial = Vec::with_capacity(2);
+        if start % block_size != 0 || target < (first_block + 1) * block_size {
+            partial.push(ScopedKey::block(ino, first_block));
+        }
+        if last_block != first_block && target % block_size != 0 {
+            partial.push(ScopedKey::block(ino, last_block));
+        }
+        let mut old_blocks: HashMap<u64, Vec<u8>> = HashMap::new();
+        if !partial.is_empty() {
+            self.op_count += 1;
+            let pairs: Vec<KvPair> = self.batch_get(partial).await?.collect();
+            for pair in pairs {
+                let block = if let Ok(ScopedKey::Block { ino: _, block }) =
+                    ScopedKey::parse(pair.key().into())
+                {
+                    block
+                } else {
+                    unreachable!("the keys from batch get should be always valid block keys")
+                };
+                let (data, _) = self.load_block(&inode, block, pair.into_value()).await?;
+                old_blocks.insert(block, data);
+            }
+        }
+        // every block in range gets a private copy, the shared ones have been read above
+        self.release_shared_blocks(&inode, first_block..last_block + 1)
+            .await?;
+
+        let stored = self
+            .stored_blocks(ino, first_block..last_block + 1)
             .await?
-            .unwrap_or_else(|| empty_block(self.block_size));
-
-        start_value[start_index..start_index + first_block.len()].copy_from_slice(first_block);
-
-        self.put(start_key, start_value).await?;
-
-        while !rest.is_empty() {
-            block_index += 1;
-            let key = ScopedKey::block(ino, block_index);
-            let (curent_block, current_rest) =
-                rest.split_at((self.block_size as usize).min(rest.len()));
-            let mut value = curent_block.to_vec();
-            if value.len() < self.block_size as usize {
-                let mut last_value = self
-                    .get(key)
-                    .await?
-                    .unwrap_or_else(|| empty_block(self.block_size));
-                last_value[..value.len()].copy_from_slice(&value);
-                value = last_value;
+            .len();
+        let mut blocks = Vec::with_capacity((last_block - first_block + 1) as usize);
+        for block in first_block..=last_block {
+            let block_start = block * block_size;
+            let from = start.max(block_start);
+            let to = target.min(block_start + block_size);
+            let chunk = &data[(from - start) as usize..(to - start) as usize];
+            // a full block of zeros becomes a hole
+            if to - from == block_size && is_zero(chunk) {
+                inode.sparse = true;
+                self.delete(ScopedKey::block(ino, block)).await?;
+                continue;
             }
-            self.put(key, value).await?;
-            rest = current_rest;
+            let value = if to - from == block_size {
+                chunk.to_vec()
+            } else {
+                let mut value = old_blocks
+                    .remove(&block)
+                    .unwrap_or_else(|| empty_block(block_size));
+                value.resize(block_size as usize, 0);
+                value[(from - block_start) as usize..(to - block_start) as usize]
+                    .copy_from_slice(chunk);
+                value
+            };
+            blocks.push((
+                ScopedKey::block(ino, block),
+                self.encode_file_block(&inode, value),
+            ));
         }
+        self.count_blocks(ino, blocks.len() as i64 - stored as i64);
+        self.batch_put(blocks).await?;
+
+        trace!("write data: {}", String::from_utf8_lossy(&data));
+        self.finish_write(inode, target, size, write_start).await
+    }
 
+    async fn finish_write(
+        &mut self,
+        mut inode: Inode,
+        target: u64,
+        size: usize,
+        write_start: SystemTime,
+    ) -> Result<usize> {
         inode.atime = SystemTime::now();
         inode.mtime = SystemTime::now();
         inode.ctime = SystemTime::now();
-        inode.set_size(inode.size.max(target), self.block_size);
+        if target > inode.size {
+            inode.set_size(target);
+        }
         self.save_inode(&inode).await?;
-        trace!("write data: {}", String::from_utf8_lossy(&data));
         debug!(
             "write {} bytes in {}ms",
-            data.len(),
+            size,
             write_start.elapsed().unwrap().as_millis()
         );
         Ok(size)
     }
 
+    /// Make the symlink `parent/name` to `link`, with the target inlined in the inode.
+    pub async fn symlink(
+        &mut self,
+        parent: u64,
+        name: ByteString,
+        link: ByteString,
+        gid: u32,
+        uid: u32,
+        creator_pid: Option<u32>,
+    ) -> Result<Inode> {
+        // fail before allocating an inode
+        check_link_target(link.as_bytes())?;
+        let mut inode = self
+            .make_inode(
+                parent,
+                name,
+                make_mode(FileType::Symlink, 0o777),
+                gid,
+                uid,
+                0,
+                creator_pid,
+            )
+            .await?;
+        self.write_link(&mut inode, link.into_bytes()).await?;
+        Ok(inode)
+    }
+
     pub async fn write_link(&mut self, inode: &mut Inode, data: Bytes) -> Result<usize> {
-        debug_assert!(inode.file_attr.kind == FileType::Symlink);
+        inode.check_symlink()?;
+        check_link_target(&data)?;
+        if data.len() as u64 > self.inline_data_threshold() {
+            return Err(FsError::NameTooLong {
+                file: String::from_utf8_lossy(&data).to_string(),
+            });
+        }
         inode.inline_data = None;
-        inode.set_size(0, self.block_size);
+        inode.set_size(0);
         self.write_inline_data(inode, 0, &data).await
     }
 
     pub async fn read_link(&mut self, ino: u64) -> Result<Vec<u8>> {
         let mut inode = self.read_inode(ino).await?;
-        debug_assert!(inode.file_attr.kind == FileType::Symlink);
+        inode.check_symlink()?;
         let size = inode.size;
         self.read_inline_data(&mut inode, 0, size).await
     }
 
+    #[instrument(skip(self), fields(txn_id = self.id))]
     pub async fn link(&mut self, ino: u64, newparent: u64, newname: ByteString) -> Result<Inode> {
+        // make sure `ino` belongs to this filesystem before touching the target entry
+        let kind = self.read_inode(ino).await?.kind;
+
         if let Some(old_ino) = self.get_index(newparent, newname.clone()).await? {
             let inode = self.read_inode(old_ino).await?;
             match inode.kind {
-                FileType::Directory => self.rmdir(newparent, newname.clone()).await?,
+                FileType::Directory => {
+                    // fail before any entry is touched rather than half way through
+                    self.check_dir_empty(&inode, &newname).await?;
+                    self.rmdir(newparent, newname.clone()).await?
+                }
                 _ => self.unlink(newparent, newname.clone()).await?,
             }
         }
         self.set_index(newparent, newname.clone(), ino).await?;
 
-        let mut inode = self.read_inode(ino).await?;
-        let mut dir = self.read_dir(newparent).await?;
+        let parent = self.read_inode(newparent).await?;
+        let mut dir = self.read_dir_for_update(&parent).await?;
+        self.check_dir_entries(newparent, &dir)?;
 
-        dir.push(DirItem {
-            ino,
-            name: newname.to_string(),
-            typ: inode.kind,
-        });
+        add_entry(&mut dir, ino, &newname, kind);
 
-        self.save_dir(newparent, &dir).await?;
-        inode.nlink += 1;
+        let parent = self.save_dir(parent, &dir).await?;
+        // `.` links a directory into itself
+        let mut inode = if ino == newparent {
+            parent
+        } else {
+            self.read_inode(ino).await?
+        };
+        if inode.tmpfile {
+            // the first name of a tmpfile takes over the link it was made with
+            inode.tmpfile = false;
+            inode.parent_ino = newparent;
+        } else {
+            inode.nlink += 1;
+        }
         inode.ctime = SystemTime::now();
         self.save_inode(&inode).await?;
         Ok(inode)
     }
 
+    /// Remove `parent/name`.
+    ///
+    /// Dropping the last link of an inode that isn't open deletes it in `save_inode`,
+    /// together with its blocks (the directory record of a directory) and xattrs.
+    #[instrument(skip(self), fields(txn_id = self.id))]
     pub async fn unlink(&mut self, parent: u64, name: ByteString) -> Result<()> {
         match self.get_index(parent, name.clone()).await? {
             None => Err(FsError::FileNotFound {
@@ -508,15 +2492,19 @@ impl Txn {
             }),
             Some(ino) => {
                 self.remove_index(parent, name.clone()).await?;
-                let parent_dir = self.read_dir(parent).await?;
-                let new_parent_dir: Directory = parent_dir
-                    .into_iter()
-                    .filter(|item| item.name != *name)
-                    .collect();
-                self.save_dir(parent, &new_parent_dir).await?;
+                let parent_inode = self.read_inode(parent).await?;
+                let mut parent_dir = self.read_dir_for_update(&parent_inode).await?;
+                parent_dir.remove(&*name);
+                self.save_dir(parent_inode, &parent_dir).await?;
 
                 let mut inode = self.read_inode(ino).await?;
-                inode.nlink -= 1;
+                inode.nlink = inode
+                    .nlink
+                    .checked_sub(1)
+                    .ok_or_else(|| FsError::CorruptedData {
+                        inode: ino,
+                        detail: format!("entry({}/{}) links an inode with nlink 0", parent, name),
+                    })?;
                 inode.ctime = SystemTime::now();
                 self.save_inode(&inode).await?;
                 Ok(())
@@ -524,23 +2512,20 @@ impl Txn {
         }
     }
 
+    /// Remove the empty directory `parent/name`.
+    ///
+    /// The directory record is block 0 of the directory, so the last `unlink` drops it
+    /// along with the inode when `save_inode` sees `nlink` reach 0; `fsck` reports any
+    /// record left behind as an `OrphanBlock`.
+    #[instrument(skip(self), fields(txn_id = self.id))]
     pub async fn rmdir(&mut self, parent: u64, name: ByteString) -> Result<()> {
         match self.get_index(parent, name.clone()).await? {
             None => Err(FsError::FileNotFound {
                 file: name.to_string(),
             }),
             Some(ino) => {
-                if self
-                    .read_dir(ino)
-                    .await?
-                    .iter()
-                    .any(|i| DIR_SELF != i.name && DIR_PARENT != i.name)
-                {
-                    let name_str = name.to_string();
-                    debug!("dir({}) not empty", &name_str);
-                    return Err(FsError::DirNotEmpty { dir: name_str });
-                }
-
+                let dir = self.read_inode(ino).await?;
+                self.check_dir_empty(&dir, &name).await?;
                 self.unlink(ino, DIR_SELF).await?;
                 self.unlink(ino, DIR_PARENT).await?;
                 self.unlink(parent, name).await
@@ -548,6 +2533,223 @@ impl Txn {
         }
     }
 
+    async fn check_dir_empty(&mut self, dir: &Inode, name: &str) -> Result<()> {
+        if self
+            .read_dir_for_update(dir)
+            .await?
+            .keys()
+            .any(|name| DIR_SELF != name && DIR_PARENT != name)
+        {
+            debug!("dir({}) not empty", name);
+            return Err(FsError::DirNotEmpty {
+                dir: name.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Move `parent/name` to `newparent/newname`, replacing the target if it exists.
+    ///
+    /// Both parents must be inodes of this filesystem. The kernel rejects renames across
+    /// mount points with `EXDEV` before they reach FUSE, so a parent outside of the inode
+    /// space here means the caller bypassed the VFS; report it the same way.
+    ///
+    /// Every step runs in this transaction, so a failure half way leaves both names as
+    /// they were instead of a file that is in neither or both directories.
+    #[instrument(skip(self), fields(txn_id = self.id))]
+    pub async fn rename(
+        &mut self,
+        parent: u64,
+        name: ByteString,
+        newparent: u64,
+        newname: ByteString,
+    ) -> Result<()> {
+        if parent < ROOT_INODE || newparent < ROOT_INODE {
+            return Err(FsError::CrossDevice { parent, newparent });
+        }
+
+        let ino = self.lookup(parent, name.clone()).await?;
+        let is_dir = self.read_inode(ino).await?.kind == FileType::Directory;
+        if let Some(old_ino) = self.get_index(newparent, newname.clone()).await? {
+            if old_ino == ino {
+                // both names are links of the same inode
+                return Ok(());
+            }
+            let replace_dir = self.read_inode(old_ino).await?.kind == FileType::Directory;
+            match (is_dir, replace_dir) {
+                (true, false) => {
+                    return Err(FsError::NotDirectory {
+                        file: newname.to_string(),
+                    })
+                }
+                (false, true) => {
+                    return Err(FsError::IsDirectory {
+                        file: newname.to_string(),
+                    })
+                }
+                _ => (),
+            }
+        }
+        if is_dir {
+            self.check_not_ancestor(ino, newparent, &name).await?;
+        }
+
+        self.link(ino, newparent, newname).await?;
+        self.unlink(parent, name).await?;
+        if is_dir {
+            self.unlink(ino, DIR_PARENT).await?;
+            self.link(newparent, ino, DIR_PARENT).await?;
+        }
+        let mut inode = self.read_inode(ino).await?;
+        inode.parent_ino = newparent;
+        self.save_inode(&inode).await
+    }
+
+    /// Rename as `renameat2(2)` does: `RENAME_NOREPLACE` refuses to replace an existing
+    /// target with `EEXIST`, and `RENAME_EXCHANGE` swaps two existing entries instead.
+    pub async fn rename2(
+        &mut self,
+        old_parent: u64,
+        old_name: ByteString,
+        new_parent: u64,
+        new_name: ByteString,
+        flags: u32,
+    ) -> Result<()> {
+        let exchange = flags & RENAME_EXCHANGE != 0;
+        let noreplace = flags & RENAME_NOREPLACE != 0;
+        if flags & !(RENAME_EXCHANGE | RENAME_NOREPLACE) != 0 || (exchange && noreplace) {
+            return Err(FsError::InvalidRenameFlags(flags));
+        }
+
+        if exchange {
+            return self
+                .exchange(old_parent, old_name, new_parent, new_name)
+                .await;
+        }
+        if noreplace
+            && self
+                .get_index(new_parent, new_name.clone())
+                .await?
+                .is_some()
+        {
+            return Err(FsError::FileExist {
+                file: new_name.to_string(),
+            });
+        }
+        self.rename(old_parent, old_name, new_parent, new_name)
+            .await
+    }
+
+    /// Swap `parent/name` and `newparent/newname`, both of which must exist.
+    ///
+    /// The entries are pointed at each other's inode in place, so neither name is ever
+    /// missing and no link count changes but those of parents of a directory moved to
+    /// another parent.
+    async fn exchange(
+        &mut self,
+        parent: u64,
+        name: ByteString,
+        newparent: u64,
+        newname: ByteString,
+    ) -> Result<()> {
+        if parent < ROOT_INODE || newparent < ROOT_INODE {
+            return Err(FsError::CrossDevice { parent, newparent });
+        }
+
+        let ino = self.lookup(parent, name.clone()).await?;
+        let new_ino = self.lookup(newparent, newname.clone()).await?;
+        if ino == new_ino {
+            // both names are links of the same inode
+            return Ok(());
+        }
+        let kind = self.read_inode(ino).await?.kind;
+        let new_kind = self.read_inode(new_ino).await?.kind;
+        if kind == FileType::Directory {
+            self.check_not_ancestor(ino, newparent, &name).await?;
+        }
+        if new_kind == FileType::Directory {
+            self.check_not_ancestor(new_ino, parent, &newname).await?;
+        }
+
+        self.set_index(parent, name.clone(), new_ino).await?;
+        self.set_index(newparent, newname.clone(), ino).await?;
+        self.retarget_entry(parent, &name, new_ino, new_kind)
+            .await?;
+        self.retarget_entry(newparent, &newname, ino, kind).await?;
+
+        if parent != newparent {
+            if kind == FileType::Directory {
+                self.reparent_dir(ino, parent, newparent).await?;
+            }
+            if new_kind == FileType::Directory {
+                self.reparent_dir(new_ino, newparent, parent).await?;
+            }
+        }
+
+        for (ino, parent) in [(ino, newparent), (new_ino, parent)].iter().copied() {
+            let mut inode = self.read_inode(ino).await?;
+            inode.parent_ino = parent;
+            inode.ctime = SystemTime::now();
+            self.save_inode(&inode).await?;
+        }
+        Ok(())
+    }
+
+    async fn retarget_entry(
+        &mut self,
+        parent: u64,
+        name: &str,
+        ino: u64,
+        typ: FileType,
+    ) -> Result<()> {
+        let parent_inode = self.read_inode(parent).await?;
+        let mut dir = self.read_dir_for_update(&parent_inode).await?;
+        if !retarget(&mut dir, name, ino, typ) {
+            return Err(FsError::CorruptedData {
+                inode: parent,
+                detail: format!("index({}/{}) is not listed in its directory", parent, name),
+            });
+        }
+        self.save_dir(parent_inode, &dir).await?;
+        Ok(())
+    }
+
+    /// Point the `..` of directory `ino` from `from` to `to`, moving its link along.
+    async fn reparent_dir(&mut self, ino: u64, from: u64, to: u64) -> Result<()> {
+        self.set_index(ino, DIR_PARENT, to).await?;
+        self.retarget_entry(ino, &DIR_PARENT, to, FileType::Directory)
+            .await?;
+
+        let mut from = self.read_inode(from).await?;
+        from.nlink = from.nlink.saturating_sub(1);
+        from.ctime = SystemTime::now();
+        self.save_inode(&from).await?;
+        let mut to = self.read_inode(to).await?;
+        to.nlink += 1;
+        to.ctime = SystemTime::now();
+        self.save_inode(&to).await
+    }
+
+    /// Make sure directory `ino` is not `dir` or one of its ancestors.
+    async fn check_not_ancestor(&mut self, ino: u64, dir: u64, name: &str) -> Result<()> {
+        let mut current = dir;
+        loop {
+            if current == ino {
+                return Err(FsError::InvalidRename {
+                    dir: name.to_string(),
+                });
+            }
+            if current == ROOT_INODE {
+                return Ok(());
+            }
+            match self.get_index(current, DIR_PARENT).await? {
+                Some(parent) => current = parent,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    #[instrument(skip(self), fields(txn_id = self.id))]
     pub async fn lookup(&mut self, parent: u64, name: ByteString) -> Result<u64> {
         self.get_index(parent, name.clone())
             .await?
@@ -556,12 +2758,169 @@ impl Txn {
             })
     }
 
-    pub async fn fallocate(&mut self, inode: &mut Inode, offset: i64, length: i64) -> Result<()> {
+    /// Take, change or release (`F_UNLCK`) the POSIX lock of `owner` on the bytes
+    /// `[start, end]` of `ino`, failing with `LockConflict` if another owner holds an
+    /// incompatible lock in the range.
+    pub async fn set_lock(
+        &mut self,
+        ino: u64,
+        owner: u64,
+        lock_type: i32,
+        start: u64,
+        end: u64,
+        pid: u32,
+    ) -> Result<()> {
+        let lock = Self::file_lock(owner, lock_type, start, end, pid)?;
+        let mut inode = self.read_inode(ino).await?;
+        if inode.file_attr.kind == FileType::Directory {
+            return Err(FsError::InvalidLock);
+        }
+        if inode.lock_state.conflicts(&lock).next().is_some() {
+            return Err(FsError::LockConflict { ino });
+        }
+        inode.lock_state.apply(lock);
+        self.save_inode(&inode).await?;
+        self.delete(ScopedKey::lock(owner)).await?;
+        Ok(())
+    }
+
+    /// Record that `owner` is blocked on a lock `set_lock` refused, failing with `Deadlock`
+    /// instead if an owner holding it is, directly or not, waiting for `owner`.
+    ///
+    /// The record is removed once `owner` sets any lock or by `cancel_lock_wait`; one left
+    /// by a waiter that went away is ignored after `LockWait::TTL`.
+    pub async fn wait_lock(
+        &mut self,
+        ino: u64,
+        owner: u64,
+        lock_type: i32,
+        start: u64,
+        end: u64,
+        pid: u32,
+    ) -> Result<()> {
+        let lock = Self::file_lock(owner, lock_type, start, end, pid)?;
+        let now = SystemTime::now();
+        let mut waits = vec![LockWait {
+            ino,
+            lock: lock.clone(),
+            refreshed: Some(now),
+        }];
+        let mut visited = BTreeSet::new();
+        while let Some(wait) = waits.pop() {
+            let holders: BTreeSet<u64> = self
+                .read_inode(wait.ino)
+                .await?
+                .lock_state
+                .conflicts(&wait.lock)
+                .map(|held| held.owner)
+                .collect();
+            if holders.contains(&owner) {
+                return Err(FsError::Deadlock { ino });
+            }
+            for holder in holders {
+                if !visited.insert(holder) {
+                    continue;
+                }
+                if let Some(value) = self.get(ScopedKey::lock(holder)).await? {
+                    let wait = LockWait::deserialize(&value)?;
+                    if wait.is_live(now) {
+                        waits.push(wait);
+                    }
+                }
+            }
+        }
+
+        let wait = LockWait {
+            ino,
+            lock,
+            refreshed: Some(now),
+        };
+        self.put(ScopedKey::lock(owner), wait.serialize()?).await
+    }
+
+    /// Remove the record `wait_lock` left for `owner`, who stopped waiting without the lock.
+    pub async fn cancel_lock_wait(&mut self, owner: u64) -> Result<()> {
+        self.delete(ScopedKey::lock(owner)).await
+    }
+
+    /// The locks held on `ino` by owners other than `owner`.
+    pub async fn get_lock(&mut self, ino: u64, owner: u64) -> Result<LockState> {
+        let mut state = self.read_inode(ino).await?.lock_state;
+        state.locks.retain(|held| held.owner != owner);
+        Ok(state)
+    }
+
+    fn file_lock(owner: u64, typ: i32, start: u64, end: u64, pid: u32) -> Result<FileLock> {
+        if start > end
+            || ![
+                libc::F_RDLCK as i32,
+                libc::F_WRLCK as i32,
+                libc::F_UNLCK as i32,
+            ]
+            .contains(&typ)
+        {
+            return Err(FsError::InvalidLock);
+        }
+        Ok(FileLock {
+            owner,
+            pid,
+            typ,
+            start,
+            end,
+        })
+    }
+
+    /// Allocate or deallocate space in `inode` as `fallocate(2)` does.
+    ///
+    /// Blocks are not reserved in advance, so only growing the size and punching holes have
+    /// an effect; other modes are refused with `EOPNOTSUPP`.
+    pub async fn fallocate(
+        &mut self,
+        inode: &mut Inode,
+        offset: i64,
+        length: i64,
+        mode: i32,
+    ) -> Result<()> {
+        // from linux/falloc.h; the mode reaches us unchanged whatever the platform
+        const FALLOC_FL_KEEP_SIZE: i32 = 0x01;
+        const FALLOC_FL_PUNCH_HOLE: i32 = 0x02;
+
+        if offset < 0 || length <= 0 {
+            return Err(FsError::InvalidOffset {
+                ino: inode.ino,
+                offset,
+            });
+        }
+        match mode {
+            0 => self.allocate(inode, offset, length).await,
+            FALLOC_FL_KEEP_SIZE => Ok(()),
+            _ if mode == FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE => {
+                self.punch_hole(inode, offset as u64, length as u64).await
+            }
+            _ => Err(FsError::UnsupportedFallocateMode(mode)),
+        }
+    }
+
+    async fn allocate(&mut self, inode: &mut Inode, offset: i64, length: i64) -> Result<()> {
         let target_size = (offset + length) as u64;
         if target_size <= inode.size {
             return Ok(());
         }
 
+        // a file without inline data is block-backed whatever its size, so only the
+        // size needs to grow; blocks past the old end read as holes. A small dense one
+        // must have its first block though, or a transfer of its inline data was lost.
+        if inode.inline_data.is_none()
+            && inode.size > 0
+            && inode.size <= self.inline_data_threshold()
+            && !inode.sparse
+            && !self.key_exists(ScopedKey::block(inode.ino, 0)).await?
+        {
+            return Err(FsError::CorruptedData {
+                inode: inode.ino,
+                detail: format!("no inline data nor block for {} bytes", inode.size),
+            });
+        }
         if inode.inline_data.is_some() {
             if target_size <= self.inline_data_threshold() {
                 let original_size = inode.size;
@@ -573,12 +2932,59 @@ impl Txn {
             }
         }
 
-        inode.set_size(target_size, self.block_size);
+        inode.set_size(target_size);
+        inode.sparse = true;
         inode.mtime = SystemTime::now();
         self.save_inode(inode).await?;
         Ok(())
     }
 
+    /// Deallocate `[offset, offset + length)` of `inode`, keeping its size: blocks covered
+    /// whole are deleted, the covered part of the blocks at the ends is zeroed.
+    async fn punch_hole(&mut self, inode: &mut Inode, offset: u64, length: u64) -> Result<()> {
+        let end = offset.saturating_add(length).min(inode.size);
+        if offset < end {
+            if inode.inline_data.is_some() {
+                self.transfer_inline_data_to_block(inode).await?;
+            }
+            let (whole, partial) = split_range(self.block_size(), offset, end);
+            inode.sparse = true;
+            self.delete_file_blocks(inode, whole).await?;
+            for (block, range) in partial {
+                let key = ScopedKey::block(inode.ino, block);
+                if let Some(value) = self.get(key).await? {
+                    let (mut value, shared) = self.load_block(inode, block, value).await?;
+                    if zero_range(&mut value, range) {
+                        if let Some(id) = shared {
+                            self.unref_shared_block(inode.ino, id).await?;
+                        }
+                        self.put(key, self.encode_file_block(inode, value)).await?;
+                    }
+                }
+            }
+        }
+        inode.mtime = SystemTime::now();
+        inode.ctime = SystemTime::now();
+        self.save_inode(inode).await
+    }
+
+    /// Make a regular file, device, FIFO or socket as `mknod(2)` does; devices have no
+    /// data, just the `rdev` stat reports.
+    pub async fn mknod(
+        &mut self,
+        parent: u64,
+        name: ByteString,
+        mode: u32,
+        gid: u32,
+        uid: u32,
+        rdev: u32,
+        creator_pid: Option<u32>,
+    ) -> Result<Inode> {
+        let (mode, rdev) = mknod_mode(mode, rdev).ok_or(FsError::UnknownFileType)?;
+        self.make_inode(parent, name, mode, gid, uid, rdev, creator_pid)
+            .await
+    }
+
     pub async fn mkdir(
         &mut self,
         parent: u64,
@@ -586,98 +2992,248 @@ impl Txn {
         mode: u32,
         gid: u32,
         uid: u32,
+        creator_pid: Option<u32>,
     ) -> Result<Inode> {
         let dir_mode = make_mode(FileType::Directory, mode as _);
-        let mut inode = self.make_inode(parent, name, dir_mode, gid, uid, 0).await?;
+        let mut inode = self
+            .make_inode(parent, name, dir_mode, gid, uid, 0, creator_pid)
+            .await?;
         inode.perm = mode as _;
-        self.save_inode(&inode).await?;
-        self.save_dir(inode.ino, &Directory::new()).await?;
+        let inode = self.save_dir(inode, &Directory::new()).await?;
+        // the record is the one block of a directory, counted until `save_inode` frees it
+        self.count_blocks(inode.ino, 1);
+        // `.` brings the new directory to nlink 2, `..` adds one to its parent
         self.link(inode.ino, inode.ino, DIR_SELF).await?;
         if parent >= ROOT_INODE {
             self.link(parent, inode.ino, DIR_PARENT).await?;
+        } else {
+            // the root has no entry in a parent, its `.` and `..` are its only links
+            let mut root = self.link(inode.ino, inode.ino, DIR_PARENT).await?;
+            root.nlink -= 1;
+            self.save_inode(&root).await?;
         }
         self.read_inode(inode.ino).await
     }
 
+    #[instrument(skip(self), fields(txn_id = self.id))]
     pub async fn read_dir(&mut self, ino: u64) -> Result<Directory> {
-        let data = self
-            .get(ScopedKey::block(ino, 0))
-            .await?
-            .ok_or(FsError::BlockNotFound {
-                inode: ino,
-                block: 0,
-            })?;
+        self.read_inode(ino).await?.check_dir()?;
+        self.get_dir(ino).await
+    }
+
+    /// Read the directory record of `ino`, known to be a directory.
+    async fn get_dir(&mut self, ino: u64) -> Result<Directory> {
+        let data = self.get(ScopedKey::block(ino, 0)).await?;
+        Self::decode_dir(ino, data)
+    }
+
+    /// The entries of directory `ino` with a sequence number of at least `offset`.
+    pub async fn read_dir_from(&mut self, ino: u64, offset: u64) -> Result<Directory> {
+        let mut dir = self.read_dir(ino).await?;
+        dir.retain(|_, item| item.seq >= offset);
+        Ok(dir)
+    }
+
+    /// Read a directory that is about to be modified.
+    ///
+    /// The transactional API has no compare-and-swap, so the directory key is locked
+    /// instead: if another transaction saves the same directory first, this commit fails
+    /// with a write conflict and `TiFs::spin` retries it on top of the new content.
+    pub async fn read_dir_for_update(&mut self, dir: &Inode) -> Result<Directory> {
+        dir.check_dir()?;
+        self.op_count += 1;
+        let data = self.get_for_update(ScopedKey::block(dir.ino, 0)).await?;
+        Self::decode_dir(dir.ino, data)
+    }
+
+    fn decode_dir(ino: u64, data: Option<Vec<u8>>) -> Result<Directory> {
+        let data = data.ok_or(FsError::BlockNotFound {
+            inode: ino,
+            block: 0,
+        })?;
         trace!("read data: {}", String::from_utf8_lossy(&data));
-        super::dir::decode(&data)
+        super::dir::decode(&data).map_err(|err| FsError::CorruptedData {
+            inode: ino,
+            detail: err.to_string(),
+        })
+    }
+
+    /// List a directory in `readdir_order` along with the attributes of every entry,
+    /// fetched with a single batch get.
+    ///
+    /// An entry whose inode is missing gets a placeholder attribute with inode number 0,
+    /// which the kernel takes as "no attributes" and skips, instead of failing the listing.
+    pub async fn readdir_plus(&mut self, ino: u64) -> Result<Vec<(DirItem, Entry)>> {
+        let dir = self.config.readdir_order.list(self.read_dir(ino).await?);
+
+        let inos: Vec<u64> = dir.iter().map(|item| item.ino).collect();
+        let inodes = self.batch_read_inodes(&inos).await?;
+
+        Ok(dir
+            .into_iter()
+            .map(|item| {
+                let entry = match inodes.get(&item.ino) {
+                    Some(inode) => Entry::new(inode.file_attr, inode.generation),
+                    None => Entry::new(Self::missing_attr(&item), 0),
+                };
+                (item, entry)
+            })
+            .collect())
     }
 
-    pub async fn save_dir(&mut self, ino: u64, dir: &[DirItem]) -> Result<Inode> {
+    fn missing_attr(item: &DirItem) -> FileAttr {
+        FileAttr {
+            ino: 0,
+            size: 0,
+            blocks: 0,
+            atime: SystemTime::UNIX_EPOCH,
+            mtime: SystemTime::UNIX_EPOCH,
+            ctime: SystemTime::UNIX_EPOCH,
+            crtime: SystemTime::UNIX_EPOCH,
+            kind: item.typ,
+            perm: 0,
+            nlink: 0,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 0,
+            flags: 0,
+        }
+    }
+
+    /// Store the entries of directory `inode` and stamp its times, so every change of the
+    /// entries (create, link, unlink, rmdir and rename) moves the mtime and ctime POSIX
+    /// requires of the parent.
+    pub async fn save_dir(&mut self, mut inode: Inode, dir: &Directory) -> Result<Inode> {
+        inode.check_dir()?;
+        let ino = inode.ino;
         let data = super::dir::encode(dir)?;
-        let mut inode = self.read_inode(ino).await?;
-        inode.set_size(data.len() as u64, self.block_size);
-        inode.atime = SystemTime::now();
-        inode.mtime = SystemTime::now();
-        inode.ctime = SystemTime::now();
+        inode.entries_changed(data.len() as u64, SystemTime::now());
         self.save_inode(&inode).await?;
+        self.lock_for_write(ScopedKey::block(ino, 0)).await?;
         self.put(ScopedKey::block(ino, 0), data).await?;
         Ok(inode)
     }
 
-    pub async fn statfs(&mut self) -> Result<StatFs> {
-        let bsize = self.block_size as u32;
+    /// Append many items to the directory `parent` in the order given, reading and saving
+    /// it only once, with the checks `make_inode` runs on one entry; their `seq` is
+    /// assigned anew.
+    pub async fn batch_save_dir_items(&mut self, parent: u64, items: Vec<DirItem>) -> Result<()> {
+        let parent = self.read_inode(parent).await?;
+        let mut dir = self.read_dir_for_update(&parent).await?;
+        for item in items {
+            check_file_name(&item.name)?;
+            if dir.contains_key(&item.name) {
+                return Err(FsError::FileExist { file: item.name });
+            }
+            self.check_dir_entries(parent.ino, &dir)?;
+            add_entry(&mut dir, item.ino, &item.name, item.typ);
+        }
+        self.save_dir(parent, &dir).await?;
+        Ok(())
+    }
+
+    /// Record a snapshot of the filesystem as of now under `name`.
+    pub async fn create_snapshot(&mut self, name: ByteString) -> Result<SnapshotId> {
+        let version = self.client.current_timestamp().await?.version();
+        let mut catalog = self.read_snapshot_catalog_for_update().await?;
+        let id = catalog.add(&name, version, SystemTime::now())?;
+        self.put(ScopedKey::snapshot_catalog(), catalog.serialize()?)
+            .await?;
+        Ok(id)
+    }
+
+    pub async fn list_snapshots(&mut self) -> Result<Vec<SnapshotInfo>> {
+        Ok(self.read_snapshot_catalog().await?.snapshots)
+    }
+
+    pub async fn find_snapshot(&mut self, id: SnapshotId) -> Result<SnapshotInfo> {
+        Ok(self.read_snapshot_catalog().await?.find(id)?.clone())
+    }
+
+    async fn read_snapshot_catalog(&mut self) -> Result<SnapshotCatalog> {
+        let value = self.get(ScopedKey::snapshot_catalog()).await?;
+        Self::decode_snapshot_catalog(value)
+    }
+
+    /// Read the catalog to add a snapshot to, locked as `read_dir_for_update` locks a
+    /// directory, so two snapshots taken at once can't both get the same id.
+    async fn read_snapshot_catalog_for_update(&mut self) -> Result<SnapshotCatalog> {
+        self.op_count += 1;
+        let value = self.get_for_update(ScopedKey::snapshot_catalog()).await?;
+        Self::decode_snapshot_catalog(value)
+    }
+
+    fn decode_snapshot_catalog(value: Option<Vec<u8>>) -> Result<SnapshotCatalog> {
+        match value {
+            Some(value) => SnapshotCatalog::deserialize(&value),
+            None => Ok(SnapshotCatalog::default()),
+        }
+    }
+
+    pub async fn statfs(&mut self) -> Result<FsStats> {
         let mut meta = self
             .read_meta()
             .await?
             .expect("meta should not be none after fs initialized");
-        let next_inode = meta.inode_next;
-        let (used_blocks, files) = self
-            .scan(
-                ScopedKey::inode_range(ROOT_INODE..next_inode),
-                (next_inode - ROOT_INODE) as u32,
-            )
-            .await?
-            .map(|pair| Inode::deserialize(pair.value()))
-            .try_fold((0, 0), |(blocks, files), inode| {
-                Ok::<_, FsError>((blocks + inode?.blocks, files + 1))
-            })?;
-        let ffree = std::u64::MAX - next_inode;
-        let bfree = match self.max_blocks {
-            Some(max_blocks) if max_blocks > used_blocks => max_blocks - used_blocks,
-            Some(_) => 0,
-            None => std::u64::MAX,
+        let used_blocks = if meta.used_blocks.is_none() || meta.used_inodes.is_none() {
+            // the meta record predates the usage counters; count once, they are kept
+            // up to date from then on
+            let stored = self.count_keys(ScopedKey::all_blocks()).await?;
+            meta.used_inodes = Some(
+                self.count_keys(ScopedKey::inode_range(ROOT_INODE..meta.inode_next))
+                    .await?,
+            );
+            if !self.is_read_only() {
+                self.reset_block_usage(&mut meta, stored).await?;
+            }
+            stored
+        } else {
+            self.blocks_in_use(&meta).await?.unwrap_or_default()
         };
-        let blocks = match self.max_blocks {
-            Some(max_blocks) => max_blocks,
-            None => used_blocks,
+        let (total_blocks, free_blocks) = match self.max_blocks() {
+            Some(max_blocks) => (max_blocks, max_blocks.saturating_sub(used_blocks)),
+            None => (used_blocks, std::u64::MAX),
         };
-
-        let stat = StatFs::new(
-            blocks,
-            bfree,
-            bfree,
-            files,
-            ffree,
-            bsize,
-            self.max_name_len,
-            0,
-        );
-        trace!("statfs: {:?}", stat);
-        meta.last_stat = Some(stat.clone());
-        self.save_meta(&meta).await?;
-        Ok(stat)
-    }
-}
-
-impl Deref for Txn {
-    type Target = Transaction;
-
-    fn deref(&self) -> &Self::Target {
-        &self.txn
+        let stats = FsStats {
+            total_blocks,
+            free_blocks,
+            used_inodes: meta.used_inodes.unwrap_or_default(),
+            free_inodes: std::u64::MAX - meta.inode_next + meta.free_inodes.len() as u64,
+        };
+        trace!("statfs: {:?}", stats);
+        Ok(stats)
     }
 }
 
-impl DerefMut for Txn {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.txn
+/// A transaction dropped without `commit` or `rollback`, e.g. on an early `?`, would hold
+/// its locks until they expire, so it is rolled back in the background and reported.
+impl Drop for Txn {
+    fn drop(&mut self) {
+        if self.finished || !matches!(self.txn, Backend::Transaction(_)) {
+            return;
+        }
+        warn!(
+            "txn({}) dropped without commit or rollback after {} ops",
+            self.id, self.op_count
+        );
+        // an empty read-only snapshot takes the place of the transaction moved out
+        let placeholder = Backend::Snapshot(self.client.snapshot(
+            Timestamp::from_version(0),
+            TransactionOptions::new_optimistic().read_only(),
+        ));
+        if let Backend::Transaction(mut txn) = std::mem::replace(&mut self.txn, placeholder) {
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    let id = self.id;
+                    runtime.spawn(async move {
+                        if let Err(err) = txn.rollback().await {
+                            warn!("fail to roll back dropped txn({}): {}", id, err);
+                        }
+                    });
+                }
+                Err(_) => warn!("no runtime to roll back dropped txn({})", self.id),
+            }
+        }
     }
 }
@@ -0,0 +1,143 @@
+use bytestring::ByteString;
+use libc::{XATTR_CREATE, XATTR_REPLACE};
+
+use super::error::{FsError, Result};
+
+/// Namespaces a user may set attributes in.
+pub const NAMESPACES: [&str; 4] = ["user.", "trusted.", "security.", "system."];
+
+/// Longest attribute name, as `XATTR_NAME_MAX` on linux.
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Upper bound of the name and value of one attribute together.
+pub const MAX_ENTRY_SIZE: usize = 65535;
+
+/// Check that `name` is in a known namespace and the attribute fits in an entry; a name
+/// past `MAX_NAME_LEN` is out of range, as linux reports it.
+pub fn check_name_and_size(name: &str, value_len: usize) -> Result<()> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(FsError::XattrRange {
+            len: name.len(),
+            size: MAX_NAME_LEN as u32,
+        });
+    }
+    if !NAMESPACES
+        .iter()
+        .any(|ns| name.starts_with(ns) && name.len() > ns.len())
+    {
+        return Err(FsError::UnsupportedXattr {
+            name: name.to_string(),
+        });
+    }
+    if name.len() + value_len > MAX_ENTRY_SIZE {
+        return Err(FsError::XattrTooLarge {
+            name: name.to_string(),
+            size: name.len() + value_len,
+        });
+    }
+    Ok(())
+}
+
+/// Encode names as the null-terminated list `listxattr` replies with.
+pub fn encode_names(names: &[ByteString]) -> Vec<u8> {
+    let mut list = Vec::with_capacity(names.iter().map(|name| name.len() + 1).sum());
+    for name in names {
+        list.extend_from_slice(name.as_bytes());
+        list.push(0);
+    }
+    list
+}
+
+/// Check the `XATTR_CREATE`/`XATTR_REPLACE` flags of `setxattr` against whether the
+/// attribute is already set.
+pub fn check_set_flags(ino: u64, name: &str, flags: i32, exists: bool) -> Result<()> {
+    match (flags & XATTR_CREATE != 0, flags & XATTR_REPLACE != 0) {
+        (true, true) => Err(FsError::InvalidXattrFlags(flags)),
+        (true, false) if exists => Err(FsError::XattrExist {
+            ino,
+            name: name.to_string(),
+        }),
+        (false, true) if !exists => Err(FsError::XattrNotFound {
+            ino,
+            name: name.to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME: &str = "user.tag";
+
+    #[test]
+    fn no_flags_always_pass() {
+        assert!(check_set_flags(1, NAME, 0, false).is_ok());
+        assert!(check_set_flags(1, NAME, 0, true).is_ok());
+    }
+
+    #[test]
+    fn create_fails_if_exists() {
+        assert!(check_set_flags(1, NAME, XATTR_CREATE, false).is_ok());
+        let err = check_set_flags(1, NAME, XATTR_CREATE, true).unwrap_err();
+        assert!(matches!(err, FsError::XattrExist { .. }));
+        assert_eq!(libc::c_int::from(err), libc::EEXIST);
+    }
+
+    #[test]
+    fn replace_fails_if_missing() {
+        assert!(check_set_flags(1, NAME, XATTR_REPLACE, true).is_ok());
+        let err = check_set_flags(1, NAME, XATTR_REPLACE, false).unwrap_err();
+        assert!(matches!(err, FsError::XattrNotFound { .. }));
+        assert_eq!(libc::c_int::from(err), libc::ENODATA);
+    }
+
+    #[test]
+    fn names_need_a_known_namespace() {
+        for name in [
+            "user.tag",
+            "trusted.a",
+            "security.selinux",
+            "system.posix_acl_access",
+        ]
+        .iter()
+        {
+            assert!(check_name_and_size(name, 0).is_ok(), "{}", name);
+        }
+        for name in ["tag", "user.", "tifs.creator_pid", "users.tag", ""].iter() {
+            let err = check_name_and_size(name, 0).unwrap_err();
+            assert_eq!(libc::c_int::from(err), libc::EOPNOTSUPP, "{}", name);
+        }
+    }
+
+    #[test]
+    fn entry_size_is_bounded() {
+        let value_len = MAX_ENTRY_SIZE - NAME.len();
+        assert!(check_name_and_size(NAME, value_len).is_ok());
+        let err = check_name_and_size(NAME, value_len + 1).unwrap_err();
+        assert_eq!(libc::c_int::from(err), libc::E2BIG);
+
+        let long_name = format!("user.{}", "x".repeat(MAX_NAME_LEN));
+        let err = check_name_and_size(&long_name, 0).unwrap_err();
+        assert_eq!(libc::c_int::from(err), libc::ERANGE);
+    }
+
+    #[test]
+    fn encode_many_names() {
+        let names: Vec<ByteString> = (0..128).map(|i| format!("user.attr{}", i).into()).collect();
+        let list = encode_names(&names);
+        let decoded: Vec<&[u8]> = list[..list.len() - 1].split(|byte| *byte == 0).collect();
+        assert_eq!(decoded.len(), names.len());
+        for (encoded, name) in decoded.iter().zip(&names) {
+            assert_eq!(*encoded, name.as_bytes());
+        }
+        assert!(encode_names(&[]).is_empty());
+    }
+
+    #[test]
+    fn create_and_replace_conflict() {
+        let err = check_set_flags(1, NAME, XATTR_CREATE | XATTR_REPLACE, true).unwrap_err();
+        assert_eq!(libc::c_int::from(err), libc::EINVAL);
+    }
+}
@@ -6,8 +6,8 @@ pub mod fs;
 use std::path::PathBuf;
 
 use fs::async_fs::AsyncFs;
+use fs::builder::TiFsBuilder;
 use fs::client::TlsConfig;
-use fs::tikv_fs::TiFs;
 use fuser::MountOption as FuseMountOption;
 use paste::paste;
 use tokio::fs::{metadata, read_to_string};
@@ -133,10 +133,28 @@ define_options! { MountOption (FuseMountOption) {
     builtin Exec,
     builtin NoExec,
     builtin DirSync,
+    builtin NoAtime,
     define "direct_io" DirectIO,
     define BlkSize(String),
     define MaxSize(String), // size of filesystem
+    define "inline_threshold" InlineThreshold(String),
     define Tls(String),
+    define "readdir_order" ReaddirOrder(String),
+    define "strict_reads" StrictReads,
+    define "relatime" RelAtime,
+    define "strictatime" StrictAtime,
+    define "max_dir_entries" MaxDirEntries(u64),
+    define "max_read_size" MaxReadSize(String),
+    define "enable_audit_xattrs" AuditXattrs,
+    define "batch_get_threshold" BatchGetThreshold(u64),
+    define "pessimistic_txn" PessimisticTxn,
+    define "max_free_inodes" MaxFreeInodes(u64),
+    define "compression" Compression(String),
+    define "encryption_key" EncryptionKey(String),
+    define "inode_cache_size" InodeCacheSize(u64),
+    define "inode_cache_ttl_ms" InodeCacheTtlMs(u64),
+    define Snapshot(u64),
+    define Label(String),
 //    define "opt" OptionName(Display_Debug_Clone_PartialEq_FromStr_able)
 }}
 
@@ -239,6 +257,13 @@ mod tests {
             ),
             "[DirectIO, NoDev, BlkSize(32)]"
         );
+        assert_eq!(
+            format!(
+                "{:?}",
+                MountOption::to_vec(vec!["readdir_order=name"].iter().copied())
+            ),
+            "[ReaddirOrder(\"name\")]"
+        );
     }
 
     #[test]
@@ -290,6 +315,12 @@ where
     fuse_options.push(FuseMountOption::AutoUnmount);
 
     fuse_options.extend(MountOption::collect_builtin(options.iter()));
+    if options
+        .iter()
+        .any(|opt| matches!(opt, MountOption::Snapshot(_)))
+    {
+        fuse_options.push(FuseMountOption::RO);
+    }
 
     let tls_cfg_path = options
         .iter()
@@ -302,15 +333,16 @@ where
         })
         .unwrap_or_else(default_tls_config_path)?;
 
-    let client_cfg = if metadata(&tls_cfg_path).await.is_ok() {
+    let mut builder = TiFsBuilder::new()
+        .with_pd_endpoints(endpoints.into_iter().map(Into::into).collect())
+        .with_options(options);
+    if metadata(&tls_cfg_path).await.is_ok() {
         let client_cfg_contents = read_to_string(tls_cfg_path).await?;
-        toml::from_str::<TlsConfig>(&client_cfg_contents)?.into()
-    } else {
-        Default::default()
-    };
+        builder = builder.with_tls_config(toml::from_str::<TlsConfig>(&client_cfg_contents)?);
+    }
 
-    debug!("use tikv client config: {:?}", client_cfg);
-    let fs_impl = TiFs::construct(endpoints, client_cfg, options).await?;
+    debug!("use tikv client config: {:?}", builder);
+    let fs_impl = builder.build().await?;
 
     make_daemon()?;
 